@@ -1,12 +1,32 @@
-use std::{collections::VecDeque, fmt::Display, sync::Arc, usize};
+use futures_util::FutureExt;
+use std::{
+    any::Any,
+    collections::VecDeque,
+    fmt::Display,
+    panic::AssertUnwindSafe,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use strum::Display;
 use thiserror::Error;
 
-use tokio::sync::{self, mpsc, oneshot};
-use tracing::{error, warn};
+use tokio::{
+    sync::{mpsc, oneshot, watch, Notify},
+    time::MissedTickBehavior,
+};
+use tracing::{debug, error, info};
 use uuid::Uuid;
 
-use crate::routes::sse::SseEvent;
+use crate::{
+    event_log::{EventKind, EventLog},
+    pins::PinnedSong,
+    routes::sse::{SseBroadcaster, SseEvent},
+    settings::KeyResetPolicy,
+    utils::{
+        slug::{song_key, sort_key},
+        yt_downloader::TrimRange,
+    },
+};
 
 fn serialize_uuid<S>(uuid: &Uuid, serializer: S) -> Result<S::Ok, S::Error>
 where
@@ -15,9 +35,12 @@ where
     serializer.serialize_str(uuid.to_string().as_str())
 }
 
-#[derive(Clone, serde::Serialize, PartialEq, Display)]
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, PartialEq, Display)]
 pub enum QueuedSongStatus {
+    // waiting in the download backlog
     InProgress,
+    // picked up by a download consumer
+    Downloading,
     Failed,
     Success,
 }
@@ -29,7 +52,29 @@ pub struct Song {
     pub uuid: Uuid,
     pub yt_link: String,
     pub status: QueuedSongStatus,
-    pub is_key_changeable: bool
+    pub is_key_changeable: bool,
+    pub trim: Option<TrimRange>,
+    pub duration_seconds: Option<f64>,
+    // assigned by the actor and increasing along the queue, so clients can
+    // sort deterministically
+    pub seq: u64,
+    // assigned by the actor from the name, for sorting by title
+    pub sort_key: String,
+    // kept through queue clears, and its download through asset eviction
+    pub pinned: bool,
+    // why the download failed, shown to guests. Only set while Failed
+    pub error: Option<String>,
+    // the title as queued, when queue.clean_titles changed it
+    pub original_name: Option<String>,
+    // the key the song starts in under the `prefer_song` key reset policy
+    pub preferred_key: Option<i8>,
+    // the song's asset folder, from the title as queued and the link. The
+    // files are served from `/dash/<folder>/<file>`
+    pub folder: String,
+    // bumped whenever the song's download is dispatched again, so a download
+    // finishing for an earlier dispatch can tell it was superseded
+    #[serde(skip)]
+    pub generation: u64,
 }
 
 impl Display for Song {
@@ -43,15 +88,36 @@ impl Display for Song {
 }
 
 impl Song {
-    pub fn new(name: String, yt_link: String, status: QueuedSongStatus, is_key_changeable: bool) -> Self {
+    pub fn new(
+        name: String,
+        yt_link: String,
+        status: QueuedSongStatus,
+        is_key_changeable: bool,
+        trim: Option<TrimRange>,
+    ) -> Self {
         Song {
+            folder: song_key(&name, &yt_link),
             name: name.to_string(),
             uuid: Uuid::new_v4(),
             yt_link,
             status,
-            is_key_changeable
+            is_key_changeable,
+            trim,
+            duration_seconds: None,
+            seq: 0,
+            sort_key: String::new(),
+            pinned: false,
+            error: None,
+            original_name: None,
+            preferred_key: None,
+            generation: 0,
         }
     }
+
+    /// The title as queued, before `queue.clean_titles` changed it.
+    pub fn title(&self) -> &str {
+        self.original_name.as_deref().unwrap_or(&self.name)
+    }
 }
 
 impl PartialEq for Song {
@@ -60,11 +126,138 @@ impl PartialEq for Song {
     }
 }
 
+/// Server-side estimate of where the front song is in its playback, based on
+/// wall-clock time since play/restart with paused time excluded.
+#[derive(Clone, Default)]
+struct PlaybackState {
+    is_playing: bool,
+    // advance to the next song once the current one reaches its duration
+    autoplay: bool,
+    song_uuid: Option<Uuid>,
+    elapsed: Duration,
+    resumed_at: Option<Instant>,
+    // `SongEnding` was sent for this play of the song
+    ending_signaled: bool,
+}
+
+impl PlaybackState {
+    fn position(&self) -> Duration {
+        self.elapsed + self.resumed_at.map(|t| t.elapsed()).unwrap_or_default()
+    }
+
+    fn toggle(&mut self) {
+        if self.is_playing {
+            self.elapsed = self.position();
+            self.resumed_at = None;
+        } else {
+            self.resumed_at = Some(Instant::now());
+        }
+        self.is_playing = !self.is_playing;
+    }
+
+    fn restart(&mut self) {
+        self.elapsed = Duration::ZERO;
+        self.resumed_at = self.is_playing.then(Instant::now);
+        self.ending_signaled = false;
+    }
+
+    fn change_song(&mut self, song_uuid: Option<Uuid>) {
+        self.song_uuid = song_uuid;
+        self.restart();
+    }
+}
+
+/// The keys that can be selected, `playback.max_key_shift` semitones either
+/// way. Keys outside `transcode.pitch_shifts` are encoded when first needed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyRange {
+    pub lowest: i8,
+    pub highest: i8,
+}
+
+impl KeyRange {
+    pub fn new(max_key_shift: u8) -> Self {
+        let max_key_shift = max_key_shift.min(i8::MAX as u8) as i8;
+        KeyRange {
+            lowest: -max_key_shift,
+            highest: max_key_shift,
+        }
+    }
+
+    pub fn contains(&self, key: i8) -> bool {
+        (self.lowest..=self.highest).contains(&key)
+    }
+}
+
+/// Which keys a room's songs can be played in, and what the key is set to
+/// when a new song starts.
+#[derive(Debug, Clone, Copy)]
+pub struct KeyPolicy {
+    pub range: KeyRange,
+    pub reset: KeyResetPolicy,
+}
+
+/// How a room's ticker paces playback and the moves between songs.
+#[derive(Clone, Copy)]
+pub struct PlaybackTiming {
+    pub tick_interval: Duration,
+    // pause after a song's end before autoplay moves on
+    pub gap: Duration,
+    // how long before autoplay moves on `SongEnding` is sent, zero disables it
+    pub ending_lead: Duration,
+}
+
+/// The result of moving past the song now playing.
+pub struct Advanced {
+    pub finished: Option<Song>,
+    // the song now at the front, which may still be downloading when no
+    // queued song is ready
+    pub now_playing: Option<Song>,
+}
+
+/// Everything a freshly connected client needs to render the session.
+#[derive(Clone, serde::Serialize)]
+pub struct SessionSnapshot {
+    pub queue: VecDeque<Song>,
+    // plays after the song now playing, for "up next" displays
+    pub up_next: Option<Song>,
+    pub current_key: i8,
+    pub is_playing: bool,
+    pub position_seconds: f64,
+    pub autoplay: bool,
+}
+
 struct SongActor {
     receiver: mpsc::Receiver<SongActorMessage>,
     song_deque: VecDeque<Song>,
     current_key: i8,
-    sse_broadcaster: Arc<sync::broadcast::Sender<SseEvent>>,
+    max_queue_length: Option<usize>,
+    // whether the song now playing can be queued again as a replay
+    allow_requeue_playing: bool,
+    next_seq: u64,
+    // leading words ignored when sorting by title
+    sort_articles: Vec<String>,
+    event_log: EventLog,
+    playback: PlaybackState,
+    key_range: KeyRange,
+    key_reset: KeyResetPolicy,
+    sse_broadcaster: Arc<SseBroadcaster>,
+    // wakes handlers long-polling for a song once one is queued
+    song_queued: Arc<Notify>,
+    // the pinned songs in queue order, for persisting them
+    pins: watch::Sender<Vec<PinnedSong>>,
+    checkpoint: Checkpoint,
+}
+
+/// The queue as it stood after the last message or tick that completed,
+/// which the actor is rolled back to when a handler panics part way through
+/// an edit.
+#[derive(Default)]
+struct Checkpoint {
+    song_deque: VecDeque<Song>,
+    current_key: i8,
+    next_seq: u64,
+    playback: PlaybackState,
 }
 
 pub enum SongActorMessage {
@@ -77,11 +270,31 @@ pub enum SongActorMessage {
         respond_to: oneshot::Sender<()>,
     },
     PopSong {
-        respond_to: oneshot::Sender<Option<Song>>,
+        respond_to: oneshot::Sender<Advanced>,
     },
     Reposition {
         song_uuid: Uuid,
-        position: usize,
+        position: isize,
+        respond_to: oneshot::Sender<Result<usize, SongCoordinatorError>>,
+    },
+    MoveToEnd {
+        song_uuid: Uuid,
+        respond_to: oneshot::Sender<Result<(), SongCoordinatorError>>,
+    },
+    ReorderAll {
+        ordered_uuids: Vec<Uuid>,
+        respond_to: oneshot::Sender<()>,
+    },
+    ReplaceQueue {
+        songs: Vec<Song>,
+        respond_to: oneshot::Sender<Result<(), SongCoordinatorError>>,
+    },
+    ClearQueue {
+        respond_to: oneshot::Sender<Vec<Song>>,
+    },
+    SetPinned {
+        song_uuid: Uuid,
+        pinned: bool,
         respond_to: oneshot::Sender<Result<(), SongCoordinatorError>>,
     },
     Current {
@@ -104,6 +317,65 @@ pub enum SongActorMessage {
         status: QueuedSongStatus,
         respond_to: oneshot::Sender<Result<(), SongCoordinatorError>>,
     },
+    UpdateSongDuration {
+        song_uuid: Uuid,
+        duration_seconds: f64,
+        respond_to: oneshot::Sender<()>,
+    },
+    FailSong {
+        song_uuid: Uuid,
+        reason: String,
+        respond_to: oneshot::Sender<Result<(), SongCoordinatorError>>,
+    },
+    GetSong {
+        song_uuid: Uuid,
+        respond_to: oneshot::Sender<Option<Song>>,
+    },
+    RetrySong {
+        song_uuid: Uuid,
+        respond_to: oneshot::Sender<Result<Song, SongCoordinatorError>>,
+    },
+    UpdateSong {
+        song_uuid: Uuid,
+        name: Option<String>,
+        // the title as submitted, when `name` was shortened from it
+        original_name: Option<String>,
+        is_key_changeable: Option<bool>,
+        respond_to: oneshot::Sender<Result<UpdatedSong, SongCoordinatorError>>,
+    },
+    TogglePlayback {
+        respond_to: oneshot::Sender<()>,
+    },
+    RestartSong {
+        respond_to: oneshot::Sender<()>,
+    },
+    ToggleAutoplay {
+        respond_to: oneshot::Sender<bool>,
+    },
+    GetSession {
+        respond_to: oneshot::Sender<SessionSnapshot>,
+    },
+    GetUpNext {
+        respond_to: oneshot::Sender<Option<Song>>,
+    },
+    PeekUpcoming {
+        count: usize,
+        respond_to: oneshot::Sender<Vec<Song>>,
+    },
+    Ping {
+        respond_to: oneshot::Sender<()>,
+    },
+    // makes the handler panic, for testing recovery
+    #[cfg(test)]
+    Panic {
+        respond_to: oneshot::Sender<()>,
+    },
+}
+
+/// A song after an edit, and whether the edit invalidated its download.
+pub struct UpdatedSong {
+    pub song: Song,
+    pub needs_download: bool,
 }
 
 #[derive(Error, Debug)]
@@ -114,6 +386,12 @@ pub enum SongCoordinatorError {
     #[error("song already queued: {name}")]
     SongAlreadyQueued { name: String },
 
+    #[error("song is playing now: {name}")]
+    SongAlreadyPlaying { name: String },
+
+    #[error("queue is full, max length: {max_length}")]
+    QueueFull { max_length: usize },
+
     #[error("unable to remove song: {uuid}")]
     RemoveSongFailed { uuid: Uuid },
 
@@ -135,47 +413,359 @@ pub enum SongCoordinatorError {
     #[error("unable to key down")]
     KeyDownFailed,
 
-    #[error("unable to update song status for: {uuid}")]
-    UpdateSongStatusFailed { uuid: Uuid },
+    #[error("song not found: {uuid}")]
+    SongNotFound { uuid: Uuid },
+
+    #[error("song is currently playing: {uuid}")]
+    SongIsPlaying { uuid: Uuid },
+
+    #[error("only failed songs can be retried, {uuid} is {status}")]
+    SongNotFailed { uuid: Uuid, status: QueuedSongStatus },
 
     #[error("failed to broadcast SSE event")]
     SseBroadcastFailed,
+
+    #[error("song actor is unavailable")]
+    ActorUnavailable,
 }
 
 impl SongActor {
     fn new(
         receiver: mpsc::Receiver<SongActorMessage>,
-        sse_broadcaster: Arc<sync::broadcast::Sender<SseEvent>>,
+        sse_broadcaster: Arc<SseBroadcaster>,
+        max_queue_length: Option<usize>,
+        allow_requeue_playing: bool,
+        sort_articles: Vec<String>,
+        event_log: EventLog,
+        keys: KeyPolicy,
     ) -> Self {
         SongActor {
             receiver,
             sse_broadcaster,
             song_deque: VecDeque::new(),
             current_key: 0,
+            max_queue_length,
+            allow_requeue_playing,
+            next_seq: 0,
+            sort_articles,
+            event_log,
+            playback: PlaybackState::default(),
+            key_range: keys.range,
+            key_reset: keys.reset,
+            song_queued: Arc::new(Notify::new()),
+            pins: watch::Sender::new(Vec::new()),
+            checkpoint: Checkpoint::default(),
+        }
+    }
+
+    /// Records the current queue as the state to recover to.
+    fn save_checkpoint(&mut self) {
+        self.checkpoint = Checkpoint {
+            song_deque: self.song_deque.clone(),
+            current_key: self.current_key,
+            next_seq: self.next_seq,
+            playback: self.playback.clone(),
+        };
+    }
+
+    fn restore_checkpoint(&mut self) {
+        let checkpoint = &self.checkpoint;
+        self.song_deque = checkpoint.song_deque.clone();
+        self.current_key = checkpoint.current_key;
+        self.next_seq = checkpoint.next_seq;
+        self.playback = checkpoint.playback.clone();
+    }
+
+    /// Broadcasts an SSE event. It's sent even with no browser connected,
+    /// since it's kept for clients that reconnect.
+    fn broadcast(&self, event: SseEvent) {
+        self.sse_broadcaster.send(event);
+    }
+
+    fn take_seq(&mut self) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        seq
+    }
+
+    /// Gives every song a fresh seq in queue order after songs are moved.
+    fn resequence(&mut self) {
+        for i in 0..self.song_deque.len() {
+            self.song_deque[i].seq = self.take_seq();
+        }
+    }
+
+    fn broadcast_queue(&self) {
+        self.broadcast(SseEvent::QueueUpdated {
+            queue: self.song_deque.clone(),
+        });
+
+        let pinned = self
+            .song_deque
+            .iter()
+            .filter(|song| song.pinned)
+            .map(PinnedSong::from_song)
+            .collect::<Vec<_>>();
+        self.pins.send_if_modified(|pins| {
+            let changed = *pins != pinned;
+            *pins = pinned;
+            changed
+        });
+    }
+
+    /// Resets the playback position whenever the front of the queue changes.
+    fn sync_now_playing(&mut self) {
+        let front_uuid = self.song_deque.front().map(|song| song.uuid);
+        if front_uuid != self.playback.song_uuid {
+            self.playback.change_song(front_uuid);
+        }
+    }
+
+    /// Drops the song now playing so the next one moves to the front. A
+    /// next song that can't stream yet is passed over for the first
+    /// downloaded one, or failing that the first still downloading, and the
+    /// songs passed keep their order behind it.
+    fn advance(&mut self) -> Option<Song> {
+        let finished_song = self.song_deque.pop_front();
+        if let Some(song) = &finished_song {
+            let reached_end = self.playback.song_uuid == Some(song.uuid)
+                && song
+                    .duration_seconds
+                    .is_some_and(|duration| self.playback.position().as_secs_f64() >= duration);
+            let kind = if reached_end {
+                EventKind::Finished
+            } else {
+                EventKind::Skipped
+            };
+            self.event_log.record(kind, song, None);
+        }
+
+        let ready_index = self
+            .song_deque
+            .iter()
+            .position(|song| song.status == QueuedSongStatus::Success)
+            // failed songs wait behind anything that may still play
+            .or_else(|| {
+                self.song_deque
+                    .iter()
+                    .position(|song| song.status != QueuedSongStatus::Failed)
+            });
+        if let Some(index) = ready_index.filter(|index| *index > 0) {
+            let song = self.song_deque.remove(index).unwrap();
+            info!(
+                "moving song: {} ahead of songs that can't play yet",
+                song.uuid
+            );
+            self.song_deque.push_front(song);
+            self.resequence();
+        }
+
+        self.reset_key();
+
+        self.broadcast_queue();
+        finished_song
+    }
+
+    /// Sets the key for the song now at the front as the key reset policy
+    /// says and tells clients.
+    fn reset_key(&mut self) {
+        self.current_key = match self.key_reset {
+            KeyResetPolicy::Always => 0,
+            KeyResetPolicy::PreferSong => self
+                .song_deque
+                .front()
+                .and_then(|song| song.preferred_key)
+                // pinned before the range was narrowed
+                .filter(|key| self.key_range.contains(*key))
+                .unwrap_or(0),
+            KeyResetPolicy::Persist => self.current_key,
+        };
+        self.broadcast(SseEvent::KeyChange {
+            current_key: self.current_key,
+        });
+    }
+
+    /// Seconds until autoplay moves past the song now playing, `None` when
+    /// it won't because autoplay is off, playback is paused or the song's
+    /// duration is unknown.
+    fn seconds_until_advance(&self, gap: Duration) -> Option<f64> {
+        if !self.playback.autoplay || !self.playback.is_playing {
+            return None;
+        }
+
+        let song = self.song_deque.front()?;
+        // the position only tracks the front song once it has been synced,
+        // and a restart resets it, so neither can advance twice
+        if self.playback.song_uuid != Some(song.uuid) {
+            return None;
+        }
+        let duration_seconds = song.duration_seconds?;
+        Some(duration_seconds + gap.as_secs_f64() - self.playback.position().as_secs_f64())
+    }
+
+    /// Advances once the song now playing has reached its duration and the
+    /// gap after it while autoplay is on. Returns whether the queue advanced.
+    fn auto_advance(&mut self, gap: Duration) -> bool {
+        if self
+            .seconds_until_advance(gap)
+            .is_none_or(|remaining| remaining > 0.0)
+        {
+            return false;
+        }
+
+        if let Some(song) = self.advance() {
+            info!("autoplay finished song: {}", song.uuid);
+        }
+        self.sync_now_playing();
+        true
+    }
+
+    fn session_snapshot(&self) -> SessionSnapshot {
+        SessionSnapshot {
+            queue: self.song_deque.clone(),
+            up_next: self.song_deque.get(1).cloned(),
+            current_key: self.current_key,
+            is_playing: self.playback.is_playing,
+            position_seconds: self.playback.position().as_secs_f64(),
+            autoplay: self.playback.autoplay,
+        }
+    }
+
+    /// Tells clients once per play that autoplay is about to move past the
+    /// song now playing, so they can fade it out. Only a signal, the audio
+    /// itself is left to the clients.
+    fn signal_song_ending(&mut self, timing: &PlaybackTiming) {
+        if timing.ending_lead.is_zero() || self.playback.ending_signaled {
+            return;
+        }
+        let Some(in_seconds) = self.seconds_until_advance(timing.gap) else {
+            return;
+        };
+        if in_seconds > timing.ending_lead.as_secs_f64() {
+            return;
         }
+        let Some(song) = self.song_deque.front() else {
+            return;
+        };
+
+        self.playback.ending_signaled = true;
+        self.broadcast(SseEvent::SongEnding {
+            uuid: song.uuid.to_string(),
+            in_seconds: in_seconds.max(0.0),
+        });
+    }
+
+    fn broadcast_playback_position(&self) {
+        if !self.playback.is_playing {
+            return;
+        }
+
+        let Some(song) = self.song_deque.front() else {
+            return;
+        };
+
+        let mut position_seconds = self.playback.position().as_secs_f64();
+        if let Some(duration_seconds) = song.duration_seconds {
+            position_seconds = position_seconds.min(duration_seconds);
+        }
+
+        self.broadcast(SseEvent::PlaybackPosition {
+            uuid: song.uuid.to_string(),
+            position_seconds,
+        });
+    }
+
+    /// Applies an edit to a queued song. The song now playing can't be edited
+    /// since its files are already being streamed.
+    fn update_song(
+        &mut self,
+        song_uuid: Uuid,
+        name: Option<String>,
+        original_name: Option<String>,
+        is_key_changeable: Option<bool>,
+    ) -> Result<UpdatedSong, SongCoordinatorError> {
+        let index = self
+            .song_deque
+            .iter()
+            .position(|song| song.uuid == song_uuid)
+            .ok_or(SongCoordinatorError::SongNotFound { uuid: song_uuid })?;
+        if index == 0 {
+            return Err(SongCoordinatorError::SongIsPlaying { uuid: song_uuid });
+        }
+
+        if let Some(name) = &name {
+            if self.song_deque.iter().any(|song| song.uuid != song_uuid && song.name == *name) {
+                return Err(SongCoordinatorError::SongAlreadyQueued { name: name.clone() });
+            }
+        }
+
+        let song = &mut self.song_deque[index];
+        let mut needs_download = false;
+        if let Some(name) = name {
+            let original_name = original_name.filter(|original| *original != name);
+            let title = original_name.as_deref().unwrap_or(&name);
+            if song.status == QueuedSongStatus::Success && name != song.name {
+                // already downloaded, so only the display name changes and
+                // the folder stays keyed by the title it was fetched under
+                song.sort_key = sort_key(&name, &self.sort_articles);
+                song.original_name = Some(song.title().to_string()).filter(|title| *title != name);
+                song.name = name;
+            } else if song.status != QueuedSongStatus::Success && title != song.title() {
+                song.sort_key = sort_key(&name, &self.sort_articles);
+                song.folder = song_key(title, &song.yt_link);
+                song.original_name = original_name;
+                song.name = name;
+                needs_download = true;
+            }
+        }
+        if let Some(is_key_changeable) = is_key_changeable.filter(|k| *k != song.is_key_changeable) {
+            song.is_key_changeable = is_key_changeable;
+            needs_download = true;
+        }
+        // the download folder is keyed by title and mode, so either change
+        // needs a fresh download
+        if needs_download {
+            song.status = QueuedSongStatus::InProgress;
+            song.error = None;
+            song.generation += 1;
+        }
+
+        Ok(UpdatedSong {
+            song: song.clone(),
+            needs_download,
+        })
     }
 
     async fn handle_message(&mut self, msg: SongActorMessage) {
         match msg {
             SongActorMessage::QueueSong { song, respond_to } => {
-                if self.song_deque.contains(&song) {
-
+                // the front song is the one playing, checked on its own so a
+                // replay of it can be allowed
+                let is_playing = self
+                    .song_deque
+                    .front()
+                    .is_some_and(|playing| *playing == song || playing.yt_link == song.yt_link);
+                if self.song_deque.iter().skip(1).any(|queued| *queued == song) {
                     let _ = respond_to.send(Err(SongCoordinatorError::SongAlreadyQueued { name: song.name }));
+                } else if is_playing && !self.allow_requeue_playing {
+                    let _ = respond_to.send(Err(SongCoordinatorError::SongAlreadyPlaying {
+                        name: song.name,
+                    }));
+                } else if let Some(max_length) = self
+                    .max_queue_length
+                    .filter(|max_length| self.song_deque.len() >= *max_length)
+                {
+                    let _ = respond_to.send(Err(SongCoordinatorError::QueueFull { max_length }));
                 } else {
-                    self.song_deque.push_back(song.clone());
-
-                    match self.sse_broadcaster.send(SseEvent::QueueUpdated {
-                        queue: self.song_deque.clone(),
-                    }) {
-                        Ok(_) => {
-                            let _ = respond_to.send(Ok(()));
-                        }
-                        Err(err) => {
-                            // Remove the song since broadcasting failed
-                            warn!("failed to broadcast SSE event for queue update event for song: {} with error: {}", song.uuid, err);
-                            let _ = respond_to.send(Ok(()));
-                        }
-                    }
+                    let mut song = song;
+                    song.seq = self.take_seq();
+                    song.sort_key = sort_key(&song.name, &self.sort_articles);
+                    self.event_log.record(EventKind::Queued, &song, None);
+                    self.song_deque.push_back(song);
+                    self.broadcast_queue();
+                    self.song_queued.notify_waiters();
+
+                    let _ = respond_to.send(Ok(()));
                 }
             }
             SongActorMessage::RemoveSong {
@@ -183,42 +773,20 @@ impl SongActor {
                 respond_to,
             } => {
                 if let Some(index) = self.song_deque.iter().position(|x| x.uuid == song_uuid) {
-                    self.song_deque.remove(index);
-                }
-
-                match self.sse_broadcaster.send(SseEvent::QueueUpdated {
-                    queue: self.song_deque.clone(),
-                }) {
-                    Ok(_) => {
-                        let _ = respond_to.send(());
-                    }
-                    Err(err) => {
-                        warn!(
-                            "failed to broadcast SSE event for queue update event for song: {} with error: {}", 
-                            song_uuid, 
-                            err
-                        );
-                        let _ = respond_to.send(());
+                    if let Some(song) = self.song_deque.remove(index) {
+                        self.event_log.record(EventKind::Removed, &song, None);
                     }
                 }
+
+                self.broadcast_queue();
+                let _ = respond_to.send(());
             }
             SongActorMessage::PopSong { respond_to } => {
-                // remove all failed songs while getting the next one
-                let next_song = self.song_deque.pop_front();
-
-                self.current_key = 0;
-
-                match self.sse_broadcaster.send(SseEvent::QueueUpdated {
-                    queue: self.song_deque.clone(),
-                }) {
-                    Ok(_) => {
-                        let _ = respond_to.send(next_song.clone());
-                    }
-                    Err(err) => {
-                        warn!("failed to broadcast SSE event for queue update event with error: {}", err);
-                        let _ = respond_to.send(next_song.clone());
-                    }
-                }
+                let finished = self.advance();
+                let _ = respond_to.send(Advanced {
+                    finished,
+                    now_playing: self.song_deque.front().cloned(),
+                });
             }
             SongActorMessage::Reposition {
                 song_uuid,
@@ -227,26 +795,111 @@ impl SongActor {
             } => {
                 if let Some(current_index) = self.song_deque.iter().position(|x| x.uuid == song_uuid) {
                     let song = self.song_deque.remove(current_index).unwrap();
-                    let new_position = position.min(self.song_deque.len());
+                    let new_position = clamp_position(position, self.song_deque.len());
                     self.song_deque.insert(new_position, song);
-                    
-                    match self.sse_broadcaster.send(SseEvent::QueueUpdated {
-                        queue: self.song_deque.clone(),
-                    }) {
-                        Ok(_) => {
-                            let _ = respond_to.send(Ok(()));
-                        }
-                        Err(err) => {
-                            warn!(
-                                "failed to broadcast SSE event for queue update event for song: {} with error: {}", 
-                                song_uuid, 
-                                err
-                            );
-                            let _ = respond_to.send(Ok(()));
-                        }
+                    self.resequence();
+
+                    self.broadcast_queue();
+                    let _ = respond_to.send(Ok(new_position));
+                } else {
+                    let _ = respond_to.send(Err(SongCoordinatorError::SongNotFound { uuid: song_uuid }));
+                }
+            }
+            SongActorMessage::MoveToEnd {
+                song_uuid,
+                respond_to,
+            } => {
+                if let Some(index) = self.song_deque.iter().position(|x| x.uuid == song_uuid) {
+                    let song = self.song_deque.remove(index).unwrap();
+                    self.song_deque.push_back(song);
+                    self.resequence();
+
+                    self.broadcast_queue();
+                    let _ = respond_to.send(Ok(()));
+                } else {
+                    let _ = respond_to.send(Err(SongCoordinatorError::SongNotFound { uuid: song_uuid }));
+                }
+            }
+            SongActorMessage::ReorderAll {
+                ordered_uuids,
+                respond_to,
+            } => {
+                let mut reordered = VecDeque::with_capacity(self.song_deque.len());
+                for song_uuid in ordered_uuids {
+                    // unknown uuids are ignored
+                    if let Some(index) = self.song_deque.iter().position(|x| x.uuid == song_uuid) {
+                        reordered.push_back(self.song_deque.remove(index).unwrap());
                     }
+                }
+                // songs omitted from the ordering keep their relative order at the end
+                reordered.append(&mut self.song_deque);
+                self.song_deque = reordered;
+                self.resequence();
+
+                self.broadcast_queue();
+                let _ = respond_to.send(());
+            }
+            SongActorMessage::ReplaceQueue { songs, respond_to } => {
+                let duplicate = songs
+                    .iter()
+                    .enumerate()
+                    .find(|(i, song)| songs[..*i].contains(song));
+                if let Some((_, song)) = duplicate {
+                    let _ = respond_to.send(Err(SongCoordinatorError::SongAlreadyQueued {
+                        name: song.name.clone(),
+                    }));
+                } else if let Some(max_length) = self
+                    .max_queue_length
+                    .filter(|max_length| songs.len() > *max_length)
+                {
+                    let _ = respond_to.send(Err(SongCoordinatorError::QueueFull { max_length }));
                 } else {
+                    self.song_deque = songs.into();
+                    self.resequence();
+                    for song in self.song_deque.iter_mut() {
+                        song.sort_key = sort_key(&song.name, &self.sort_articles);
+                        self.event_log.record(EventKind::Queued, song, None);
+                    }
+                    self.reset_key();
+
+                    self.broadcast_queue();
+                    self.song_queued.notify_waiters();
+                    let _ = respond_to.send(Ok(()));
+                }
+            }
+            SongActorMessage::ClearQueue { respond_to } => {
+                let front_uuid = self.song_deque.front().map(|song| song.uuid);
+                let (pinned, removed): (VecDeque<Song>, VecDeque<Song>) =
+                    std::mem::take(&mut self.song_deque)
+                        .into_iter()
+                        .partition(|song| song.pinned);
+                for song in &removed {
+                    self.event_log.record(EventKind::Removed, song, None);
+                }
+                self.song_deque = pinned;
+                if self.song_deque.front().map(|song| song.uuid) != front_uuid {
+                    self.reset_key();
+                }
+
+                self.broadcast_queue();
+                let _ = respond_to.send(self.song_deque.iter().cloned().collect());
+            }
+            SongActorMessage::SetPinned {
+                song_uuid,
+                pinned,
+                respond_to,
+            } => {
+                if let Some(song) = self
+                    .song_deque
+                    .iter_mut()
+                    .find(|song| song.uuid == song_uuid)
+                {
+                    song.pinned = pinned;
+
+                    self.broadcast_queue();
                     let _ = respond_to.send(Ok(()));
+                } else {
+                    let _ = respond_to.send(Err(SongCoordinatorError::SongNotFound { uuid: song_uuid }));
                 }
             }
             SongActorMessage::Current { respond_to } => {
@@ -256,12 +909,11 @@ impl SongActor {
                 let _ = respond_to.send(Ok(self.song_deque.clone()));
             }
             SongActorMessage::KeyUp { respond_to } => {
-                if self.current_key >= 3 {
-                    // TODO fix this and grab it from some settings descriptor
+                if self.current_key >= self.key_range.highest {
                     let _ = respond_to.send(Err(SongCoordinatorError::KeyUpFailed));
                 } else {
                     self.current_key += 1;
-                    let _ = self.sse_broadcaster.send(SseEvent::KeyChange {
+                    self.broadcast(SseEvent::KeyChange {
                         current_key: self.current_key,
                     });
 
@@ -269,12 +921,11 @@ impl SongActor {
                 }
             }
             SongActorMessage::KeyDown { respond_to } => {
-                if self.current_key <= -3 {
-                    // TODO fix this and grab it from some settings descriptor
+                if self.current_key <= self.key_range.lowest {
                     let _ = respond_to.send(Err(SongCoordinatorError::KeyDownFailed));
                 } else {
                     self.current_key -= 1;
-                    let _ = self.sse_broadcaster.send(SseEvent::KeyChange {
+                    self.broadcast(SseEvent::KeyChange {
                         current_key: self.current_key,
                     });
 
@@ -295,93 +946,513 @@ impl SongActor {
                     .find(|song| song.uuid == song_uuid)
                 {
                     song.status = status;
+                    if song.status != QueuedSongStatus::Failed {
+                        song.error = None;
+                    }
+                    if song.status == QueuedSongStatus::Success {
+                        self.event_log.record(EventKind::Downloaded, song, None);
+                    }
 
-                    let _ = self.sse_broadcaster.send(SseEvent::QueueUpdated {
-                        queue: self.song_deque.clone(),
-                    });
+                    self.broadcast_queue();
 
                     let _ = respond_to.send(Ok(()));
                 } else {
-                    let _ = respond_to.send(Err(SongCoordinatorError::UpdateSongStatusFailed {
-                        uuid: (song_uuid),
-                    }));
+                    // the song was removed while its download ran
+                    debug!("Ignoring status {} for removed song {}", status, song_uuid);
+                    let _ = respond_to.send(Ok(()));
                 }
             }
-        }
-    }
-}
-
-async fn run_song_actor(mut actor: SongActor) {
-    while let Some(msg) = actor.receiver.recv().await {
-        actor.handle_message(msg).await;
-    }
-}
-
-#[derive(Clone)]
-pub struct SongActorHandle {
-    sender: mpsc::Sender<SongActorMessage>,
-}
-
-impl SongActorHandle {
-    pub fn new(sse_broadcaster: Arc<sync::broadcast::Sender<SseEvent>>) -> Self {
-        let (sender, receiver) = mpsc::channel(8);
-        let song_actor = SongActor::new(receiver, sse_broadcaster);
-        tokio::spawn(run_song_actor(song_actor));
-
-        Self { sender }
-    }
-
-    pub async fn queue_song(&self, song: Song) -> Result<(), SongCoordinatorError> {
-        let (send, recv) = oneshot::channel();
-        let msg = SongActorMessage::QueueSong {
-            song,
-            respond_to: send,
-        };
-
-        let _ = self.sender.send(msg).await;
-        recv.await.expect("Actor task has been killed")
-    }
+            SongActorMessage::FailSong {
+                song_uuid,
+                reason,
+                respond_to,
+            } => {
+                if let Some(song) = self
+                    .song_deque
+                    .iter_mut()
+                    .find(|song| song.uuid == song_uuid)
+                {
+                    song.status = QueuedSongStatus::Failed;
+                    song.error = Some(reason.clone());
+                    self.event_log.record(EventKind::Failed, song, Some(reason.clone()));
 
-    pub async fn update_song_status(
-        &self,
-        song_uuid: Uuid,
-        new_status: QueuedSongStatus,
-    ) -> Result<(), SongCoordinatorError> {
-        let (send, recv) = oneshot::channel();
-        let msg = SongActorMessage::UpdateSongStatus {
-            song_uuid,
-            status: new_status,
-            respond_to: send,
-        };
+                    self.broadcast(SseEvent::SongFailed {
+                        uuid: song_uuid.to_string(),
+                        reason,
+                    });
+                    self.broadcast_queue();
 
-        let _ = self.sender.send(msg).await;
-        recv.await.expect("Actor task has been killed")
+                    let _ = respond_to.send(Ok(()));
+                } else {
+                    debug!("Ignoring failure of removed song {}: {}", song_uuid, reason);
+                    let _ = respond_to.send(Ok(()));
+                }
+            }
+            SongActorMessage::UpdateSongDuration {
+                song_uuid,
+                duration_seconds,
+                respond_to,
+            } => {
+                if let Some(song) = self
+                    .song_deque
+                    .iter_mut()
+                    .find(|song| song.uuid == song_uuid)
+                {
+                    song.duration_seconds = Some(duration_seconds);
+                }
+                let _ = respond_to.send(());
+            }
+            SongActorMessage::GetSong {
+                song_uuid,
+                respond_to,
+            } => {
+                let song = self.song_deque.iter().find(|song| song.uuid == song_uuid).cloned();
+                let _ = respond_to.send(song);
+            }
+            SongActorMessage::RetrySong {
+                song_uuid,
+                respond_to,
+            } => {
+                let result = match self
+                    .song_deque
+                    .iter_mut()
+                    .find(|song| song.uuid == song_uuid)
+                {
+                    Some(song) if song.status == QueuedSongStatus::Failed => {
+                        song.status = QueuedSongStatus::InProgress;
+                        song.error = None;
+                        song.generation += 1;
+                        Ok(song.clone())
+                    }
+                    Some(song) => Err(SongCoordinatorError::SongNotFailed {
+                        uuid: song_uuid,
+                        status: song.status.clone(),
+                    }),
+                    None => Err(SongCoordinatorError::SongNotFound { uuid: song_uuid }),
+                };
+                if result.is_ok() {
+                    self.broadcast_queue();
+                }
+                let _ = respond_to.send(result);
+            }
+            SongActorMessage::UpdateSong {
+                song_uuid,
+                name,
+                original_name,
+                is_key_changeable,
+                respond_to,
+            } => {
+                let result = self.update_song(song_uuid, name, original_name, is_key_changeable);
+                if result.is_ok() {
+                    self.broadcast_queue();
+                }
+                let _ = respond_to.send(result);
+            }
+            SongActorMessage::TogglePlayback { respond_to } => {
+                self.playback.toggle();
+                self.broadcast(SseEvent::TogglePlayback);
+                let _ = respond_to.send(());
+            }
+            SongActorMessage::RestartSong { respond_to } => {
+                self.playback.restart();
+                self.broadcast(SseEvent::RestartSong);
+                let _ = respond_to.send(());
+            }
+            SongActorMessage::ToggleAutoplay { respond_to } => {
+                self.playback.autoplay = !self.playback.autoplay;
+                self.broadcast(SseEvent::AutoplayToggled {
+                    enabled: self.playback.autoplay,
+                });
+                let _ = respond_to.send(self.playback.autoplay);
+            }
+            SongActorMessage::GetSession { respond_to } => {
+                let _ = respond_to.send(self.session_snapshot());
+            }
+            SongActorMessage::GetUpNext { respond_to } => {
+                let _ = respond_to.send(self.song_deque.get(1).cloned());
+            }
+            SongActorMessage::PeekUpcoming { count, respond_to } => {
+                let upcoming = self.song_deque.iter().skip(1).take(count).cloned();
+                let _ = respond_to.send(upcoming.collect());
+            }
+            SongActorMessage::Ping { respond_to } => {
+                let _ = respond_to.send(());
+            }
+            #[cfg(test)]
+            SongActorMessage::Panic { respond_to } => {
+                // dropped while unwinding, as a real handler's would be
+                let _respond_to = respond_to;
+                // a half-applied edit
+                self.song_deque.clear();
+                panic!("forced panic");
+            }
+        }
+
+        self.sync_now_playing();
+    }
+}
+
+/// The index a song moved to `position` is inserted at, among the `len`
+/// other songs. Negative positions count from the end.
+fn clamp_position(position: isize, len: usize) -> usize {
+    if position < 0 {
+        (len + 1).saturating_sub(position.unsigned_abs())
+    } else {
+        position.unsigned_abs().min(len)
+    }
+}
+
+async fn run_song_actor(mut actor: SongActor, timing: PlaybackTiming) {
+    let mut ticker = tokio::time::interval(timing.tick_interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        tokio::select! {
+            msg = actor.receiver.recv() => match msg {
+                Some(msg) => supervise_message(&mut actor, msg).await,
+                None => break,
+            },
+            _ = ticker.tick() => {
+                if supervise_tick(&mut actor, &timing) {
+                    ticker.reset();
+                }
+            }
+        }
     }
+}
+
+/// Runs a single message handler, catching panics so that one bad message
+/// doesn't take down the actor. The caller of the panicked message gets
+/// `ActorUnavailable`, and whatever the handler changed before panicking is
+/// undone; see `recover`.
+async fn supervise_message(actor: &mut SongActor, msg: SongActorMessage) {
+    match AssertUnwindSafe(actor.handle_message(msg))
+        .catch_unwind()
+        .await
+    {
+        Ok(()) => actor.save_checkpoint(),
+        Err(panic) => recover(actor, "handler", panic),
+    }
+}
+
+/// Runs the periodic playback work, catching panics like
+/// `supervise_message`. Returns whether the queue advanced.
+fn supervise_tick(actor: &mut SongActor, timing: &PlaybackTiming) -> bool {
+    supervise(actor, "tick", |actor| {
+        let advanced = actor.auto_advance(timing.gap);
+        actor.signal_song_ending(timing);
+        actor.broadcast_playback_position();
+        advanced
+    })
+    .unwrap_or(false)
+}
+
+/// Runs synchronous work on the actor, recovering if it panics.
+fn supervise<T>(
+    actor: &mut SongActor,
+    source: &str,
+    work: impl FnOnce(&mut SongActor) -> T,
+) -> Option<T> {
+    match std::panic::catch_unwind(AssertUnwindSafe(|| work(actor))) {
+        Ok(result) => {
+            actor.save_checkpoint();
+            Some(result)
+        }
+        Err(panic) => {
+            recover(actor, source, panic);
+            None
+        }
+    }
+}
 
-    pub async fn remove_song(&self, song_uuid: Uuid) {
+/// Logs a caught panic and rebuilds the actor from its last checkpoint, so
+/// the queue, key and playback are as they were before the panicking message
+/// or tick. Clients get the restored queue again, since a half-applied change
+/// may already have been broadcast.
+fn recover(actor: &mut SongActor, source: &str, panic: Box<dyn Any + Send>) {
+    let reason = panic
+        .downcast_ref::<&str>()
+        .map(|reason| reason.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string());
+    error!("song actor {} panicked, recovering: {}", source, reason);
+    actor.restore_checkpoint();
+    actor.sync_now_playing();
+    actor.broadcast_queue();
+}
+
+#[derive(Clone)]
+pub struct SongActorHandle {
+    sender: mpsc::Sender<SongActorMessage>,
+    song_queued: Arc<Notify>,
+    pins: watch::Receiver<Vec<PinnedSong>>,
+    key_range: KeyRange,
+}
+
+impl SongActorHandle {
+    pub fn new(
+        sse_broadcaster: Arc<SseBroadcaster>,
+        max_queue_length: Option<usize>,
+        allow_requeue_playing: bool,
+        timing: PlaybackTiming,
+        sort_articles: Vec<String>,
+        event_log: EventLog,
+        keys: KeyPolicy,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(8);
+        let song_actor = SongActor::new(
+            receiver,
+            sse_broadcaster,
+            max_queue_length,
+            allow_requeue_playing,
+            sort_articles,
+            event_log,
+            keys,
+        );
+        let song_queued = song_actor.song_queued.clone();
+        let pins = song_actor.pins.subscribe();
+        tokio::spawn(run_song_actor(song_actor, timing));
+
+        Self {
+            sender,
+            song_queued,
+            pins,
+            key_range: keys.range,
+        }
+    }
+
+    /// The keys songs can be played in, and queued with.
+    pub fn key_range(&self) -> KeyRange {
+        self.key_range
+    }
+
+    /// The pinned songs, updated whenever they change. The sender closes
+    /// once the actor stops.
+    pub fn watch_pins(&self) -> watch::Receiver<Vec<PinnedSong>> {
+        self.pins.clone()
+    }
+
+    async fn send<T>(
+        &self,
+        msg: SongActorMessage,
+        recv: oneshot::Receiver<T>,
+    ) -> Result<T, SongCoordinatorError> {
+        self.sender
+            .send(msg)
+            .await
+            .map_err(|_| SongCoordinatorError::ActorUnavailable)?;
+        // the responder is dropped without a reply if the handler panicked
+        recv.await.map_err(|_| SongCoordinatorError::ActorUnavailable)
+    }
+
+    pub async fn queue_song(&self, song: Song) -> Result<(), SongCoordinatorError> {
+        let (send, recv) = oneshot::channel();
+        let msg = SongActorMessage::QueueSong {
+            song,
+            respond_to: send,
+        };
+
+        self.send(msg, recv).await?
+    }
+
+    /// Sets a song's download status. A song removed from the queue in the
+    /// meantime is left alone without an error.
+    pub async fn update_song_status(
+        &self,
+        song_uuid: Uuid,
+        new_status: QueuedSongStatus,
+    ) -> Result<(), SongCoordinatorError> {
+        let (send, recv) = oneshot::channel();
+        let msg = SongActorMessage::UpdateSongStatus {
+            song_uuid,
+            status: new_status,
+            respond_to: send,
+        };
+
+        self.send(msg, recv).await?
+    }
+
+    pub async fn get_song(&self, song_uuid: Uuid) -> Result<Option<Song>, SongCoordinatorError> {
+        let (send, recv) = oneshot::channel();
+        let msg = SongActorMessage::GetSong {
+            song_uuid,
+            respond_to: send,
+        };
+
+        self.send(msg, recv).await
+    }
+
+    pub async fn update_song(
+        &self,
+        song_uuid: Uuid,
+        name: Option<String>,
+        original_name: Option<String>,
+        is_key_changeable: Option<bool>,
+    ) -> Result<UpdatedSong, SongCoordinatorError> {
+        let (send, recv) = oneshot::channel();
+        let msg = SongActorMessage::UpdateSong {
+            song_uuid,
+            name,
+            original_name,
+            is_key_changeable,
+            respond_to: send,
+        };
+
+        self.send(msg, recv).await?
+    }
+
+    /// Puts a failed song back to `InProgress` in place, returning it so its
+    /// download can be dispatched again.
+    pub async fn retry_song(&self, song_uuid: Uuid) -> Result<Song, SongCoordinatorError> {
+        let (send, recv) = oneshot::channel();
+        let msg = SongActorMessage::RetrySong {
+            song_uuid,
+            respond_to: send,
+        };
+
+        self.send(msg, recv).await?
+    }
+
+    /// Marks a song as failed and tells clients why, unless it was removed
+    /// from the queue in the meantime.
+    pub async fn fail_song(&self, song_uuid: Uuid, reason: String) -> Result<(), SongCoordinatorError> {
+        let (send, recv) = oneshot::channel();
+        let msg = SongActorMessage::FailSong {
+            song_uuid,
+            reason,
+            respond_to: send,
+        };
+
+        self.send(msg, recv).await?
+    }
+
+    pub async fn update_song_duration(
+        &self,
+        song_uuid: Uuid,
+        duration_seconds: f64,
+    ) -> Result<(), SongCoordinatorError> {
+        let (send, recv) = oneshot::channel();
+        let msg = SongActorMessage::UpdateSongDuration {
+            song_uuid,
+            duration_seconds,
+            respond_to: send,
+        };
+
+        self.send(msg, recv).await
+    }
+
+    pub async fn toggle_playback(&self) -> Result<(), SongCoordinatorError> {
+        let (send, recv) = oneshot::channel();
+        let msg = SongActorMessage::TogglePlayback { respond_to: send };
+
+        self.send(msg, recv).await
+    }
+
+    pub async fn restart_song(&self) -> Result<(), SongCoordinatorError> {
+        let (send, recv) = oneshot::channel();
+        let msg = SongActorMessage::RestartSong { respond_to: send };
+
+        self.send(msg, recv).await
+    }
+
+    /// Empties the queue except for pinned songs, which are returned.
+    pub async fn clear_queue(&self) -> Result<Vec<Song>, SongCoordinatorError> {
+        let (send, recv) = oneshot::channel();
+        let msg = SongActorMessage::ClearQueue { respond_to: send };
+
+        self.send(msg, recv).await
+    }
+
+    pub async fn set_pinned(&self, song_uuid: Uuid, pinned: bool) -> Result<(), SongCoordinatorError> {
+        let (send, recv) = oneshot::channel();
+        let msg = SongActorMessage::SetPinned {
+            song_uuid,
+            pinned,
+            respond_to: send,
+        };
+
+        self.send(msg, recv).await?
+    }
+
+    pub async fn remove_song(&self, song_uuid: Uuid) -> Result<(), SongCoordinatorError> {
         let (send, recv) = oneshot::channel();
         let msg = SongActorMessage::RemoveSong {
             song_uuid,
             respond_to: send,
         };
 
-        let _ = self.sender.send(msg).await;
-        recv.await.expect("Actor task has been killed")
+        self.send(msg, recv).await
     }
 
-    pub async fn pop_song(&self) -> Option<Song> {
+    /// Round-trips a no-op message to check the actor is still processing.
+    pub async fn ping(&self) -> Result<(), SongCoordinatorError> {
+        let (send, recv) = oneshot::channel();
+        let msg = SongActorMessage::Ping { respond_to: send };
+
+        self.send(msg, recv).await
+    }
+
+    pub async fn toggle_autoplay(&self) -> Result<bool, SongCoordinatorError> {
+        let (send, recv) = oneshot::channel();
+        let msg = SongActorMessage::ToggleAutoplay { respond_to: send };
+
+        self.send(msg, recv).await
+    }
+
+    pub async fn get_session(&self) -> Result<SessionSnapshot, SongCoordinatorError> {
+        let (send, recv) = oneshot::channel();
+        let msg = SongActorMessage::GetSession { respond_to: send };
+
+        self.send(msg, recv).await
+    }
+
+    /// The song queued right after the one now playing.
+    pub async fn up_next(&self) -> Result<Option<Song>, SongCoordinatorError> {
+        let (send, recv) = oneshot::channel();
+        let msg = SongActorMessage::GetUpNext { respond_to: send };
+
+        self.send(msg, recv).await
+    }
+
+    /// The first `count` songs after the current one, cloning only those
+    /// rather than the whole queue.
+    pub async fn peek_upcoming(&self, count: usize) -> Result<Vec<Song>, SongCoordinatorError> {
+        let (send, recv) = oneshot::channel();
+        let msg = SongActorMessage::PeekUpcoming {
+            count,
+            respond_to: send,
+        };
+
+        self.send(msg, recv).await
+    }
+
+    /// Replaces the whole queue, e.g. with an imported session. Nothing is
+    /// replaced if the songs contain duplicates or exceed the max length.
+    pub async fn replace_queue(&self, songs: Vec<Song>) -> Result<(), SongCoordinatorError> {
+        let (send, recv) = oneshot::channel();
+        let msg = SongActorMessage::ReplaceQueue {
+            songs,
+            respond_to: send,
+        };
+
+        self.send(msg, recv).await?
+    }
+
+    pub async fn pop_song(&self) -> Result<Advanced, SongCoordinatorError> {
         let (send, recv) = oneshot::channel();
         let msg = SongActorMessage::PopSong { respond_to: send };
 
-        let _ = self.sender.send(msg).await;
-        recv.await.expect("Actor task has been killed")
+        self.send(msg, recv).await
     }
 
+    /// Moves a song to `position` in the queue, counting from the end when
+    /// negative so `-1` is the last place. Positions past either end are
+    /// clamped to it. Returns the index the song ended up at.
     pub async fn reposition_song(
         &self,
         song_uuid: Uuid,
-        position: usize,
-    ) -> Result<(), SongCoordinatorError> {
+        position: isize,
+    ) -> Result<usize, SongCoordinatorError> {
         let (send, recv) = oneshot::channel();
         let msg = SongActorMessage::Reposition {
             song_uuid,
@@ -389,47 +1460,689 @@ impl SongActorHandle {
             respond_to: send,
         };
 
-        let _ = self.sender.send(msg).await;
-        recv.await.expect("Actor task has been killed")
+        self.send(msg, recv).await?
+    }
+
+    /// Sends a song to the back of the queue, e.g. when its singer isn't
+    /// ready yet.
+    pub async fn move_to_end(&self, song_uuid: Uuid) -> Result<(), SongCoordinatorError> {
+        let (send, recv) = oneshot::channel();
+        let msg = SongActorMessage::MoveToEnd {
+            song_uuid,
+            respond_to: send,
+        };
+
+        self.send(msg, recv).await?
+    }
+
+    pub async fn reorder_all(
+        &self,
+        ordered_uuids: Vec<Uuid>,
+    ) -> Result<(), SongCoordinatorError> {
+        let (send, recv) = oneshot::channel();
+        let msg = SongActorMessage::ReorderAll {
+            ordered_uuids,
+            respond_to: send,
+        };
+
+        self.send(msg, recv).await
     }
 
     pub async fn current_song(&self) -> Result<Option<Song>, SongCoordinatorError> {
         let (send, recv) = oneshot::channel();
         let msg = SongActorMessage::Current { respond_to: send };
 
-        let _ = self.sender.send(msg).await;
-        recv.await.expect("Actor task has been killed")
+        self.send(msg, recv).await?
+    }
+
+    /// The song now playing, waiting up to `timeout` for one to be queued
+    /// when the queue is empty.
+    pub async fn wait_for_current_song(
+        &self,
+        timeout: Duration,
+    ) -> Result<Option<Song>, SongCoordinatorError> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            // registered before checking so a song queued in between isn't missed
+            let notified = self.song_queued.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if let Some(song) = self.current_song().await? {
+                return Ok(Some(song));
+            }
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                return Ok(None);
+            }
+        }
     }
 
     pub async fn get_queue(&self) -> Result<VecDeque<Song>, SongCoordinatorError> {
         let (send, recv) = oneshot::channel();
         let msg = SongActorMessage::GetQueue { respond_to: send };
 
-        let _ = self.sender.send(msg).await;
-        recv.await.expect("Actor task has been killed")
+        self.send(msg, recv).await?
     }
 
     pub async fn key_up(&self) -> Result<i8, SongCoordinatorError> {
         let (send, recv) = oneshot::channel();
         let msg = SongActorMessage::KeyUp { respond_to: send };
 
-        let _ = self.sender.send(msg).await;
-        recv.await.expect("Actor task has been killed")
+        self.send(msg, recv).await?
     }
 
     pub async fn key_down(&self) -> Result<i8, SongCoordinatorError> {
         let (send, recv) = oneshot::channel();
         let msg = SongActorMessage::KeyDown { respond_to: send };
 
-        let _ = self.sender.send(msg).await;
-        recv.await.expect("Actor task has been killed")
+        self.send(msg, recv).await?
     }
 
     pub async fn get_key(&self) -> Result<i8, SongCoordinatorError> {
         let (send, recv) = oneshot::channel();
         let msg = SongActorMessage::GetKey { respond_to: send };
 
-        let _ = self.sender.send(msg).await;
-        recv.await.expect("Actor task has been killed")
+        self.send(msg, recv).await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::settings::{EventLogSettings, PlaybackSettings};
+
+    fn handle() -> SongActorHandle {
+        handle_with(Arc::new(SseBroadcaster::new(16)))
+    }
+
+    fn handle_with(broadcaster: Arc<SseBroadcaster>) -> SongActorHandle {
+        spawn_handle(broadcaster, true)
+    }
+
+    /// The default `playback.max_key_shift`, back to the original key.
+    fn keys() -> KeyPolicy {
+        KeyPolicy {
+            range: KeyRange::new(3),
+            reset: KeyResetPolicy::Always,
+        }
+    }
+
+    fn spawn_handle(
+        broadcaster: Arc<SseBroadcaster>,
+        allow_requeue_playing: bool,
+    ) -> SongActorHandle {
+        let event_log = EventLog::new(
+            PathBuf::new(),
+            &EventLogSettings {
+                enabled: false,
+                ..Default::default()
+            },
+        );
+        SongActorHandle::new(
+            broadcaster,
+            None,
+            allow_requeue_playing,
+            PlaybackTiming {
+                tick_interval: Duration::from_secs(3600),
+                gap: Duration::ZERO,
+                ending_lead: Duration::ZERO,
+            },
+            Vec::new(),
+            event_log,
+            keys(),
+        )
+    }
+
+    fn song(name: &str) -> Song {
+        Song::new(
+            name.to_string(),
+            format!("https://www.youtube.com/watch?v={}", name),
+            QueuedSongStatus::InProgress,
+            false,
+            None,
+        )
+    }
+
+    #[tokio::test]
+    async fn shortened_renames_keep_the_full_title_folder() {
+        let songs = handle();
+        songs.queue_song(song("Playing")).await.unwrap();
+        let queued = song("Queued");
+        songs.queue_song(queued.clone()).await.unwrap();
+
+        let long_title = format!("{} (Extended Live Version)", "Song ".repeat(40));
+        let shortened = format!("{}…", &long_title[..20]);
+        let updated = songs
+            .update_song(
+                queued.uuid,
+                Some(shortened.clone()),
+                Some(long_title.clone()),
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(updated.needs_download);
+        assert_eq!(updated.song.name, shortened);
+        assert_eq!(updated.song.title(), long_title);
+        assert_eq!(updated.song.folder, song_key(&long_title, &queued.yt_link));
+
+        // another title shortened to the same name still gets its own folder
+        let other_title = format!("{} (Acoustic)", "Song ".repeat(40));
+        let renamed = songs
+            .update_song(
+                queued.uuid,
+                Some(shortened),
+                Some(other_title.clone()),
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(renamed.needs_download);
+        assert_ne!(renamed.song.folder, updated.song.folder);
+        assert_eq!(renamed.song.folder, song_key(&other_title, &queued.yt_link));
+    }
+
+    #[tokio::test]
+    async fn renaming_a_downloaded_song_keeps_its_download() {
+        let songs = handle();
+        songs.queue_song(song("Playing")).await.unwrap();
+        let queued = song("Queued");
+        songs.queue_song(queued.clone()).await.unwrap();
+        songs
+            .update_song_status(queued.uuid, QueuedSongStatus::Success)
+            .await
+            .unwrap();
+
+        let updated = songs
+            .update_song(queued.uuid, Some("Renamed".to_string()), None, None)
+            .await
+            .unwrap();
+        assert!(!updated.needs_download);
+        assert_eq!(updated.song.name, "Renamed");
+        assert_eq!(updated.song.status, QueuedSongStatus::Success);
+        assert_eq!(updated.song.folder, queued.folder);
+
+        // renaming it back drops the original title again
+        let restored = songs
+            .update_song(queued.uuid, Some("Queued".to_string()), None, None)
+            .await
+            .unwrap();
+        assert!(!restored.needs_download);
+        assert_eq!(restored.song.original_name, None);
+        assert_eq!(restored.song.folder, queued.folder);
+    }
+
+    #[tokio::test]
+    async fn seq_increases_along_the_queue_after_every_edit() {
+        let songs = handle();
+        let assert_ordered = |queue: VecDeque<Song>| {
+            let seqs = queue.iter().map(|song| song.seq).collect::<Vec<_>>();
+            assert!(seqs.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", seqs);
+            queue
+        };
+
+        let queued = ["A", "B", "C", "D"].map(song);
+        for queued in &queued {
+            songs.queue_song(queued.clone()).await.unwrap();
+        }
+        assert_ordered(songs.get_queue().await.unwrap());
+
+        songs.reposition_song(queued[3].uuid, 1).await.unwrap();
+        let queue = assert_ordered(songs.get_queue().await.unwrap());
+
+        let reversed = queue.iter().rev().map(|song| song.uuid).collect();
+        songs.reorder_all(reversed).await.unwrap();
+        assert_ordered(songs.get_queue().await.unwrap());
+
+        songs
+            .replace_queue(["E", "F", "G"].map(song).to_vec())
+            .await
+            .unwrap();
+        let queue = assert_ordered(songs.get_queue().await.unwrap());
+        let names = queue.into_iter().map(|song| song.name).collect::<Vec<_>>();
+        assert_eq!(names, ["E", "F", "G"]);
+    }
+
+    #[tokio::test]
+    async fn clearing_keeps_pinned_songs() {
+        let songs = handle();
+        let mut pins = songs.watch_pins();
+        let queued = ["Playing", "Pinned", "Other", "Also pinned"].map(song);
+        for queued in &queued {
+            songs.queue_song(queued.clone()).await.unwrap();
+        }
+        for pinned in [&queued[1], &queued[3]] {
+            songs.set_pinned(pinned.uuid, true).await.unwrap();
+        }
+        assert!(pins.has_changed().unwrap());
+        let pinned_names =
+            |pins: &[PinnedSong]| pins.iter().map(|pin| pin.name.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            pinned_names(&pins.borrow_and_update()),
+            ["Pinned", "Also pinned"]
+        );
+
+        let kept = songs.clear_queue().await.unwrap();
+        let names = |songs: Vec<Song>| songs.into_iter().map(|song| song.name).collect::<Vec<_>>();
+        assert_eq!(names(kept), ["Pinned", "Also pinned"]);
+        let queue = songs.get_queue().await.unwrap();
+        assert_eq!(names(queue.into()), ["Pinned", "Also pinned"]);
+        // the pins didn't change, so nothing needs saving
+        assert!(!pins.has_changed().unwrap());
+
+        songs.set_pinned(queued[1].uuid, false).await.unwrap();
+        assert_eq!(pinned_names(&pins.borrow_and_update()), ["Also pinned"]);
+    }
+
+    #[tokio::test]
+    async fn recovers_from_a_panicking_handler() {
+        let songs = handle();
+        songs.queue_song(song("Before")).await.unwrap();
+
+        let (send, recv) = oneshot::channel();
+        let result = songs
+            .send(SongActorMessage::Panic { respond_to: send }, recv)
+            .await;
+        assert!(matches!(
+            result,
+            Err(SongCoordinatorError::ActorUnavailable)
+        ));
+
+        // the queue the handler cleared before panicking is back
+        songs.queue_song(song("After")).await.unwrap();
+        let names = songs
+            .get_queue()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|song| song.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["Before", "After"]);
+    }
+
+    /// An actor driven directly, without its message loop.
+    fn actor(broadcaster: Arc<SseBroadcaster>) -> SongActor {
+        let (_sender, receiver) = mpsc::channel(1);
+        SongActor::new(
+            receiver,
+            broadcaster,
+            None,
+            true,
+            Vec::new(),
+            EventLog::new(
+                PathBuf::new(),
+                &EventLogSettings {
+                    enabled: false,
+                    ..Default::default()
+                },
+            ),
+            keys(),
+        )
+    }
+
+    #[test]
+    fn recovers_from_a_panicking_tick() {
+        let mut actor = actor(Arc::new(SseBroadcaster::new(16)));
+        actor.song_deque.extend([song("First"), song("Second")]);
+        actor.sync_now_playing();
+
+        actor.save_checkpoint();
+        let first = actor.song_deque[0].uuid;
+
+        // the panic strikes after the queue moved but before playback did
+        let result = supervise(&mut actor, "tick", |actor| {
+            actor.song_deque.pop_front();
+            panic!("forced panic");
+        });
+        assert!(result.is_none());
+        assert_eq!(actor.song_deque.len(), 2);
+        assert_eq!(actor.playback.song_uuid, Some(first));
+
+        let timing = PlaybackTiming {
+            tick_interval: Duration::from_secs(1),
+            gap: Duration::ZERO,
+            ending_lead: Duration::ZERO,
+        };
+        assert!(!supervise_tick(&mut actor, &timing));
+    }
+
+    async fn queue_names(songs: &SongActorHandle) -> Vec<String> {
+        let queue = songs.get_queue().await.unwrap();
+        queue.into_iter().map(|song| song.name).collect()
+    }
+
+    #[tokio::test]
+    async fn reordering_keeps_omitted_songs_at_the_end() {
+        let songs = handle();
+        let queued = ["A", "B", "C", "D"].map(song);
+        for queued in &queued {
+            songs.queue_song(queued.clone()).await.unwrap();
+        }
+
+        // D and B are placed first, an unknown uuid is skipped, and A and C
+        // follow in their previous order
+        songs
+            .reorder_all(vec![queued[3].uuid, Uuid::new_v4(), queued[1].uuid])
+            .await
+            .unwrap();
+        assert_eq!(queue_names(&songs).await, ["D", "B", "A", "C"]);
+    }
+
+    #[tokio::test]
+    async fn picked_up_downloads_are_broadcast_as_downloading() {
+        let broadcaster = Arc::new(SseBroadcaster::new(16));
+        let songs = handle_with(broadcaster.clone());
+        let queued = song("Queued");
+        songs.queue_song(queued.clone()).await.unwrap();
+
+        let (_, mut events, _) = broadcaster.subscribe_after(None);
+        songs
+            .update_song_status(queued.uuid, QueuedSongStatus::Downloading)
+            .await
+            .unwrap();
+
+        let (_, SseEvent::QueueUpdated { queue }) = events.recv().await.unwrap() else {
+            panic!("expected a queue update");
+        };
+        assert!(queue[0].status == QueuedSongStatus::Downloading);
+        let stored = songs.get_song(queued.uuid).await.unwrap().unwrap();
+        assert!(stored.status == QueuedSongStatus::Downloading);
+    }
+
+    #[tokio::test]
+    async fn failed_songs_keep_their_reason_until_they_recover() {
+        let broadcaster = Arc::new(SseBroadcaster::new(16));
+        let songs = handle_with(broadcaster.clone());
+        let queued = song("Queued");
+        songs.queue_song(queued.clone()).await.unwrap();
+        let stored = songs.get_song(queued.uuid).await.unwrap().unwrap();
+        assert_eq!(stored.error, None);
+
+        let (_, mut events, _) = broadcaster.subscribe_after(None);
+        let reason = "This video is private".to_string();
+        songs.fail_song(queued.uuid, reason.clone()).await.unwrap();
+        let (_, SseEvent::SongFailed { uuid, reason: sent }) = events.recv().await.unwrap() else {
+            panic!("expected a failure");
+        };
+        assert_eq!((uuid, &sent), (queued.uuid.to_string(), &reason));
+
+        let failed = songs.get_song(queued.uuid).await.unwrap().unwrap();
+        assert!(failed.status == QueuedSongStatus::Failed);
+        assert_eq!(failed.error, Some(reason));
+        let json = serde_json::to_value(&failed).unwrap();
+        assert_eq!(json["error"], "This video is private");
+
+        songs
+            .update_song_status(queued.uuid, QueuedSongStatus::Success)
+            .await
+            .unwrap();
+        let recovered = songs.get_song(queued.uuid).await.unwrap().unwrap();
+        assert_eq!(recovered.error, None);
+    }
+
+    #[tokio::test]
+    async fn requeuing_the_playing_song_follows_the_setting() {
+        for allow_requeue_playing in [true, false] {
+            let songs = spawn_handle(Arc::new(SseBroadcaster::new(16)), allow_requeue_playing);
+            let playing = song("Playing");
+            songs.queue_song(playing.clone()).await.unwrap();
+
+            // a fresh request for the same link, as a guest would send
+            let replay = Song::new(
+                "Playing again".to_string(),
+                playing.yt_link.clone(),
+                QueuedSongStatus::InProgress,
+                false,
+                None,
+            );
+            let result = songs.queue_song(replay).await;
+            if allow_requeue_playing {
+                assert!(result.is_ok());
+                assert_eq!(queue_names(&songs).await, ["Playing", "Playing again"]);
+            } else {
+                assert!(matches!(
+                    result,
+                    Err(SongCoordinatorError::SongAlreadyPlaying { .. })
+                ));
+                assert_eq!(queue_names(&songs).await, ["Playing"]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn moved_songs_end_up_last() {
+        let songs = handle();
+        let moved = song("Moved");
+        for queued in [song("Playing"), moved.clone(), song("Next"), song("Later")] {
+            songs.queue_song(queued).await.unwrap();
+        }
+
+        songs.move_to_end(moved.uuid).await.unwrap();
+        assert_eq!(
+            queue_names(&songs).await,
+            ["Playing", "Next", "Later", "Moved"]
+        );
+
+        assert!(matches!(
+            songs.move_to_end(Uuid::new_v4()).await,
+            Err(SongCoordinatorError::SongNotFound { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn waiting_for_a_song_returns_once_one_is_queued() {
+        let songs = handle();
+        let wait = Duration::from_secs(5);
+        let timed_out = songs.wait_for_current_song(Duration::from_millis(10));
+        assert!(timed_out.await.unwrap().is_none());
+
+        let waiting = tokio::spawn({
+            let songs = songs.clone();
+            async move { songs.wait_for_current_song(wait).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        songs.queue_song(song("Waited")).await.unwrap();
+        let waited = waiting.await.unwrap().unwrap().unwrap();
+        assert_eq!(waited.name, "Waited");
+
+        // already playing, so no wait at all
+        let immediate = songs.wait_for_current_song(wait);
+        let playing = tokio::time::timeout(Duration::from_secs(1), immediate)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(playing.name, "Waited");
+    }
+
+    #[tokio::test]
+    async fn advancing_passes_over_songs_still_downloading() {
+        let songs = handle();
+        let ready = song("Ready");
+        for queued in [song("Playing"), song("Downloading"), ready.clone()] {
+            songs.queue_song(queued).await.unwrap();
+        }
+        songs
+            .update_song_status(ready.uuid, QueuedSongStatus::Success)
+            .await
+            .unwrap();
+
+        let advanced = songs.pop_song().await.unwrap();
+        assert_eq!(advanced.finished.unwrap().name, "Playing");
+        assert_eq!(advanced.now_playing.unwrap().name, "Ready");
+        assert_eq!(queue_names(&songs).await, ["Ready", "Downloading"]);
+
+        // nothing ready, so the client is handed the song still downloading
+        let advanced = songs.pop_song().await.unwrap();
+        let now_playing = advanced.now_playing.unwrap();
+        assert_eq!(now_playing.name, "Downloading");
+        assert_eq!(now_playing.status, QueuedSongStatus::InProgress);
+    }
+
+    #[tokio::test]
+    async fn advancing_passes_over_failed_songs() {
+        let songs = handle();
+        let failed = song("Failed");
+        for queued in [song("Playing"), failed.clone(), song("Downloading")] {
+            songs.queue_song(queued).await.unwrap();
+        }
+        songs
+            .update_song_status(failed.uuid, QueuedSongStatus::Failed)
+            .await
+            .unwrap();
+
+        let advanced = songs.pop_song().await.unwrap();
+        assert_eq!(advanced.now_playing.unwrap().name, "Downloading");
+        assert_eq!(queue_names(&songs).await, ["Downloading", "Failed"]);
+
+        // only failed songs left, so they come up to be retried or skipped
+        let advanced = songs.pop_song().await.unwrap();
+        let now_playing = advanced.now_playing.unwrap();
+        assert_eq!(now_playing.name, "Failed");
+        assert_eq!(now_playing.status, QueuedSongStatus::Failed);
+    }
+
+    #[tokio::test]
+    async fn downloads_finishing_after_removal_are_ignored() {
+        let songs = handle();
+        let removed = song("Removed");
+        songs.queue_song(song("Playing")).await.unwrap();
+        songs.queue_song(removed.clone()).await.unwrap();
+        songs.remove_song(removed.uuid).await.unwrap();
+
+        songs
+            .update_song_status(removed.uuid, QueuedSongStatus::Success)
+            .await
+            .unwrap();
+        assert_eq!(queue_names(&songs).await, ["Playing"]);
+    }
+
+    #[test]
+    fn song_ending_is_signaled_once_at_the_lead_time() {
+        let broadcaster = Arc::new(SseBroadcaster::new(16));
+        let mut actor = actor(broadcaster.clone());
+        let mut playing = song("Playing");
+        playing.duration_seconds = Some(10.0);
+        actor.song_deque.push_back(playing.clone());
+        actor.sync_now_playing();
+        actor.playback.autoplay = true;
+        actor.playback.is_playing = true;
+        let timing = PlaybackTiming {
+            tick_interval: Duration::from_secs(1),
+            gap: Duration::from_secs(1),
+            ending_lead: Duration::from_secs(5),
+        };
+
+        let (_, mut events, _) = broadcaster.subscribe_after(None);
+        // the position stands still while `resumed_at` is unset
+        actor.playback.elapsed = Duration::from_secs(5);
+        actor.signal_song_ending(&timing);
+        assert!(events.try_recv().is_err());
+
+        actor.playback.elapsed = Duration::from_secs(7);
+        actor.signal_song_ending(&timing);
+        let (_, SseEvent::SongEnding { uuid, in_seconds }) = events.try_recv().unwrap() else {
+            panic!("expected the song ending");
+        };
+        assert_eq!(uuid, playing.uuid.to_string());
+        assert_eq!(in_seconds, 4.0);
+
+        actor.playback.elapsed = Duration::from_secs(8);
+        actor.signal_song_ending(&timing);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn new_songs_start_in_the_key_the_policy_picks() {
+        for (policy, expected) in [
+            (KeyResetPolicy::Always, [0, 0]),
+            (KeyResetPolicy::PreferSong, [-2, 0]),
+            (KeyResetPolicy::Persist, [1, 1]),
+        ] {
+            let broadcaster = Arc::new(SseBroadcaster::new(16));
+            let mut actor = actor(broadcaster.clone());
+            actor.key_reset = policy;
+            let mut preferring = song("Preferring");
+            preferring.preferred_key = Some(-2);
+            actor
+                .song_deque
+                .extend([song("Playing"), preferring, song("Plain")]);
+            actor.current_key = 1;
+
+            let (_, mut events, _) = broadcaster.subscribe_after(None);
+            for expected in expected {
+                actor.advance();
+                assert_eq!(actor.current_key, expected, "{:?}", policy);
+                let (_, SseEvent::KeyChange { current_key }) = events.try_recv().unwrap() else {
+                    panic!("expected a key change");
+                };
+                assert_eq!(current_key, expected);
+                // the queue update that follows
+                events.try_recv().unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn the_key_range_spans_the_max_shift_either_way() {
+        let range = KeyRange::new(PlaybackSettings::default().max_key_shift);
+        assert_eq!((range.lowest, range.highest), (-3, 3));
+        assert!(range.contains(0) && !range.contains(4));
+        assert_eq!(KeyRange::new(u8::MAX).lowest, -i8::MAX);
+    }
+
+    #[tokio::test]
+    async fn keys_stop_at_the_ends_of_the_range() {
+        async fn change_key(actor: &mut SongActor, up: bool) -> Result<i8, SongCoordinatorError> {
+            let (send, recv) = oneshot::channel();
+            let msg = if up {
+                SongActorMessage::KeyUp { respond_to: send }
+            } else {
+                SongActorMessage::KeyDown { respond_to: send }
+            };
+            actor.handle_message(msg).await;
+            recv.await.unwrap()
+        }
+
+        let mut actor = actor(Arc::new(SseBroadcaster::new(16)));
+        actor.key_range = KeyRange::new(2);
+        assert_eq!(change_key(&mut actor, true).await.unwrap(), 1);
+        assert_eq!(change_key(&mut actor, true).await.unwrap(), 2);
+        assert!(matches!(
+            change_key(&mut actor, true).await,
+            Err(SongCoordinatorError::KeyUpFailed)
+        ));
+        for expected in [1, 0, -1, -2] {
+            assert_eq!(change_key(&mut actor, false).await.unwrap(), expected);
+        }
+        assert!(matches!(
+            change_key(&mut actor, false).await,
+            Err(SongCoordinatorError::KeyDownFailed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn peeking_upcoming_songs_leaves_the_queue_alone() {
+        let songs = handle();
+        for name in ["Playing", "First", "Second", "Last"] {
+            songs.queue_song(song(name)).await.unwrap();
+        }
+
+        let names = |songs: Vec<Song>| songs.into_iter().map(|song| song.name).collect::<Vec<_>>();
+        assert_eq!(
+            names(songs.peek_upcoming(2).await.unwrap()),
+            ["First", "Second"]
+        );
+        assert_eq!(
+            names(songs.peek_upcoming(10).await.unwrap()),
+            ["First", "Second", "Last"]
+        );
+        assert!(songs.peek_upcoming(0).await.unwrap().is_empty());
+        assert_eq!(
+            queue_names(&songs).await,
+            ["Playing", "First", "Second", "Last"]
+        );
     }
 }
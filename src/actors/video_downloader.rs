@@ -1,48 +1,398 @@
 use serde::{Deserialize, Serialize};
-use std::{fs::File, io::BufReader, path::Path, sync::Arc};
-use tokio::sync::oneshot;
-use tracing::{debug, error, info, trace};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fs::File,
+    io::BufReader,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore, TryAcquireError};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, trace, warn, Instrument, Span};
+use uuid::Uuid;
 
-use crate::utils::{
-    dash_processor::{DashProcessor, ProcessingMode},
-    yt_downloader::{VideoProcessError, YtDownloader},
+use crate::{
+    actors::song_coordinator::{QueuedSongStatus, Song, SongActorHandle},
+    settings::{DownloadSettings, TranscodeSettings},
+    utils::{
+        dash_processor::{
+            media_segment_name, DashProcessor, ProcessingMode, VideoOutput, MEDIA_SEGMENT_TEMPLATE,
+        },
+        mpd::{adaptation_set_templates, append_adaptation_sets},
+        process::{capture_stderr, ProcessGroups},
+        slug::AssetPaths,
+        yt_downloader::{TrimRange, VideoProcessError, YtDownloader},
+    },
 };
 
 #[derive(Serialize, Deserialize)]
-struct VideoStatus {
-    segments: u32,
-    is_key_changeable: bool,
+pub struct VideoStatus {
+    // the song's title as shown in the queue
+    #[serde(default)]
+    pub display_name: Option<String>,
+    // the full title when the queued one was cleaned or shortened
+    #[serde(default)]
+    pub original_name: Option<String>,
+    // the link the folder was downloaded from, absent for folders from
+    // before it was recorded
+    #[serde(default)]
+    pub yt_link: Option<String>,
+    pub segments: u32,
+    // ffmpeg's `-media_seg_name`, absent for folders from before it was set
+    #[serde(default)]
+    pub media_segment_template: Option<String>,
+    pub is_key_changeable: bool,
+    #[serde(default)]
+    pub audio_only: bool,
+    #[serde(default)]
+    pub video_output: VideoOutput,
+    // semitone shift to the DASH adaptation set carrying that audio
+    #[serde(default)]
+    pub audio_adaptation_sets: BTreeMap<i32, usize>,
+    #[serde(default)]
+    pub trim: Option<TrimRange>,
+    #[serde(default)]
+    pub duration_seconds: Option<f64>,
+    // file name of the merged source video, set when it is retained
+    #[serde(default)]
+    pub source_file: Option<String>,
+    // file names of the WebVTT captions fetched with the video, served from
+    // `/dash/<folder>/<file>`
+    #[serde(default)]
+    pub subtitles: Vec<String>,
+}
+
+impl VideoStatus {
+    /// Reads `status.json` from a song's folder.
+    pub fn load(song_dir: &Path) -> std::io::Result<Self> {
+        let file = File::open(song_dir.join("status.json"))?;
+        serde_json::from_reader(BufReader::new(file)).map_err(std::io::Error::other)
+    }
+
+    /// The title the folder was downloaded for, before any cleaning.
+    pub fn title(&self) -> Option<&str> {
+        self.original_name
+            .as_deref()
+            .or(self.display_name.as_deref())
+    }
+
+    /// Whether the folder was downloaded for this title and link, so it may
+    /// be cleared and downloaded again for them. Folders that didn't record
+    /// one of them aren't checked against it.
+    pub fn belongs_to(&self, title: &str, yt_link: &str) -> bool {
+        self.title().is_none_or(|owner| owner == title)
+            && self.yt_link.as_deref().is_none_or(|link| link == yt_link)
+    }
+
+    /// Whether the folder can be served from the cache for this title and
+    /// link. Unlike `belongs_to`, a folder that didn't record its link
+    /// never is, since nothing tells which video it holds.
+    pub fn serves(&self, title: &str, yt_link: &str) -> bool {
+        self.yt_link.is_some() && self.belongs_to(title, yt_link)
+    }
+
+    /// Writes `status.json` to a song's folder.
+    pub fn save(&self, song_dir: &Path) -> std::io::Result<()> {
+        let file = File::create(song_dir.join("status.json"))?;
+        serde_json::to_writer_pretty(file, self).map_err(std::io::Error::other)
+    }
+}
+
+#[derive(Debug)]
+pub struct DownloadedVideo {
+    // the merged source file left for the caller to clean up, absent when
+    // the song was already cached or the source is retained
+    pub source_path: Option<String>,
+    pub duration_seconds: Option<f64>,
 }
 
 pub enum VideoDlActorMessage {
     DownloadVideo {
+        song_uuid: Uuid,
         yt_link: String,
         name: String,
+        original_name: Option<String>,
+        folder: String,
         is_key_changeable: bool,
-        respond_to: oneshot::Sender<Result<String, VideoProcessError>>,
+        trim: Option<TrimRange>,
+        // the room the song is queued in, told when a consumer starts on it
+        song_actor_handle: Arc<SongActorHandle>,
+        // the requesting song's span, so consumer logs carry its uuid
+        span: Span,
+        respond_to: oneshot::Sender<Result<DownloadedVideo, VideoProcessError>>,
+    },
+    ExtendPitchShifts {
+        yt_link: String,
+        folder: String,
+        trim: Option<TrimRange>,
+        pitch_shifts: Vec<i32>,
+        // released once the consumer is done with the folder, not when the
+        // caller stops waiting
+        extending: ExtendingFolder,
+        span: Span,
+        respond_to: oneshot::Sender<Result<BTreeMap<i32, usize>, VideoProcessError>>,
     },
 }
 
+impl VideoDlActorMessage {
+    fn span(&self) -> Span {
+        match self {
+            VideoDlActorMessage::DownloadVideo { span, .. } => span.clone(),
+            VideoDlActorMessage::ExtendPitchShifts { span, .. } => span.clone(),
+        }
+    }
+}
+
+/// A download a consumer is currently processing.
+struct ActiveDownload {
+    yt_link: String,
+    folder: String,
+    consumer_id: u8,
+    started_at: Instant,
+    cancel: CancellationToken,
+}
+
+/// In-flight downloads by song uuid, shared by the consumers and the handle.
+type ActiveDownloads = Arc<Mutex<HashMap<Uuid, ActiveDownload>>>;
+
+/// How many failed downloads keep their error output.
+const MAX_DOWNLOAD_LOGS: usize = 50;
+
+/// Only the tail of longer output is kept, that's where yt-dlp and ffmpeg
+/// report what went wrong.
+const MAX_DOWNLOAD_LOG_BYTES: usize = 16 * 1024;
+
+/// The pause before retrying a failed transcode, giving a host short on
+/// memory a moment to recover.
+const TRANSCODE_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Length of a DASH segment in seconds.
+pub(crate) const SEGMENT_SECONDS: u32 = 4;
+
+/// Clients whose slots are pruned once this many have been seen.
+const MAX_TRACKED_CLIENTS: usize = 1024;
+
+/// Download slots per client IP, created on a client's first download.
+type ClientSlots = Arc<Mutex<HashMap<IpAddr, Arc<Semaphore>>>>;
+
+/// Error output of the most recent failed downloads by song uuid, so a
+/// failure can be diagnosed without shell access to the server.
+#[derive(Clone, Default)]
+struct DownloadLogs(Arc<Mutex<VecDeque<(Uuid, String)>>>);
+
+impl DownloadLogs {
+    fn record(&self, song_uuid: Uuid, log: &str) {
+        let mut start = log.len().saturating_sub(MAX_DOWNLOAD_LOG_BYTES);
+        while !log.is_char_boundary(start) {
+            start += 1;
+        }
+
+        let mut logs = self.0.lock().unwrap();
+        logs.retain(|(uuid, _)| *uuid != song_uuid);
+        logs.push_back((song_uuid, log[start..].to_string()));
+        if logs.len() > MAX_DOWNLOAD_LOGS {
+            logs.pop_front();
+        }
+    }
+
+    fn clear(&self, song_uuid: Uuid) {
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|(uuid, _)| *uuid != song_uuid);
+    }
+
+    fn get(&self, song_uuid: Uuid) -> Option<String> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(uuid, _)| *uuid == song_uuid)
+            .map(|(_, log)| log.clone())
+    }
+}
+
+#[derive(Serialize)]
+pub struct ActiveDownloadInfo {
+    pub song_uuid: String,
+    pub yt_link: String,
+    pub consumer_id: u8,
+    pub running_seconds: u64,
+}
+
+/// Checks whether a song is already processed in a way that can be served
+/// for a request, shared by the consumers and the handle.
+#[derive(Clone)]
+struct CacheProbe {
+    assets: AssetPaths,
+    audio_only: bool,
+    video_output: VideoOutput,
+    pitch_shifts: Vec<i32>,
+}
+
+impl CacheProbe {
+    fn is_cached(
+        &self,
+        folder: &str,
+        title: &str,
+        yt_link: &str,
+        is_key_changeable: bool,
+        trim: &Option<TrimRange>,
+    ) -> bool {
+        let base_path = self.assets.song_dir(folder).display().to_string();
+        let status = match VideoStatus::load(Path::new(&base_path)) {
+            Ok(status) => status,
+            Err(e) => {
+                trace!("Failed to read status.json in {}: {}", base_path, e);
+                return false;
+            }
+        };
+
+        // folder names are hashed from the title and link, so this only
+        // trips on a hash collision or a folder from before the link was
+        // recorded
+        if !status.serves(title, yt_link) {
+            trace!(
+                "Folder {} belongs to {:?} from {:?}",
+                base_path,
+                status.title(),
+                status.yt_link
+            );
+            return false;
+        }
+
+        // Check key_changeable compatibility
+        if is_key_changeable && !status.is_key_changeable {
+            trace!("Key change requested but existing file doesn't support it");
+            return false;
+        }
+
+        // folders from before the mapping was recorded are trusted as is
+        if is_key_changeable
+            && !status.audio_adaptation_sets.is_empty()
+            && self
+                .pitch_shifts
+                .iter()
+                .any(|shift| !status.audio_adaptation_sets.contains_key(shift))
+        {
+            trace!("Existing file lacks configured pitch shifts");
+            return false;
+        }
+
+        if status.audio_only != self.audio_only {
+            trace!(
+                "Existing file audio_only {} differs from config",
+                status.audio_only
+            );
+            return false;
+        }
+
+        if !self.audio_only && status.video_output != self.video_output {
+            trace!(
+                "Existing file video {:?} differs from config",
+                status.video_output
+            );
+            return false;
+        }
+
+        if status.trim != *trim {
+            trace!(
+                "Requested trim {:?} differs from existing {:?}",
+                trim,
+                status.trim
+            );
+            return false;
+        }
+
+        // Check if corresponding chunk file exists
+        let template = status
+            .media_segment_template
+            .as_deref()
+            .unwrap_or(MEDIA_SEGMENT_TEMPLATE);
+        let chunk_path = format!(
+            "{}/{}",
+            base_path,
+            // audio is the first stream without video
+            media_segment_name(template, if status.audio_only { 0 } else { 1 }, status.segments)
+        );
+
+        let chunk_exists = Path::new(&chunk_path).exists();
+
+        trace!(
+            "Checking for chunk file: {} - {}",
+            chunk_path,
+            if chunk_exists { "found" } else { "not found" }
+        );
+
+        chunk_exists
+    }
+
+    /// The folders downloaded from `yt_link`, with the titles they were
+    /// downloaded for.
+    fn folders_for_link(&self, yt_link: &str) -> Vec<(String, String)> {
+        let dirs = match self.assets.song_dirs() {
+            Ok(dirs) => dirs,
+            Err(e) => {
+                trace!("Failed to read {}: {}", self.assets.root.display(), e);
+                return Vec::new();
+            }
+        };
+
+        dirs.iter()
+            .filter_map(|dir| {
+                let status = VideoStatus::load(dir).ok()?;
+                if status.yt_link.as_deref() != Some(yt_link) {
+                    return None;
+                }
+                let folder = dir.file_name()?.to_str()?.to_string();
+                Some((folder, status.title()?.to_string()))
+            })
+            .collect()
+    }
+}
+
+/// State shared by every consumer.
+#[derive(Clone)]
+struct ConsumerPool {
+    // limits concurrent transcodes across consumers
+    transcode_permits: Arc<Semaphore>,
+    active_downloads: ActiveDownloads,
+    download_logs: DownloadLogs,
+    cache: CacheProbe,
+    ffmpeg_path: PathBuf,
+    processes: ProcessGroups,
+}
+
 struct VideoDlActor {
     receiver: async_channel::Receiver<VideoDlActorMessage>,
     downloader: Arc<YtDownloader>,
-    base_dir: String,
+    assets: AssetPaths,
+    download_settings: DownloadSettings,
+    transcode_settings: TranscodeSettings,
+    pool: ConsumerPool,
     consumer_id: u8,
 }
 
 impl VideoDlActor {
     fn new(
         receiver: async_channel::Receiver<VideoDlActorMessage>,
-        base_dir: String,
+        assets: AssetPaths,
         video_downloader: Arc<YtDownloader>,
+        download_settings: DownloadSettings,
+        transcode_settings: TranscodeSettings,
+        pool: ConsumerPool,
         consumer_id: u8,
-
     ) -> Self {
         trace!("Initializing VideoDlActor consumer {}", consumer_id);
         VideoDlActor {
             receiver,
-            base_dir,
+            assets,
             downloader: video_downloader,
+            download_settings,
+            transcode_settings,
+            pool,
             consumer_id,
         }
     }
@@ -55,31 +405,56 @@ impl VideoDlActor {
 
         match msg {
             VideoDlActorMessage::DownloadVideo {
+                song_uuid,
                 yt_link,
                 name,
+                original_name,
+                folder,
                 is_key_changeable,
+                trim,
+                song_actor_handle,
                 respond_to,
+                ..
             } => {
                 info!(
                     "Consumer {} starting to process video from {} to path {}",
                     self.consumer_id, yt_link, name
                 );
 
-                let video_path = format!("{}/{}", self.base_dir, name);
+                let video_path = self.assets.song_dir(&folder).display().to_string();
+                let title = original_name.as_deref().unwrap_or(&name);
 
-                info!(
-                    "video exists: {}",
-                    self.video_exists(&video_path, is_key_changeable)
-                );
-                if Path::new(&video_path).exists()
-                    && self.video_exists(&video_path, is_key_changeable)
-                {
+                let cached = self
+                    .pool
+                    .cache
+                    .is_cached(&folder, title, &yt_link, is_key_changeable, &trim);
+                info!("video exists: {}", cached);
+                if Path::new(&video_path).exists() && cached {
                     info!(
-                        "Consumer {} found existing processed video {} in path {}/{}",
-                        self.consumer_id, yt_link, self.base_dir, name
+                        "Consumer {} found existing processed video {} in path {}",
+                        self.consumer_id, yt_link, video_path
                     );
-                    let _ = respond_to.send(Ok(String::from("success")));
+                    let duration_seconds = self
+                        .read_status(&video_path)
+                        .and_then(|status| status.duration_seconds);
+                    let _ = respond_to.send(Ok(DownloadedVideo {
+                        source_path: None,
+                        duration_seconds,
+                    }));
                 } else {
+                    if let Some(owner) = foreign_owner(Path::new(&video_path), title, &yt_link) {
+                        // another song's folder is never cleared, even though
+                        // only a hash collision can lead here
+                        error!(
+                            "Consumer {} found folder {} in use by {}",
+                            self.consumer_id, video_path, owner
+                        );
+                        let _ = respond_to.send(Err(VideoProcessError::FilenameError(format!(
+                            "{} belongs to {}",
+                            video_path, owner
+                        ))));
+                        return;
+                    }
                     if Path::new(&video_path).exists() {
                         trace!(
                             "Consumer {} clearing existing folder at {}",
@@ -98,9 +473,46 @@ impl VideoDlActor {
                         }
                     }
 
-                    let result = self
-                        .process_video(&yt_link, &self.base_dir, &name, &is_key_changeable, &4)
-                        .await;
+                    if let Err(err) = song_actor_handle
+                        .update_song_status(song_uuid, QueuedSongStatus::Downloading)
+                        .await
+                    {
+                        debug!(
+                            "Consumer {} could not mark {} as downloading: {}",
+                            self.consumer_id, song_uuid, err
+                        );
+                    }
+
+                    let mode = ProcessingMode::for_song(
+                        is_key_changeable,
+                        &self.transcode_settings.pitch_shifts,
+                    );
+                    let cancel = self.register_download(song_uuid, &yt_link, &folder);
+                    // dropping the processing future kills its child process
+                    let (result, stderr) = capture_stderr(async {
+                        tokio::select! {
+                            result = self.process_video(&yt_link, &name, original_name.as_deref(), &folder, &mode, &trim) => result,
+                            _ = cancel.cancelled() => {
+                                warn!(
+                                    "Consumer {} cancelled download of {}",
+                                    self.consumer_id, yt_link
+                                );
+                                Err(VideoProcessError::Cancelled)
+                            }
+                        }
+                    })
+                    .await;
+                    self.unregister_download(song_uuid);
+                    match &result {
+                        Ok(_) => self.pool.download_logs.clear(song_uuid),
+                        // the error goes last, as only the log's tail is kept
+                        Err(err) if !stderr.trim().is_empty() => self
+                            .pool
+                            .download_logs
+                            .record(song_uuid, &format!("{}\n{}", stderr.trim_end(), err)),
+                        Err(err) => self.pool.download_logs.record(song_uuid, &err.to_string()),
+                    }
+
                     info!(
                         "Consumer {} finished processing video from {}: {:?}",
                         self.consumer_id,
@@ -110,87 +522,131 @@ impl VideoDlActor {
                     let _ = respond_to.send(result);
                 }
             }
+            VideoDlActorMessage::ExtendPitchShifts {
+                yt_link,
+                folder,
+                trim,
+                pitch_shifts,
+                extending: _extending,
+                respond_to,
+                ..
+            } => {
+                info!(
+                    "Consumer {} extending {} with pitch shifts {:?}",
+                    self.consumer_id, folder, pitch_shifts
+                );
+                let result = self
+                    .extend_pitch_shifts(&yt_link, &folder, &trim, &pitch_shifts)
+                    .await;
+                if let Err(e) = &result {
+                    error!(
+                        "Consumer {} failed to extend {}: {}",
+                        self.consumer_id, folder, e
+                    );
+                }
+                let _ = respond_to.send(result);
+            }
         }
     }
 
-    fn video_exists(&self, base_path: &str, is_key_changeable: bool) -> bool {
-        let status_path = format!("{}/status.json", base_path);
+    fn register_download(&self, song_uuid: Uuid, yt_link: &str, folder: &str) -> CancellationToken {
+        let cancel = CancellationToken::new();
+        self.pool.active_downloads.lock().unwrap().insert(
+            song_uuid,
+            ActiveDownload {
+                yt_link: yt_link.to_string(),
+                folder: folder.to_string(),
+                consumer_id: self.consumer_id,
+                started_at: Instant::now(),
+                cancel: cancel.clone(),
+            },
+        );
+        cancel
+    }
 
-        // Check if status.json exists
-        if !Path::new(&status_path).exists() {
-            trace!(
-                "Consumer {} - status.json not found at {}",
-                self.consumer_id,
-                status_path
-            );
-            return false;
+    fn unregister_download(&self, song_uuid: Uuid) {
+        let mut active_downloads = self.pool.active_downloads.lock().unwrap();
+        // a re-dispatched song may have been picked up by another consumer
+        if active_downloads
+            .get(&song_uuid)
+            .is_some_and(|download| download.consumer_id == self.consumer_id)
+        {
+            active_downloads.remove(&song_uuid);
         }
+    }
 
-        // Read and parse status.json
-        let file = match File::open(&status_path) {
-            Ok(file) => file,
-            Err(e) => {
-                trace!(
-                    "Consumer {} - Failed to open status.json: {}",
-                    self.consumer_id,
-                    e
-                );
-                return false;
-            }
-        };
-
-        let status: VideoStatus = match serde_json::from_reader(BufReader::new(file)) {
-            Ok(status) => status,
+    fn read_status(&self, base_path: &str) -> Option<VideoStatus> {
+        match VideoStatus::load(Path::new(base_path)) {
+            Ok(status) => Some(status),
             Err(e) => {
                 trace!(
-                    "Consumer {} - Failed to parse status.json: {}",
+                    "Consumer {} - Failed to read status.json in {}: {}",
                     self.consumer_id,
+                    base_path,
                     e
                 );
-                return false;
+                None
             }
-        };
-
-        // Check key_changeable compatibility
-        if is_key_changeable && !status.is_key_changeable {
-            trace!(
-                "Consumer {} - Key change requested but existing file doesn't support it",
-                self.consumer_id
-            );
-            return false;
         }
+    }
 
-        // Check if corresponding chunk file exists
-        let chunk_path = format!("{}/chunk-stream1-{:05}.m4s", base_path, status.segments);
-    
-        debug!("chunk_path: {}", chunk_path);
-
-        let chunk_exists = Path::new(&chunk_path).exists();
-
+    /// Checks the video's metadata against `trim` and `max_seconds` before
+    /// anything is downloaded, see `RemoteMetadata::check`.
+    async fn check_duration(
+        &self,
+        yt_link: &str,
+        trim: &Option<TrimRange>,
+        max_seconds: Option<u64>,
+    ) -> Result<(), VideoProcessError> {
+        let metadata = self.downloader.fetch_metadata(yt_link).await?;
         trace!(
-            "Consumer {} - Checking for chunk file: {} - {}",
+            "Consumer {} fetched metadata for {}: {:?}",
             self.consumer_id,
-            chunk_path,
-            if chunk_exists { "found" } else { "not found" }
+            yt_link,
+            metadata
         );
-
-        chunk_exists
+        metadata.check(trim, max_seconds)
     }
 
     async fn process_video(
         &self,
         yt_link: &str,
-        base_dir: &str,
         name: &str,
-        is_key_changeable: &bool,
-        segment_duration: &u32,
-    ) -> Result<String, VideoProcessError> {
+        original_name: Option<&str>,
+        slug: &str,
+        mode: &ProcessingMode,
+        trim: &Option<TrimRange>,
+    ) -> Result<DownloadedVideo, VideoProcessError> {
         trace!(
             "Consumer {} starting download of {}",
             self.consumer_id,
             yt_link
         );
-        let video_metadata = self.downloader.download(yt_link, base_dir, name).await?;
+        let max_seconds = self.download_settings.max_duration_seconds;
+        if trim.is_some() || max_seconds.is_some() {
+            self.check_duration(yt_link, trim, max_seconds).await?;
+        }
+
+        let scratch = ScratchDir(
+            self.download_settings
+                .temp_dir
+                .as_ref()
+                .map(|temp_dir| temp_dir.join(slug)),
+        );
+
+        let video_metadata = self
+            .downloader
+            .download(
+                yt_link,
+                &self.assets.song_dir(slug),
+                slug,
+                *trim,
+                scratch.0.as_deref(),
+                // a generated background only needs the audio
+                self.download_settings.audio_only
+                    || self.transcode_settings.video_output.is_generated(),
+            )
+            .await?;
         let (dir, file_name, extension, duration_seconds) = (
             video_metadata.directory,
             video_metadata.filename,
@@ -198,46 +654,51 @@ impl VideoDlActor {
             video_metadata.duration_seconds,
         );
 
+        trace!(
+            "Consumer {} preparing dash processing ({:?}) for {}",
+            self.consumer_id,
+            mode,
+            file_name
+        );
+
         let status_file_path = format!("{}/status.json", dir);
         let status = VideoStatus {
-            segments: (duration_seconds / (*segment_duration as f64)).ceil() as u32,
-            is_key_changeable: *is_key_changeable,
+            display_name: Some(name.to_string()),
+            original_name: original_name.map(str::to_string),
+            yt_link: Some(yt_link.to_string()),
+            audio_adaptation_sets: mode.audio_adaptation_sets(),
+            media_segment_template: Some(MEDIA_SEGMENT_TEMPLATE.to_string()),
+            segments: (duration_seconds / SEGMENT_SECONDS as f64).ceil() as u32,
+            is_key_changeable: matches!(mode, ProcessingMode::PitchShift(_)),
+            audio_only: self.download_settings.audio_only,
+            video_output: self.transcode_settings.video_output,
+            trim: *trim,
+            duration_seconds: Some(duration_seconds),
+            source_file: self
+                .download_settings
+                .retain_source
+                .then(|| format!("{}.{}", file_name, extension)),
+            subtitles: subtitle_files(Path::new(&dir)),
         };
 
-        match File::create(&status_file_path) {
-            Ok(file) => {
-                if let Err(e) = serde_json::to_writer_pretty(file, &status) {
-                    trace!(
-                        "Consumer {} failed to write status file {}: {}",
-                        self.consumer_id,
-                        status_file_path,
-                        e
-                    );
-                    return Err(VideoProcessError::PitchShiftError(format!(
-                        "Failed to write status file: {}",
-                        e
-                    )));
-                }
-                trace!(
-                    "Consumer {} wrote status file with {} segments to {}",
-                    self.consumer_id,
-                    status.segments,
-                    status_file_path
-                );
-            }
-            Err(e) => {
-                trace!(
-                    "Consumer {} failed to create status file {}: {}",
-                    self.consumer_id,
-                    status_file_path,
-                    e
-                );
-                return Err(VideoProcessError::PitchShiftError(format!(
-                    "Failed to create status file: {}",
-                    e
-                )));
-            }
+        if let Err(e) = status.save(Path::new(&dir)) {
+            trace!(
+                "Consumer {} failed to write status file {}: {}",
+                self.consumer_id,
+                status_file_path,
+                e
+            );
+            return Err(VideoProcessError::PitchShiftError(format!(
+                "Failed to write status file: {}",
+                e
+            )));
         }
+        trace!(
+            "Consumer {} wrote status file with {} segments to {}",
+            self.consumer_id,
+            status.segments,
+            status_file_path
+        );
 
         trace!(
             "Consumer {} completed download. Dir: {}, File: {}.{}",
@@ -247,37 +708,120 @@ impl VideoDlActor {
             extension
         );
 
-        let dash_processor = DashProcessor::new(4);
-        let mode;
+        let dash_processor = DashProcessor::from_settings(
+            self.pool.ffmpeg_path.clone(),
+            self.pool.processes.clone(),
+            SEGMENT_SECONDS,
+            &self.download_settings,
+            &self.transcode_settings,
+        );
 
-        if *is_key_changeable {
-            trace!(
-                "Consumer {} starting dash processing with pitch shifting for {}",
-                self.consumer_id,
-                file_name
-            );
-            mode = ProcessingMode::PitchShift(vec![-3, -2, -1, 0, 1, 2, 3])
-        } else {
-            trace!(
-                "Consumer {} starting dash processing with no pitch shifting for {}",
-                self.consumer_id,
-                file_name
-            );
-            mode = ProcessingMode::Copy;
-        }
+        let _permit = match self.pool.transcode_permits.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => {
+                info!(
+                    "Consumer {} waiting for a transcode slot for {}",
+                    self.consumer_id, file_name
+                );
+                self.pool.transcode_permits.acquire().await.map_err(|e| {
+                    VideoProcessError::PitchShiftError(format!(
+                        "Transcode slots unavailable: {}",
+                        e
+                    ))
+                })?
+            }
+        };
 
-        match dash_processor.execute(
-            &format!("{}/{}.{}", dir, file_name, extension),
-            &format!("{}/{}.mpd", dir, file_name),
-            &mode,
-        ) {
+        // ffmpeg writes to the scratch dir when set, and only the finished
+        // output is moved into the song folder
+        let dash_dir = match &scratch.0 {
+            Some(scratch_dir) => {
+                std::fs::create_dir_all(scratch_dir).map_err(|e| {
+                    VideoProcessError::PitchShiftError(format!(
+                        "Failed to create temp dir: {}",
+                        e
+                    ))
+                })?;
+                scratch_dir.display().to_string()
+            }
+            None => dir.clone(),
+        };
+
+        // the source stays on disk, so a failed transcode (e.g. ffmpeg running
+        // out of memory) is retried without downloading it again
+        let transcode_started = Instant::now();
+        let manifest_path = format!("{}/{}.mpd", dash_dir, file_name);
+        let max_attempts = self.transcode_settings.retries + 1;
+        let transcode = || async {
+            dash_processor
+                .execute(
+                    &format!("{}/{}.{}", dir, file_name, extension),
+                    &manifest_path,
+                    mode,
+                )
+                .await
+                .and_then(|_| match &scratch.0 {
+                    Some(scratch_dir) => move_dir_contents(scratch_dir, Path::new(&dir), ""),
+                    None => Ok(()),
+                })
+        };
+        let (result, attempt) = retry_transcode(
+            &manifest_path,
+            max_attempts,
+            TRANSCODE_RETRY_DELAY,
+            transcode,
+        )
+        .await;
+        match result {
             Ok(_) => {
                 trace!(
                     "Consumer {} completed pitch shifting for {}",
                     self.consumer_id,
                     file_name
                 );
-                Ok(format!("{}/{}.{}", dir, file_name, extension))
+                let output_manifest = Path::new(&dir).join(format!("{}.mpd", file_name));
+                match has_audio_stream(&output_manifest) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        warn!(
+                            "Consumer {} transcoded {} without an audio stream",
+                            self.consumer_id, file_name
+                        );
+                        // the folder would otherwise be served from the cache
+                        if let Err(e) = std::fs::remove_file(Path::new(&dir).join("status.json")) {
+                            warn!("Failed to remove status file in {}: {}", dir, e);
+                        }
+                        return Err(VideoProcessError::NoAudioStream);
+                    }
+                    Err(e) => {
+                        return Err(VideoProcessError::PitchShiftError(format!(
+                            "Failed to read manifest: {}",
+                            e
+                        )))
+                    }
+                }
+
+                let source_file = format!("{}.{}", file_name, extension);
+                info!(
+                    song = name,
+                    transcode_ms = transcode_started.elapsed().as_millis() as u64,
+                    output_bytes = output_size(Path::new(&dir), &source_file),
+                    "Consumer {} finished transcode",
+                    self.consumer_id
+                );
+
+                let source_path = format!("{}/{}", dir, source_file);
+                if self.download_settings.retain_source {
+                    info!(
+                        "Consumer {} retaining source video {}",
+                        self.consumer_id, source_path
+                    );
+                }
+
+                Ok(DownloadedVideo {
+                    source_path: (!self.download_settings.retain_source).then_some(source_path),
+                    duration_seconds: Some(duration_seconds),
+                })
             }
             Err(e) => {
                 trace!(
@@ -286,13 +830,253 @@ impl VideoDlActor {
                     file_name,
                     e
                 );
-                Err(VideoProcessError::PitchShiftError(format!(
-                    "Pitch shift failed: {}",
-                    e
-                )))
+                Err(VideoProcessError::TranscodeError {
+                    attempts: attempt,
+                    reason: e.to_string(),
+                })
             }
         }
     }
+
+    /// Encodes the `pitch_shifts` a processed song lacks and appends them to
+    /// its manifest, leaving the streams it already has untouched. The
+    /// source audio is downloaded again unless it was retained.
+    async fn extend_pitch_shifts(
+        &self,
+        yt_link: &str,
+        slug: &str,
+        trim: &Option<TrimRange>,
+        pitch_shifts: &[i32],
+    ) -> Result<BTreeMap<i32, usize>, VideoProcessError> {
+        let dir = self.assets.song_dir(slug);
+        let mut status = VideoStatus::load(&dir).map_err(|e| {
+            VideoProcessError::PitchShiftError(format!("Failed to read status file: {}", e))
+        })?;
+        if !status.is_key_changeable {
+            return Err(VideoProcessError::PitchShiftError(format!(
+                "{} was not processed for key changes",
+                slug
+            )));
+        }
+
+        let missing: Vec<i32> = pitch_shifts
+            .iter()
+            .copied()
+            .filter(|shift| !status.audio_adaptation_sets.contains_key(shift))
+            .collect();
+        if missing.is_empty() {
+            return Ok(status.audio_adaptation_sets);
+        }
+
+        let retained_source = status
+            .source_file
+            .as_ref()
+            .map(|source_file| dir.join(source_file))
+            .filter(|source_path| source_path.exists());
+        let (source_path, downloaded) = match retained_source {
+            Some(source_path) => (source_path.display().to_string(), false),
+            None => {
+                let metadata = self
+                    .downloader
+                    .download(yt_link, &dir, slug, *trim, None, true)
+                    .await?;
+                (metadata.path(), true)
+            }
+        };
+
+        let scratch_dir = match &self.download_settings.temp_dir {
+            Some(temp_dir) => temp_dir.join(format!("{}-keys", slug)),
+            None => dir.join("keys-tmp"),
+        };
+        let _scratch = ScratchDir(Some(scratch_dir.clone()));
+        let result = async {
+            std::fs::create_dir_all(&scratch_dir)?;
+            let _permit = self
+                .pool
+                .transcode_permits
+                .acquire()
+                .await
+                .map_err(std::io::Error::other)?;
+            DashProcessor::from_settings(
+                self.pool.ffmpeg_path.clone(),
+                self.pool.processes.clone(),
+                SEGMENT_SECONDS,
+                &self.download_settings,
+                &self.transcode_settings,
+            )
+            .with_audio_only(true)
+            .execute(
+                &source_path,
+                &scratch_dir.join(format!("{}.mpd", slug)).display().to_string(),
+                &ProcessingMode::PitchShift(missing.clone()),
+            )
+            .await
+        }
+        .await;
+        if downloaded {
+            if let Err(e) = std::fs::remove_file(&source_path) {
+                warn!("Failed to remove source {}: {}", source_path, e);
+            }
+        }
+        result.map_err(|e| {
+            VideoProcessError::PitchShiftError(format!("Pitch shift failed: {}", e))
+        })?;
+
+        // the extra segments are renamed so they can't collide with the
+        // existing ones, which share representation ids
+        let file_prefix = format!(
+            "keys{}-",
+            status.audio_adaptation_sets.values().max().map_or(0, |id| id + 1)
+        );
+        let manifest_path = dir.join(format!("{}.mpd", slug));
+        let merged = (|| {
+            let extra_path = scratch_dir.join(format!("{}.mpd", slug));
+            let extra = std::fs::read(&extra_path)?;
+            std::fs::remove_file(&extra_path)?;
+            move_dir_contents(&scratch_dir, &dir, &file_prefix)?;
+
+            let (manifest, set_ids) =
+                append_adaptation_sets(&std::fs::read(&manifest_path)?, &extra, &file_prefix)?;
+            let staged_path = dir.join(format!("{}.mpd.tmp", slug));
+            std::fs::write(&staged_path, manifest)?;
+            std::fs::rename(&staged_path, &manifest_path)?;
+            Ok::<_, std::io::Error>(set_ids)
+        })()
+        .map_err(|e| {
+            VideoProcessError::PitchShiftError(format!("Failed to extend manifest: {}", e))
+        })?;
+
+        status.audio_adaptation_sets.extend(missing.into_iter().zip(merged));
+        status.save(&dir).map_err(|e| {
+            VideoProcessError::PitchShiftError(format!("Failed to write status file: {}", e))
+        })?;
+        info!(
+            "Consumer {} extended {} to pitch shifts {:?}",
+            self.consumer_id,
+            slug,
+            status.audio_adaptation_sets.keys().collect::<Vec<_>>()
+        );
+        Ok(status.audio_adaptation_sets)
+    }
+}
+
+/// A song's folder in the configured temp dir, removed once processing
+/// finishes whether it succeeded or not.
+struct ScratchDir(Option<PathBuf>);
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let Some(path) = &self.0 else {
+            return;
+        };
+        if path.exists() {
+            if let Err(e) = std::fs::remove_dir_all(path) {
+                warn!("Failed to remove temp dir {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// The title and link another song downloaded `dir` for, `None` when it's
+/// free or already belongs to `title` from `yt_link`.
+fn foreign_owner(dir: &Path, title: &str, yt_link: &str) -> Option<String> {
+    let status = VideoStatus::load(dir).ok()?;
+    (!status.belongs_to(title, yt_link)).then(|| {
+        format!(
+            "{} ({})",
+            status.title().unwrap_or_default(),
+            status.yt_link.as_deref().unwrap_or_default()
+        )
+    })
+}
+
+/// Whether a manifest has an audio adaptation set. Uploads without audio,
+/// e.g. a still image or a video-only stream, transcode fine but leave the
+/// player nothing to sing along to.
+fn has_audio_stream(manifest_path: &Path) -> std::io::Result<bool> {
+    let sets = adaptation_set_templates(&std::fs::read(manifest_path)?)?;
+    Ok(sets
+        .iter()
+        .any(|set| set.content_type.as_deref() == Some("audio")))
+}
+
+/// Runs `transcode` until it succeeds or `max_attempts` runs have failed,
+/// returning the last result and the number of runs.
+async fn retry_transcode<F, Fut>(
+    manifest_path: &str,
+    max_attempts: u32,
+    retry_delay: Duration,
+    mut transcode: F,
+) -> (std::io::Result<()>, u32)
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = std::io::Result<()>>,
+{
+    let mut attempt = 1;
+    loop {
+        match transcode().await {
+            Err(e) if attempt < max_attempts => {
+                warn!(
+                    "Transcode attempt {}/{} for {} failed, retrying: {}",
+                    attempt, max_attempts, manifest_path, e
+                );
+                // ffmpeg won't overwrite a manifest left by the failed run
+                if let Err(e) = std::fs::remove_file(manifest_path) {
+                    if e.kind() != std::io::ErrorKind::NotFound {
+                        warn!("Failed to remove partial manifest {}: {}", manifest_path, e);
+                    }
+                }
+                attempt += 1;
+                tokio::time::sleep(retry_delay).await;
+            }
+            result => return (result, attempt),
+        }
+    }
+}
+
+/// Moves every file in `from` into `to`, prepending `prefix` to their names
+/// and copying when a rename can't cross filesystems (e.g. out of a tmpfs).
+fn move_dir_contents(from: &Path, to: &Path, prefix: &str) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let mut file_name = std::ffi::OsString::from(prefix);
+        file_name.push(entry.file_name());
+        let target = to.join(file_name);
+        if std::fs::rename(entry.path(), &target).is_err() {
+            std::fs::copy(entry.path(), &target)?;
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// The WebVTT files in a song folder, sorted so status.json is stable.
+fn subtitle_files(dir: &Path) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut files: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.ends_with(".vtt"))
+        .collect();
+    files.sort();
+    files
+}
+
+/// Total size of the files in a song folder, excluding the source video.
+fn output_size(dir: &Path, source_file: &str) -> u64 {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+
+    entries
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_name() != source_file)
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
 }
 
 async fn run_video_dl_actor(mut actor: VideoDlActor) {
@@ -318,7 +1102,8 @@ async fn run_video_dl_actor(mut actor: VideoDlActor) {
                     actor.receiver.capacity().unwrap(),
                     actor.receiver.len()
                 );
-                actor.handle_message(msg).await;
+                let span = msg.span();
+                actor.handle_message(msg).instrument(span).await;
                 trace!(
                     "Consumer {} completed processing. Channel capacity: {}, len: {}",
                     actor.consumer_id,
@@ -341,11 +1126,30 @@ async fn run_video_dl_actor(mut actor: VideoDlActor) {
 #[derive(Clone)]
 pub struct VideoDlActorHandle {
     sender: async_channel::Sender<VideoDlActorMessage>,
+    active_downloads: ActiveDownloads,
+    download_logs: DownloadLogs,
+    cache: CacheProbe,
+    // one per dispatched download until its task ends, bounding the tasks
+    // waiting on the channel
+    dispatch_permits: Arc<Semaphore>,
+    max_pending: usize,
+    client_slots: ClientSlots,
+    max_per_client: usize,
+    // song folders with pitch shifts being added, one run per song at a time
+    extending: Arc<Mutex<HashSet<String>>>,
 }
 
 impl VideoDlActorHandle {
-    pub fn new(base_dir: String, yt_downloader: Arc<YtDownloader>) -> Self {
+    pub fn new(
+        assets: AssetPaths,
+        yt_downloader: Arc<YtDownloader>,
+        ffmpeg_path: PathBuf,
+        processes: ProcessGroups,
+        download_settings: DownloadSettings,
+        transcode_settings: TranscodeSettings,
+    ) -> Self {
         trace!("Initializing VideoDlActorHandle");
+        let max_pending = download_settings.max_pending.max(1);
         let (sender, receiver) = async_channel::bounded(100);
         trace!(
             "Created channel with capacity: {}",
@@ -354,12 +1158,28 @@ impl VideoDlActorHandle {
 
         const NUM_CONSUMERS: u8 = 5;
         trace!("Starting {} consumers", NUM_CONSUMERS);
+        let pool = ConsumerPool {
+            transcode_permits: Arc::new(Semaphore::new(transcode_settings.max_concurrent.max(1))),
+            active_downloads: ActiveDownloads::default(),
+            download_logs: DownloadLogs::default(),
+            cache: CacheProbe {
+                assets: assets.clone(),
+                audio_only: download_settings.audio_only,
+                video_output: transcode_settings.video_output,
+                pitch_shifts: transcode_settings.pitch_shifts.clone(),
+            },
+            ffmpeg_path,
+            processes,
+        };
         for consumer_id in 0..NUM_CONSUMERS {
             trace!("Spawning consumer {}", consumer_id);
             let actor = VideoDlActor::new(
                 receiver.clone(),
-                base_dir.clone(),
+                assets.clone(),
                 yt_downloader.clone(),
+                download_settings.clone(),
+                transcode_settings.clone(),
+                pool.clone(),
                 consumer_id,
             );
             tokio::spawn(run_video_dl_actor(actor));
@@ -367,15 +1187,184 @@ impl VideoDlActorHandle {
         trace!("All consumers spawned");
         trace!("Total receiver count: {}", receiver.receiver_count());
 
-        Self { sender }
+        Self {
+            sender,
+            active_downloads: pool.active_downloads,
+            download_logs: pool.download_logs,
+            cache: pool.cache,
+            dispatch_permits: Arc::new(Semaphore::new(max_pending)),
+            max_pending,
+            client_slots: ClientSlots::default(),
+            max_per_client: download_settings.max_per_client,
+            extending: Arc::default(),
+        }
+    }
+
+    /// Whether a download of the song with `title` from `yt_link` into
+    /// `folder` would be served from the cache without fetching anything.
+    pub fn is_cached(
+        &self,
+        folder: &str,
+        title: &str,
+        yt_link: &str,
+        is_key_changeable: bool,
+        trim: &Option<TrimRange>,
+    ) -> bool {
+        self.cache
+            .is_cached(folder, title, yt_link, is_key_changeable, trim)
+    }
+
+    /// The folders downloaded from `yt_link` and their titles, for checking
+    /// the cache without knowing the title a song would be queued with.
+    pub fn folders_for_link(&self, yt_link: &str) -> Vec<(String, String)> {
+        self.cache.folders_for_link(yt_link)
+    }
+
+    /// Number of download requests waiting for a free consumer.
+    pub fn backlog(&self) -> usize {
+        self.sender.len()
+    }
+
+    pub fn is_saturated(&self) -> bool {
+        self.sender.is_full()
+    }
+
+    /// Waits for a slot for dispatching a download, held until its task
+    /// ends. At most `download.max_pending` are handed out at once.
+    pub async fn reserve_dispatch(&self) -> OwnedSemaphorePermit {
+        self.dispatch_permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("dispatch permits are never closed")
+    }
+
+    /// A slot for dispatching a download, or `None` when all
+    /// `download.max_pending` are taken, so requests can be rejected before
+    /// their task is spawned.
+    pub fn try_reserve_dispatch(&self) -> Option<OwnedSemaphorePermit> {
+        self.dispatch_permits.clone().try_acquire_owned().ok()
+    }
+
+    /// A slot for one of `client`'s downloads, held until the download
+    /// ends, or an error when it already has `download.max_per_client`
+    /// running. Checked before a dispatch permit is taken, so one client
+    /// can't hold the permits every other client needs. `None` when the
+    /// downloads per client are unlimited.
+    pub fn try_reserve_client_slot(
+        &self,
+        client: IpAddr,
+    ) -> Result<Option<OwnedSemaphorePermit>, TryAcquireError> {
+        if self.max_per_client == 0 {
+            return Ok(None);
+        }
+
+        let slots = {
+            let mut client_slots = self.client_slots.lock().unwrap();
+            if client_slots.len() >= MAX_TRACKED_CLIENTS {
+                // an idle client's slots are the same as fresh ones
+                client_slots.retain(|_, slots| slots.available_permits() < self.max_per_client);
+            }
+            client_slots
+                .entry(client)
+                .or_insert_with(|| Arc::new(Semaphore::new(self.max_per_client)))
+                .clone()
+        };
+        slots.try_acquire_owned().map(Some)
+    }
+
+    pub fn max_per_client(&self) -> usize {
+        self.max_per_client
+    }
+
+    /// Downloads dispatched and not yet finished, and the most allowed.
+    pub fn pending_dispatches(&self) -> (usize, usize) {
+        (
+            self.max_pending - self.dispatch_permits.available_permits(),
+            self.max_pending,
+        )
+    }
+
+    pub fn active_downloads(&self) -> Vec<ActiveDownloadInfo> {
+        self.active_downloads
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(song_uuid, download)| ActiveDownloadInfo {
+                song_uuid: song_uuid.to_string(),
+                yt_link: download.yt_link.clone(),
+                consumer_id: download.consumer_id,
+                running_seconds: download.started_at.elapsed().as_secs(),
+            })
+            .collect()
+    }
+
+    /// Folders a download or a pitch shift extension is writing to, which
+    /// must not be deleted from under it.
+    pub fn busy_folders(&self) -> HashSet<String> {
+        let mut folders = self.extending.lock().unwrap().clone();
+        folders.extend(
+            self.active_downloads
+                .lock()
+                .unwrap()
+                .values()
+                .map(|download| download.folder.clone()),
+        );
+        folders
+    }
+
+    /// Aborts an in-flight download, killing its yt-dlp or ffmpeg process.
+    /// Returns false when no download is running for the song.
+    pub fn cancel_download(&self, song_uuid: Uuid) -> bool {
+        match self.active_downloads.lock().unwrap().get(&song_uuid) {
+            Some(download) => {
+                download.cancel.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The error output of the song's last download, if it failed recently.
+    pub fn download_log(&self, song_uuid: Uuid) -> Option<String> {
+        self.download_logs.get(song_uuid)
+    }
+
+    /// Number of consumers still running. A consumer whose task died drops
+    /// its receiver, so this falls below the pool size.
+    pub fn live_consumers(&self) -> usize {
+        self.sender.receiver_count()
+    }
+
+    /// Stops accepting new downloads and waits up to `timeout` for the
+    /// consumers to work through what was already sent.
+    pub async fn drain(&self, timeout: Duration) {
+        self.sender.close();
+        info!(
+            "Draining downloads, {} pending, waiting up to {:?}",
+            self.backlog(),
+            timeout
+        );
+
+        let wait_for_consumers = async {
+            while self.sender.receiver_count() > 0 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        };
+
+        if tokio::time::timeout(timeout, wait_for_consumers).await.is_err() {
+            warn!("Timed out draining downloads, {} pending", self.backlog());
+        } else {
+            info!("All download consumers drained");
+        }
     }
 
     pub async fn download_video(
         &self,
-        yt_link: String,
-        name: String,
-        pitch_shift: bool,
-    ) -> Result<String, VideoProcessError> {
+        song: &Song,
+        song_actor_handle: Arc<SongActorHandle>,
+    ) -> Result<DownloadedVideo, VideoProcessError> {
+        let yt_link = &song.yt_link;
         trace!(
             "Requesting video download for {} (channel len: {})",
             yt_link,
@@ -384,9 +1373,15 @@ impl VideoDlActorHandle {
 
         let (send, recv) = oneshot::channel();
         let msg = VideoDlActorMessage::DownloadVideo {
+            song_uuid: song.uuid,
             yt_link: yt_link.clone(),
-            name: name.clone(),
-            is_key_changeable: pitch_shift.clone(),
+            name: song.name.clone(),
+            original_name: song.original_name.clone(),
+            folder: song.folder.clone(),
+            is_key_changeable: song.is_key_changeable,
+            trim: song.trim,
+            song_actor_handle,
+            span: Span::current(),
             respond_to: send,
         };
 
@@ -395,7 +1390,25 @@ impl VideoDlActorHandle {
             yt_link,
             self.sender.len()
         );
-        let _ = self.sender.send(msg).await;
+        if let Err(err) = self.sender.try_send(msg) {
+            return match err {
+                async_channel::TrySendError::Full(_) => {
+                    warn!(
+                        "Download queue full, rejecting {} (backlog: {})",
+                        yt_link,
+                        self.backlog()
+                    );
+                    Err(VideoProcessError::QueueFull {
+                        backlog: self.backlog(),
+                    })
+                }
+                async_channel::TrySendError::Closed(_) => {
+                    error!("Download channel closed, cannot download {}", yt_link);
+                    Err(VideoProcessError::ActorUnavailable)
+                }
+            };
+        }
+        info!("Download backlog depth: {}", self.backlog());
 
         trace!(
             "Message sent for {}. Channel status - len: {}, capacity: {}",
@@ -405,7 +1418,10 @@ impl VideoDlActorHandle {
         );
 
         trace!("Awaiting response for {}", yt_link);
-        let result = recv.await.expect("Actor task has been killed");
+        let result = recv
+            .await
+            .map_err(|_| VideoProcessError::ActorUnavailable)
+            .and_then(|result| result);
         trace!(
             "Received response for {}: {:?}",
             yt_link,
@@ -413,4 +1429,383 @@ impl VideoDlActorHandle {
         );
         result
     }
+
+    /// Encodes any of `pitch_shifts` a processed song doesn't have yet, for
+    /// keys outside the pre-generated set. Returns the song's full mapping of
+    /// semitone shift to adaptation set.
+    pub async fn extend_pitch_shifts(
+        &self,
+        yt_link: String,
+        folder: String,
+        trim: Option<TrimRange>,
+        pitch_shifts: Vec<i32>,
+    ) -> Result<BTreeMap<i32, usize>, VideoProcessError> {
+        let Some(extending) = ExtendingFolder::claim(&self.extending, &folder) else {
+            return Err(VideoProcessError::AlreadyExtending);
+        };
+
+        let (send, recv) = oneshot::channel();
+        let msg = VideoDlActorMessage::ExtendPitchShifts {
+            yt_link,
+            folder,
+            trim,
+            pitch_shifts,
+            extending,
+            span: Span::current(),
+            respond_to: send,
+        };
+
+        match self.sender.send(msg).await {
+            Ok(_) => recv
+                .await
+                .map_err(|_| VideoProcessError::ActorUnavailable)
+                .and_then(|result| result),
+            Err(_) => Err(VideoProcessError::ActorUnavailable),
+        }
+    }
+}
+
+/// A song folder claimed for a pitch shift extension, released when
+/// dropped. Sent along with the extension, so a caller that stops waiting
+/// doesn't release it before the consumer has finished with the folder.
+pub struct ExtendingFolder {
+    extending: Arc<Mutex<HashSet<String>>>,
+    folder: String,
+}
+
+impl ExtendingFolder {
+    /// `None` when an extension of the folder is already running.
+    fn claim(extending: &Arc<Mutex<HashSet<String>>>, folder: &str) -> Option<Self> {
+        extending
+            .lock()
+            .unwrap()
+            .insert(folder.to_string())
+            .then(|| ExtendingFolder {
+                extending: extending.clone(),
+                folder: folder.to_string(),
+            })
+    }
+}
+
+impl Drop for ExtendingFolder {
+    fn drop(&mut self) {
+        self.extending.lock().unwrap().remove(&self.folder);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{settings::AssetLayout, utils::binary::BinaryPaths};
+
+    /// A pool storing songs under `assets_root`, with binaries that don't
+    /// exist so only cached songs download.
+    pub(crate) fn handle(assets_root: &Path) -> VideoDlActorHandle {
+        handle_with(assets_root, DownloadSettings::default())
+    }
+
+    pub(crate) fn handle_with(
+        assets_root: &Path,
+        download_settings: DownloadSettings,
+    ) -> VideoDlActorHandle {
+        let ytdlp = assets_root.join("missing-yt-dlp");
+        handle_with_ytdlp(assets_root, download_settings, ytdlp)
+    }
+
+    /// A handle whose downloads run until a `go` file shows up in
+    /// `assets_root` and then fail, so tests can see them in flight.
+    #[cfg(unix)]
+    pub(crate) fn stalled_handle(
+        assets_root: &Path,
+        download_settings: DownloadSettings,
+    ) -> VideoDlActorHandle {
+        use std::os::unix::fs::PermissionsExt;
+
+        let ytdlp = assets_root.join("stalled-yt-dlp");
+        let go = assets_root.join("go");
+        let script = format!(
+            "#!/bin/sh\nwhile [ ! -f {} ]; do sleep 0.05; done\nexit 1\n",
+            go.display()
+        );
+        std::fs::write(&ytdlp, script).unwrap();
+        std::fs::set_permissions(&ytdlp, std::fs::Permissions::from_mode(0o755)).unwrap();
+        handle_with_ytdlp(assets_root, download_settings, ytdlp)
+    }
+
+    pub(crate) fn handle_with_ytdlp(
+        assets_root: &Path,
+        download_settings: DownloadSettings,
+        ytdlp: PathBuf,
+    ) -> VideoDlActorHandle {
+        let binaries = BinaryPaths {
+            ytdlp,
+            ffmpeg: assets_root.join("missing-ffmpeg"),
+        };
+        let processes = ProcessGroups::default();
+        let yt_downloader =
+            YtDownloader::new(binaries.clone(), processes.clone(), &download_settings);
+        VideoDlActorHandle::new(
+            AssetPaths::new(assets_root, AssetLayout::PerSong),
+            Arc::new(yt_downloader),
+            binaries.ffmpeg,
+            processes,
+            download_settings,
+            TranscodeSettings::default(),
+        )
+    }
+
+    pub(crate) fn write_status(dir: &Path, status: serde_json::Value) {
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join("status.json"), status.to_string()).unwrap();
+    }
+
+    pub(crate) fn write_last_segment(dir: &Path, segments: u32) {
+        let name = media_segment_name(MEDIA_SEGMENT_TEMPLATE, 1, segments);
+        std::fs::write(dir.join(name), "").unwrap();
+    }
+
+    fn probe(base_dir: &Path) -> CacheProbe {
+        CacheProbe {
+            assets: AssetPaths::new(base_dir, AssetLayout::PerSong),
+            audio_only: false,
+            video_output: VideoOutput::Source,
+            pitch_shifts: Vec::new(),
+        }
+    }
+
+    const LINK: &str = "https://www.youtube.com/watch?v=aaaaaaaaaaa";
+    const OTHER_LINK: &str = "https://www.youtube.com/watch?v=bbbbbbbbbbb";
+
+    #[test]
+    fn folder_of_another_song_is_foreign() {
+        let assets = tempfile::tempdir().unwrap();
+        let dir = assets.path().join("song");
+        assert_eq!(foreign_owner(&dir, "Song", LINK), None);
+
+        write_status(
+            &dir,
+            serde_json::json!({
+                "display_name": "Song",
+                "original_name": "Song (Official Video)",
+                "yt_link": LINK,
+                "segments": 1,
+                "is_key_changeable": false,
+            }),
+        );
+        assert_eq!(foreign_owner(&dir, "Song (Official Video)", LINK), None);
+        assert_eq!(
+            foreign_owner(&dir, "Song (Live)", LINK).as_deref(),
+            Some(format!("Song (Official Video) ({})", LINK).as_str())
+        );
+        assert!(foreign_owner(&dir, "Song (Official Video)", OTHER_LINK).is_some());
+    }
+
+    #[test]
+    fn folder_without_a_link_is_claimed_by_its_title() {
+        let assets = tempfile::tempdir().unwrap();
+        let dir = assets.path().join("song");
+        write_status(
+            &dir,
+            serde_json::json!({
+                "display_name": "Song",
+                "segments": 1,
+                "is_key_changeable": false,
+            }),
+        );
+        assert_eq!(foreign_owner(&dir, "Song", LINK), None);
+        assert!(foreign_owner(&dir, "Other Song", LINK).is_some());
+    }
+
+    #[test]
+    fn cache_only_serves_the_folder_owner() {
+        let assets = tempfile::tempdir().unwrap();
+        let dir = assets.path().join("song");
+        write_status(
+            &dir,
+            serde_json::json!({
+                "display_name": "Song",
+                "yt_link": LINK,
+                "segments": 2,
+                "is_key_changeable": false,
+            }),
+        );
+        write_last_segment(&dir, 2);
+
+        let cache = probe(assets.path());
+        assert!(cache.is_cached("song", "Song", LINK, false, &None));
+        assert!(!cache.is_cached("song", "Other Song", LINK, false, &None));
+        assert!(!cache.is_cached("song", "Song", OTHER_LINK, false, &None));
+    }
+
+    #[test]
+    fn cache_needs_a_recorded_link() {
+        let assets = tempfile::tempdir().unwrap();
+        let dir = assets.path().join("song");
+        write_status(
+            &dir,
+            serde_json::json!({
+                "display_name": "Song",
+                "segments": 2,
+                "is_key_changeable": false,
+            }),
+        );
+        write_last_segment(&dir, 2);
+
+        assert!(!probe(assets.path()).is_cached("song", "Song", LINK, false, &None));
+        // but the song's own download may still replace it
+        assert_eq!(foreign_owner(&dir, "Song", LINK), None);
+    }
+
+    #[test]
+    fn subtitles_are_listed_in_a_stable_order() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["song.ja.vtt", "song.mp4", "song.en.vtt", "manifest.mpd"] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+        assert_eq!(subtitle_files(dir.path()), ["song.en.vtt", "song.ja.vtt"]);
+        assert!(subtitle_files(&dir.path().join("missing")).is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_transcodes_are_retried_without_their_partial_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("song.mpd");
+        let manifest_path = manifest.display().to_string();
+        let runs = std::cell::Cell::new(0);
+        let transcode = || async {
+            runs.set(runs.get() + 1);
+            // ffmpeg refuses to run over an existing manifest
+            if manifest.exists() {
+                return Err(std::io::Error::other("manifest already exists"));
+            }
+            std::fs::write(&manifest, "").unwrap();
+            if runs.get() == 1 {
+                Err(std::io::Error::other("out of memory"))
+            } else {
+                Ok(())
+            }
+        };
+
+        let (result, attempts) =
+            retry_transcode(&manifest_path, 2, Duration::ZERO, transcode).await;
+        assert!(result.is_ok());
+        assert_eq!(attempts, 2);
+
+        // without retries the first failure is final
+        std::fs::remove_file(&manifest).unwrap();
+        runs.set(0);
+        let (result, attempts) =
+            retry_transcode(&manifest_path, 1, Duration::ZERO, transcode).await;
+        assert!(result.is_err());
+        assert_eq!((attempts, runs.get()), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn downloads_past_a_clients_quota_are_refused_a_slot() {
+        let assets = tempfile::tempdir().unwrap();
+        let settings = DownloadSettings {
+            max_per_client: 2,
+            ..Default::default()
+        };
+        let videodl = handle_with(assets.path(), settings);
+        let guest: IpAddr = "192.0.2.1".parse().unwrap();
+        let other_guest: IpAddr = "192.0.2.2".parse().unwrap();
+
+        // the guest queues three songs, the third is over its quota
+        let first = videodl.try_reserve_client_slot(guest).unwrap();
+        let second = videodl.try_reserve_client_slot(guest).unwrap();
+        assert!(first.is_some() && second.is_some());
+        assert!(videodl.try_reserve_client_slot(guest).is_err());
+
+        // other guests aren't held up
+        assert!(videodl.try_reserve_client_slot(other_guest).unwrap().is_some());
+
+        drop(first);
+        assert!(videodl.try_reserve_client_slot(guest).unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn a_zero_quota_leaves_downloads_unlimited() {
+        let assets = tempfile::tempdir().unwrap();
+        let settings = DownloadSettings {
+            max_per_client: 0,
+            ..Default::default()
+        };
+        let videodl = handle_with(assets.path(), settings);
+
+        let guest: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(videodl.try_reserve_client_slot(guest).unwrap().is_none());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn cancelled_extensions_keep_their_folder_until_the_consumer_is_done() {
+        let assets = tempfile::tempdir().unwrap();
+        let videodl = stalled_handle(assets.path(), DownloadSettings::default());
+        write_status(
+            &assets.path().join("song"),
+            serde_json::json!({
+                "yt_link": LINK,
+                "segments": 1,
+                "is_key_changeable": true,
+            }),
+        );
+        let extend = || {
+            videodl.extend_pitch_shifts(LINK.to_string(), "song".to_string(), None, vec![4])
+        };
+
+        // sent to a consumer, then dropped like on a client disconnect
+        let mut extension = Box::pin(extend());
+        assert!(futures_util::poll!(&mut extension).is_pending());
+        drop(extension);
+
+        // the consumer is still downloading the source into the folder
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(videodl.busy_folders().contains("song"));
+        assert!(matches!(
+            extend().await,
+            Err(VideoProcessError::AlreadyExtending)
+        ));
+
+        std::fs::write(assets.path().join("go"), "").unwrap();
+        for _ in 0..200 {
+            if videodl.busy_folders().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(videodl.busy_folders().is_empty());
+    }
+
+    #[test]
+    fn video_only_output_has_no_audio_stream() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("song.mpd");
+        let adaptation_set = |id, content_type| {
+            format!(
+                r#"<AdaptationSet id="{}" contentType="{}"><Representation id="{}"/></AdaptationSet>"#,
+                id, content_type, id
+            )
+        };
+
+        let video_only = format!("<MPD><Period>{}</Period></MPD>", adaptation_set(0, "video"));
+        std::fs::write(&manifest, video_only).unwrap();
+        assert!(!has_audio_stream(&manifest).unwrap());
+
+        let with_audio = format!(
+            "<MPD><Period>{}{}</Period></MPD>",
+            adaptation_set(0, "video"),
+            adaptation_set(1, "audio")
+        );
+        std::fs::write(&manifest, with_audio).unwrap();
+        assert!(has_audio_stream(&manifest).unwrap());
+
+        assert!(has_audio_stream(&dir.path().join("missing.mpd")).is_err());
+
+        // guests are told why the song failed
+        assert_eq!(
+            VideoProcessError::NoAudioStream.guest_reason(),
+            "The video has no audio stream"
+        );
+    }
 }
@@ -1,13 +1,34 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
-use tokio::sync::oneshot;
-use tracing::{error, info, trace};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, trace};
 
-use crate::utils::yt_searcher::{SearchError, SearchResult, YtSearcher};
+use crate::utils::yt_searcher::{SearchError, SearchOptions, SearchResult, YtSearcher};
 
 pub enum VideoSearcherActorMessage {
     SearchVideo {
         query: String,
+        options: SearchOptions,
+        // cancelled once a newer query from the same client arrives
+        cancel: CancellationToken,
+        respond_to: oneshot::Sender<Result<Vec<SearchResult>, SearchError>>,
+    },
+    // sends results one by one as yt-dlp prints them
+    StreamSearch {
+        query: String,
+        options: SearchOptions,
+        cancel: CancellationToken,
+        results: mpsc::Sender<Result<SearchResult, SearchError>>,
+        // dropped once the search is over
+        done: oneshot::Sender<()>,
+    },
+    ExpandPlaylist {
+        url: String,
+        max_items: usize,
         respond_to: oneshot::Sender<Result<Vec<SearchResult>, SearchError>>,
     },
 }
@@ -15,6 +36,7 @@ pub enum VideoSearcherActorMessage {
 struct VideoSearcherActor {
     receiver: async_channel::Receiver<VideoSearcherActorMessage>,
     yt_searcher: Arc<YtSearcher>,
+    retries: u32,
     consumer_id: u8,
 }
 
@@ -22,12 +44,14 @@ impl VideoSearcherActor {
     fn new(
         receiver: async_channel::Receiver<VideoSearcherActorMessage>,
         yt_searcher: Arc<YtSearcher>,
+        retries: u32,
         consumer_id: u8,
     ) -> Self {
         trace!("Initializing VideoDlActor consumer {}", consumer_id);
         VideoSearcherActor {
             receiver,
             yt_searcher,
+            retries,
             consumer_id,
         }
     }
@@ -38,18 +62,80 @@ impl VideoSearcherActor {
         match msg {
             VideoSearcherActorMessage::SearchVideo {
                 query,
+                options,
+                cancel,
                 respond_to,
             } => {
+                if cancel.is_cancelled() {
+                    debug!("Consumer {} skipping superseded search {}", self.consumer_id, query);
+                    let _ = respond_to.send(Err(SearchError::Superseded));
+                    return;
+                }
+
                 info!("Consumer {} starting to process search query {}", 
                     self.consumer_id, query);
 
-                let result = self.yt_searcher.search(&query).await;
+                // dropping the search kills its yt-dlp process
+                let result = tokio::select! {
+                    result = self.yt_searcher.search(&query, options, self.retries) => result,
+                    _ = cancel.cancelled() => Err(SearchError::Superseded),
+                };
 
                 info!("Consumer {} finished searching for {} result {}", 
                     self.consumer_id, query, 
                     if result.is_ok() { "success" } else { "failed" });
                 let _ = respond_to.send(result);
             }
+            VideoSearcherActorMessage::StreamSearch {
+                query,
+                options,
+                cancel,
+                results,
+                done,
+            } => {
+                if cancel.is_cancelled() {
+                    debug!(
+                        "Consumer {} skipping superseded search {}",
+                        self.consumer_id, query
+                    );
+                    let _ = results.send(Err(SearchError::Superseded)).await;
+                    return;
+                }
+
+                info!(
+                    "Consumer {} starting to stream search query {}",
+                    self.consumer_id, query
+                );
+
+                // dropping the search kills its yt-dlp process
+                let search =
+                    self.yt_searcher
+                        .search_streaming(&query, options, self.retries, &results);
+                let result = tokio::select! {
+                    result = search => result,
+                    _ = cancel.cancelled() => Err(SearchError::Superseded),
+                };
+                if let Err(err) = result {
+                    let _ = results.send(Err(err)).await;
+                }
+                drop(done);
+
+                info!(
+                    "Consumer {} finished streaming search for {}",
+                    self.consumer_id, query
+                );
+            }
+            VideoSearcherActorMessage::ExpandPlaylist {
+                url,
+                max_items,
+                respond_to,
+            } => {
+                let result = self
+                    .yt_searcher
+                    .expand_playlist(&url, max_items, self.retries)
+                    .await;
+                let _ = respond_to.send(result);
+            }
         }
     }
 }
@@ -87,13 +173,28 @@ async fn run_video_searcher_actor(mut actor: VideoSearcherActor) {
     info!("Consumer {} shutting down", actor.consumer_id);
 }
 
+/// Identifies a client's search so a newer one can supersede it, e.g. the
+/// keystrokes of an autocomplete field.
+pub struct SearchClient {
+    pub id: String,
+    pub seq: u64,
+}
+
+struct LatestSearch {
+    seq: u64,
+    cancel: CancellationToken,
+}
+
 #[derive(Clone)]
 pub struct VideoSearcherActorHandle {
     sender: async_channel::Sender<VideoSearcherActorMessage>,
+    // the newest search per client id
+    latest_searches: Arc<Mutex<HashMap<String, LatestSearch>>>,
 }
 
 impl VideoSearcherActorHandle {
-    pub fn new(yt_searcher: Arc<YtSearcher>) -> Self {
+    /// `retries` is how many times a failed yt-dlp search is retried.
+    pub fn new(yt_searcher: Arc<YtSearcher>, retries: u32) -> Self {
         trace!("Initializing VideoSearcherActorHandle");
         let (sender, receiver) = async_channel::bounded(100);
         trace!("Created channel with capacity: {}", sender.capacity().unwrap());
@@ -102,16 +203,83 @@ impl VideoSearcherActorHandle {
         trace!("Starting {} consumers", NUM_CONSUMERS);
         for consumer_id in 0..NUM_CONSUMERS {
             trace!("Spawning consumer {}", consumer_id);
-            let actor = VideoSearcherActor::new(receiver.clone(), yt_searcher.clone(), consumer_id);
+            let actor = VideoSearcherActor::new(receiver.clone(), yt_searcher.clone(), retries, consumer_id);
             tokio::spawn(run_video_searcher_actor(actor));
         }
         trace!("All consumers spawned");
         trace!("Total receiver count: {}", receiver.receiver_count());
 
-        Self { sender }
+        Self {
+            sender,
+            latest_searches: Arc::default(),
+        }
     }
 
-    pub async fn search_videos(&self, query: &str) -> Result<Vec<SearchResult>, SearchError> {
+    /// Records `client`'s search as its newest, cancelling the one it
+    /// replaces. Fails when a newer search from the client was already seen.
+    fn supersede(&self, client: &SearchClient) -> Result<CancellationToken, SearchError> {
+        let mut latest_searches = self.latest_searches.lock().unwrap();
+        if let Some(latest) = latest_searches.get(&client.id) {
+            if latest.seq > client.seq {
+                return Err(SearchError::Superseded);
+            }
+            latest.cancel.cancel();
+        }
+
+        let cancel = CancellationToken::new();
+        latest_searches.insert(
+            client.id.clone(),
+            LatestSearch {
+                seq: client.seq,
+                cancel: cancel.clone(),
+            },
+        );
+        Ok(cancel)
+    }
+
+    fn finish_search(&self, client: &SearchClient) {
+        let mut latest_searches = self.latest_searches.lock().unwrap();
+        if latest_searches
+            .get(&client.id)
+            .is_some_and(|latest| latest.seq == client.seq)
+        {
+            latest_searches.remove(&client.id);
+        }
+    }
+
+    /// Number of consumers still running. A consumer whose task died drops
+    /// its receiver, so this falls below the pool size.
+    pub fn live_consumers(&self) -> usize {
+        self.sender.receiver_count()
+    }
+
+    /// Searches for `query`. With a `client`, an older search still running
+    /// for the same client id is cancelled, and this one fails with
+    /// `SearchError::Superseded` if a newer one arrives first.
+    pub async fn search_videos(
+        &self,
+        query: &str,
+        options: SearchOptions,
+        client: Option<SearchClient>,
+    ) -> Result<Vec<SearchResult>, SearchError> {
+        let cancel = match &client {
+            Some(client) => self.supersede(client)?,
+            None => CancellationToken::new(),
+        };
+        let _running = RunningSearch {
+            handle: self,
+            client: client.as_ref(),
+            cancel: cancel.clone(),
+        };
+        self.run_search(query, options, cancel).await
+    }
+
+    async fn run_search(
+        &self,
+        query: &str,
+        options: SearchOptions,
+        cancel: CancellationToken,
+    ) -> Result<Vec<SearchResult>, SearchError> {
         trace!("Requesting searches for {} (channel len: {})", 
             query, 
             self.sender.len());
@@ -119,13 +287,18 @@ impl VideoSearcherActorHandle {
         let (send, recv) = oneshot::channel();
         let msg = VideoSearcherActorMessage::SearchVideo {
             query: query.to_owned(),
+            options,
+            cancel,
             respond_to: send,
         };
 
         trace!("Sending search request {} to video searcher actor (channel len: {})", 
             query,
             self.sender.len());
-        let _ = self.sender.send(msg).await;
+        self.sender
+            .send(msg)
+            .await
+            .map_err(|_| SearchError::ActorUnavailable)?;
         
         trace!("Message sent for {}. Channel status - len: {}, capacity: {}", 
             query,
@@ -133,10 +306,175 @@ impl VideoSearcherActorHandle {
             self.sender.capacity().unwrap());
             
         trace!("Awaiting response for {}", query);
-        let result = recv.await.expect("Actor task has been killed");
+        let result = recv
+            .await
+            .map_err(|_| SearchError::ActorUnavailable)
+            .and_then(|result| result);
         trace!("Received response for {}: {:?}", 
             query, 
             if result.is_ok() { "success" } else { "failed" });
         result
     }
-}
\ No newline at end of file
+
+    /// Like `search_videos`, but results arrive on the returned channel as
+    /// yt-dlp prints them. A failure, including being superseded midway, is
+    /// sent as the last item.
+    pub async fn search_videos_streaming(
+        &self,
+        query: &str,
+        options: SearchOptions,
+        client: Option<SearchClient>,
+    ) -> Result<mpsc::Receiver<Result<SearchResult, SearchError>>, SearchError> {
+        let cancel = match &client {
+            Some(client) => self.supersede(client)?,
+            None => CancellationToken::new(),
+        };
+
+        let (results, recv) = mpsc::channel(16);
+        let (done, finished) = oneshot::channel();
+        let msg = VideoSearcherActorMessage::StreamSearch {
+            query: query.to_owned(),
+            options,
+            cancel,
+            results,
+            done,
+        };
+        let sent = self.sender.send(msg).await;
+
+        if let Some(client) = client {
+            let handle = self.clone();
+            tokio::spawn(async move {
+                let _ = finished.await;
+                handle.finish_search(&client);
+            });
+        }
+        sent.map_err(|_| SearchError::ActorUnavailable)?;
+        Ok(recv)
+    }
+
+    pub async fn expand_playlist(
+        &self,
+        url: &str,
+        max_items: usize,
+    ) -> Result<Vec<SearchResult>, SearchError> {
+        let (send, recv) = oneshot::channel();
+        let msg = VideoSearcherActorMessage::ExpandPlaylist {
+            url: url.to_owned(),
+            max_items,
+            respond_to: send,
+        };
+
+        self.sender
+            .send(msg)
+            .await
+            .map_err(|_| SearchError::ActorUnavailable)?;
+        recv.await
+            .map_err(|_| SearchError::ActorUnavailable)
+            .and_then(|result| result)
+    }
+}
+
+/// Ends a search when `search_videos` returns or is dropped, e.g. when the
+/// client disconnects, so yt-dlp stops and the client's entry is removed.
+struct RunningSearch<'a> {
+    handle: &'a VideoSearcherActorHandle,
+    client: Option<&'a SearchClient>,
+    cancel: CancellationToken,
+}
+
+impl Drop for RunningSearch<'_> {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+        if let Some(client) = self.client {
+            self.handle.finish_search(client);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::utils::process::ProcessGroups;
+
+    use super::*;
+
+    fn client(seq: u64) -> Option<SearchClient> {
+        Some(SearchClient {
+            id: "phone".to_string(),
+            seq,
+        })
+    }
+
+    fn spawn_search(
+        handle: &VideoSearcherActorHandle,
+        query: &'static str,
+        seq: u64,
+    ) -> tokio::task::JoinHandle<Result<Vec<SearchResult>, SearchError>> {
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            handle
+                .search_videos(query, SearchOptions::default(), client(seq))
+                .await
+        })
+    }
+
+    #[tokio::test]
+    async fn only_the_latest_query_from_a_client_runs() {
+        // no consumers, so the test runs the queued messages itself
+        let (sender, receiver) = async_channel::bounded(4);
+        let handle = VideoSearcherActorHandle {
+            sender,
+            latest_searches: Arc::default(),
+        };
+        let searcher = YtSearcher::new(PathBuf::new(), ProcessGroups::default(), Default::default());
+        let mut actor = VideoSearcherActor::new(receiver.clone(), Arc::new(searcher), 0, 0);
+
+        let first = spawn_search(&handle, "kara", 1);
+        let first_msg = receiver.recv().await.unwrap();
+        let second = spawn_search(&handle, "karaoke", 2);
+        // held so the second search stays in flight
+        let second_msg = receiver.recv().await.unwrap();
+        let VideoSearcherActorMessage::SearchVideo { cancel, .. } = &second_msg else {
+            panic!("expected a search");
+        };
+        assert!(!cancel.is_cancelled());
+
+        actor.handle_message(first_msg).await;
+        assert!(matches!(first.await.unwrap(), Err(SearchError::Superseded)));
+
+        // a query older than the latest is rejected before it's queued
+        let stale = handle
+            .search_videos("kar", SearchOptions::default(), client(0))
+            .await;
+        assert!(matches!(stale, Err(SearchError::Superseded)));
+        assert!(receiver.is_empty());
+
+        actor.handle_message(second_msg).await;
+        let second = second.await.unwrap();
+        assert!(!matches!(second, Err(SearchError::Superseded)));
+    }
+
+    #[tokio::test]
+    async fn dropped_searches_are_cancelled_and_forgotten() {
+        // no consumers, so the search stays queued until it's dropped
+        let (sender, receiver) = async_channel::bounded(4);
+        let handle = VideoSearcherActorHandle {
+            sender,
+            latest_searches: Arc::default(),
+        };
+
+        let search = spawn_search(&handle, "karaoke", 1);
+        let msg = receiver.recv().await.unwrap();
+        let VideoSearcherActorMessage::SearchVideo { cancel, .. } = &msg else {
+            panic!("expected a search");
+        };
+        assert!(handle.latest_searches.lock().unwrap().contains_key("phone"));
+
+        // like a client disconnecting mid-search
+        search.abort();
+        assert!(search.await.unwrap_err().is_cancelled());
+        assert!(cancel.is_cancelled());
+        assert!(handle.latest_searches.lock().unwrap().is_empty());
+    }
+}
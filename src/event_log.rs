@@ -0,0 +1,273 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{actors::song_coordinator::Song, settings::EventLogSettings};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Queued,
+    Downloaded,
+    Failed,
+    Removed,
+    // left the front of the queue after reaching its duration
+    Finished,
+    // left the front of the queue before reaching its duration
+    Skipped,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EventRecord {
+    // seconds since the unix epoch
+    pub timestamp: u64,
+    pub kind: EventKind,
+    pub song_uuid: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Append-only JSON-lines record of what happened to songs, kept in the
+/// config dir across restarts. Once the file reaches `max_bytes` it is moved
+/// to `events.jsonl.1`, replacing the previous one, and a new file started.
+/// Records are written by a dedicated thread, so recording never blocks the
+/// song actors on the disk.
+#[derive(Clone)]
+pub struct EventLog {
+    writer: Option<EventWriter>,
+}
+
+#[derive(Clone)]
+struct EventWriter {
+    path: PathBuf,
+    sender: mpsc::Sender<WriterMessage>,
+    // held by the writer while it rotates and appends, and by readers while
+    // they open the files
+    lock: Arc<Mutex<()>>,
+}
+
+enum WriterMessage {
+    Record(EventRecord),
+    // answered once every record sent before it is written
+    Flush(mpsc::Sender<()>),
+}
+
+impl EventLog {
+    pub fn new(path: PathBuf, settings: &EventLogSettings) -> Self {
+        if !settings.enabled {
+            return EventLog { writer: None };
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        let lock = Arc::<Mutex<()>>::default();
+        let writer = EventWriter {
+            path: path.clone(),
+            sender,
+            lock: lock.clone(),
+        };
+        let max_bytes = settings.max_bytes;
+        // ends once every clone of the log is dropped
+        std::thread::spawn(move || write_events(&path, max_bytes, &lock, receiver));
+        EventLog {
+            writer: Some(writer),
+        }
+    }
+
+    fn rotated_path(path: &Path) -> PathBuf {
+        path.with_extension("jsonl.1")
+    }
+
+    pub fn record(&self, kind: EventKind, song: &Song, detail: Option<String>) {
+        let Some(writer) = &self.writer else {
+            return;
+        };
+
+        let record = EventRecord {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.as_secs())
+                .unwrap_or_default(),
+            kind,
+            song_uuid: song.uuid.to_string(),
+            name: song.name.clone(),
+            detail,
+        };
+        if writer.sender.send(WriterMessage::Record(record)).is_err() {
+            warn!("event log writer stopped, dropping {:?} event", kind);
+        }
+    }
+
+    /// Reads back the recorded events, oldest first, optionally limited to
+    /// timestamps within `since..=until`. Lines that don't parse are skipped.
+    /// Waits for events recorded so far to be written, so call it off the
+    /// async runtime.
+    pub fn read(
+        &self,
+        since: Option<u64>,
+        until: Option<u64>,
+    ) -> std::io::Result<Vec<EventRecord>> {
+        let Some(writer) = &self.writer else {
+            return Ok(Vec::new());
+        };
+
+        let (flushed, wait) = mpsc::channel();
+        if writer.sender.send(WriterMessage::Flush(flushed)).is_ok() {
+            let _ = wait.recv();
+        }
+
+        // open handles keep reading the same files if they rotate meanwhile,
+        // so the lock isn't held while parsing
+        let files = {
+            let _guard = writer.lock.lock().unwrap();
+            let mut files = Vec::new();
+            for file_path in [Self::rotated_path(&writer.path), writer.path.clone()] {
+                match fs::File::open(&file_path) {
+                    Ok(file) => files.push(file),
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(err) => return Err(err),
+                }
+            }
+            files
+        };
+
+        let mut events = Vec::new();
+        for file in files {
+            for line in BufReader::new(file).lines() {
+                let Ok(event) = serde_json::from_str::<EventRecord>(&line?) else {
+                    continue;
+                };
+                if since.is_some_and(|since| event.timestamp < since)
+                    || until.is_some_and(|until| event.timestamp > until)
+                {
+                    continue;
+                }
+                events.push(event);
+            }
+        }
+        Ok(events)
+    }
+}
+
+fn write_events(
+    path: &Path,
+    max_bytes: u64,
+    lock: &Mutex<()>,
+    receiver: mpsc::Receiver<WriterMessage>,
+) {
+    for message in receiver {
+        let record = match message {
+            WriterMessage::Record(record) => record,
+            WriterMessage::Flush(flushed) => {
+                let _ = flushed.send(());
+                continue;
+            }
+        };
+
+        let _guard = lock.lock().unwrap();
+        let result = (|| {
+            if fs::metadata(path).is_ok_and(|metadata| metadata.len() >= max_bytes) {
+                fs::rename(path, EventLog::rotated_path(path))?;
+            }
+
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            let mut line = serde_json::to_vec(&record).map_err(std::io::Error::other)?;
+            line.push(b'\n');
+            file.write_all(&line)
+        })();
+        if let Err(err) = result {
+            warn!(
+                "failed to write {:?} event to {}: {}",
+                record.kind,
+                path.display(),
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actors::song_coordinator::QueuedSongStatus;
+
+    fn log(path: PathBuf, max_bytes: u64) -> EventLog {
+        let settings = EventLogSettings {
+            enabled: true,
+            max_bytes,
+        };
+        EventLog::new(path, &settings)
+    }
+
+    fn song(name: &str) -> Song {
+        Song::new(
+            name.to_string(),
+            format!("https://www.youtube.com/watch?v={}", name),
+            QueuedSongStatus::InProgress,
+            false,
+            None,
+        )
+    }
+
+    #[test]
+    fn full_logs_rotate_into_one_previous_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.jsonl");
+        // every record fills the file, so each one rotates the last
+        let log = log(path.clone(), 1);
+
+        for name in ["first", "second", "third"] {
+            log.record(EventKind::Queued, &song(name), None);
+        }
+        let names = |events: Vec<EventRecord>| {
+            events
+                .into_iter()
+                .map(|event| event.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(log.read(None, None).unwrap()), ["second", "third"]);
+        assert!(dir.path().join("events.jsonl.1").exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn reads_are_limited_to_the_requested_window() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.jsonl");
+        let line = |timestamp: u64| {
+            format!(
+                "{{\"timestamp\":{},\"kind\":\"queued\",\"song_uuid\":\"{}\",\"name\":\"at {}\"}}\n",
+                timestamp,
+                uuid::Uuid::nil(),
+                timestamp
+            )
+        };
+        fs::write(
+            EventLog::rotated_path(&path),
+            line(100) + &line(200) + "not json\n",
+        )
+        .unwrap();
+        fs::write(&path, line(300) + &line(400)).unwrap();
+        let log = log(path, 1 << 20);
+
+        let timestamps = |since, until| {
+            log.read(since, until)
+                .unwrap()
+                .into_iter()
+                .map(|event| event.timestamp)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(timestamps(None, None), [100, 200, 300, 400]);
+        // both bounds are inclusive
+        assert_eq!(timestamps(Some(200), Some(300)), [200, 300]);
+        assert_eq!(timestamps(Some(250), None), [300, 400]);
+        assert_eq!(timestamps(None, Some(100)), [100]);
+    }
+}
@@ -1,12 +1,27 @@
 use once_cell::sync::OnceCell;
 use std::path::PathBuf;
 
+use crate::utils::binary::DependencyError;
+
+// Compatibility shim for code not yet handed its paths. New code takes the
+// config dir or `BinaryPaths` as an argument instead.
 static CONFIG_DIR: OnceCell<PathBuf> = OnceCell::new();
 
-pub fn init_config_dir(path: PathBuf) {
-    CONFIG_DIR.set(path).expect("Config dir already set");
+/// Sets the config dir once for the process. Setting the same path again is
+/// a no-op, a different one is an error.
+pub fn init_config_dir(path: PathBuf) -> Result<(), DependencyError> {
+    set_once(&CONFIG_DIR, path)
+}
+
+fn set_once(cell: &OnceCell<PathBuf>, path: PathBuf) -> Result<(), DependencyError> {
+    let current = cell.get_or_init(|| path.clone());
+    if *current != path {
+        return Err(DependencyError::ConfigDirAlreadySet(current.clone()));
+    }
+    Ok(())
 }
 
+#[allow(dead_code)]
 pub fn get_binary_path(name: &str) -> PathBuf {
     CONFIG_DIR
         .get()
@@ -17,3 +32,31 @@ pub fn get_binary_path(name: &str) -> PathBuf {
             name.to_string()
         })
 }
+
+/// A file in the config dir, e.g. the event log.
+#[allow(dead_code)]
+pub fn get_config_path(name: &str) -> PathBuf {
+    CONFIG_DIR
+        .get()
+        .expect("Config dir not initialized")
+        .join(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_dir_can_only_be_set_to_one_path() {
+        let cell = OnceCell::new();
+        set_once(&cell, PathBuf::from("/etc/ferris")).unwrap();
+        set_once(&cell, PathBuf::from("/etc/ferris")).unwrap();
+
+        let err = set_once(&cell, PathBuf::from("/tmp/ferris")).unwrap_err();
+        let DependencyError::ConfigDirAlreadySet(current) = err else {
+            panic!("unexpected error: {}", err);
+        };
+        assert_eq!(current, PathBuf::from("/etc/ferris"));
+        assert_eq!(cell.get(), Some(&PathBuf::from("/etc/ferris")));
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use tracing::{info, warn};
+
+use crate::{
+    actors::song_coordinator::Song,
+    rooms::Rooms,
+    routes::streaming::ManifestCache,
+    state::AppState,
+    utils::slug::AssetPaths,
+};
+
+/// When the API last received a mutating request or a room was last found in
+/// use, used to clear the session of an instance left running after an
+/// event.
+#[derive(Clone)]
+pub struct Activity {
+    last: Arc<Mutex<Instant>>,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Activity {
+            last: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    fn touch(&self) {
+        *self.last.lock().unwrap() = Instant::now();
+    }
+
+    fn last(&self) -> Instant {
+        *self.last.lock().unwrap()
+    }
+}
+
+/// Records non-GET/HEAD requests as activity. Reads such as segment fetches
+/// don't count on their own, playback is checked by `clear_when_idle`
+/// instead.
+pub async fn track_activity(
+    State(activity): State<Activity>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !request.method().is_safe() {
+        activity.touch();
+    }
+    next.run(request).await
+}
+
+/// Clears every room's queue once no mutating request has arrived for
+/// `idle_after`, and deletes the downloaded songs and their cached manifests
+/// when `idle.evict_assets` is set. A room playing a song counts as activity, as
+/// does a client subscribed to its events with
+/// `idle.subscribers_count_as_activity`. Pinned songs and their downloads
+/// are kept, as are folders still being downloaded to. Runs once per idle
+/// stretch until shutdown.
+pub async fn clear_when_idle(activity: Activity, state: AppState, idle_after: Duration) {
+    let AppState {
+        rooms,
+        videodl_actor_handle,
+        shutdown,
+        settings,
+        assets,
+        manifests,
+        ..
+    } = state;
+    let settings = &settings.idle;
+    let evict_assets = settings.evict_assets;
+    let check_interval = idle_after.min(Duration::from_secs(60));
+    let mut cleared_for = None;
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(check_interval) => {}
+            _ = shutdown.wait() => return,
+        }
+
+        if rooms_in_use(&rooms, settings.subscribers_count_as_activity).await {
+            activity.touch();
+            continue;
+        }
+        let last_activity = activity.last();
+        if last_activity.elapsed() < idle_after || cleared_for == Some(last_activity) {
+            continue;
+        }
+        cleared_for = Some(last_activity);
+
+        info!(
+            "No activity for {:?}, clearing the queue{}",
+            last_activity.elapsed(),
+            if evict_assets { " and cached songs" } else { "" }
+        );
+        let mut pinned = Vec::new();
+        let mut cleared_all = true;
+        for (name, room) in rooms.all() {
+            match room.song_actor_handle.clear_queue().await {
+                Ok(kept) => pinned.extend(kept),
+                Err(err) => {
+                    warn!("Failed to clear idle queue of room {}: {}", name, err);
+                    cleared_all = false;
+                }
+            }
+        }
+        // a queue that couldn't be cleared may still be using any download
+        if evict_assets && cleared_all {
+            evict_cached_songs(
+                &assets,
+                &manifests,
+                &pinned,
+                &videodl_actor_handle.busy_folders(),
+            );
+        }
+    }
+}
+
+/// Whether a room is playing a song, or has a client subscribed to its
+/// events when `count_subscribers` is set, e.g. a display waiting for the
+/// next song.
+async fn rooms_in_use(rooms: &Rooms, count_subscribers: bool) -> bool {
+    for (_, room) in rooms.all() {
+        if count_subscribers && room.sse_broadcaster.subscribers() > 0 {
+            return true;
+        }
+        if room
+            .song_actor_handle
+            .get_session()
+            .await
+            .is_ok_and(|session| session.is_playing)
+        {
+            return true;
+        }
+    }
+    false
+}
+
+/// Deletes every song folder except those of `pinned` songs and the `busy`
+/// folders still being written to, along with their cached manifests.
+fn evict_cached_songs(
+    assets: &AssetPaths,
+    manifests: &ManifestCache,
+    pinned: &[Song],
+    busy: &HashSet<String>,
+) {
+    let kept_dirs = pinned
+        .iter()
+        .map(|song| song.folder.as_str())
+        .chain(busy.iter().map(String::as_str))
+        .map(|folder| assets.song_dir(folder))
+        .collect::<Vec<_>>();
+
+    let dirs = match assets.song_dirs() {
+        Ok(dirs) => dirs,
+        Err(err) => {
+            warn!("Failed to read {}: {}", assets.root.display(), err);
+            return;
+        }
+    };
+
+    for path in dirs {
+        if !kept_dirs.contains(&path) {
+            if let Err(err) = std::fs::remove_dir_all(&path) {
+                warn!("Failed to evict {}: {}", path.display(), err);
+                continue;
+            }
+            if let Some(folder) = path.file_name().and_then(|name| name.to_str()) {
+                manifests.remove(folder);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        actors::song_coordinator::QueuedSongStatus, rooms::tests::rooms, settings::AssetLayout,
+        utils::slug::song_key,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn playback_counts_as_activity() {
+        let rooms = rooms(1);
+        assert!(!rooms_in_use(&rooms, false).await);
+
+        let (_, room) = rooms.all().pop().unwrap();
+        room.song_actor_handle.toggle_playback().await.unwrap();
+        assert!(rooms_in_use(&rooms, false).await);
+
+        room.song_actor_handle.toggle_playback().await.unwrap();
+        assert!(!rooms_in_use(&rooms, false).await);
+    }
+
+    #[tokio::test]
+    async fn subscribers_only_count_as_activity_when_asked_to() {
+        let rooms = rooms(1);
+        let (_, room) = rooms.all().pop().unwrap();
+        // a kiosk display that never unsubscribes
+        let _display = room.sse_broadcaster.subscribe_after(None);
+
+        assert!(!rooms_in_use(&rooms, false).await);
+        assert!(rooms_in_use(&rooms, true).await);
+    }
+
+    #[test]
+    fn eviction_keeps_pinned_and_busy_folders() {
+        for layout in [AssetLayout::PerSong, AssetLayout::Dated] {
+            evicts_unpinned_idle_folders(layout);
+        }
+    }
+
+    fn evicts_unpinned_idle_folders(layout: AssetLayout) {
+        let root = tempfile::tempdir().unwrap();
+        let assets = AssetPaths::new(root.path(), layout);
+        let song = |name: &str| {
+            Song::new(
+                name.to_string(),
+                format!("https://www.youtube.com/watch?v={}", name),
+                QueuedSongStatus::Success,
+                false,
+                None,
+            )
+        };
+        let (pinned, downloading, cached) = (song("pinned"), song("downloading"), song("cached"));
+        for song in [&pinned, &downloading, &cached] {
+            std::fs::create_dir_all(assets.song_dir(&song.folder)).unwrap();
+        }
+
+        let busy = HashSet::from([downloading.folder.clone(), song_key("gone", "link")]);
+        let manifests = ManifestCache::default();
+        evict_cached_songs(&assets, &manifests, std::slice::from_ref(&pinned), &busy);
+
+        assert!(assets.song_dir(&pinned.folder).exists());
+        assert!(assets.song_dir(&downloading.folder).exists());
+        assert!(!assets.song_dir(&cached.folder).exists());
+    }
+}
@@ -1,91 +1,407 @@
-use axum::serve;
+use axum::{
+    body::Body,
+    http::{HeaderName, HeaderValue, Method, Request},
+    serve,
+};
 use dotenv::dotenv;
 use router::create_router_with_state;
-use std::fs;
+use settings::{CorsSettings, LogRotation, LogSettings, Settings};
+use shutdown::Shutdown;
+use std::{fs, net::SocketAddr, path::Path};
 use tokio::net::TcpListener;
 use tower_http::{
     cors::{Any, CorsLayer},
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
     trace::TraceLayer,
 };
-use tracing::{debug, error, info};
-use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
-use utils::binary::{setup_binary, update_ytdlp, Binary, DependencyError};
+use tracing::{debug, error, info, info_span, warn};
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{
+    fmt::{self, format::FmtSpan},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
+use utils::binary::{setup_binaries, DependencyError, RecordedVersions};
+use utils::process::ProcessGroups;
+use utils::title::TitleCleaner;
 
 mod actors;
+mod event_log;
 mod globals;
+mod idle;
+mod pins;
+mod rooms;
 mod router;
 mod routes;
+mod settings;
+mod shutdown;
 mod state;
 mod utils;
 
+pub const SERVER_PORT: u16 = 8000;
+
 #[tokio::main]
 async fn main() -> Result<(), DependencyError> {
     // Initialize environment
     dotenv().ok();
 
-    // Initialize logging with timestamps and target info
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            EnvFilter::try_from_default_env()
-                .or_else(|_| EnvFilter::try_new("ferris=debug,tower_http=debug"))
-                .unwrap(),
-        )
-        .with_target(true)
-        .with_thread_ids(true)
-        .with_thread_names(true)
-        .with_file(true)
-        .with_line_number(true)
-        .with_span_events(FmtSpan::CLOSE)
-        .init();
-
-    info!("Starting ferris server");
-    debug!("Initializing configuration and directories");
-
-    // Setup config directory and binaries
+    // Setup config directory, which holds the settings that configure
+    // logging. Errors before logging is up are reported by main's return
     let config_dir = dirs::config_dir()
-        .ok_or_else(|| {
-            error!("Failed to determine config directory");
-            DependencyError::NoConfigDir
-        })?
+        .ok_or(DependencyError::NoConfigDir)?
         .join("pi-tchperfect");
 
-    globals::init_config_dir(config_dir.clone());
+    globals::init_config_dir(config_dir.clone())?;
 
-    debug!("Creating config directory at: {}", config_dir.display());
-    fs::create_dir_all(&config_dir).map_err(|e| {
-        error!("Failed to create config directory: {}", e);
-        DependencyError::Io(e)
-    })?;
+    fs::create_dir_all(&config_dir).map_err(DependencyError::Io)?;
+
+    let settings =
+        Settings::load(&config_dir).map_err(|e| DependencyError::InvalidConfig(e.to_string()))?;
+
+    let log_guard = init_logging(&settings.log, &config_dir);
+
+    info!("Starting ferris server");
+    debug!("Using config directory at: {}", config_dir.display());
+    debug!("Loaded settings: {:?}", settings);
+    info!(
+        "Songs are key-changeable by default: {}",
+        settings.queue.default_key_changeable
+    );
+
+    check_assets_dir(Path::new("./assets"))?;
+    check_frontends(settings.server.require_frontends)?;
+    if let Some(frontend) = &settings.server.root_frontend {
+        if !router::FRONTENDS.contains(&frontend.as_str()) {
+            return Err(DependencyError::InvalidConfig(format!(
+                "server.root_frontend must be one of {}, got {}",
+                router::FRONTENDS.join(", "),
+                frontend
+            )));
+        }
+    }
 
     info!("Setting up required binaries");
-    setup_binary(Binary::Ffmpeg, &config_dir)?;
-    setup_binary(Binary::Ytdlp, &config_dir)?;
-    update_ytdlp(&config_dir)?;
+    let binaries = setup_binaries(&config_dir, &settings.binaries)?;
+    let versions = RecordedVersions::probe(&binaries);
 
     // Setup CORS
     debug!("Configuring CORS");
-    let cors_layer = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any);
+    let cors_layer = build_cors_layer(&settings.cors)?;
+
+    if let Some(country) = &settings.download.geo_bypass_country {
+        if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Err(DependencyError::InvalidConfig(format!(
+                "download.geo_bypass_country must be a two-letter country code, got {}",
+                country
+            )));
+        }
+    }
+
+    let title_cleaner = TitleCleaner::new(&settings.queue).map_err(|e| {
+        DependencyError::InvalidConfig(format!("invalid queue.title_noise_patterns: {}", e))
+    })?;
 
     // Create and configure app
     info!("Creating router and configuring middleware");
-    let app = create_router_with_state()
+    let shutdown = Shutdown::new();
+    let processes = ProcessGroups::default();
+    let app = create_router_with_state(
+        settings,
+        binaries,
+        versions,
+        title_cleaner,
+        processes.clone(),
+        shutdown.clone(),
+        &config_dir,
+    )
         .await
         .layer(cors_layer)
-        .layer(TraceLayer::new_for_http());
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(TraceLayer::new_for_http().make_span_with(|request: &Request<Body>| {
+            let request_id = request
+                .headers()
+                .get("x-request-id")
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("unknown");
+
+            info_span!(
+                "request",
+                method = %request.method(),
+                uri = %request.uri(),
+                request_id = %request_id,
+            )
+        }))
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid));
 
     // Start server
-    let addr = "0.0.0.0:8000";
+    let addr = format!("0.0.0.0:{}", SERVER_PORT);
     info!("Starting server on {}", addr);
-    let listener = TcpListener::bind(addr).await.unwrap();
+    let listener = TcpListener::bind(&addr).await.unwrap();
+
+    tokio::spawn(shutdown_on_signal(shutdown.clone()));
 
     info!("Server is ready to accept connections");
-    match serve(listener, app).await {
+    let graceful_shutdown = shutdown.clone();
+    // the connecting address is used to rate limit searches per client
+    match serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move { graceful_shutdown.wait().await })
+        .await
+    {
         Ok(_) => info!("Server shutdown gracefully"),
         Err(e) => error!("Server error: {}", e),
     }
+    // in-flight downloads and transcodes would otherwise keep running
+    processes.kill_all();
+
+    let exit_code = shutdown.exit_code();
+    if exit_code != 0 {
+        info!("Exiting with code {}", exit_code);
+        // process::exit skips destructors, flush the log file first
+        drop(log_guard);
+        std::process::exit(exit_code);
+    }
 
     Ok(())
 }
+
+/// Logs to stdout, and to a rotating file in the config dir when enabled.
+/// The file is written from a background thread so a slow SD card doesn't
+/// stall request handling. The returned guard flushes it when dropped.
+fn init_logging(settings: &LogSettings, config_dir: &Path) -> Option<WorkerGuard> {
+    // Initialize logging with timestamps and target info
+    let stdout_layer = fmt::layer()
+        .with_target(true)
+        .with_thread_ids(true)
+        .with_thread_names(true)
+        .with_file(true)
+        .with_line_number(true)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_filter(
+            EnvFilter::try_from_default_env()
+                .or_else(|_| EnvFilter::try_new("ferris=debug,tower_http=debug"))
+                .unwrap(),
+        );
+
+    let file_layer = settings.file.then(|| -> Result<_, String> {
+        let filter = EnvFilter::try_new(&settings.file_level)
+            .map_err(|e| format!("invalid log.file_level: {}", e))?;
+        let rotation = match settings.rotation {
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        };
+        let appender = RollingFileAppender::builder()
+            .rotation(rotation)
+            .filename_prefix("ferris")
+            .filename_suffix("log")
+            .max_log_files(settings.max_files.max(1))
+            .build(config_dir.join("logs"))
+            .map_err(|e| e.to_string())?;
+        let (writer, guard) = tracing_appender::non_blocking(appender);
+
+        let layer = fmt::layer()
+            .with_ansi(false)
+            .with_target(true)
+            .with_thread_ids(true)
+            .with_file(true)
+            .with_line_number(true)
+            .with_span_events(FmtSpan::CLOSE)
+            .with_writer(writer)
+            .with_filter(filter);
+        Ok((layer, guard))
+    });
+
+    let (file_layer, guard, file_error) = match file_layer {
+        Some(Ok((layer, guard))) => (Some(layer), Some(guard), None),
+        Some(Err(err)) => (None, None, Some(err)),
+        None => (None, None, None),
+    };
+
+    tracing_subscriber::registry()
+        .with(stdout_layer)
+        .with(file_layer)
+        .init();
+
+    if let Some(err) = file_error {
+        warn!("File logging disabled, failed to set it up: {}", err);
+    }
+    guard
+}
+
+/// Creates the assets directory and round-trips a probe file through it, so a
+/// read-only mount or full disk fails at startup rather than deep inside the
+/// first download.
+fn check_assets_dir(assets_dir: &Path) -> Result<(), DependencyError> {
+    let not_writable = |e| DependencyError::AssetsNotWritable(assets_dir.to_path_buf(), e);
+
+    fs::create_dir_all(assets_dir).map_err(not_writable)?;
+    let probe = assets_dir.join(".write-probe");
+    fs::write(&probe, b"ferris").map_err(not_writable)?;
+    fs::remove_file(&probe).map_err(not_writable)?;
+
+    let resolved = fs::canonicalize(assets_dir).map_err(not_writable)?;
+    info!("Storing songs in {}", resolved.display());
+    Ok(())
+}
+
+fn check_frontends(required: bool) -> Result<(), DependencyError> {
+    let built = router::built_frontends();
+    let missing: Vec<_> = router::FRONTENDS
+        .into_iter()
+        .filter(|frontend| !built.contains(frontend))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    if required {
+        return Err(DependencyError::MissingFrontends(missing));
+    }
+    warn!(
+        "Frontends not embedded, build them before the server to serve them: {}",
+        missing.join(", ")
+    );
+    Ok(())
+}
+
+/// Shuts down on Ctrl-C, or on SIGTERM from a service manager, so children
+/// are killed instead of orphaned. Downloads are drained like for
+/// `/admin/shutdown`; a second signal stops without waiting for them.
+async fn shutdown_on_signal(shutdown: Shutdown) {
+    #[cfg(unix)]
+    let mut terminate = {
+        use tokio::signal::unix::{signal, SignalKind};
+        signal(SignalKind::terminate())
+            .map_err(|e| error!("Failed to listen for SIGTERM: {}", e))
+            .ok()
+    };
+
+    loop {
+        #[cfg(unix)]
+        let terminated = async {
+            match terminate.as_mut() {
+                Some(terminate) => {
+                    terminate.recv().await;
+                }
+                None => std::future::pending::<()>().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let terminated = std::future::pending::<()>();
+
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => {
+                if let Err(e) = result {
+                    error!("Failed to listen for shutdown signal: {}", e);
+                    return;
+                }
+            }
+            _ = terminated => {}
+        }
+        if !shutdown.request(0) {
+            shutdown.trigger();
+            return;
+        }
+    }
+}
+
+fn build_cors_layer(cors: &CorsSettings) -> Result<CorsLayer, DependencyError> {
+    let mut cors_layer = CorsLayer::new();
+
+    if cors.allowed_origins.is_empty() {
+        cors_layer = cors_layer.allow_origin(Any);
+    } else {
+        let origins = cors
+            .allowed_origins
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin).map_err(|_| {
+                    DependencyError::InvalidConfig(format!("invalid CORS origin: {}", origin))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        cors_layer = cors_layer.allow_origin(origins);
+    }
+
+    if cors.allowed_methods.is_empty() {
+        cors_layer = cors_layer.allow_methods(Any);
+    } else {
+        let methods = cors
+            .allowed_methods
+            .iter()
+            .map(|method| {
+                Method::from_bytes(method.to_uppercase().as_bytes()).map_err(|_| {
+                    DependencyError::InvalidConfig(format!("invalid CORS method: {}", method))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        cors_layer = cors_layer.allow_methods(methods);
+    }
+
+    if cors.allowed_headers.is_empty() {
+        cors_layer = cors_layer.allow_headers(Any);
+    } else {
+        let headers = cors
+            .allowed_headers
+            .iter()
+            .map(|header| {
+                HeaderName::from_bytes(header.as_bytes()).map_err(|_| {
+                    DependencyError::InvalidConfig(format!("invalid CORS header: {}", header))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        cors_layer = cors_layer.allow_headers(headers);
+    }
+
+    info!(
+        "CORS policy - origins: {}, methods: {}, headers: {}",
+        describe_cors_list(&cors.allowed_origins),
+        describe_cors_list(&cors.allowed_methods),
+        describe_cors_list(&cors.allowed_headers)
+    );
+
+    Ok(cors_layer)
+}
+
+fn describe_cors_list(values: &[String]) -> String {
+    if values.is_empty() {
+        "any".to_string()
+    } else {
+        values.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assets_dir_is_created_and_left_clean() {
+        let root = tempfile::tempdir().unwrap();
+        let assets_dir = root.path().join("assets");
+
+        check_assets_dir(&assets_dir).unwrap();
+        assert!(assets_dir.is_dir());
+        assert_eq!(fs::read_dir(&assets_dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn unwritable_assets_dir_fails_fast() {
+        // a file in the way fails even for root, unlike permission bits
+        let root = tempfile::tempdir().unwrap();
+        let blocker = root.path().join("assets");
+        fs::write(&blocker, "").unwrap();
+
+        for assets_dir in [blocker.clone(), blocker.join("songs")] {
+            let err = check_assets_dir(&assets_dir).unwrap_err();
+            assert!(
+                matches!(&err, DependencyError::AssetsNotWritable(path, _) if *path == assets_dir),
+                "{}",
+                err
+            );
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tracing::warn;
+
+use crate::{
+    actors::song_coordinator::{QueuedSongStatus, Song},
+    utils::yt_downloader::TrimRange,
+};
+
+/// What is kept of a pinned song, enough to queue and download it again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PinnedSong {
+    // the title as shown in the queue
+    pub name: String,
+    // the title as queued, which keys the song's folder, when it was
+    // cleaned. Pins saved before it was kept only have `name`, as queued
+    #[serde(default)]
+    pub original_name: Option<String>,
+    pub yt_link: String,
+    pub is_key_changeable: bool,
+    pub trim: Option<TrimRange>,
+    pub preferred_key: Option<i8>,
+}
+
+impl PinnedSong {
+    pub fn from_song(song: &Song) -> Self {
+        PinnedSong {
+            name: song.name.clone(),
+            original_name: song.original_name.clone(),
+            yt_link: song.yt_link.clone(),
+            is_key_changeable: song.is_key_changeable,
+            trim: song.trim,
+            preferred_key: song.preferred_key,
+        }
+    }
+
+    /// A new pinned song waiting for its download, under the title it was
+    /// queued with, for the caller to clean again.
+    pub fn into_song(self) -> Song {
+        let mut song = Song::new(
+            self.original_name.unwrap_or(self.name),
+            self.yt_link,
+            QueuedSongStatus::InProgress,
+            self.is_key_changeable,
+            self.trim,
+        );
+        song.pinned = true;
+        song.preferred_key = self.preferred_key;
+        song
+    }
+}
+
+/// The pinned songs of every room, kept in `pins.json` in the config dir so
+/// a house playlist survives restarts. The file is rewritten whenever a
+/// room's pins change.
+#[derive(Clone)]
+pub struct PinStore {
+    path: Option<PathBuf>,
+    // serializes rewrites by different rooms
+    lock: Arc<Mutex<()>>,
+}
+
+impl PinStore {
+    /// A store at `path`, or one that keeps nothing when `None`.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        PinStore {
+            path,
+            lock: Arc::default(),
+        }
+    }
+
+    /// Unparsable pins are moved aside to `pins.json.corrupt`, so the next
+    /// save doesn't overwrite every other room's pins with only its own.
+    fn read(&self) -> BTreeMap<String, Vec<PinnedSong>> {
+        let Some(path) = &self.path else {
+            return BTreeMap::new();
+        };
+        match fs::read(path) {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|err| {
+                let corrupt_path = path.with_extension("json.corrupt");
+                warn!(
+                    "moving unreadable pins in {} to {}: {}",
+                    path.display(),
+                    corrupt_path.display(),
+                    err
+                );
+                if let Err(err) = fs::rename(path, &corrupt_path) {
+                    warn!("failed to move unreadable pins aside: {}", err);
+                }
+                BTreeMap::new()
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => {
+                warn!("failed to read pins from {}: {}", path.display(), err);
+                BTreeMap::new()
+            }
+        }
+    }
+
+    pub fn load(&self, room: &str) -> Vec<PinnedSong> {
+        let _guard = self.lock.lock().unwrap();
+        self.read().remove(room).unwrap_or_default()
+    }
+
+    pub fn save(&self, room: &str, songs: &[PinnedSong]) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        let _guard = self.lock.lock().unwrap();
+        let mut pins = self.read();
+        if songs.is_empty() {
+            pins.remove(room);
+        } else {
+            pins.insert(room.to_string(), songs.to_vec());
+        }
+
+        // written beside the pins and renamed over them, so a crash half way
+        // through a write can't leave the file truncated
+        let temp_path = path.with_extension("json.tmp");
+        let result = serde_json::to_vec_pretty(&pins)
+            .map_err(std::io::Error::other)
+            .and_then(|contents| fs::write(&temp_path, contents))
+            .and_then(|()| fs::rename(&temp_path, path));
+        if let Err(err) = result {
+            warn!("failed to write pins to {}: {}", path.display(), err);
+        }
+    }
+
+    /// Saves a room's pins each time they change, until its actor stops.
+    pub async fn persist(self, room: String, mut pins: watch::Receiver<Vec<PinnedSong>>) {
+        while pins.changed().await.is_ok() {
+            let songs = pins.borrow_and_update().clone();
+            let store = self.clone();
+            let room = room.clone();
+            let _ = tokio::task::spawn_blocking(move || store.save(&room, &songs)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pinned(name: &str) -> PinnedSong {
+        let mut song = Song::new(
+            name.to_string(),
+            format!("https://www.youtube.com/watch?v={}", name),
+            QueuedSongStatus::Success,
+            true,
+            TrimRange::new(Some(5.0), None),
+        );
+        song.preferred_key = Some(-2);
+        PinnedSong::from_song(&song)
+    }
+
+    #[test]
+    fn pins_are_kept_per_room() {
+        let config_dir = tempfile::tempdir().unwrap();
+        let store = PinStore::new(Some(config_dir.path().join("pins.json")));
+        assert!(store.load("default").is_empty());
+
+        store.save("default", &[pinned("a"), pinned("b")]);
+        store.save("stage", &[pinned("c")]);
+        assert_eq!(store.load("default"), [pinned("a"), pinned("b")]);
+        assert_eq!(store.load("stage"), [pinned("c")]);
+
+        store.save("default", &[]);
+        assert!(store.load("default").is_empty());
+        assert_eq!(store.load("stage"), [pinned("c")]);
+    }
+
+    #[test]
+    fn restored_songs_are_pinned_and_keep_their_folder() {
+        let original = Song::new(
+            "Song".to_string(),
+            "https://www.youtube.com/watch?v=a".to_string(),
+            QueuedSongStatus::Success,
+            false,
+            None,
+        );
+        let restored = PinnedSong::from_song(&original).into_song();
+
+        assert!(restored.pinned);
+        assert_eq!(restored.folder, original.folder);
+        assert_eq!(restored.status, QueuedSongStatus::InProgress);
+    }
+
+    #[test]
+    fn disabled_store_keeps_nothing() {
+        let store = PinStore::new(None);
+        store.save("default", &[pinned("a")]);
+        assert!(store.load("default").is_empty());
+    }
+
+    #[test]
+    fn unreadable_pins_are_moved_aside() {
+        let config_dir = tempfile::tempdir().unwrap();
+        let path = config_dir.path().join("pins.json");
+        fs::write(&path, "{\"stage\": [").unwrap();
+        let store = PinStore::new(Some(path.clone()));
+
+        store.save("default", &[pinned("a")]);
+        assert_eq!(store.load("default"), [pinned("a")]);
+        let corrupt = fs::read_to_string(config_dir.path().join("pins.json.corrupt")).unwrap();
+        assert_eq!(corrupt, "{\"stage\": [");
+    }
+
+    #[test]
+    fn pins_are_replaced_whole() {
+        let config_dir = tempfile::tempdir().unwrap();
+        let path = config_dir.path().join("pins.json");
+        let store = PinStore::new(Some(path.clone()));
+        store.save("default", &[pinned("a")]);
+        assert!(!config_dir.path().join("pins.json.tmp").exists());
+
+        // a write that can't finish leaves the previous pins as they were
+        fs::create_dir(config_dir.path().join("pins.json.tmp")).unwrap();
+        store.save("default", &[pinned("a"), pinned("b")]);
+        assert_eq!(store.load("default"), [pinned("a")]);
+    }
+}
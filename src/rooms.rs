@@ -0,0 +1,395 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{FromRef, FromRequestParts, Query},
+    http::{request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::info;
+
+use crate::{
+    actors::song_coordinator::{KeyPolicy, PlaybackTiming, SongActorHandle},
+    event_log::EventLog,
+    pins::PinStore,
+    routes::sse::{SseBroadcaster, SseEvent},
+};
+
+/// The room requests without a `room` parameter go to.
+pub const DEFAULT_ROOM: &str = "default";
+
+const MAX_ROOM_NAME_LEN: usize = 32;
+
+/// One screen's queue and the events its clients subscribe to.
+#[derive(Clone)]
+pub struct Room {
+    pub song_actor_handle: Arc<SongActorHandle>,
+    pub sse_broadcaster: Arc<SseBroadcaster>,
+}
+
+/// What every room's song actor is started with.
+#[derive(Clone)]
+pub struct RoomConfig {
+    pub max_queue_length: Option<usize>,
+    pub allow_requeue_playing: bool,
+    pub timing: PlaybackTiming,
+    pub sort_articles: Vec<String>,
+    pub event_log: EventLog,
+    pub keys: KeyPolicy,
+    pub sse_replay_capacity: usize,
+    pub pins: PinStore,
+}
+
+impl RoomConfig {
+    fn start_room(&self, name: &str) -> Room {
+        let sse_broadcaster = Arc::new(SseBroadcaster::new(self.sse_replay_capacity));
+        let song_actor_handle = Arc::new(SongActorHandle::new(
+            sse_broadcaster.clone(),
+            self.max_queue_length,
+            self.allow_requeue_playing,
+            self.timing,
+            self.sort_articles.clone(),
+            self.event_log.clone(),
+            self.keys,
+        ));
+        tokio::spawn(
+            self.pins
+                .clone()
+                .persist(name.to_string(), song_actor_handle.watch_pins()),
+        );
+        Room {
+            song_actor_handle,
+            sse_broadcaster,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum RoomError {
+    #[error("invalid room name: {0}")]
+    InvalidName(String),
+
+    #[error("room not found: {0}")]
+    NotFound(String),
+
+    #[error("room already exists: {0}")]
+    AlreadyExists(String),
+
+    #[error("room limit reached, max rooms: {max_rooms}")]
+    LimitReached { max_rooms: usize },
+
+    #[error("the default room can't be removed")]
+    DefaultRoom,
+}
+
+#[derive(Serialize)]
+struct RoomErrorBody {
+    error: String,
+}
+
+impl IntoResponse for RoomError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            RoomError::InvalidName(_) | RoomError::DefaultRoom => StatusCode::BAD_REQUEST,
+            RoomError::NotFound(_) => StatusCode::NOT_FOUND,
+            RoomError::AlreadyExists(_) | RoomError::LimitReached { .. } => StatusCode::CONFLICT,
+        };
+        let body = RoomErrorBody {
+            error: self.to_string(),
+        };
+        (status, Json(body)).into_response()
+    }
+}
+
+/// The running rooms by name. The default room always exists, others are
+/// created and removed by an admin, up to `max_rooms`.
+#[derive(Clone)]
+pub struct Rooms {
+    rooms: Arc<Mutex<HashMap<String, Room>>>,
+    config: RoomConfig,
+    max_rooms: usize,
+}
+
+impl Rooms {
+    pub fn new(config: RoomConfig, max_rooms: usize) -> Self {
+        let default_room = config.start_room(DEFAULT_ROOM);
+        Rooms {
+            rooms: Arc::new(Mutex::new(HashMap::from([(
+                DEFAULT_ROOM.to_string(),
+                default_room,
+            )]))),
+            config,
+            max_rooms: max_rooms.max(1),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Result<Room, RoomError> {
+        self.rooms
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| RoomError::NotFound(name.to_string()))
+    }
+
+    pub fn create(&self, name: &str) -> Result<Room, RoomError> {
+        let valid_name = !name.is_empty()
+            && name.len() <= MAX_ROOM_NAME_LEN
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid_name {
+            return Err(RoomError::InvalidName(name.to_string()));
+        }
+
+        let mut rooms = self.rooms.lock().unwrap();
+        if rooms.contains_key(name) {
+            return Err(RoomError::AlreadyExists(name.to_string()));
+        }
+        if rooms.len() >= self.max_rooms {
+            return Err(RoomError::LimitReached {
+                max_rooms: self.max_rooms,
+            });
+        }
+
+        info!("Starting room {}", name);
+        let room = self.config.start_room(name);
+        rooms.insert(name.to_string(), room.clone());
+        Ok(room)
+    }
+
+    /// Stops routing requests to a room. Its actor exits once the requests
+    /// still holding it have finished.
+    pub fn remove(&self, name: &str) -> Result<(), RoomError> {
+        if name == DEFAULT_ROOM {
+            return Err(RoomError::DefaultRoom);
+        }
+        let room = self
+            .rooms
+            .lock()
+            .unwrap()
+            .remove(name)
+            .ok_or_else(|| RoomError::NotFound(name.to_string()))?;
+
+        info!("Removing room {}", name);
+        room.sse_broadcaster.send(SseEvent::RoomClosed);
+        Ok(())
+    }
+
+    /// Where the rooms' pinned songs are kept across restarts.
+    pub fn pins(&self) -> &PinStore {
+        &self.config.pins
+    }
+
+    pub fn all(&self) -> Vec<(String, Room)> {
+        self.rooms
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, room)| (name.clone(), room.clone()))
+            .collect()
+    }
+
+    /// Sends an event to the clients of every room, e.g. a server restart.
+    pub fn broadcast_all(&self, event: SseEvent) {
+        for (_, room) in self.all() {
+            room.sse_broadcaster.send(event.clone());
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RoomQuery {
+    room: Option<String>,
+}
+
+fn resolve_room<S>(parts: &Parts, state: &S) -> Result<Room, RoomError>
+where
+    Rooms: FromRef<S>,
+{
+    // other query parameters belong to the handler, only `room` is read here
+    let name = Query::<RoomQuery>::try_from_uri(&parts.uri)
+        .ok()
+        .and_then(|Query(query)| query.room)
+        .unwrap_or_else(|| DEFAULT_ROOM.to_string());
+    Rooms::from_ref(state).get(&name)
+}
+
+/// The song actor of the room named by the `room` query parameter.
+pub struct RoomActor(pub Arc<SongActorHandle>);
+
+impl<S> FromRequestParts<S> for RoomActor
+where
+    Rooms: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = RoomError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        resolve_room(parts, state).map(|room| RoomActor(room.song_actor_handle))
+    }
+}
+
+/// The event broadcaster of the room named by the `room` query parameter.
+pub struct RoomEvents(pub Arc<SseBroadcaster>);
+
+impl<S> FromRequestParts<S> for RoomEvents
+where
+    Rooms: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = RoomError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        resolve_room(parts, state).map(|room| RoomEvents(room.sse_broadcaster))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use crate::{
+        actors::song_coordinator::{KeyRange, QueuedSongStatus, Song},
+        settings::{EventLogSettings, KeyResetPolicy},
+    };
+
+    use super::*;
+
+    pub(crate) fn rooms(max_rooms: usize) -> Rooms {
+        rooms_with_pins(max_rooms, PinStore::new(None))
+    }
+
+    fn rooms_with_pins(max_rooms: usize, pins: PinStore) -> Rooms {
+        let event_log = EventLog::new(
+            PathBuf::new(),
+            &EventLogSettings {
+                enabled: false,
+                ..Default::default()
+            },
+        );
+        let config = RoomConfig {
+            max_queue_length: None,
+            allow_requeue_playing: true,
+            timing: PlaybackTiming {
+                tick_interval: Duration::from_secs(3600),
+                gap: Duration::ZERO,
+                ending_lead: Duration::ZERO,
+            },
+            sort_articles: Vec::new(),
+            event_log,
+            keys: KeyPolicy {
+                range: KeyRange::new(3),
+                reset: KeyResetPolicy::Always,
+            },
+            sse_replay_capacity: 16,
+            pins,
+        };
+        Rooms::new(config, max_rooms)
+    }
+
+    fn song(name: &str) -> Song {
+        Song::new(
+            name.to_string(),
+            format!("https://www.youtube.com/watch?v={}", name),
+            QueuedSongStatus::Success,
+            false,
+            None,
+        )
+    }
+
+    #[tokio::test]
+    async fn rooms_keep_separate_queues_and_events() {
+        let rooms = rooms(2);
+        let stage = rooms.create("stage").unwrap();
+        let default = rooms.get(DEFAULT_ROOM).unwrap();
+
+        for (room, name) in [(&stage, "a"), (&default, "b"), (&default, "c")] {
+            room.song_actor_handle.queue_song(song(name)).await.unwrap();
+        }
+
+        let names = |queue: std::collections::VecDeque<Song>| {
+            queue.into_iter().map(|song| song.name).collect::<Vec<_>>()
+        };
+        let stage_queue = stage.song_actor_handle.get_queue().await.unwrap();
+        let default_queue = default.song_actor_handle.get_queue().await.unwrap();
+        assert_eq!(names(stage_queue), ["a"]);
+        assert_eq!(names(default_queue), ["b", "c"]);
+
+        let (stage_events, _) = stage.sse_broadcaster.replay_occupancy();
+        let (default_events, _) = default.sse_broadcaster.replay_occupancy();
+        assert!(stage_events < default_events);
+    }
+
+    #[tokio::test]
+    async fn requests_dont_create_rooms() {
+        let rooms = rooms(4);
+        assert!(matches!(rooms.get("stage"), Err(RoomError::NotFound(_))));
+        assert_eq!(rooms.all().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn creation_is_validated_and_limited() {
+        let rooms = rooms(2);
+        assert!(matches!(
+            rooms.create("a/b"),
+            Err(RoomError::InvalidName(_))
+        ));
+        assert!(matches!(
+            rooms.create(DEFAULT_ROOM),
+            Err(RoomError::AlreadyExists(_))
+        ));
+
+        rooms.create("stage").unwrap();
+        assert!(matches!(
+            rooms.create("bar"),
+            Err(RoomError::LimitReached { max_rooms: 2 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn removed_rooms_free_their_slot() {
+        let rooms = rooms(2);
+        assert!(matches!(
+            rooms.remove(DEFAULT_ROOM),
+            Err(RoomError::DefaultRoom)
+        ));
+        assert!(matches!(rooms.remove("stage"), Err(RoomError::NotFound(_))));
+
+        rooms.create("stage").unwrap();
+        rooms.remove("stage").unwrap();
+        assert!(matches!(rooms.get("stage"), Err(RoomError::NotFound(_))));
+        rooms.create("bar").unwrap();
+    }
+
+    #[tokio::test]
+    async fn pins_are_saved_per_room() {
+        let config_dir = tempfile::tempdir().unwrap();
+        let pins = PinStore::new(Some(config_dir.path().join("pins.json")));
+        let rooms = rooms_with_pins(2, pins.clone());
+        let stage = rooms.create("stage").unwrap().song_actor_handle;
+
+        let pinned = song("pinned");
+        for queued in [song("playing"), pinned.clone()] {
+            stage.queue_song(queued).await.unwrap();
+        }
+        stage.set_pinned(pinned.uuid, true).await.unwrap();
+
+        let mut saved = Vec::new();
+        for _ in 0..100 {
+            saved = pins.load("stage");
+            if !saved.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let names = saved.into_iter().map(|pin| pin.name).collect::<Vec<_>>();
+        assert_eq!(names, ["pinned"]);
+        assert!(pins.load(DEFAULT_ROOM).is_empty());
+    }
+}
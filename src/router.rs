@@ -1,25 +1,55 @@
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 
-use axum::routing::{get_service, post};
+use axum::extract::{OriginalUri, Request, State};
+use axum::http::{header, Method, StatusCode};
+use axum::middleware;
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::routing::{get_service, patch, post};
 use axum::{routing::get, Router};
-use tokio::sync;
+use tower::ServiceExt;
+use tracing::info;
 
+use crate::actors::song_coordinator::{KeyPolicy, KeyRange, PlaybackTiming};
 use crate::actors::video_downloader::VideoDlActorHandle;
 use crate::actors::video_searcher::VideoSearcherActorHandle;
-use crate::routes::admin::{get_key, remove_song, reposition_song, restart_song};
-use crate::routes::karaoke::{current_song, play_next_song, queue_song, search, song_list};
+use crate::event_log::EventLog;
+use crate::idle::{clear_when_idle, track_activity, Activity};
+use crate::pins::PinStore;
+use crate::rooms::{RoomConfig, Rooms};
+use crate::routes::admin::{
+    get_key, move_to_end, pin_song, remove_song, reorder_queue, reposition_song, restart_song,
+    set_song_status, unpin_song,
+};
+use crate::routes::karaoke::{
+    current_song, export_session, import_session, is_cached, play_next_song, queue_song,
+    queue_song_batch, restore_pins, retry_song, search, session, song_list, up_next, upcoming,
+    update_song,
+};
+use crate::routes::rate_limit::{rate_limit, RateLimiter};
 use crate::routes::sse::sse;
-use crate::routes::streaming::serve_dash_file;
-use crate::routes::sys::server_ip;
+use crate::routes::auth::require_admin;
+use crate::routes::streaming::{
+    dash_index, download_source_file, head_dash_file, serve_dash_file, ManifestCache,
+};
+use crate::routes::sys::{
+    cancel_download, create_room, list_downloads, list_events, not_found, preview_ffmpeg_command,
+    remove_room, server_ip, shutdown_server, song_download_log, update_ytdlp, version,
+};
+use crate::settings::Settings;
+use crate::shutdown::{drain_on_request, Shutdown};
+use crate::utils::binary::{BinaryPaths, RecordedVersions};
+use crate::utils::process::ProcessGroups;
+use crate::utils::slug::AssetPaths;
+use crate::utils::title::TitleCleaner;
 use crate::utils::yt_downloader::YtDownloader;
 use crate::utils::yt_searcher::YtSearcher;
-use crate::{
-    actors::song_coordinator::SongActorHandle,
-    routes::admin::{key_down, key_up, toggle_playback},
-};
-use crate::{routes::healthcheck::healthcheck, state::AppState};
+use crate::routes::admin::{key_down, key_up, toggle_autoplay, toggle_playback};
+use crate::routes::healthcheck::{healthcheck, DependencyProbe};
+use crate::state::AppState;
 use rust_embed::RustEmbed;
-use axum_embed::ServeEmbed;
+use axum_embed::{FallbackBehavior, ServeEmbed};
 
 #[derive(RustEmbed, Clone)]
 #[folder = "./static/goldie/dist"]
@@ -29,45 +59,409 @@ struct Goldie;
 #[folder = "./static/phippy/dist"]
 struct Phippy;
 
-pub async fn create_router_with_state() -> Router {
-    let yt_downloader = Arc::new(YtDownloader {});
-    let yt_searcher = Arc::new(YtSearcher {});
+/// The embedded frontends by mount path. `/` redirects to the first built
+/// one unless `server.root_frontend` serves one there directly.
+pub const FRONTENDS: [&str; 2] = ["goldie", "phippy"];
+
+/// The frontends whose dist dir had an `index.html` when the server was
+/// built. The dirs are embedded at compile time, so a frontend built
+/// afterwards isn't served until the server is rebuilt.
+pub fn built_frontends() -> Vec<&'static str> {
+    let built = [
+        Goldie::get("index.html").is_some(),
+        Phippy::get("index.html").is_some(),
+    ];
+    FRONTENDS
+        .into_iter()
+        .zip(built)
+        .filter_map(|(name, built)| built.then_some(name))
+        .collect()
+}
 
-    let (sse_broadcaster, _) = sync::broadcast::channel(10);
-    let sse_broadcaster = Arc::new(sse_broadcaster);
+/// A frontend as a single page app: paths that aren't files get its
+/// `index.html`, leaving the routing to the client.
+fn spa<E: RustEmbed + Clone>() -> ServeEmbed<E> {
+    ServeEmbed::with_parameters(
+        Some("index.html".to_string()),
+        FallbackBehavior::Ok,
+        Some("index.html".to_string()),
+    )
+}
+
+/// The fallback with `E` served at `/`. Page loads and the frontend's own
+/// files get the single page app, while anything else, like a mistyped API
+/// path or a POST, still gets the JSON 404.
+async fn frontend_fallback<E: RustEmbed + Clone>(uri: OriginalUri, request: Request) -> Response {
+    let is_page_load = request
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+    let is_file = E::get(request.uri().path().trim_start_matches('/')).is_some();
+    if !matches!(*request.method(), Method::GET | Method::HEAD) || !(is_page_load || is_file) {
+        return not_found(uri).await.into_response();
+    }
+
+    match spa::<E>().oneshot(request).await {
+        Ok(response) => response.into_response(),
+        Err(never) => match never {},
+    }
+}
+
+async fn root(State(settings): State<Arc<Settings>>) -> Response {
+    match built_frontends().first() {
+        Some(frontend) => {
+            Redirect::temporary(&format!("{}/{}/", settings.server.base_path(), frontend))
+                .into_response()
+        }
+        None => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "No frontend was embedded in this build. Build static/goldie and \
+             static/phippy, then rebuild the server.",
+        )
+            .into_response(),
+    }
+}
 
-    let song_actor_handle = Arc::new(SongActorHandle::new(sse_broadcaster.clone()));
+pub async fn create_router_with_state(
+    settings: Settings,
+    binaries: BinaryPaths,
+    versions: RecordedVersions,
+    title_cleaner: TitleCleaner,
+    processes: ProcessGroups,
+    shutdown: Shutdown,
+    config_dir: &Path,
+) -> Router {
+    let yt_downloader = Arc::new(YtDownloader::new(
+        binaries.clone(),
+        processes.clone(),
+        &settings.download,
+    ));
+    let yt_searcher = Arc::new(YtSearcher::new(
+        binaries.ytdlp.clone(),
+        processes.clone(),
+        settings.search.clone(),
+    ));
+    let assets = AssetPaths::new("./assets", settings.download.layout);
+
+    let event_log = EventLog::new(config_dir.join("events.jsonl"), &settings.events);
+
+    let rooms = Rooms::new(
+        RoomConfig {
+            max_queue_length: settings.queue.max_length,
+            allow_requeue_playing: settings.queue.allow_requeue_playing,
+            timing: PlaybackTiming {
+                tick_interval: Duration::from_millis(settings.playback.tick_interval_ms.max(1)),
+                gap: Duration::from_millis(settings.playback.gap_ms),
+                ending_lead: Duration::from_millis(settings.playback.ending_lead_ms),
+            },
+            sort_articles: settings.queue.sort_articles.clone(),
+            event_log: event_log.clone(),
+            keys: KeyPolicy {
+                range: KeyRange::new(settings.playback.max_key_shift),
+                reset: settings.playback.key_reset,
+            },
+            sse_replay_capacity: settings.sse.replay_buffer,
+            pins: PinStore::new(Some(config_dir.join("pins.json"))),
+        },
+        settings.rooms.max_rooms,
+    );
     let videodl_actor_handle = Arc::new(VideoDlActorHandle::new(
-        String::from("./assets"),
+        assets.clone(),
         yt_downloader,
+        binaries.ffmpeg.clone(),
+        processes.clone(),
+        settings.download.clone(),
+        settings.transcode.clone(),
+    ));
+    for (name, room) in rooms.all() {
+        restore_pins(
+            room.song_actor_handle,
+            videodl_actor_handle.clone(),
+            &title_cleaner,
+            rooms.pins().load(&name),
+        )
+        .await;
+    }
+    let videosearcher_actor_handle = Arc::new(VideoSearcherActorHandle::new(yt_searcher, settings.search.retries));
+
+    tokio::spawn(drain_on_request(
+        shutdown.clone(),
+        rooms.clone(),
+        videodl_actor_handle.clone(),
+        Duration::from_secs(settings.admin.shutdown_drain_seconds),
     ));
-    let videosearcher_actor_handle = Arc::new(VideoSearcherActorHandle::new(yt_searcher));
 
-    let app_state = AppState::new(
-        song_actor_handle,
+    let app_state = AppState {
+        rooms,
         videodl_actor_handle,
         videosearcher_actor_handle,
-        sse_broadcaster.clone(),
-    );
+        settings: Arc::new(settings),
+        dependency_probe: DependencyProbe::new(binaries.clone()),
+        binaries,
+        versions,
+        assets,
+        manifests: ManifestCache::default(),
+        processes,
+        shutdown,
+        event_log,
+        title_cleaner: Arc::new(title_cleaner),
+    };
+
+    let activity = Activity::new();
+    if let Some(minutes) = app_state.settings.idle.clear_after_minutes {
+        info!("Clearing the queue after {} idle minutes", minutes);
+        tokio::spawn(clear_when_idle(
+            activity.clone(),
+            app_state.clone(),
+            Duration::from_secs(minutes * 60),
+        ));
+    }
+
+    let search_limiter = Arc::new(RateLimiter::new(
+        app_state.settings.search.rate_limit_per_minute,
+        app_state.settings.search.rate_limit_burst,
+        app_state.settings.server.trusted_proxies.clone(),
+    ));
+
+    let admin_router = Router::new()
+        .route("/shutdown", post(shutdown_server))
+        .route("/downloads", get(list_downloads))
+        .route("/downloads/{song_uuid}/cancel", post(cancel_download))
+        .route("/song/{song_uuid}/log", get(song_download_log))
+        .route("/song/{song_uuid}/status", post(set_song_status))
+        .route("/debug/ffmpeg", get(preview_ffmpeg_command))
+        .route("/events", get(list_events))
+        .route("/rooms/{room}", post(create_room).delete(remove_room))
+        .route("/update_ytdlp", post(update_ytdlp))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            require_admin,
+        ));
 
-    Router::new()
+    let base_path = app_state.settings.server.base_path();
+
+    let router = Router::new()
         .nest_service("/goldie", get_service(ServeEmbed::<Goldie>::new()))
         .nest_service("/phippy", get_service(ServeEmbed::<Phippy>::new()))
         .route("/api/healthcheck", get(healthcheck))
         .route("/server_ip", get(server_ip))
+        .route("/version", get(version))
         .route("/queue_song", post(queue_song))
+        .route("/queue_batch", post(queue_song_batch))
+        .route("/song/{song_uuid}", patch(update_song))
+        .route("/retry_song", post(retry_song))
         .route("/play_next", post(play_next_song))
         .route("/song_list", get(song_list))
+        .route("/session", get(session))
+        .route("/session/export", get(export_session))
+        .route("/session/import", post(import_session))
         .route("/current_song", get(current_song))
-        .route("/dash/{song_name}/{file}", get(serve_dash_file))
+        .route("/up_next", get(up_next))
+        .route("/upcoming", get(upcoming))
+        .route("/is_cached", get(is_cached))
+        .route("/dash/{folder}/index", get(dash_index))
+        .route(
+            "/dash/{folder}/{file}",
+            get(serve_dash_file).head(head_dash_file),
+        )
+        .route("/download/{folder}", get(download_source_file))
         .route("/sse", get(sse))
         .route("/toggle_playback", post(toggle_playback))
+        .route("/autoplay", post(toggle_autoplay))
         .route("/key_up", post(key_up))
         .route("/key_down", post(key_down))
         .route("/get_key", get(get_key))
         .route("/reposition_song", post(reposition_song))
+        .route("/move_to_end", post(move_to_end))
+        .route("/reorder", post(reorder_queue))
         .route("/remove_song", post(remove_song))
+        .route("/pin", post(pin_song))
+        .route("/unpin", post(unpin_song))
         .route("/restart", post(restart_song))
-        .route("/search", get(search))
-        .with_state(app_state)
+        .route(
+            "/search",
+            get(search).layer(middleware::from_fn_with_state(search_limiter, rate_limit)),
+        )
+        .nest("/admin", admin_router);
+
+    // the frontend only gets the requests no route matched, so the API always
+    // takes precedence over its client side routes
+    let router = match app_state.settings.server.root_frontend.as_deref() {
+        Some("goldie") => router
+            .route_service("/", get_service(spa::<Goldie>()))
+            .fallback(frontend_fallback::<Goldie>),
+        Some("phippy") => router
+            .route_service("/", get_service(spa::<Phippy>()))
+            .fallback(frontend_fallback::<Phippy>),
+        _ => router.route("/", get(root)).fallback(not_found),
+    };
+
+    let router = router
+        .layer(middleware::from_fn_with_state(activity, track_activity))
+        .with_state(app_state);
+
+    if base_path.is_empty() {
+        router
+    } else {
+        info!("Serving under base path {}", base_path);
+        Router::new().nest(&base_path, router).fallback(not_found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn router(base_path: &str, config_dir: &Path) -> Router {
+        let mut settings = Settings::default();
+        settings.server.base_path = base_path.to_string();
+        router_with(settings, config_dir).await
+    }
+
+    async fn router_with(mut settings: Settings, config_dir: &Path) -> Router {
+        settings.events.enabled = false;
+        let binaries = BinaryPaths {
+            ytdlp: "yt-dlp".into(),
+            ffmpeg: "ffmpeg".into(),
+        };
+
+        let title_cleaner = TitleCleaner::new(&settings.queue).unwrap();
+        create_router_with_state(
+            settings,
+            binaries,
+            RecordedVersions::default(),
+            title_cleaner,
+            ProcessGroups::default(),
+            Shutdown::new(),
+            config_dir,
+        )
+        .await
+    }
+
+    async fn get(router: &Router, uri: &str) -> axum::response::Response {
+        let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        router.clone().oneshot(request).await.unwrap()
+    }
+
+    async fn status(router: &Router, uri: &str) -> StatusCode {
+        get(router, uri).await.status()
+    }
+
+    #[tokio::test]
+    async fn routes_resolve_under_the_base_path() {
+        let config_dir = tempfile::tempdir().unwrap();
+        let router = router("/karaoke/", config_dir.path()).await;
+
+        assert_eq!(status(&router, "/karaoke/song_list").await, StatusCode::OK);
+        assert_eq!(status(&router, "/song_list").await, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn routers_keep_to_their_own_config_dirs() {
+        use crate::actors::song_coordinator::{QueuedSongStatus, Song};
+        use crate::pins::PinnedSong;
+
+        let (pinned_dir, empty_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let song = Song::new(
+            "House song".to_string(),
+            "https://www.youtube.com/watch?v=house".to_string(),
+            QueuedSongStatus::Success,
+            false,
+            None,
+        );
+        let pins = PinStore::new(Some(pinned_dir.path().join("pins.json")));
+        pins.save("default", &[PinnedSong::from_song(&song)]);
+
+        for (config_dir, pinned) in [(&pinned_dir, true), (&empty_dir, false)] {
+            let router = router("", config_dir.path()).await;
+            let response = get(&router, "/song_list").await;
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            assert_eq!(body.contains("House song"), pinned, "{}", body);
+        }
+        assert!(!empty_dir.path().join("pins.json").exists());
+    }
+
+    #[tokio::test]
+    async fn unknown_paths_get_a_json_not_found() {
+        let config_dir = tempfile::tempdir().unwrap();
+        let router = router("", config_dir.path()).await;
+
+        let response = get(&router, "/api/nope?x=1").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "not found");
+        assert_eq!(body["path"], "/api/nope");
+
+        // the frontends answer their own paths
+        let response = get(&router, "/goldie/missing.js").await;
+        assert_ne!(
+            response.headers().get(axum::http::header::CONTENT_TYPE),
+            Some(&"application/json".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn root_frontend_leaves_api_paths_their_not_found() {
+        let mut settings = Settings::default();
+        settings.server.root_frontend = Some("goldie".to_string());
+        let config_dir = tempfile::tempdir().unwrap();
+        let router = router_with(settings, config_dir.path()).await;
+        let is_json = |response: &Response| {
+            response.headers().get(header::CONTENT_TYPE)
+                == Some(&"application/json".parse().unwrap())
+        };
+
+        let response = get(&router, "/").await;
+        match Goldie::get("index.html") {
+            Some(index) => {
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                assert_eq!(body, index.data.as_ref());
+            }
+            // not built here, but still the frontend answering over the API
+            None => {
+                assert!(!response.status().is_redirection());
+                assert!(!is_json(&response));
+            }
+        }
+
+        // client side routes load the app
+        let request = Request::builder()
+            .uri("/queue")
+            .header(header::ACCEPT, "text/html,application/xhtml+xml")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert!(!is_json(&response));
+
+        for uri in ["/admin/typo", "/song_lsit"] {
+            let response = get(&router, uri).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            assert!(is_json(&response));
+        }
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/nope")
+            .header(header::ACCEPT, "text/html")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(is_json(&response));
+    }
 }
@@ -1,49 +1,140 @@
 use std::sync::Arc;
 
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
 use serde::Deserialize;
-use tokio::sync;
+use tracing::{info, warn};
 use uuid::Uuid;
 
-use crate::actors::song_coordinator::SongActorHandle;
-
-use super::sse::SseEvent;
+use crate::{
+    actors::{
+        song_coordinator::{QueuedSongStatus, SongActorHandle, SongCoordinatorError},
+        video_downloader::{VideoDlActorHandle, VideoStatus},
+    },
+    rooms::{RoomActor, RoomEvents},
+    routes::{
+        extract::ValidJson,
+        sse::{SseBroadcaster, SseEvent},
+    },
+    utils::slug::AssetPaths,
+};
 
 pub async fn toggle_playback(
-    State(sse_broadcaster): State<Arc<sync::broadcast::Sender<SseEvent>>>,
+    RoomActor(song_actor_handle): RoomActor,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let _ = sse_broadcaster.send(SseEvent::TogglePlayback);
+    song_actor_handle
+        .toggle_playback()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     Ok(StatusCode::ACCEPTED)
 }
 
+pub async fn toggle_autoplay(
+    RoomActor(song_actor_handle): RoomActor,
+) -> Result<impl IntoResponse, StatusCode> {
+    let autoplay = song_actor_handle
+        .toggle_autoplay()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok((StatusCode::OK, Json(autoplay)))
+}
+
+/// Starts encoding the now-playing song in `key` when that key wasn't
+/// pre-generated, announcing the new streams once they're ready.
+pub(crate) async fn ensure_key_encoded(
+    song_actor_handle: Arc<SongActorHandle>,
+    videodl_actor_handle: Arc<VideoDlActorHandle>,
+    sse_broadcaster: Arc<SseBroadcaster>,
+    assets: AssetPaths,
+    key: i8,
+) {
+    let Ok(Some(song)) = song_actor_handle.current_song().await else {
+        return;
+    };
+    let Ok(status) = VideoStatus::load(&assets.song_dir(&song.folder)) else {
+        return;
+    };
+    if !status.is_key_changeable || status.audio_adaptation_sets.contains_key(&(key as i32)) {
+        return;
+    }
+    // already being extended, e.g. from an earlier request for the key
+    if videodl_actor_handle.busy_folders().contains(&song.folder) {
+        return;
+    }
+
+    tokio::spawn(async move {
+        info!("Encoding key {} on demand for {}", key, song.name);
+        match videodl_actor_handle
+            .extend_pitch_shifts(song.yt_link, song.folder.clone(), song.trim, vec![key as i32])
+            .await
+        {
+            Ok(audio_adaptation_sets) => {
+                sse_broadcaster.send(SseEvent::PitchShiftsExtended {
+                    uuid: song.uuid.to_string(),
+                    audio_adaptation_sets,
+                });
+            }
+            Err(err) => warn!("Could not encode key {} for {}: {}", key, song.name, err),
+        }
+    });
+}
+
 pub async fn key_up(
-    State(song_actor_handle): State<Arc<SongActorHandle>>,
+    RoomActor(song_actor_handle): RoomActor,
+    State(videodl_actor_handle): State<Arc<VideoDlActorHandle>>,
+    State(assets): State<AssetPaths>,
+    RoomEvents(sse_broadcaster): RoomEvents,
 ) -> Result<impl IntoResponse, StatusCode> {
     let song_actor_response = song_actor_handle.key_up().await;
     match song_actor_response {
-        Ok(current_key) => Ok((StatusCode::OK, Json(current_key))),
+        Ok(current_key) => {
+            ensure_key_encoded(
+                song_actor_handle,
+                videodl_actor_handle,
+                sse_broadcaster,
+                assets,
+                current_key,
+            )
+            .await;
+            Ok((StatusCode::OK, Json(current_key)))
+        }
         Err(_) => Err(StatusCode::NOT_MODIFIED),
     }
 }
 
 pub async fn key_down(
-    State(song_actor_handle): State<Arc<SongActorHandle>>,
+    RoomActor(song_actor_handle): RoomActor,
+    State(videodl_actor_handle): State<Arc<VideoDlActorHandle>>,
+    State(assets): State<AssetPaths>,
+    RoomEvents(sse_broadcaster): RoomEvents,
 ) -> Result<impl IntoResponse, StatusCode> {
     let song_actor_response = song_actor_handle.key_down().await;
     match song_actor_response {
-        Ok(current_key) => Ok((StatusCode::OK, Json(current_key))),
+        Ok(current_key) => {
+            ensure_key_encoded(
+                song_actor_handle,
+                videodl_actor_handle,
+                sse_broadcaster,
+                assets,
+                current_key,
+            )
+            .await;
+            Ok((StatusCode::OK, Json(current_key)))
+        }
         Err(_) => Err(StatusCode::NOT_MODIFIED),
     }
 }
 
 pub async fn get_key(
-    State(song_actor_handle): State<Arc<SongActorHandle>>,
+    RoomActor(song_actor_handle): RoomActor,
 ) -> Result<impl IntoResponse, StatusCode> {
     let song_actor_response = song_actor_handle.get_key().await;
     match song_actor_response {
-        Ok(current_key) => { 
-            Ok((StatusCode::OK, Json(current_key))) 
-        },
+        Ok(current_key) => Ok((StatusCode::OK, Json(current_key))),
         Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
     }
 }
@@ -51,42 +142,241 @@ pub async fn get_key(
 #[derive(Deserialize)]
 pub struct RepositionSongRequest {
     song_uuid: String,
-    position: usize,
+    // negative counts from the end, `-1` being the last place. Positions past
+    // either end are clamped to it
+    position: isize,
 }
 
 pub async fn reposition_song(
-    State(song_actor_handle): State<Arc<SongActorHandle>>,
-    Json(payload): Json<RepositionSongRequest>,
+    RoomActor(song_actor_handle): RoomActor,
+    ValidJson(payload): ValidJson<RepositionSongRequest>,
 ) -> Result<impl IntoResponse, StatusCode> {
     let song_uuid = Uuid::parse_str(&payload.song_uuid).map_err(|_| StatusCode::BAD_REQUEST)?;
     let position = payload.position;
 
+    // the clamped index is returned so clients can reconcile their order
     let song_actor_response = song_actor_handle.reposition_song(song_uuid, position).await;
     match song_actor_response {
-        Ok(_) => Ok(StatusCode::OK),
-        Err(_) => Err(StatusCode::NOT_MODIFIED),
+        Ok(position) => Ok((
+            StatusCode::OK,
+            Json(serde_json::json!({ "position": position })),
+        )),
+        Err(SongCoordinatorError::SongNotFound { .. }) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
     }
 }
 
+#[derive(Deserialize)]
+pub struct MoveToEndRequest {
+    song_uuid: String,
+}
+
+pub async fn move_to_end(
+    RoomActor(song_actor_handle): RoomActor,
+    ValidJson(payload): ValidJson<MoveToEndRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let song_uuid = Uuid::parse_str(&payload.song_uuid).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    match song_actor_handle.move_to_end(song_uuid).await {
+        Ok(()) => Ok(StatusCode::OK),
+        Err(SongCoordinatorError::SongNotFound { .. }) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SetStatusRequest {
+    status: QueuedSongStatus,
+}
+
+/// Overrides a song's status, for recovering a song stuck `InProgress`
+/// after its download task died. Doesn't touch a download still running.
+pub async fn set_song_status(
+    RoomActor(song_actor_handle): RoomActor,
+    Path(song_uuid): Path<String>,
+    ValidJson(payload): ValidJson<SetStatusRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let song_uuid = Uuid::parse_str(&song_uuid).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    // the actor ignores status updates for unknown songs, so they're
+    // rejected here instead
+    match song_actor_handle.get_song(song_uuid).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+
+    warn!("setting status of song {} to {}", song_uuid, payload.status);
+    let result = match payload.status {
+        QueuedSongStatus::Failed => {
+            song_actor_handle
+                .fail_song(song_uuid, "Marked as failed by the host".to_string())
+                .await
+        }
+        status => {
+            song_actor_handle
+                .update_song_status(song_uuid, status)
+                .await
+        }
+    };
+    result.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(StatusCode::OK)
+}
+
+#[derive(Deserialize)]
+pub struct ReorderRequest {
+    song_uuids: Vec<String>,
+}
+
+pub async fn reorder_queue(
+    RoomActor(song_actor_handle): RoomActor,
+    ValidJson(payload): ValidJson<ReorderRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let ordered_uuids = payload
+        .song_uuids
+        .iter()
+        .map(|song_uuid| Uuid::parse_str(song_uuid))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    song_actor_handle
+        .reorder_all(ordered_uuids)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(StatusCode::OK)
+}
+
 #[derive(Deserialize)]
 pub struct DeleteSongRequest {
     song_uuid: String,
 }
 
 pub async fn remove_song(
-    State(song_actor_handle): State<Arc<SongActorHandle>>,
-    Json(payload): Json<DeleteSongRequest>,
+    RoomActor(song_actor_handle): RoomActor,
+    ValidJson(payload): ValidJson<DeleteSongRequest>,
 ) -> Result<impl IntoResponse, StatusCode> {
     let song_uuid = Uuid::parse_str(&payload.song_uuid).map_err(|_| StatusCode::BAD_REQUEST)?;
 
-    song_actor_handle.remove_song(song_uuid).await;
+    song_actor_handle
+        .remove_song(song_uuid)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     Ok(StatusCode::OK)
 }
 
+#[derive(Deserialize)]
+pub struct PinSongRequest {
+    song_uuid: String,
+}
+
+async fn set_pinned(
+    song_actor_handle: Arc<SongActorHandle>,
+    payload: PinSongRequest,
+    pinned: bool,
+) -> Result<StatusCode, StatusCode> {
+    let song_uuid = Uuid::parse_str(&payload.song_uuid).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    match song_actor_handle.set_pinned(song_uuid, pinned).await {
+        Ok(()) => Ok(StatusCode::OK),
+        Err(SongCoordinatorError::SongNotFound { .. }) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Keeps a song in the queue and its download on disk when the queue is
+/// cleared.
+pub async fn pin_song(
+    RoomActor(song_actor_handle): RoomActor,
+    ValidJson(payload): ValidJson<PinSongRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    set_pinned(song_actor_handle, payload, true).await
+}
+
+pub async fn unpin_song(
+    RoomActor(song_actor_handle): RoomActor,
+    ValidJson(payload): ValidJson<PinSongRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    set_pinned(song_actor_handle, payload, false).await
+}
+
 pub async fn restart_song(
-    State(sse_broadcaster): State<Arc<sync::broadcast::Sender<SseEvent>>>,
+    RoomActor(song_actor_handle): RoomActor,
 ) -> Result<impl IntoResponse, StatusCode> {
-    let _ = sse_broadcaster.send(SseEvent::RestartSong);
+    song_actor_handle
+        .restart_song()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     Ok(StatusCode::ACCEPTED)
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::actors::song_coordinator::Song;
+
+    use super::*;
+
+    fn song(name: &str) -> Song {
+        Song::new(
+            name.to_string(),
+            format!("https://www.youtube.com/watch?v={}", name.len()),
+            QueuedSongStatus::InProgress,
+            false,
+            None,
+        )
+    }
+
+    #[tokio::test]
+    async fn stuck_songs_can_be_marked_failed() {
+        let room = crate::rooms::tests::rooms(1).get("default").unwrap();
+        let songs = room.song_actor_handle;
+        let stuck = song("Stuck");
+        songs.queue_song(stuck.clone()).await.unwrap();
+
+        let set_status = |song_uuid: Uuid, status| {
+            set_song_status(
+                RoomActor(songs.clone()),
+                Path(song_uuid.to_string()),
+                ValidJson(SetStatusRequest { status }),
+            )
+        };
+        let marked = set_status(stuck.uuid, QueuedSongStatus::Failed).await;
+        assert!(marked.is_ok());
+        let failed = songs.get_song(stuck.uuid).await.unwrap().unwrap();
+        assert_eq!(failed.status, QueuedSongStatus::Failed);
+        assert!(failed.error.is_some());
+
+        assert!(matches!(
+            set_status(Uuid::new_v4(), QueuedSongStatus::Success).await,
+            Err(StatusCode::NOT_FOUND)
+        ));
+        assert!(serde_json::from_str::<SetStatusRequest>(r#"{"status": "Stuck"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn oversized_positions_clamp_to_the_end() {
+        let room = crate::rooms::tests::rooms(1).get("default").unwrap();
+        let songs = room.song_actor_handle;
+        let moved = song("Moved");
+        for queued in [moved.clone(), song("Second"), song("Next")] {
+            songs.queue_song(queued).await.unwrap();
+        }
+
+        let reposition = |position| {
+            let payload = RepositionSongRequest {
+                song_uuid: moved.uuid.to_string(),
+                position,
+            };
+            reposition_song(RoomActor(songs.clone()), ValidJson(payload))
+        };
+        for (position, landed) in [(isize::MAX, 2), (0, 0), (-2, 1), (-100, 0)] {
+            let response = reposition(position).await.unwrap().into_response();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["position"], landed, "{}", position);
+            let queue = songs.get_queue().await.unwrap();
+            assert_eq!(queue[landed].uuid, moved.uuid);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use tracing::warn;
+
+use crate::settings::Settings;
+
+/// Guards the `/admin` routes with a bearer token from `admin.token`. When no
+/// token is configured the admin routes are disabled entirely.
+pub async fn require_admin(
+    State(settings): State<Arc<Settings>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let Some(expected_token) = settings.admin.token.as_deref() else {
+        warn!("rejecting admin request, no admin token configured");
+        return Err(StatusCode::FORBIDDEN);
+    };
+
+    let provided_token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    if !provided_token.is_some_and(|token| tokens_match(token, expected_token)) {
+        warn!("rejecting admin request with missing or invalid token");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// Compares every byte instead of stopping at the first mismatch, so the
+/// response time doesn't tell a guess how much of the token it got right.
+/// Only the length can leak.
+fn tokens_match(provided: &str, expected: &str) -> bool {
+    let (provided, expected) = (provided.as_bytes(), expected.as_bytes());
+    if provided.len() != expected.len() {
+        return false;
+    }
+    let difference = provided
+        .iter()
+        .zip(expected)
+        .fold(0, |difference, (a, b)| difference | (a ^ b));
+    std::hint::black_box(difference) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn status(token: Option<&str>, authorization: Option<&str>) -> StatusCode {
+        let mut settings = Settings::default();
+        settings.admin.token = token.map(str::to_string);
+        let router = Router::new()
+            .route("/admin/ping", get(|| async { "pong" }))
+            .layer(middleware::from_fn_with_state(
+                Arc::new(settings),
+                require_admin,
+            ));
+
+        let mut request = axum::http::Request::builder().uri("/admin/ping");
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        let request = request.body(Body::empty()).unwrap();
+        router.oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn admin_routes_need_the_exact_token() {
+        let token = Some("s3cret-token");
+        assert_eq!(
+            status(token, Some("Bearer s3cret-token")).await,
+            StatusCode::OK
+        );
+        for authorization in [
+            None,
+            Some("s3cret-token"),
+            Some("Bearer s3cret-tokeN"),
+            Some("Bearer s3cret"),
+            Some("Bearer s3cret-token-and-more"),
+        ] {
+            let status = status(token, authorization).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{:?}", authorization);
+        }
+
+        let disabled = status(None, Some("Bearer s3cret-token")).await;
+        assert_eq!(disabled, StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn tokens_are_compared_whole() {
+        assert!(tokens_match("token", "token"));
+        assert!(tokens_match("", ""));
+        assert!(!tokens_match("tokem", "token"));
+        assert!(!tokens_match("toke", "token"));
+        assert!(!tokens_match("token", ""));
+    }
+}
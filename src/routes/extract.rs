@@ -0,0 +1,244 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        ConnectInfo, FromRef, FromRequest, FromRequestParts,
+    },
+    http::{request::Parts, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use crate::settings::Settings;
+
+/// `Json` that rejects malformed bodies with a structured `400`.
+#[derive(FromRequest)]
+#[from_request(via(axum::Json), rejection(InvalidRequest))]
+pub struct ValidJson<T>(pub T);
+
+/// `Query` that rejects malformed query strings with a structured `400`.
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(InvalidRequest))]
+pub struct ValidQuery<T>(pub T);
+
+/// The address of the client making a request. Behind one of
+/// `server.trusted_proxies` it comes from `X-Forwarded-For`, otherwise it's
+/// the connecting address.
+pub struct ClientIp(pub IpAddr);
+
+impl<S> FromRequestParts<S> for ClientIp
+where
+    Arc<Settings>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let settings = Arc::<Settings>::from_ref(state);
+        let ConnectInfo(peer) = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+        Ok(ClientIp(client_ip(
+            peer.ip(),
+            &parts.headers,
+            &settings.server.trusted_proxies,
+        )))
+    }
+}
+
+/// The client behind `peer`. Only a trusted proxy's `X-Forwarded-For` is
+/// believed, read from the end since each proxy appends the address it saw,
+/// and the first untrusted entry is the client. Anything unparseable falls
+/// back to the last trusted hop.
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> IpAddr {
+    let mut client = peer;
+    if !trusted_proxies.contains(&client) {
+        return client;
+    }
+
+    let hops = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect::<Vec<_>>();
+    for hop in hops.into_iter().rev() {
+        let Ok(ip) = hop.trim().parse::<IpAddr>() else {
+            break;
+        };
+        client = ip;
+        if !trusted_proxies.contains(&ip) {
+            break;
+        }
+    }
+    client
+}
+
+#[derive(Serialize)]
+pub struct InvalidRequest {
+    #[serde(skip)]
+    status: StatusCode,
+    error: String,
+    // the offending field, when the deserializer names one
+    field: Option<String>,
+}
+
+impl InvalidRequest {
+    fn new(status: StatusCode, error: String) -> Self {
+        // a wrong content type keeps its 415, everything else is a bad request
+        let status = if status == StatusCode::UNSUPPORTED_MEDIA_TYPE {
+            status
+        } else {
+            StatusCode::BAD_REQUEST
+        };
+
+        InvalidRequest {
+            status,
+            field: field_name(&error),
+            error,
+        }
+    }
+}
+
+impl From<JsonRejection> for InvalidRequest {
+    fn from(rejection: JsonRejection) -> Self {
+        InvalidRequest::new(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for InvalidRequest {
+    fn from(rejection: QueryRejection) -> Self {
+        InvalidRequest::new(rejection.status(), rejection.body_text())
+    }
+}
+
+impl IntoResponse for InvalidRequest {
+    fn into_response(self) -> Response {
+        (self.status, Json(self)).into_response()
+    }
+}
+
+/// Pulls the field name out of a deserialize error, either from serde's
+/// "missing field `name`" or the path axum prefixes to JSON data errors
+/// (e.g. "...target type: position: invalid type: ...").
+fn field_name(error: &str) -> Option<String> {
+    if let Some((_, rest)) = error.split_once("field `") {
+        return rest.split('`').next().map(|field| field.to_string());
+    }
+
+    let (_, detail) = error.split_once("target type: ")?;
+    let (path, _) = detail.split_once(": ")?;
+    (!path.contains(' ')).then(|| path.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    const PROXY: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    fn forwarded_for(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn forwarded_client_is_used_behind_a_trusted_proxy() {
+        let headers = forwarded_for("203.0.113.7");
+        assert_eq!(
+            client_ip(PROXY, &headers, &[PROXY]),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_from_untrusted_peers() {
+        let peer = "192.168.1.20".parse().unwrap();
+        let headers = forwarded_for("203.0.113.7");
+        assert_eq!(client_ip(peer, &headers, &[PROXY]), peer);
+        assert_eq!(client_ip(PROXY, &headers, &[]), PROXY);
+    }
+
+    #[test]
+    fn spoofed_hops_before_the_client_are_skipped() {
+        // the client sent its own header, which the proxy appended to
+        let headers = forwarded_for("10.0.0.1, 203.0.113.7");
+        assert_eq!(
+            client_ip(PROXY, &headers, &[PROXY]),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+
+        // chained proxies are all skipped
+        let inner = "10.1.1.1".parse().unwrap();
+        let headers = forwarded_for("203.0.113.7, 10.1.1.1");
+        assert_eq!(
+            client_ip(PROXY, &headers, &[PROXY, inner]),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn unparseable_hops_fall_back_to_the_last_trusted_one() {
+        assert_eq!(client_ip(PROXY, &forwarded_for("unknown"), &[PROXY]), PROXY);
+        assert_eq!(client_ip(PROXY, &HeaderMap::new(), &[PROXY]), PROXY);
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[allow(dead_code)]
+    struct Reposition {
+        song_uuid: String,
+        position: isize,
+    }
+
+    async fn rejection(body: &'static str, content_type: &str) -> (StatusCode, serde_json::Value) {
+        let request = axum::http::Request::builder()
+            .method("POST")
+            .header("content-type", content_type)
+            .body(axum::body::Body::from(body))
+            .unwrap();
+        let Err(rejection) = ValidJson::<Reposition>::from_request(request, &()).await else {
+            panic!("{} was accepted", body);
+        };
+        let response = rejection.into_response();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn malformed_bodies_name_the_offending_field() {
+        let (status, body) = rejection(r#"{"song_uuid": "a"}"#, "application/json").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["field"], "position");
+
+        let (status, body) = rejection(
+            r#"{"song_uuid": "a", "position": "up"}"#,
+            "application/json",
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["field"], "position");
+
+        let (status, body) = rejection("{", "application/json").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["field"].is_null());
+        assert!(body["error"].is_string());
+    }
+
+    #[tokio::test]
+    async fn wrong_content_type_keeps_its_status() {
+        let (status, _) = rejection(r#"{"song_uuid": "a", "position": 0}"#, "text/plain").await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+}
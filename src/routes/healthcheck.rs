@@ -1,16 +1,280 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use axum::{
+    extract::State,
+    http::StatusCode,
     response::IntoResponse,
     Json
 };
 
-pub async fn healthcheck() -> impl IntoResponse {
-    const MESSAGE: &str = "Build Simple CRUD API in Rust using Axum";
+use rand::Rng;
+use tracing::{debug, warn};
+
+use crate::{
+    actors::{video_downloader::VideoDlActorHandle, video_searcher::VideoSearcherActorHandle},
+    rooms::Rooms,
+    utils::binary::{self, BinaryPaths, BinaryVersions},
+};
+
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a binary probe is reused, so a monitor polling every second
+/// doesn't spawn ffmpeg and yt-dlp every second.
+const PROBE_TTL: Duration = Duration::from_secs(30);
+/// Up to this much is added to each TTL, so probes drift off the poll
+/// interval instead of always landing on the same request.
+const PROBE_JITTER_MS: u64 = 5_000;
+
+struct CachedProbe {
+    versions: BinaryVersions,
+    expires_at: Instant,
+}
+
+// held across a refresh, so concurrent healthchecks wait for one probe
+// instead of each running their own
+type ProbeCache = tokio::sync::Mutex<Option<CachedProbe>>;
+
+/// The binaries a router runs, with the results of their last probe.
+#[derive(Clone)]
+pub struct DependencyProbe {
+    binaries: BinaryPaths,
+    cache: Arc<ProbeCache>,
+}
+
+impl DependencyProbe {
+    pub fn new(binaries: BinaryPaths) -> Self {
+        DependencyProbe {
+            binaries,
+            cache: Arc::new(ProbeCache::const_new(None)),
+        }
+    }
+
+    /// The binary versions from the last probe, running a new one once it
+    /// has expired. `None` versions mean the binary could not be run.
+    async fn versions(&self) -> BinaryVersions {
+        let mut cached = self.cache.lock().await;
+        if let Some(probe) = cached
+            .as_ref()
+            .filter(|probe| probe.expires_at > Instant::now())
+        {
+            return probe.versions.clone();
+        }
+
+        debug!("Probing ffmpeg and yt-dlp");
+        let paths = self.binaries.clone();
+        let versions = match tokio::task::spawn_blocking(move || binary::run_versions(&paths)).await {
+            Ok(versions) => versions,
+            Err(e) => {
+                warn!("Binary probe task failed: {}", e);
+                BinaryVersions {
+                    ffmpeg: None,
+                    yt_dlp: None,
+                }
+            }
+        };
+        let jitter = Duration::from_millis(rand::thread_rng().gen_range(0..=PROBE_JITTER_MS));
+        *cached = Some(CachedProbe {
+            versions: versions.clone(),
+            expires_at: Instant::now() + PROBE_TTL + jitter,
+        });
+        versions
+    }
+}
+
+/// Reports whether each actor is still alive and the binaries still run,
+/// returning `503` if any is not. Every room's song actor is pinged; the
+/// download and search pools are checked by their live consumer count, since
+/// a ping would queue behind long downloads.
+pub async fn healthcheck(
+    State(rooms): State<Rooms>,
+    State(videodl_actor_handle): State<Arc<VideoDlActorHandle>>,
+    State(videosearcher_actor_handle): State<Arc<VideoSearcherActorHandle>>,
+    State(dependency_probe): State<DependencyProbe>,
+) -> impl IntoResponse {
+    let mut song_coordinator_alive = true;
+    // how full each room's SSE replay buffer is, to size `sse.replay_buffer`
+    let mut sse_replay = serde_json::Map::new();
+    for (name, room) in rooms.all() {
+        song_coordinator_alive &= matches!(
+            tokio::time::timeout(PING_TIMEOUT, room.song_actor_handle.ping()).await,
+            Ok(Ok(()))
+        );
+        let (buffered, capacity) = room.sse_broadcaster.replay_occupancy();
+        sse_replay.insert(
+            name,
+            serde_json::json!({ "buffered": buffered, "capacity": capacity }),
+        );
+    }
+    let download_consumers = videodl_actor_handle.live_consumers();
+    let (pending_downloads, max_pending_downloads) = videodl_actor_handle.pending_dispatches();
+    let search_consumers = videosearcher_actor_handle.live_consumers();
+
+    let dependencies = dependency_probe.versions().await;
+
+    let failing: Vec<&str> = [
+        ("song_coordinator", song_coordinator_alive),
+        ("video_downloader", download_consumers > 0),
+        ("video_searcher", search_consumers > 0),
+        ("ffmpeg", dependencies.ffmpeg.is_some()),
+        ("yt-dlp", dependencies.yt_dlp.is_some()),
+    ]
+    .into_iter()
+    .filter_map(|(name, ok)| (!ok).then_some(name))
+    .collect();
+    let healthy = failing.is_empty();
+    let message = if healthy {
+        "all actors and dependencies are up".to_string()
+    } else {
+        format!("not working: {}", failing.join(", "))
+    };
 
     let json_response = serde_json::json!({
-        "status": "success",
-        "message": MESSAGE
+        "status": if healthy { "success" } else { "unhealthy" },
+        "message": message,
+        "actors": {
+            "song_coordinator": { "alive": song_coordinator_alive },
+            "video_downloader": {
+                "alive": download_consumers > 0,
+                "consumers": download_consumers,
+                // queue requests get 503 once pending reaches max_pending
+                "pending": pending_downloads,
+                "max_pending": max_pending_downloads,
+            },
+            "video_searcher": { "alive": search_consumers > 0, "consumers": search_consumers },
+        },
+        "sse_replay": sse_replay,
+        "dependencies": dependencies,
     });
 
-    Json(json_response)
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(json_response))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::utils::{process::ProcessGroups, yt_searcher::YtSearcher};
+
+    use super::*;
+
+    /// Binaries that print a version, recording each run in `runs`.
+    #[cfg(unix)]
+    fn stub_binaries(dir: &std::path::Path, runs: &std::path::Path) -> BinaryPaths {
+        use std::os::unix::fs::PermissionsExt;
+
+        let stub = |name: &str| {
+            let path = dir.join(name);
+            let script = format!(
+                "#!/bin/sh\necho {} >> {}\nsleep 0.2\necho '{} version 1.0'\n",
+                name,
+                runs.display(),
+                name
+            );
+            std::fs::write(&path, script).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            path
+        };
+        BinaryPaths {
+            ffmpeg: stub("ffmpeg"),
+            ytdlp: stub("yt-dlp"),
+        }
+    }
+
+    async fn health(
+        videodl: VideoDlActorHandle,
+        binaries: BinaryPaths,
+    ) -> (StatusCode, serde_json::Value) {
+        let searcher =
+            YtSearcher::new(PathBuf::new(), ProcessGroups::default(), Default::default());
+        let videosearcher = VideoSearcherActorHandle::new(Arc::new(searcher), 0);
+
+        let response = healthcheck(
+            State(crate::rooms::tests::rooms(2)),
+            State(Arc::new(videodl)),
+            State(Arc::new(videosearcher)),
+            State(DependencyProbe::new(binaries)),
+        )
+        .await
+        .into_response();
+
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn reports_live_actors_and_missing_binaries() {
+        let missing = tempfile::tempdir().unwrap();
+        let binaries = BinaryPaths {
+            ytdlp: missing.path().join("yt-dlp"),
+            ffmpeg: missing.path().join("ffmpeg"),
+        };
+        let videodl = crate::actors::video_downloader::tests::handle(missing.path());
+
+        let (status, json) = health(videodl, binaries).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(json["status"], "unhealthy");
+        assert_eq!(json["message"], "not working: ffmpeg, yt-dlp");
+        assert_eq!(json["actors"]["song_coordinator"]["alive"], true);
+        assert_eq!(json["actors"]["video_downloader"]["alive"], true);
+        assert_eq!(json["actors"]["video_searcher"]["alive"], true);
+        assert!(json["dependencies"]["ffmpeg"].is_null());
+        assert!(json["sse_replay"]["default"]["capacity"].is_number());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dead_actors_are_unhealthy_with_working_binaries() {
+        let dir = tempfile::tempdir().unwrap();
+        let binaries = stub_binaries(dir.path(), &dir.path().join("runs"));
+        let videodl = crate::actors::video_downloader::tests::handle(dir.path());
+
+        let (status, json) = health(videodl.clone(), binaries.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["message"], "all actors and dependencies are up");
+
+        // every consumer stops once the channel is closed and empty
+        videodl.drain(Duration::from_secs(5)).await;
+        assert_eq!(videodl.live_consumers(), 0);
+
+        let (status, json) = health(videodl, binaries).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(json["status"], "unhealthy");
+        assert_eq!(json["message"], "not working: video_downloader");
+        assert_eq!(json["actors"]["video_downloader"]["alive"], false);
+        assert_eq!(json["actors"]["video_downloader"]["consumers"], 0);
+        assert_eq!(json["actors"]["song_coordinator"]["alive"], true);
+        assert_eq!(json["dependencies"]["ffmpeg"], "1.0");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_healthchecks_share_one_probe() {
+        let dir = tempfile::tempdir().unwrap();
+        let runs = dir.path().join("runs");
+        let binaries = stub_binaries(dir.path(), &runs);
+        let probe = DependencyProbe::new(binaries);
+        let probe_count = || std::fs::read_to_string(&runs).unwrap().lines().count();
+
+        let probes = (0..8).map(|_| probe.versions());
+        for versions in futures_util::future::join_all(probes).await {
+            assert_eq!(versions.ffmpeg.as_deref(), Some("1.0"));
+        }
+        assert_eq!(probe_count(), 2);
+
+        // expired results are refreshed by the next healthcheck
+        probe.cache.lock().await.as_mut().unwrap().expires_at = Instant::now();
+        probe.versions().await;
+        assert_eq!(probe_count(), 4);
+    }
 }
@@ -1,174 +1,1147 @@
-use std::sync::Arc;
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    hash::{DefaultHasher, Hash, Hasher},
+    net::IpAddr,
+    sync::Arc,
+    time::Duration,
+};
 
 use axum::{
-    extract::{Query, State},
-    http::StatusCode,
-    response::
-        IntoResponse
-    ,
+    body::Body,
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     Json,
 };
-use serde::Deserialize;
-use tracing::{error, info};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::{mpsc, OwnedSemaphorePermit};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use uuid::Uuid;
 
 use crate::actors::{
-    song_coordinator::{QueuedSongStatus, Song, SongActorHandle},
-    video_downloader::VideoDlActorHandle,
-    video_searcher::VideoSearcherActorHandle,
+    song_coordinator::{
+        KeyRange, QueuedSongStatus, Song, SongActorHandle, SongCoordinatorError,
+    },
+    video_downloader::{VideoDlActorHandle, VideoStatus},
+    video_searcher::{SearchClient, VideoSearcherActorHandle},
+};
+use crate::pins::PinnedSong;
+use crate::rooms::{RoomActor, RoomEvents};
+use crate::routes::admin::ensure_key_encoded;
+use crate::routes::extract::{ClientIp, ValidJson, ValidQuery};
+use crate::utils::{
+    slug::AssetPaths,
+    title::TitleCleaner,
+    yt_downloader::{TrimRange, VideoProcessError},
+    yt_searcher::{is_playlist_url, SearchError, SearchOptions, SearchResult, UploadDate},
 };
+use crate::settings::{QueueSettings, Settings};
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct QueueSong {
     name: String,
     yt_link: String,
-    is_key_changeable: bool,
+    // falls back to `queue.default_key_changeable` when omitted
+    #[serde(default)]
+    is_key_changeable: Option<bool>,
+    start: Option<f64>,
+    end: Option<f64>,
+    // semitone shift to start in under the `prefer_song` key reset policy
+    #[serde(default)]
+    preferred_key: Option<i8>,
 }
 
 pub async fn queue_song(
-    State(song_actor_handle): State<Arc<SongActorHandle>>,
+    RoomActor(song_actor_handle): RoomActor,
     State(videodl_actor_handle): State<Arc<VideoDlActorHandle>>,
-    Json(payload): Json<QueueSong>,
-) -> impl IntoResponse {
-    let queueable_song = Song::new(
+    State(videosearcher_actor_handle): State<Arc<VideoSearcherActorHandle>>,
+    State(settings): State<Arc<Settings>>,
+    State(title_cleaner): State<Arc<TitleCleaner>>,
+    ClientIp(client): ClientIp,
+    ValidJson(payload): ValidJson<QueueSong>,
+) -> Response {
+    if is_playlist_url(&payload.yt_link) {
+        return queue_playlist(
+            song_actor_handle,
+            videodl_actor_handle,
+            videosearcher_actor_handle,
+            &settings.queue,
+            &title_cleaner,
+            client,
+            payload,
+        )
+        .await;
+    }
+
+    match enqueue_song(
+        song_actor_handle,
+        videodl_actor_handle,
+        settings.queue.default_key_changeable,
+        &title_cleaner,
+        Some(client),
+        payload,
+    )
+    .await
+    {
+        Ok(enqueued) => (StatusCode::ACCEPTED, Json(enqueued)).into_response(),
+        Err(err) => queue_song_error_response(err),
+    }
+}
+
+/// The response for a song that wasn't queued, with the reason as a JSON
+/// error.
+fn queue_song_error_response(err: QueueSongError) -> Response {
+    let status = match &err {
+        QueueSongError::DownloadQueueFull { .. }
+        | QueueSongError::Coordinator(SongCoordinatorError::ActorUnavailable) => {
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+        QueueSongError::ClientQuotaFull { .. } => StatusCode::TOO_MANY_REQUESTS,
+        QueueSongError::InvalidRequest(_) | QueueSongError::InvalidKey { .. } => {
+            StatusCode::BAD_REQUEST
+        }
+        QueueSongError::Coordinator(
+            SongCoordinatorError::SongAlreadyPlaying { .. }
+            | SongCoordinatorError::SongAlreadyQueued { .. }
+            | SongCoordinatorError::QueueFull { .. },
+        ) => StatusCode::CONFLICT,
+        QueueSongError::Coordinator(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (
+        status,
+        Json(serde_json::json!({ "error": err.to_string() })),
+    )
+        .into_response()
+}
+
+/// Queues each available entry of a playlist as its own song, stopping once
+/// the queue or the download backlog is full. The whole playlist takes one
+/// of the client's download slots. Returns the queued uuids.
+async fn queue_playlist(
+    song_actor_handle: Arc<SongActorHandle>,
+    videodl_actor_handle: Arc<VideoDlActorHandle>,
+    videosearcher_actor_handle: Arc<VideoSearcherActorHandle>,
+    queue_settings: &QueueSettings,
+    title_cleaner: &TitleCleaner,
+    client: IpAddr,
+    payload: QueueSong,
+) -> Response {
+    let entries = match videosearcher_actor_handle
+        .expand_playlist(&payload.yt_link, queue_settings.playlist_max_items)
+        .await
+    {
+        Ok(entries) => entries,
+        Err(err) => {
+            error!("unable to expand playlist {} with error: {}", payload.yt_link, err);
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+    };
+    info!("queueing {} songs from playlist {}", entries.len(), payload.yt_link);
+
+    let client_slot = match reserve_client_slot(&videodl_actor_handle, client) {
+        Ok(client_slot) => client_slot,
+        Err(err) => return queue_song_error_response(err),
+    };
+    let mut song_uuids = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let queue_request = QueueSong {
+            name: entry.title,
+            yt_link: entry.url,
+            is_key_changeable: payload.is_key_changeable,
+            start: None,
+            end: None,
+            preferred_key: payload.preferred_key,
+        };
+
+        match enqueue_song_with_slot(
+            song_actor_handle.clone(),
+            videodl_actor_handle.clone(),
+            queue_settings.default_key_changeable,
+            title_cleaner,
+            client_slot.clone(),
+            queue_request,
+        )
+        .await
+        {
+            Ok(enqueued) => song_uuids.push(enqueued.uuid),
+            Err(
+                err @ (QueueSongError::DownloadQueueFull { .. }
+                | QueueSongError::Coordinator(SongCoordinatorError::QueueFull { .. })),
+            ) => {
+                warn!("stopped queueing playlist: {}", err);
+                break;
+            }
+            Err(err) => warn!("skipping playlist entry: {}", err),
+        }
+    }
+
+    (StatusCode::ACCEPTED, Json(song_uuids)).into_response()
+}
+
+#[derive(Error, Debug)]
+pub enum QueueSongError {
+    #[error(transparent)]
+    Coordinator(#[from] SongCoordinatorError),
+
+    #[error("download queue is full ({backlog} pending)")]
+    DownloadQueueFull { backlog: usize },
+
+    #[error("{client} already has {max_per_client} downloads running")]
+    ClientQuotaFull { client: IpAddr, max_per_client: usize },
+
+    #[error(transparent)]
+    InvalidRequest(#[from] VideoProcessError),
+
+    #[error("preferred key {key} is outside {} to {}", .range.lowest, .range.highest)]
+    InvalidKey { key: i8, range: KeyRange },
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum QueueBatchResult {
+    Queued(EnqueuedSong),
+    Failed { error: String },
+}
+
+/// Queues several songs at once, e.g. a setlist, reporting each one's
+/// outcome. The batch takes one of the client's download slots.
+pub async fn queue_song_batch(
+    RoomActor(song_actor_handle): RoomActor,
+    State(videodl_actor_handle): State<Arc<VideoDlActorHandle>>,
+    State(settings): State<Arc<Settings>>,
+    State(title_cleaner): State<Arc<TitleCleaner>>,
+    ClientIp(client): ClientIp,
+    ValidJson(payload): ValidJson<Vec<QueueSong>>,
+) -> Response {
+    info!("received queue_batch request with {} songs", payload.len());
+
+    let client_slot = match reserve_client_slot(&videodl_actor_handle, client) {
+        Ok(client_slot) => client_slot,
+        Err(err) => return queue_song_error_response(err),
+    };
+    let mut results = Vec::with_capacity(payload.len());
+    for queue_request in payload {
+        let result = match enqueue_song_with_slot(
+            song_actor_handle.clone(),
+            videodl_actor_handle.clone(),
+            settings.queue.default_key_changeable,
+            &title_cleaner,
+            client_slot.clone(),
+            queue_request,
+        )
+        .await
+        {
+            Ok(enqueued) => QueueBatchResult::Queued(enqueued),
+            Err(err) => QueueBatchResult::Failed {
+                error: err.to_string(),
+            },
+        };
+        results.push(result);
+    }
+
+    (StatusCode::ACCEPTED, Json(results)).into_response()
+}
+
+#[derive(Serialize)]
+pub struct EnqueuedSong {
+    uuid: String,
+    // already processed, so it will be ready without a download
+    cached: bool,
+}
+
+/// One of a client's `download.max_per_client` slots, held by the download
+/// tasks of the songs queued with it. A playlist or batch shares one slot,
+/// so its songs count as a single download toward the client's quota.
+type ClientSlot = Option<Arc<OwnedSemaphorePermit>>;
+
+fn reserve_client_slot(
+    videodl_actor_handle: &VideoDlActorHandle,
+    client: IpAddr,
+) -> Result<ClientSlot, QueueSongError> {
+    match videodl_actor_handle.try_reserve_client_slot(client) {
+        Ok(slot) => Ok(slot.map(Arc::new)),
+        Err(_) => {
+            let max_per_client = videodl_actor_handle.max_per_client();
+            warn!(
+                "rejecting queue request, {} has {} downloads running",
+                client, max_per_client
+            );
+            Err(QueueSongError::ClientQuotaFull { client, max_per_client })
+        }
+    }
+}
+
+/// Queues a song and dispatches its download in the background, counting it
+/// toward the downloads of the client that requested it.
+async fn enqueue_song(
+    song_actor_handle: Arc<SongActorHandle>,
+    videodl_actor_handle: Arc<VideoDlActorHandle>,
+    default_key_changeable: bool,
+    title_cleaner: &TitleCleaner,
+    client: Option<IpAddr>,
+    payload: QueueSong,
+) -> Result<EnqueuedSong, QueueSongError> {
+    // checked first, so a client over its quota never holds a dispatch
+    // permit another client's song could use
+    let client_slot = match client {
+        Some(client) => reserve_client_slot(&videodl_actor_handle, client)?,
+        None => None,
+    };
+    enqueue_song_with_slot(
+        song_actor_handle,
+        videodl_actor_handle,
+        default_key_changeable,
+        title_cleaner,
+        client_slot,
+        payload,
+    )
+    .await
+}
+
+/// Queues a song whose download holds `client_slot`, already reserved by
+/// the caller.
+async fn enqueue_song_with_slot(
+    song_actor_handle: Arc<SongActorHandle>,
+    videodl_actor_handle: Arc<VideoDlActorHandle>,
+    default_key_changeable: bool,
+    title_cleaner: &TitleCleaner,
+    client_slot: ClientSlot,
+    payload: QueueSong,
+) -> Result<EnqueuedSong, QueueSongError> {
+    if videodl_actor_handle.is_saturated() {
+        let backlog = videodl_actor_handle.backlog();
+        warn!("rejecting queue request, download backlog at {}", backlog);
+        return Err(QueueSongError::DownloadQueueFull { backlog });
+    }
+    // taken before the task is spawned and held by it, so a burst of
+    // requests can't spawn more than `download.max_pending` tasks
+    let Some(dispatch_permit) = videodl_actor_handle.try_reserve_dispatch() else {
+        let (pending, _) = videodl_actor_handle.pending_dispatches();
+        warn!("rejecting queue request, {} downloads pending", pending);
+        return Err(QueueSongError::DownloadQueueFull { backlog: pending });
+    };
+
+    let trim = TrimRange::new(payload.start, payload.end);
+    if let Some(trim) = &trim {
+        trim.validate()?;
+    }
+    let range = song_actor_handle.key_range();
+    if let Some(key) = payload.preferred_key.filter(|key| !range.contains(*key)) {
+        return Err(QueueSongError::InvalidKey { key, range });
+    }
+
+    let mut queueable_song = Song::new(
         payload.name,
         payload.yt_link,
         QueuedSongStatus::InProgress,
-        payload.is_key_changeable,
+        payload.is_key_changeable.unwrap_or(default_key_changeable),
+        trim,
     );
+    queueable_song.preferred_key = payload.preferred_key;
+    clean_title(title_cleaner, &mut queueable_song);
     info!("received queue_song request: {}", queueable_song);
 
     match song_actor_handle.queue_song(queueable_song.clone()).await {
         Ok(_) => {
             info!("successfully queued song: {}", queueable_song.uuid);
 
-            tokio::spawn(async move {
-                match videodl_actor_handle
-                    .download_video(
-                        queueable_song.yt_link,
-                        queueable_song.name.to_string(),
-                        queueable_song.is_key_changeable,
-                    )
+            let enqueued = EnqueuedSong {
+                uuid: queueable_song.uuid.to_string(),
+                cached: videodl_actor_handle.is_cached(
+                    &queueable_song.folder,
+                    queueable_song.title(),
+                    &queueable_song.yt_link,
+                    queueable_song.is_key_changeable,
+                    &queueable_song.trim,
+                ),
+            };
+            dispatch_download(
+                song_actor_handle,
+                videodl_actor_handle,
+                queueable_song,
+                client_slot,
+                dispatch_permit,
+            );
+
+            Ok(enqueued)
+        }
+        Err(err) => {
+            error!(
+                "unable to queue song: {} with error: {}",
+                queueable_song.uuid, err
+            );
+            Err(err.into())
+        }
+    }
+}
+
+/// Cleans a new song's title, keeping the one it was queued with as its
+/// `original_name`.
+fn clean_title(title_cleaner: &TitleCleaner, song: &mut Song) {
+    if let Some(cleaned) = title_cleaner.clean(&song.name) {
+        debug!("cleaned title {} to {}", song.name, cleaned);
+        song.original_name = Some(std::mem::replace(&mut song.name, cleaned));
+    }
+}
+
+/// Downloads a queued song in the background and reflects the outcome in
+/// its queue status. The task holds `dispatch_permit` until it ends, so
+/// every spawned task counts toward `download.max_pending`, and the
+/// requesting client's `client_slot` along with it.
+fn dispatch_download(
+    song_actor_handle: Arc<SongActorHandle>,
+    videodl_actor_handle: Arc<VideoDlActorHandle>,
+    queueable_song: Song,
+    client_slot: ClientSlot,
+    dispatch_permit: OwnedSemaphorePermit,
+) {
+    let song_uuid = queueable_song.uuid;
+    let download_task = async move {
+        let _dispatch_permit = dispatch_permit;
+        let _client_slot = client_slot;
+        if matches!(
+            download_target(&song_actor_handle, &queueable_song).await,
+            DownloadTarget::Removed
+        ) {
+            debug!(
+                "song: {} was removed before its download started",
+                queueable_song.uuid
+            );
+            return;
+        }
+
+        let result = videodl_actor_handle
+            .download_video(&queueable_song, song_actor_handle.clone())
+            .await;
+
+        match download_target(&song_actor_handle, &queueable_song).await {
+            DownloadTarget::Current => {}
+            DownloadTarget::Edited => {
+                info!(
+                    "song: {} was edited while downloading, discarding result",
+                    queueable_song.uuid
+                );
+                return;
+            }
+            DownloadTarget::Removed => {
+                debug!(
+                    "song: {} was removed while downloading, discarding result",
+                    queueable_song.uuid
+                );
+                return;
+            }
+        }
+
+        match result {
+            Ok(downloaded_video) => {
+                info!("successfully downloaded video: {:?}", downloaded_video);
+
+                if let Some(duration_seconds) = downloaded_video.duration_seconds {
+                    if let Err(err) = song_actor_handle
+                        .update_song_duration(queueable_song.uuid, duration_seconds)
+                        .await
+                    {
+                        error!(
+                            "unable to update duration for song: {} with error: {}",
+                            queueable_song.uuid, err
+                        );
+                    }
+                }
+
+                match song_actor_handle
+                    .update_song_status(queueable_song.uuid, QueuedSongStatus::Success)
                     .await
                 {
-                    Ok(video_file_path) => {
-                        info!("successfully downloaded video in: {}", video_file_path);
-
-                        match song_actor_handle
-                            .update_song_status(queueable_song.uuid, QueuedSongStatus::Success)
-                            .await
-                        {
-                            Ok(_) => {
-                                info!(
-                                    "successfully updated song: {} with status: {}",
-                                    queueable_song.uuid,
-                                    QueuedSongStatus::Success
-                                );
-                            }
-                            Err(err) => {
-                                error!(
-                                    "unable to update status for song: {} with error: {}",
-                                    queueable_song.uuid, err
-                                );
-                            }
-                        }
-
-                        std::fs::remove_file(&video_file_path).unwrap_or_else(|err| {
-                            error!(
-                                "unable to delete file {} with error: {}",
-                                &video_file_path, err
-                            );
-                        });
+                    Ok(_) => {
+                        info!(
+                            "successfully updated song: {} with status: {}",
+                            queueable_song.uuid,
+                            QueuedSongStatus::Success
+                        );
                     }
                     Err(err) => {
                         error!(
-                            "could not download video for song: {} with error: {}",
+                            "unable to update status for song: {} with error: {}",
                             queueable_song.uuid, err
                         );
+                    }
+                }
 
-                        match song_actor_handle
-                            .update_song_status(queueable_song.uuid, QueuedSongStatus::Failed)
-                            .await
-                        {
-                            Ok(_) => {
-                                info!(
-                                    "successfully updated song: {} with status: {}",
-                                    queueable_song.uuid,
-                                    QueuedSongStatus::Failed
-                                );
-                            }
-                            Err(err) => {
-                                error!(
-                                    "unable to update status for song: {} with error: {}",
-                                    queueable_song.uuid, err
-                                );
-                            }
-                        }
+                if let Some(video_file_path) = downloaded_video.source_path {
+                    std::fs::remove_file(&video_file_path).unwrap_or_else(|err| {
+                        error!(
+                            "unable to delete file {} with error: {}",
+                            &video_file_path, err
+                        );
+                    });
+                }
+            }
+            Err(err) => {
+                error!(
+                    "could not download video for song: {} with error: {}",
+                    queueable_song.uuid, err
+                );
+
+                match song_actor_handle
+                    .fail_song(queueable_song.uuid, err.guest_reason())
+                    .await
+                {
+                    Ok(_) => {
+                        info!(
+                            "successfully updated song: {} with status: {}",
+                            queueable_song.uuid,
+                            QueuedSongStatus::Failed
+                        );
+                    }
+                    Err(err) => {
+                        error!(
+                            "unable to update status for song: {} with error: {}",
+                            queueable_song.uuid, err
+                        );
                     }
                 }
-            });
+            }
         }
+    };
+    tokio::spawn(download_task.instrument(info_span!("song", song_uuid = %song_uuid)));
+}
+
+/// Dispatches the downloads of songs queued together from a single task,
+/// which waits for a dispatch permit before spawning each, as the songs are
+/// already queued and shouldn't be rejected.
+fn dispatch_downloads(
+    song_actor_handle: Arc<SongActorHandle>,
+    videodl_actor_handle: Arc<VideoDlActorHandle>,
+    songs: Vec<Song>,
+) {
+    tokio::spawn(async move {
+        for song in songs {
+            let dispatch_permit = videodl_actor_handle.reserve_dispatch().await;
+            dispatch_download(
+                song_actor_handle.clone(),
+                videodl_actor_handle.clone(),
+                song,
+                None,
+                dispatch_permit,
+            );
+        }
+    });
+}
+
+/// What became of a queued song while its download was pending.
+enum DownloadTarget {
+    Current,
+    // edited or retried, a newer download owns its status
+    Edited,
+    Removed,
+}
+
+/// Whether the song is still queued from the dispatch that downloaded it, so
+/// a superseded or orphaned download doesn't touch the queue.
+async fn download_target(song_actor_handle: &SongActorHandle, downloaded: &Song) -> DownloadTarget {
+    match song_actor_handle.get_song(downloaded.uuid).await {
+        Ok(Some(song)) if song.generation != downloaded.generation => DownloadTarget::Edited,
+        Ok(None) => DownloadTarget::Removed,
+        _ => DownloadTarget::Current,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UpdateSongRequest {
+    name: Option<String>,
+    is_key_changeable: Option<bool>,
+}
+
+pub async fn update_song(
+    RoomActor(song_actor_handle): RoomActor,
+    State(videodl_actor_handle): State<Arc<VideoDlActorHandle>>,
+    State(title_cleaner): State<Arc<TitleCleaner>>,
+    Path(song_uuid): Path<String>,
+    ValidJson(payload): ValidJson<UpdateSongRequest>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let song_uuid = Uuid::parse_str(&song_uuid).map_err(|_| StatusCode::BAD_REQUEST)?;
+    // an edit may need a fresh download, so it's refused while every
+    // dispatch is pending instead of being made without one
+    let dispatch_permit = videodl_actor_handle
+        .try_reserve_dispatch()
+        .ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    // the folder is keyed by the full title, so it's kept when shortened
+    let (name, original_name) = match payload.name {
+        Some(name) => match title_cleaner.shorten(&name) {
+            Some(shortened) => (Some(shortened), Some(name)),
+            None => (Some(name), None),
+        },
+        None => (None, None),
+    };
+
+    match song_actor_handle
+        .update_song(song_uuid, name, original_name, payload.is_key_changeable)
+        .await
+    {
+        Ok(updated) => {
+            info!("updated song: {}", updated.song);
+            if updated.needs_download {
+                dispatch_download(
+                    song_actor_handle,
+                    videodl_actor_handle,
+                    updated.song.clone(),
+                    None,
+                    dispatch_permit,
+                );
+            }
+            Ok((StatusCode::OK, Json(updated.song)))
+        }
+        Err(SongCoordinatorError::SongNotFound { .. }) => Err(StatusCode::NOT_FOUND),
+        Err(
+            SongCoordinatorError::SongIsPlaying { .. }
+            | SongCoordinatorError::SongAlreadyQueued { .. },
+        ) => Err(StatusCode::CONFLICT),
         Err(err) => {
-            error!(
-                "unable to queue song: {} with error: {}",
-                queueable_song.uuid, err
+            error!("unable to update song: {} with error: {}", song_uuid, err);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RetrySongRequest {
+    song_uuid: String,
+}
+
+/// Downloads a failed song again without moving it in the queue.
+pub async fn retry_song(
+    RoomActor(song_actor_handle): RoomActor,
+    State(videodl_actor_handle): State<Arc<VideoDlActorHandle>>,
+    ValidJson(payload): ValidJson<RetrySongRequest>,
+) -> Response {
+    let Ok(song_uuid) = Uuid::parse_str(&payload.song_uuid) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    let Some(dispatch_permit) = videodl_actor_handle.try_reserve_dispatch() else {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    };
+
+    match song_actor_handle.retry_song(song_uuid).await {
+        Ok(song) => {
+            info!("retrying download of song: {}", song);
+            dispatch_download(
+                song_actor_handle,
+                videodl_actor_handle,
+                song.clone(),
+                None,
+                dispatch_permit,
             );
+            (StatusCode::ACCEPTED, Json(song)).into_response()
+        }
+        Err(SongCoordinatorError::SongNotFound { .. }) => StatusCode::NOT_FOUND.into_response(),
+        Err(err @ SongCoordinatorError::SongNotFailed { .. }) => (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({ "error": err.to_string() })),
+        )
+            .into_response(),
+        Err(err) => {
+            error!("unable to retry song: {} with error: {}", song_uuid, err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// A saved queue that can be imported again, e.g. for a recurring event.
+#[derive(Serialize, Deserialize)]
+pub struct SessionExport {
+    songs: Vec<ExportedSong>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportedSong {
+    #[serde(flatten)]
+    song: QueueSong,
+    #[serde(default)]
+    pinned: bool,
+}
+
+pub async fn export_session(
+    RoomActor(song_actor_handle): RoomActor,
+) -> Result<impl IntoResponse, StatusCode> {
+    let queue = song_actor_handle
+        .get_queue()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let songs = queue
+        .into_iter()
+        .map(|song| ExportedSong {
+            song: QueueSong {
+                // cleaned again on import, and the title keys the song's folder
+                name: song.title().to_string(),
+                yt_link: song.yt_link,
+                is_key_changeable: Some(song.is_key_changeable),
+                start: song.trim.and_then(|trim| trim.start),
+                end: song.trim.and_then(|trim| trim.end),
+                preferred_key: song.preferred_key,
+            },
+            pinned: song.pinned,
+        })
+        .collect();
+
+    Ok((StatusCode::OK, Json(SessionExport { songs })))
+}
+
+/// Replaces the queue with an exported session and downloads its songs,
+/// which returns straight away for songs that are already cached.
+pub async fn import_session(
+    RoomActor(song_actor_handle): RoomActor,
+    State(videodl_actor_handle): State<Arc<VideoDlActorHandle>>,
+    State(settings): State<Arc<Settings>>,
+    State(title_cleaner): State<Arc<TitleCleaner>>,
+    ValidJson(session): ValidJson<SessionExport>,
+) -> Result<impl IntoResponse, StatusCode> {
+    info!("received session import with {} songs", session.songs.len());
+
+    let key_range = song_actor_handle.key_range();
+    let mut songs = Vec::with_capacity(session.songs.len());
+    for ExportedSong {
+        song: imported,
+        pinned,
+    } in session.songs
+    {
+        if imported.name.trim().is_empty() || imported.yt_link.trim().is_empty() {
+            warn!("rejecting session import with an empty name or link");
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        let trim = TrimRange::new(imported.start, imported.end);
+        if let Some(trim) = &trim {
+            trim.validate().map_err(|err| {
+                warn!("rejecting session import: {}", err);
+                StatusCode::BAD_REQUEST
+            })?;
         }
+        if let Some(key) = imported.preferred_key.filter(|key| !key_range.contains(*key)) {
+            warn!("rejecting session import: key {} out of range", key);
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        let mut song = Song::new(
+            imported.name,
+            imported.yt_link,
+            QueuedSongStatus::InProgress,
+            imported
+                .is_key_changeable
+                .unwrap_or(settings.queue.default_key_changeable),
+            trim,
+        );
+        song.pinned = pinned;
+        song.preferred_key = imported.preferred_key;
+        clean_title(&title_cleaner, &mut song);
+        songs.push(song);
     }
 
-    StatusCode::ACCEPTED
+    match song_actor_handle.replace_queue(songs.clone()).await {
+        Ok(()) => {}
+        Err(
+            err @ (SongCoordinatorError::SongAlreadyQueued { .. }
+            | SongCoordinatorError::QueueFull { .. }),
+        ) => {
+            warn!("rejecting session import: {}", err);
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        Err(err) => {
+            error!("unable to import session with error: {}", err);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+
+    let song_uuids = songs
+        .iter()
+        .map(|song| song.uuid.to_string())
+        .collect::<Vec<_>>();
+    dispatch_downloads(song_actor_handle, videodl_actor_handle, songs);
+
+    Ok((StatusCode::ACCEPTED, Json(song_uuids)))
+}
+
+/// Queues a room's persisted pins again and downloads them, which returns
+/// straight away for songs that are still cached. Titles are cleaned from
+/// the one they were queued with, like any other song.
+pub async fn restore_pins(
+    song_actor_handle: Arc<SongActorHandle>,
+    videodl_actor_handle: Arc<VideoDlActorHandle>,
+    title_cleaner: &TitleCleaner,
+    pins: Vec<PinnedSong>,
+) {
+    if pins.is_empty() {
+        return;
+    }
+    info!("restoring {} pinned songs", pins.len());
+
+    let mut restored = Vec::with_capacity(pins.len());
+    for mut song in pins.into_iter().map(PinnedSong::into_song) {
+        clean_title(title_cleaner, &mut song);
+        if let Err(err) = song_actor_handle.queue_song(song.clone()).await {
+            warn!("unable to restore pinned song {}: {}", song.name, err);
+            continue;
+        }
+        restored.push(song);
+    }
+    dispatch_downloads(song_actor_handle, videodl_actor_handle, restored);
 }
 
+#[derive(Deserialize)]
+pub struct PlayNextQuery {
+    // seconds to wait for a song to be queued when the queue runs empty
+    wait: Option<u64>,
+}
+
+/// Advances the queue, passing over songs still downloading when a later
+/// one is ready and failed songs while any other can still play. Returns
+/// the song now up, or `null` when the queue is empty, with `202` while it
+/// is still downloading so the client waits for its status to change. With
+/// `?wait=`, waits for a song to be queued when the queue runs empty, or
+/// answers `204` on timeout, so idle displays can long-poll instead of
+/// polling.
 pub async fn play_next_song(
-    State(song_actor_handle): State<Arc<SongActorHandle>>,
-) -> impl IntoResponse {
+    RoomActor(song_actor_handle): RoomActor,
+    State(settings): State<Arc<Settings>>,
+    ValidQuery(query): ValidQuery<PlayNextQuery>,
+) -> Response {
     info!("received play_next_song request");
 
-    match song_actor_handle.pop_song().await {
-        Some(song) => {
-            info!("successfully popped song: {}", song);
-            StatusCode::OK
+    let advanced = match song_actor_handle.pop_song().await {
+        Ok(advanced) => advanced,
+        Err(err) => {
+            error!("unable to pop song with error: {}", err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
         }
-        None => {
-            info!("successfully popped song: {}", "none");
+    };
+    match &advanced.finished {
+        Some(song) => info!("successfully popped song: {}", song),
+        None => info!("successfully popped song: {}", "none"),
+    }
+
+    let Some(wait) = query.wait else {
+        let downloading = advanced.now_playing.as_ref().is_some_and(|song| {
+            matches!(
+                song.status,
+                QueuedSongStatus::InProgress | QueuedSongStatus::Downloading
+            )
+        });
+        let status = if downloading {
+            StatusCode::ACCEPTED
+        } else {
             StatusCode::OK
+        };
+        return (status, Json(advanced.now_playing)).into_response();
+    };
+    let wait = Duration::from_secs(wait.min(settings.playback.max_next_wait_seconds));
+    match song_actor_handle.wait_for_current_song(wait).await {
+        Ok(Some(song)) => (StatusCode::OK, Json(song)).into_response(),
+        Ok(None) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => {
+            error!("unable to wait for next song with error: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
         }
     }
 }
 
-pub async fn song_list(State(song_actor_handle): State<Arc<SongActorHandle>>) -> impl IntoResponse {
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SongListOrder {
+    #[default]
+    Queue,
+    // by title, ignoring case, accents and leading articles
+    Name,
+}
+
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SongListFields {
+    #[default]
+    Full,
+    // just enough to render a queue display
+    Summary,
+}
+
+#[derive(Deserialize)]
+pub struct SongListQuery {
+    #[serde(default)]
+    sort: SongListOrder,
+    #[serde(default)]
+    fields: SongListFields,
+}
+
+#[derive(Serialize)]
+pub struct SongSummary {
+    uuid: String,
+    name: String,
+    status: QueuedSongStatus,
+}
+
+impl From<Song> for SongSummary {
+    fn from(song: Song) -> Self {
+        SongSummary {
+            uuid: song.uuid.to_string(),
+            name: song.name,
+            status: song.status,
+        }
+    }
+}
+
+/// Serializes `value` with a weak ETag of its contents, answering `304` when
+/// the client's `If-None-Match` already has it.
+fn json_with_etag<T: Serialize>(headers: &HeaderMap, value: &T) -> Response {
+    let body = match serde_json::to_vec(value) {
+        Ok(body) => body,
+        Err(err) => {
+            error!("unable to serialize response with error: {}", err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    let etag = format!("W/\"{:016x}\"", hasher.finish());
+
+    // weak comparison, so the W/ prefix is ignored on both sides
+    let opaque_tag = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let matches = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value.trim() == "*"
+                || value
+                    .split(',')
+                    .any(|tag| opaque_tag(tag) == opaque_tag(&etag))
+        });
+    if matches {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+    }
+
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            (header::ETAG, etag),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// The queue, with a weak ETag so polling clients can skip unchanged lists.
+pub async fn song_list(
+    RoomActor(song_actor_handle): RoomActor,
+    headers: HeaderMap,
+    ValidQuery(query): ValidQuery<SongListQuery>,
+) -> impl IntoResponse {
     match song_actor_handle.get_queue().await {
-        Ok(list_of_songs) => (StatusCode::OK, Json(list_of_songs)).into_response(),
+        Ok(list_of_songs) => {
+            let mut list_of_songs = Vec::from(list_of_songs);
+            if query.sort == SongListOrder::Name {
+                list_of_songs.sort_by(|a, b| a.sort_key.cmp(&b.sort_key));
+            }
+            if query.fields == SongListFields::Summary {
+                let summaries = list_of_songs
+                    .into_iter()
+                    .map(SongSummary::from)
+                    .collect::<Vec<_>>();
+                return json_with_etag(&headers, &summaries);
+            }
+            json_with_etag(&headers, &list_of_songs)
+        }
         Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
     }
 }
 
+pub async fn session(RoomActor(song_actor_handle): RoomActor) -> impl IntoResponse {
+    match song_actor_handle.get_session().await {
+        Ok(snapshot) => (StatusCode::OK, Json(snapshot)).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// The song now playing along with what a client joining mid-song needs to
+/// start at the right pitch.
+#[derive(Serialize)]
+pub struct CurrentSong {
+    #[serde(flatten)]
+    song: Song,
+    current_key: i8,
+    // semitone shift to the DASH adaptation set carrying that audio, empty
+    // until the song has finished processing
+    audio_adaptation_sets: BTreeMap<i32, usize>,
+}
+
+/// The song now playing. When it starts in a key that wasn't pre-generated,
+/// e.g. one it was queued with or kept from the song before, that key is
+/// encoded on demand like a key change.
 pub async fn current_song(
-    State(song_actor_handle): State<Arc<SongActorHandle>>,
+    RoomActor(song_actor_handle): RoomActor,
+    State(videodl_actor_handle): State<Arc<VideoDlActorHandle>>,
+    State(assets): State<AssetPaths>,
+    RoomEvents(sse_broadcaster): RoomEvents,
 ) -> impl IntoResponse {
-    let song_actor_response = song_actor_handle.current_song().await;
-    match song_actor_response {
-        Ok(current_song) => match current_song {
-            Some(current_song) => (StatusCode::OK, Json(current_song)).into_response(),
-            None => StatusCode::NO_CONTENT.into_response(),
-        },
+    // one snapshot so the song and key can't disagree
+    let session = match song_actor_handle.get_session().await {
+        Ok(session) => session,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    let Some(song) = session.queue.front().cloned() else {
+        return StatusCode::NO_CONTENT.into_response();
+    };
+
+    let audio_adaptation_sets = VideoStatus::load(&assets.song_dir(&song.folder))
+        .map(|status| status.audio_adaptation_sets)
+        .unwrap_or_default();
+    let current_key = session.current_key;
+    if !audio_adaptation_sets.is_empty()
+        && !audio_adaptation_sets.contains_key(&(current_key as i32))
+    {
+        ensure_key_encoded(
+            song_actor_handle,
+            videodl_actor_handle,
+            sse_broadcaster,
+            assets,
+            current_key,
+        )
+        .await;
+    }
+
+    let current_song = CurrentSong {
+        song,
+        current_key,
+        audio_adaptation_sets,
+    };
+    (StatusCode::OK, Json(current_song)).into_response()
+}
+
+/// The song that plays after the current one, or `204` when there is none.
+pub async fn up_next(RoomActor(song_actor_handle): RoomActor) -> impl IntoResponse {
+    match song_actor_handle.up_next().await {
+        Ok(Some(song)) => (StatusCode::OK, Json(song)).into_response(),
+        Ok(None) => StatusCode::NO_CONTENT.into_response(),
         Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
     }
 }
 
+#[derive(Deserialize)]
+pub struct UpcomingQuery {
+    // defaults to `queue.upcoming_count`
+    count: Option<usize>,
+}
+
+/// The next few songs after the current one, for "coming up" lists.
+pub async fn upcoming(
+    RoomActor(song_actor_handle): RoomActor,
+    State(settings): State<Arc<Settings>>,
+    ValidQuery(query): ValidQuery<UpcomingQuery>,
+) -> impl IntoResponse {
+    let count = query.count.unwrap_or(settings.queue.upcoming_count);
+    match song_actor_handle.peek_upcoming(count).await {
+        Ok(songs) => (StatusCode::OK, Json(songs)).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct IsCachedQuery {
+    yt_link: String,
+    start: Option<f64>,
+    end: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct CacheState {
+    cached: bool,
+    // the cached copy can be played in other keys
+    key_changeable: bool,
+}
+
+/// Whether queueing a link would be served from the cache, e.g. for an
+/// "instant" badge on search results. Any folder downloaded from the link
+/// counts, whatever title it was queued with. Nothing is queued or
+/// downloaded.
+pub async fn is_cached(
+    State(videodl_actor_handle): State<Arc<VideoDlActorHandle>>,
+    ValidQuery(query): ValidQuery<IsCachedQuery>,
+) -> Json<CacheState> {
+    let trim = TrimRange::new(query.start, query.end);
+    let folders = videodl_actor_handle.folders_for_link(&query.yt_link);
+
+    // a key-changeable copy also serves requests that don't need one
+    let is_cached = |is_key_changeable| {
+        folders.iter().any(|(folder, title)| {
+            videodl_actor_handle.is_cached(folder, title, &query.yt_link, is_key_changeable, &trim)
+        })
+    };
+    let key_changeable = is_cached(true);
+    let cached = key_changeable || is_cached(false);
+    Json(CacheState {
+        cached,
+        key_changeable,
+    })
+}
+
 #[derive(Deserialize)]
 pub struct SearchSong {
     query: String,
+    #[serde(default)]
+    karaoke: bool,
+    #[serde(default)]
+    family_friendly: bool,
+    // unset uses the configured `search.transliterate`
+    transliterate: Option<bool>,
+    // `YYYYMMDD` bounds on the upload date, unset doesn't filter
+    uploaded_after: Option<UploadDate>,
+    uploaded_before: Option<UploadDate>,
+    // with `seq`, lets a client's newer query supersede its older ones
+    client_id: Option<String>,
+    seq: Option<u64>,
+    // send results as newline-delimited JSON as they are found
+    #[serde(default)]
+    stream: bool,
 }
 
 pub async fn search(
     State(videosearcher_actor_handle): State<Arc<VideoSearcherActorHandle>>,
-    search_request: Query<SearchSong>,
+    ValidQuery(search_request): ValidQuery<SearchSong>,
 ) -> impl IntoResponse {
-    match videosearcher_actor_handle
-        .search_videos(&search_request.query)
-        .await
-    {
-        Ok(results) => (StatusCode::OK, Json(results)).into_response(),
+    let options = SearchOptions {
+        karaoke: search_request.karaoke,
+        family_friendly: search_request.family_friendly,
+        transliterate: search_request.transliterate,
+        uploaded_after: search_request.uploaded_after,
+        uploaded_before: search_request.uploaded_before,
+    };
+    let client = search_request
+        .client_id
+        .zip(search_request.seq)
+        .map(|(id, seq)| SearchClient { id, seq });
+
+    let results = if search_request.stream {
+        videosearcher_actor_handle
+            .search_videos_streaming(&search_request.query, options, client)
+            .await
+            .map(ndjson_results)
+    } else {
+        videosearcher_actor_handle
+            .search_videos(&search_request.query, options, client)
+            .await
+            .map(|results| (StatusCode::OK, Json(results)).into_response())
+    };
+
+    match results {
+        Ok(response) => response,
+        Err(SearchError::Superseded) => {
+            debug!("search superseded for {}", search_request.query);
+            StatusCode::CONFLICT.into_response()
+        }
         Err(_) => {
             error!("search failed for {}", search_request.query);
             StatusCode::INTERNAL_SERVER_ERROR.into_response()
@@ -176,3 +1149,667 @@ pub async fn search(
     }
 }
 
+/// Streams search results as one JSON object per line. The status is sent
+/// before the search ends, so a failure arrives as a final `{"error": ...}`
+/// line instead.
+fn ndjson_results(results: mpsc::Receiver<Result<SearchResult, SearchError>>) -> Response {
+    let lines = ReceiverStream::new(results).map(|result| {
+        let line = result
+            .and_then(|result| serde_json::to_string(&result).map_err(SearchError::from))
+            .unwrap_or_else(|err| serde_json::json!({ "error": err.to_string() }).to_string());
+        Ok::<_, Infallible>(format!("{}\n", line))
+    });
+    (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn error_of(response: Response) -> (StatusCode, String) {
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        (status, body["error"].as_str().unwrap().to_string())
+    }
+
+    #[tokio::test]
+    async fn unqueued_songs_are_not_accepted() {
+        let (status, error) = error_of(queue_song_error_response(
+            SongCoordinatorError::QueueFull { max_length: 20 }.into(),
+        ))
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(error, "queue is full, max length: 20");
+
+        let (status, _) = error_of(queue_song_error_response(
+            SongCoordinatorError::SongAlreadyQueued {
+                name: "Song".to_string(),
+            }
+            .into(),
+        ))
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (status, error) = error_of(queue_song_error_response(
+            SongCoordinatorError::ActorUnavailable.into(),
+        ))
+        .await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(error, "song actor is unavailable");
+
+        let (status, _) = error_of(queue_song_error_response(
+            QueueSongError::DownloadQueueFull { backlog: 100 },
+        ))
+        .await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        let (status, error) = error_of(queue_song_error_response(
+            QueueSongError::ClientQuotaFull {
+                client: IpAddr::from([192, 168, 1, 10]),
+                max_per_client: 3,
+            },
+        ))
+        .await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(error, "192.168.1.10 already has 3 downloads running");
+
+        let (status, error) = error_of(queue_song_error_response(QueueSongError::InvalidKey {
+            key: 99,
+            range: KeyRange::new(4),
+        }))
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error, "preferred key 99 is outside -4 to 4");
+
+        let (status, _) = error_of(queue_song_error_response(
+            SongCoordinatorError::GetQueueFailed.into(),
+        ))
+        .await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    fn song(name: &str) -> Song {
+        Song::new(
+            name.to_string(),
+            format!("https://www.youtube.com/watch?v={}", name),
+            QueuedSongStatus::InProgress,
+            true,
+            None,
+        )
+    }
+
+    async fn queued_songs(names: &[&str]) -> Arc<SongActorHandle> {
+        let room = crate::rooms::tests::rooms(1).get("default").unwrap();
+        for name in names {
+            room.song_actor_handle.queue_song(song(name)).await.unwrap();
+        }
+        room.song_actor_handle
+    }
+
+    async fn list(
+        songs: &Arc<SongActorHandle>,
+        fields: SongListFields,
+        headers: HeaderMap,
+    ) -> Response {
+        let query = SongListQuery {
+            sort: SongListOrder::Queue,
+            fields,
+        };
+        song_list(RoomActor(songs.clone()), headers, ValidQuery(query))
+            .await
+            .into_response()
+    }
+
+    #[tokio::test]
+    async fn summaries_carry_only_uuid_name_and_status() {
+        let songs = queued_songs(&["a", "b"]).await;
+        let response = list(&songs, SongListFields::Summary, HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: Vec<serde_json::Map<String, serde_json::Value>> =
+            serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.len(), 2);
+        for summary in &body {
+            let mut keys = summary.keys().collect::<Vec<_>>();
+            keys.sort();
+            assert_eq!(keys, ["name", "status", "uuid"]);
+        }
+        assert_eq!(body[1]["name"], "b");
+    }
+
+    #[tokio::test]
+    async fn unchanged_lists_are_not_modified() {
+        let songs = queued_songs(&["a"]).await;
+        let response = list(&songs, SongListFields::Full, HeaderMap::new()).await;
+        let etag = response.headers()[header::ETAG].clone();
+        assert!(etag.to_str().unwrap().starts_with("W/\""));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, etag.clone());
+        let response = list(&songs, SongListFields::Full, headers.clone()).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag);
+
+        // any edit changes the tag
+        songs.queue_song(song("b")).await.unwrap();
+        let response = list(&songs, SongListFields::Full, headers).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers()[header::ETAG], etag);
+    }
+
+    #[tokio::test]
+    async fn up_next_is_the_song_after_the_current_one() {
+        let songs = queued_songs(&["playing"]).await;
+        let response = up_next(RoomActor(songs.clone())).await.into_response();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        songs.queue_song(song("next")).await.unwrap();
+        songs.queue_song(song("later")).await.unwrap();
+        let response = up_next(RoomActor(songs.clone())).await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["name"], "next");
+
+        let snapshot = songs.get_session().await.unwrap();
+        assert_eq!(snapshot.up_next.unwrap().name, "next");
+    }
+
+    #[tokio::test]
+    async fn failed_songs_retry_in_place() {
+        use crate::actors::video_downloader::tests::{handle, write_last_segment, write_status};
+
+        let assets = tempfile::tempdir().unwrap();
+        let videodl = Arc::new(handle(assets.path()));
+        let songs = queued_songs(&["first"]).await;
+        let mut failed = song("failed");
+        failed.is_key_changeable = false;
+        songs.queue_song(failed.clone()).await.unwrap();
+        songs.queue_song(song("last")).await.unwrap();
+        let reason = "timed out".to_string();
+        songs.fail_song(failed.uuid, reason).await.unwrap();
+
+        // already downloaded, so the retry succeeds without yt-dlp
+        let dir = assets.path().join(&failed.folder);
+        write_status(
+            &dir,
+            serde_json::json!({
+                "display_name": "failed",
+                "yt_link": failed.yt_link,
+                "segments": 1,
+                "is_key_changeable": false,
+            }),
+        );
+        write_last_segment(&dir, 1);
+
+        let retry = || {
+            let request = RetrySongRequest {
+                song_uuid: failed.uuid.to_string(),
+            };
+            retry_song(
+                RoomActor(songs.clone()),
+                State(videodl.clone()),
+                ValidJson(request),
+            )
+        };
+        assert_eq!(retry().await.status(), StatusCode::ACCEPTED);
+
+        let mut status = QueuedSongStatus::InProgress;
+        for _ in 0..100 {
+            status = songs.get_song(failed.uuid).await.unwrap().unwrap().status;
+            if matches!(status, QueuedSongStatus::Success | QueuedSongStatus::Failed) {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert!(status == QueuedSongStatus::Success);
+        let queue = songs.get_queue().await.unwrap();
+        assert_eq!(queue[1].uuid, failed.uuid);
+
+        // only failed songs can be retried
+        assert_eq!(retry().await.status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn queueing_is_rejected_while_every_dispatch_is_pending() {
+        let assets = tempfile::tempdir().unwrap();
+        let settings = crate::settings::DownloadSettings {
+            max_pending: 2,
+            ..Default::default()
+        };
+        let videodl = Arc::new(crate::actors::video_downloader::tests::handle_with(
+            assets.path(),
+            settings,
+        ));
+        let songs = queued_songs(&[]).await;
+        let title_cleaner = TitleCleaner::new(&Default::default()).unwrap();
+        let enqueue = |name: &str| {
+            let payload = QueueSong {
+                name: name.to_string(),
+                yt_link: format!("https://www.youtube.com/watch?v={}", name.len()),
+                is_key_changeable: None,
+                start: None,
+                end: None,
+                preferred_key: None,
+            };
+            let songs = songs.clone();
+            enqueue_song(songs, videodl.clone(), false, &title_cleaner, None, payload)
+        };
+
+        // downloads still waiting to be dispatched
+        let mut held = Vec::new();
+        for _ in 0..2 {
+            held.push(videodl.reserve_dispatch().await);
+        }
+        assert_eq!(videodl.pending_dispatches(), (2, 2));
+        assert!(matches!(
+            enqueue("Rejected").await,
+            Err(QueueSongError::DownloadQueueFull { backlog: 2 })
+        ));
+        assert!(songs.get_queue().await.unwrap().is_empty());
+
+        drop(held);
+        assert!(enqueue("Accepted").await.is_ok());
+        assert_eq!(songs.get_queue().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn cached_songs_are_reported_without_queueing() {
+        use crate::{
+            actors::video_downloader::tests::{handle, write_last_segment, write_status},
+            utils::slug::song_key,
+        };
+
+        const PLAIN: &str = "https://www.youtube.com/watch?v=aaaaaaaaaaa";
+        const CHANGEABLE: &str = "https://www.youtube.com/watch?v=bbbbbbbbbbb";
+        const MISSING: &str = "https://www.youtube.com/watch?v=ccccccccccc";
+        let assets = tempfile::tempdir().unwrap();
+        let videodl = Arc::new(handle(assets.path()));
+        for (name, link, is_key_changeable) in [
+            ("Plain Song", PLAIN, false),
+            ("Changeable Song", CHANGEABLE, true),
+        ] {
+            let dir = assets.path().join(song_key(name, link));
+            let status = serde_json::json!({
+                "display_name": name,
+                "yt_link": link,
+                "segments": 2,
+                "is_key_changeable": is_key_changeable,
+            });
+            write_status(&dir, status);
+            write_last_segment(&dir, 2);
+        }
+
+        // looked up by the link alone, whatever title it was queued with
+        let cache_state = |link: &str| {
+            let query = IsCachedQuery {
+                yt_link: link.to_string(),
+                start: None,
+                end: None,
+            };
+            is_cached(State(videodl.clone()), ValidQuery(query))
+        };
+        for (link, cached, key_changeable) in [
+            (PLAIN, true, false),
+            (CHANGEABLE, true, true),
+            (MISSING, false, false),
+        ] {
+            let Json(state) = cache_state(link).await;
+            assert_eq!(state.cached, cached, "{}", link);
+            assert_eq!(state.key_changeable, key_changeable, "{}", link);
+        }
+        assert!(videodl.backlog() == 0 && videodl.active_downloads().is_empty());
+    }
+
+    #[tokio::test]
+    async fn long_titles_are_shortened_for_display_only() {
+        use crate::{
+            actors::video_downloader::tests::handle,
+            utils::slug::{is_song_key, song_key},
+        };
+
+        let assets = tempfile::tempdir().unwrap();
+        let videodl = Arc::new(handle(assets.path()));
+        let songs = queued_songs(&[]).await;
+        let title_cleaner = TitleCleaner::new(&Default::default()).unwrap();
+        let long_title = format!("Artist - {}", "Very Long Song Title ".repeat(20));
+        let payload = QueueSong {
+            name: long_title.clone(),
+            yt_link: "https://www.youtube.com/watch?v=aaaaaaaaaaa".to_string(),
+            is_key_changeable: None,
+            start: None,
+            end: None,
+            preferred_key: None,
+        };
+
+        enqueue_song(songs.clone(), videodl, false, &title_cleaner, None, payload)
+            .await
+            .unwrap();
+        let queued = songs.get_queue().await.unwrap().pop_front().unwrap();
+        assert_eq!(queued.name.chars().count(), 150);
+        assert!(queued.name.ends_with('…'));
+        assert_eq!(queued.original_name.as_deref(), Some(long_title.as_str()));
+        // the folder is named after the full title, cut to a safe length
+        assert_eq!(queued.folder, song_key(&long_title, &queued.yt_link));
+        assert!(is_song_key(&queued.folder));
+        assert!(queued.folder.len() < 100);
+    }
+
+    #[tokio::test]
+    async fn downloads_of_edited_songs_are_superseded() {
+        let songs = queued_songs(&["playing", "queued"]).await;
+        let dispatched = songs.get_queue().await.unwrap()[1].clone();
+        let is_edited = |downloaded: Song| {
+            let songs = songs.clone();
+            async move {
+                matches!(
+                    download_target(&songs, &downloaded).await,
+                    DownloadTarget::Edited
+                )
+            }
+        };
+        assert!(!is_edited(dispatched.clone()).await);
+
+        // renamed and back, so only the dispatch tells the downloads apart
+        for name in ["renamed", "queued"] {
+            let name = Some(name.to_string());
+            let updated = songs.update_song(dispatched.uuid, name, None, None).await;
+            assert!(updated.unwrap().needs_download);
+        }
+        assert!(is_edited(dispatched.clone()).await);
+
+        let reason = "timed out".to_string();
+        songs.fail_song(dispatched.uuid, reason).await.unwrap();
+        let before_retry = songs.get_song(dispatched.uuid).await.unwrap().unwrap();
+        let retried = songs.retry_song(dispatched.uuid).await.unwrap();
+        assert!(is_edited(before_retry).await);
+        assert!(!is_edited(retried).await);
+    }
+
+    #[tokio::test]
+    async fn exported_sessions_import_with_cleaned_titles() {
+        use crate::actors::video_downloader::tests::handle;
+
+        let assets = tempfile::tempdir().unwrap();
+        let videodl = Arc::new(handle(assets.path()));
+        let songs = queued_songs(&[]).await;
+        let settings = Settings::default();
+        let title_cleaner = Arc::new(
+            TitleCleaner::new(&QueueSettings {
+                clean_titles: true,
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let noisy = "Artist - Song (Official Video) [HD]";
+        enqueue_song(
+            songs.clone(),
+            videodl.clone(),
+            false,
+            &title_cleaner,
+            None,
+            queue_request(noisy),
+        )
+        .await
+        .unwrap();
+        let queued = songs.get_queue().await.unwrap().pop_front().unwrap();
+
+        let response = export_session(RoomActor(songs.clone()))
+            .await
+            .unwrap()
+            .into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let session: SessionExport = serde_json::from_slice(&body).unwrap();
+        assert_eq!(session.songs[0].song.name, noisy);
+
+        let imported = import_session(
+            RoomActor(songs.clone()),
+            State(videodl),
+            State(Arc::new(settings)),
+            State(title_cleaner),
+            ValidJson(session),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        assert_eq!(imported.status(), StatusCode::ACCEPTED);
+
+        let reimported = songs.get_queue().await.unwrap().pop_front().unwrap();
+        assert_ne!(reimported.uuid, queued.uuid);
+        assert_eq!(reimported.name, "Artist - Song");
+        assert_eq!(reimported.name, queued.name);
+        assert_eq!(reimported.original_name.as_deref(), Some(noisy));
+        assert_eq!(reimported.folder, queued.folder);
+    }
+
+    #[tokio::test]
+    async fn restored_pins_are_cleaned_like_queued_songs() {
+        use crate::actors::video_downloader::tests::handle;
+
+        let assets = tempfile::tempdir().unwrap();
+        let videodl = Arc::new(handle(assets.path()));
+        let title_cleaner = TitleCleaner::new(&QueueSettings {
+            clean_titles: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let noisy = "Artist - Song (Official Video) [HD]";
+        let mut queued = Song::new(
+            noisy.to_string(),
+            "https://www.youtube.com/watch?v=song".to_string(),
+            QueuedSongStatus::Success,
+            false,
+            None,
+        );
+        clean_title(&title_cleaner, &mut queued);
+        // pinned before the cleaner ran, with only the title as queued
+        let legacy = serde_json::from_value::<PinnedSong>(serde_json::json!({
+            "name": "Other - Song (Lyrics)",
+            "yt_link": "https://www.youtube.com/watch?v=other",
+            "is_key_changeable": false,
+            "trim": null,
+            "preferred_key": null,
+        }))
+        .unwrap();
+
+        let songs = queued_songs(&[]).await;
+        let pins = vec![PinnedSong::from_song(&queued), legacy];
+        restore_pins(songs.clone(), videodl, &title_cleaner, pins).await;
+
+        let restored = songs.get_queue().await.unwrap();
+        assert_eq!(restored[0].name, "Artist - Song");
+        assert_eq!(restored[0].original_name.as_deref(), Some(noisy));
+        assert_eq!(restored[0].folder, queued.folder);
+        assert_eq!(restored[1].name, "Other - Song");
+        assert_eq!(restored[1].original_name.as_deref(), Some("Other - Song (Lyrics)"));
+    }
+
+    fn queue_request(name: &str) -> QueueSong {
+        QueueSong {
+            name: name.to_string(),
+            yt_link: format!("https://www.youtube.com/watch?v={}", name.replace(' ', "")),
+            is_key_changeable: None,
+            start: None,
+            end: None,
+            preferred_key: None,
+        }
+    }
+
+    async fn downloading(songs: &SongActorHandle) -> Vec<String> {
+        let queue = songs.get_queue().await.unwrap();
+        queue
+            .into_iter()
+            .filter(|song| song.status == QueuedSongStatus::Downloading)
+            .map(|song| song.name)
+            .collect()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn bursts_of_queue_requests_spawn_at_most_max_pending_downloads() {
+        let assets = tempfile::tempdir().unwrap();
+        let settings = crate::settings::DownloadSettings {
+            max_pending: 3,
+            max_per_client: 1,
+            ..Default::default()
+        };
+        let videodl = Arc::new(crate::actors::video_downloader::tests::stalled_handle(
+            assets.path(),
+            settings,
+        ));
+        let songs = queued_songs(&[]).await;
+        let title_cleaner = TitleCleaner::new(&Default::default()).unwrap();
+        let guest = IpAddr::from([192, 168, 1, 10]);
+
+        // half from one guest, whose songs past its first are over its quota,
+        // and the rest from guests queueing one each
+        let requests = (0..8).map(|n| {
+            let client = if n < 4 { guest } else { IpAddr::from([192, 168, 1, 11 + n]) };
+            let payload = queue_request(&format!("Song {}", n));
+            let (songs, videodl) = (songs.clone(), videodl.clone());
+            enqueue_song(songs, videodl, false, &title_cleaner, Some(client), payload)
+        });
+        let results = futures_util::future::join_all(requests).await;
+
+        let accepted = results.iter().filter(|result| result.is_ok()).count();
+        assert_eq!(accepted, 3);
+        assert!(results.iter().all(|result| matches!(
+            result,
+            Ok(_)
+                | Err(QueueSongError::DownloadQueueFull { .. })
+                | Err(QueueSongError::ClientQuotaFull { .. })
+        )));
+        assert_eq!(songs.get_queue().await.unwrap().len(), 3);
+        // every spawned task holds a permit until it ends
+        assert_eq!(videodl.pending_dispatches(), (3, 3));
+
+        std::fs::write(assets.path().join("go"), "").unwrap();
+        for _ in 0..200 {
+            if videodl.pending_dispatches().0 == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(videodl.pending_dispatches(), (0, 3));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn one_client_downloads_at_most_max_per_client_songs_at_once() {
+        let assets = tempfile::tempdir().unwrap();
+        let settings = crate::settings::DownloadSettings {
+            max_pending: 3,
+            max_per_client: 2,
+            ..Default::default()
+        };
+        let videodl = Arc::new(crate::actors::video_downloader::tests::stalled_handle(
+            assets.path(),
+            settings,
+        ));
+        let songs = queued_songs(&[]).await;
+        let title_cleaner = TitleCleaner::new(&Default::default()).unwrap();
+        let enqueue = |name: &str, client: [u8; 4]| {
+            let payload = queue_request(name);
+            let client = Some(IpAddr::from(client));
+            let (songs, videodl) = (songs.clone(), videodl.clone());
+            enqueue_song(songs, videodl, false, &title_cleaner, client, payload)
+        };
+        let wait_for_downloads = |count: usize| {
+            let songs = songs.clone();
+            async move {
+                for _ in 0..200 {
+                    if downloading(&songs).await.len() >= count {
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+                downloading(&songs).await
+            }
+        };
+
+        for name in ["Guest A", "Guest B"] {
+            enqueue(name, [192, 168, 1, 10]).await.unwrap();
+        }
+        assert_eq!(wait_for_downloads(2).await.len(), 2);
+        // over the quota, rejected without taking a pending download
+        for name in ["Guest C", "Guest D", "Guest E"] {
+            let result = enqueue(name, [192, 168, 1, 10]).await;
+            assert!(matches!(
+                result,
+                Err(QueueSongError::ClientQuotaFull { max_per_client: 2, .. })
+            ));
+        }
+        assert_eq!(songs.get_queue().await.unwrap().len(), 2);
+        assert_eq!(videodl.pending_dispatches(), (2, 3));
+
+        enqueue("Other guest", [192, 168, 1, 11]).await.unwrap();
+        let downloads = wait_for_downloads(3).await;
+        assert_eq!(downloads.len(), 3);
+        assert!(downloads.contains(&"Other guest".to_string()));
+
+        std::fs::write(assets.path().join("go"), "").unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn a_batch_longer_than_the_quota_takes_one_slot() {
+        let assets = tempfile::tempdir().unwrap();
+        let download = crate::settings::DownloadSettings {
+            max_per_client: 2,
+            ..Default::default()
+        };
+        let videodl = Arc::new(crate::actors::video_downloader::tests::stalled_handle(
+            assets.path(),
+            download,
+        ));
+        let songs = queued_songs(&[]).await;
+        let settings = Arc::new(Settings::default());
+        let title_cleaner = Arc::new(TitleCleaner::new(&Default::default()).unwrap());
+        let guest = IpAddr::from([192, 168, 1, 10]);
+        let batch = |names: &[&str]| {
+            queue_song_batch(
+                RoomActor(songs.clone()),
+                State(videodl.clone()),
+                State(settings.clone()),
+                State(title_cleaner.clone()),
+                ClientIp(guest),
+                ValidJson(names.iter().map(|name| queue_request(name)).collect()),
+            )
+        };
+
+        let setlist = ["First", "Second", "Third", "Fourth", "Fifth"];
+        let response = batch(&setlist).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let results: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        assert!(results.iter().all(|result| result.get("uuid").is_some()));
+        assert_eq!(songs.get_queue().await.unwrap().len(), setlist.len());
+
+        // the guest's second slot is still free, a third batch is over quota
+        let title_cleaner = TitleCleaner::new(&Default::default()).unwrap();
+        let payload = queue_request("Single");
+        enqueue_song(songs.clone(), videodl.clone(), false, &title_cleaner, Some(guest), payload)
+            .await
+            .unwrap();
+        let response = batch(&["Encore"]).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        std::fs::write(assets.path().join("go"), "").unwrap();
+    }
+}
@@ -1,6 +1,9 @@
 pub mod admin;
+pub mod auth;
+pub mod extract;
 pub mod healthcheck;
 pub mod karaoke;
+pub mod rate_limit;
 pub mod sse;
 pub mod streaming;
 pub mod sys;
\ No newline at end of file
@@ -0,0 +1,196 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tracing::warn;
+
+use crate::routes::extract::client_ip;
+
+// buckets are pruned once this many clients have been seen
+const MAX_TRACKED_CLIENTS: usize = 1024;
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// A token bucket per client IP. Each request takes a token, and tokens
+/// refill at `per_minute` up to `burst`. Requests through one of
+/// `trusted_proxies` count toward the client it forwarded them for.
+pub struct RateLimiter {
+    burst: f64,
+    per_second: f64,
+    trusted_proxies: Vec<IpAddr>,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32, burst: u32, trusted_proxies: Vec<IpAddr>) -> Self {
+        RateLimiter {
+            burst: burst.max(1) as f64,
+            per_second: per_minute as f64 / 60.0,
+            trusted_proxies,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for `ip`, or returns how many seconds until one is free.
+    fn acquire(&self, ip: IpAddr) -> Result<(), u64> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(&ip) {
+            // a full bucket behaves the same as a missing one
+            buckets.retain(|_, bucket| {
+                let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+                bucket.tokens + elapsed * self.per_second < self.burst
+            });
+            // still too many clients mid-burst, so the longest idle ones go
+            if buckets.len() >= MAX_TRACKED_CLIENTS {
+                let mut idle_since: Vec<_> = buckets
+                    .iter()
+                    .map(|(ip, bucket)| (bucket.refilled_at, *ip))
+                    .collect();
+                idle_since.sort_unstable();
+                let excess = buckets.len() + 1 - MAX_TRACKED_CLIENTS;
+                for (_, ip) in idle_since.into_iter().take(excess) {
+                    buckets.remove(&ip);
+                }
+            }
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            refilled_at: now,
+        });
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / self.per_second).ceil() as u64)
+        }
+    }
+}
+
+/// Rejects requests over the limit with `429 Too Many Requests` and a
+/// `Retry-After` header. A limiter with a zero rate lets everything through.
+pub async fn rate_limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let client = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(peer)| client_ip(peer.ip(), request.headers(), &limiter.trusted_proxies));
+
+    if let Some(ip) = client.filter(|_| limiter.per_second > 0.0) {
+        if let Err(retry_after) = limiter.acquire(ip) {
+            warn!(
+                "rate limiting {} on {}, retry in {}s",
+                ip,
+                request.uri().path(),
+                retry_after
+            );
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+            )
+                .into_response();
+        }
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forwarded_clients_get_their_own_buckets() {
+        let proxy: IpAddr = "127.0.0.1".parse().unwrap();
+        let limiter = RateLimiter::new(60, 1, vec![proxy]);
+        let mut headers = axum::http::HeaderMap::new();
+
+        for client in ["203.0.113.7", "203.0.113.8"] {
+            headers.insert("x-forwarded-for", client.parse().unwrap());
+            let ip = client_ip(proxy, &headers, &limiter.trusted_proxies);
+            assert!(limiter.acquire(ip).is_ok());
+            assert_eq!(limiter.acquire(ip), Err(1));
+        }
+    }
+
+    #[test]
+    fn the_longest_idle_clients_are_evicted_past_the_limit() {
+        // no refill, so no bucket is ever full again and pruning keeps all
+        let limiter = RateLimiter::new(0, 2, Vec::new());
+        let client = |n: usize| IpAddr::from((n as u32).to_be_bytes());
+        for n in 0..MAX_TRACKED_CLIENTS + 10 {
+            let _ = limiter.acquire(client(n));
+        }
+
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), MAX_TRACKED_CLIENTS);
+        assert!(!buckets.contains_key(&client(0)));
+        assert!(buckets.contains_key(&client(MAX_TRACKED_CLIENTS + 9)));
+    }
+
+    async fn hammer(limiter: RateLimiter, peer: &str, requests: usize) -> Vec<Response> {
+        use tower::ServiceExt;
+
+        let router = axum::Router::new().route(
+            "/search",
+            axum::routing::get(|| async { "results" }).layer(axum::middleware::from_fn_with_state(
+                Arc::new(limiter),
+                rate_limit,
+            )),
+        );
+        let peer: SocketAddr = peer.parse().unwrap();
+
+        let mut responses = Vec::new();
+        for _ in 0..requests {
+            let mut request = Request::builder()
+                .uri("/search")
+                .body(axum::body::Body::empty())
+                .unwrap();
+            request.extensions_mut().insert(ConnectInfo(peer));
+            responses.push(router.clone().oneshot(request).await.unwrap());
+        }
+        responses
+    }
+
+    #[tokio::test]
+    async fn requests_past_the_burst_are_throttled() {
+        let responses = hammer(RateLimiter::new(6, 3, Vec::new()), "192.0.2.1:5000", 5).await;
+        let statuses = responses
+            .iter()
+            .map(|response| response.status())
+            .collect::<Vec<_>>();
+        assert_eq!(statuses[..3], [StatusCode::OK; 3]);
+        assert_eq!(statuses[3..], [StatusCode::TOO_MANY_REQUESTS; 2]);
+        // one token every 10 seconds
+        assert_eq!(responses[3].headers()[header::RETRY_AFTER], "10");
+    }
+
+    #[tokio::test]
+    async fn zero_rate_disables_the_limit() {
+        let responses = hammer(RateLimiter::new(0, 1, Vec::new()), "192.0.2.1:5000", 5).await;
+        assert!(responses
+            .iter()
+            .all(|response| response.status() == StatusCode::OK));
+    }
+}
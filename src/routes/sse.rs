@@ -1,38 +1,532 @@
-use std::{collections::VecDeque, convert::Infallible, sync::Arc};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use crate::actors::song_coordinator::Song;
+use crate::{
+    actors::song_coordinator::Song, rooms::RoomEvents, settings::Settings, shutdown::Shutdown,
+};
 use axum::{
     extract::State,
+    http::HeaderMap,
     response::{
         sse::{Event, KeepAlive},
         Sse,
     },
 };
 use futures_util::{stream, StreamExt};
-use tokio::sync;
+use tracing::debug;
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    time::Instant,
+};
 
 #[derive(Clone, serde::Serialize)]
 #[serde(tag = "type")]
 pub enum SseEvent {
-    QueueUpdated { queue: VecDeque<Song> },
-    KeyChange { current_key: i8 },
+    QueueUpdated {
+        queue: VecDeque<Song>,
+    },
+    KeyChange {
+        current_key: i8,
+    },
     TogglePlayback,
     RestartSong,
+    PlaybackPosition {
+        uuid: String,
+        position_seconds: f64,
+    },
+    AutoplayToggled {
+        enabled: bool,
+    },
+    // autoplay moves past the song in `in_seconds`, clients can fade it out
+    SongEnding {
+        uuid: String,
+        in_seconds: f64,
+    },
+    SongFailed {
+        uuid: String,
+        reason: String,
+    },
+    // a key outside the pre-generated set was encoded, reload the manifest
+    PitchShiftsExtended {
+        uuid: String,
+        audio_adaptation_sets: BTreeMap<i32, usize>,
+    },
+    ServerRestarting,
+    // an admin removed the room, clients should go back to the default one
+    RoomClosed,
 }
 
-pub async fn sse(
-    State(sse_broadcaster): State<Arc<sync::broadcast::Sender<SseEvent>>>,
-) -> Sse<impl stream::Stream<Item = Result<Event, Infallible>>> {
-    let stream = tokio_stream::wrappers::BroadcastStream::new(sse_broadcaster.subscribe())
-        .filter_map(|result| async move {
-            match result {
-                Ok(sse_event) => {
-                    let event_json = serde_json::to_string(&sse_event).ok()?;
-                    Some(Ok(Event::default().data(event_json)))
+impl SseEvent {
+    /// Whether a reconnecting client should be sent the event again. Position
+    /// ticks are superseded every interval and would crowd out the rest, and
+    /// an ending notice is stale by the time a client reconnects.
+    fn is_replayable(&self) -> bool {
+        !matches!(
+            self,
+            SseEvent::PlaybackPosition { .. } | SseEvent::SongEnding { .. }
+        )
+    }
+}
+
+/// An event with the id clients echo back in `Last-Event-ID`.
+type NumberedEvent = (u64, SseEvent);
+
+struct ReplayBuffer {
+    next_id: u64,
+    events: VecDeque<NumberedEvent>,
+    capacity: usize,
+    // the newest replayable event that is no longer buffered
+    dropped_through: Option<u64>,
+    // sent instead of the missed events when those were dropped
+    latest_queue: Option<NumberedEvent>,
+}
+
+impl ReplayBuffer {
+    /// The buffered events from `first_id` on. When some of them were
+    /// already dropped, the latest queue is sent ahead of the rest in case
+    /// a queue update was among them.
+    fn missed_from(&self, first_id: u64) -> VecDeque<NumberedEvent> {
+        let mut missed: VecDeque<NumberedEvent> = self
+            .events
+            .iter()
+            .filter(|(id, _)| *id >= first_id)
+            .cloned()
+            .collect();
+        let dropped = self
+            .dropped_through
+            .is_some_and(|dropped| dropped >= first_id);
+        if let Some(snapshot @ (snapshot_id, _)) = &self.latest_queue {
+            let buffered = missed.iter().any(|(id, _)| id == snapshot_id);
+            if dropped && *snapshot_id >= first_id && !buffered {
+                missed.push_front(snapshot.clone());
+            }
+        }
+        missed
+    }
+}
+
+/// Broadcasters started so far, so a room recreated under the same name
+/// doesn't reuse the ids of the one before it.
+static BROADCASTERS: AtomicU64 = AtomicU64::new(0);
+
+/// Sends a room's events to its SSE clients, numbering each one and keeping
+/// the latest in a bounded buffer so a reconnecting client can catch up from
+/// its `Last-Event-ID`.
+pub struct SseBroadcaster {
+    sender: broadcast::Sender<NumberedEvent>,
+    replay: Mutex<ReplayBuffer>,
+    // prefixed to event ids, so ids from before a restart aren't mistaken
+    // for current ones
+    epoch: String,
+}
+
+impl SseBroadcaster {
+    /// A broadcaster replaying up to `replay_capacity` events. The channel
+    /// to the clients is as big, so a client that lags behind it can still
+    /// be caught up from the buffer.
+    pub fn new(replay_capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(replay_capacity.max(1));
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        SseBroadcaster {
+            sender,
+            replay: Mutex::new(ReplayBuffer {
+                next_id: 0,
+                events: VecDeque::with_capacity(replay_capacity),
+                capacity: replay_capacity,
+                dropped_through: None,
+                latest_queue: None,
+            }),
+            epoch: format!(
+                "{:x}.{}",
+                started,
+                BROADCASTERS.fetch_add(1, Ordering::Relaxed)
+            ),
+        }
+    }
+
+    /// The `id` field of an event, `<epoch>-<number>`.
+    fn event_id(&self, id: u64) -> String {
+        format!("{}-{}", self.epoch, id)
+    }
+
+    /// The event number of a `Last-Event-ID` this broadcaster handed out.
+    fn parse_event_id(&self, value: &str) -> Option<u64> {
+        let (epoch, id) = value.rsplit_once('-')?;
+        (epoch == self.epoch).then(|| id.parse().ok()).flatten()
+    }
+
+    /// Sends an event to the connected clients and keeps it for replay.
+    /// Having no clients is the normal idle state, so it isn't reported.
+    pub fn send(&self, event: SseEvent) {
+        // numbering, buffering and sending happen under the lock so replayed
+        // and live events can't interleave out of order
+        let mut replay = self.replay.lock().unwrap();
+        let id = replay.next_id;
+        replay.next_id += 1;
+        if matches!(event, SseEvent::QueueUpdated { .. }) {
+            replay.latest_queue = Some((id, event.clone()));
+        }
+        if event.is_replayable() {
+            if replay.capacity == 0 {
+                replay.dropped_through = Some(id);
+            } else {
+                if replay.events.len() >= replay.capacity {
+                    replay.dropped_through = replay.events.pop_front().map(|(id, _)| id);
                 }
-                Err(_) => None,
+                replay.events.push_back((id, event.clone()));
             }
-        });
+        }
+        let _ = self.sender.send((id, event));
+    }
+
+    /// Subscribes to new events, returning the buffered ones after
+    /// `last_event_id` to send first, and the id of the first event the
+    /// receiver gets. When the id isn't one of ours (e.g. from before a
+    /// restart), the latest queue is sent instead, followed by whatever is
+    /// buffered after it. A new connection replays nothing.
+    pub(crate) fn subscribe_after(
+        &self,
+        last_event_id: Option<&str>,
+    ) -> (VecDeque<NumberedEvent>, broadcast::Receiver<NumberedEvent>, u64) {
+        let replay = self.replay.lock().unwrap();
+        let missed = match last_event_id {
+            None => VecDeque::new(),
+            Some(value) => match self.parse_event_id(value) {
+                Some(last_id) if last_id < replay.next_id => replay.missed_from(last_id + 1),
+                _ => match &replay.latest_queue {
+                    Some(snapshot @ (snapshot_id, _)) => {
+                        let mut missed = replay.missed_from(snapshot_id + 1);
+                        missed.push_front(snapshot.clone());
+                        missed
+                    }
+                    None => replay.events.clone(),
+                },
+            },
+        };
+        (missed, self.sender.subscribe(), replay.next_id)
+    }
+
+    /// The buffered events from `first_id` on, for a client that fell
+    /// behind the channel.
+    fn missed_from(&self, first_id: u64) -> VecDeque<NumberedEvent> {
+        self.replay.lock().unwrap().missed_from(first_id)
+    }
+
+    /// Number of clients subscribed to the events.
+    pub fn subscribers(&self) -> usize {
+        self.sender.receiver_count()
+    }
+
+    /// The buffered events and the buffer's capacity.
+    pub fn replay_occupancy(&self) -> (usize, usize) {
+        let replay = self.replay.lock().unwrap();
+        (replay.events.len(), replay.capacity)
+    }
+}
+
+/// A client's subscription, holding back an event that arrived while a burst
+/// of queue updates was being coalesced.
+struct Subscription {
+    broadcaster: Arc<SseBroadcaster>,
+    receiver: broadcast::Receiver<NumberedEvent>,
+    // missed events, from before a reconnect or while lagging behind the
+    // channel, sent ahead of new ones
+    replay: VecDeque<NumberedEvent>,
+    // the first event the client hasn't been sent from the channel
+    next_id: u64,
+    held: Option<NumberedEvent>,
+    coalesce_window: Duration,
+}
+
+impl Subscription {
+    fn new(
+        broadcaster: Arc<SseBroadcaster>,
+        last_event_id: Option<&str>,
+        coalesce_window: Duration,
+    ) -> Self {
+        let (replay, receiver, next_id) = broadcaster.subscribe_after(last_event_id);
+        Subscription {
+            broadcaster,
+            receiver,
+            replay,
+            next_id,
+            held: None,
+            coalesce_window,
+        }
+    }
+
+    async fn recv(&mut self) -> Option<NumberedEvent> {
+        if let Some(event) = self.replay.pop_front() {
+            return Some(event);
+        }
+        loop {
+            match self.receiver.recv().await {
+                // already sent from the buffer after a lag
+                Ok((id, _)) if id < self.next_id => continue,
+                Ok(event) => {
+                    self.next_id = event.0 + 1;
+                    return Some(event);
+                }
+                // a lagging client is caught up from the replay buffer,
+                // anything the channel still holds after it follows
+                Err(RecvError::Lagged(skipped)) => {
+                    debug!("SSE client lagged by {} events, replaying them", skipped);
+                    self.replay = self.broadcaster.missed_from(self.next_id);
+                    if let Some((last_id, _)) = self.replay.back() {
+                        self.next_id = last_id + 1;
+                    }
+                    if let Some(event) = self.replay.pop_front() {
+                        return Some(event);
+                    }
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// The next event to send. Queue updates arriving within the window of
+    /// the first are collapsed into the latest, since each carries the whole
+    /// queue. Any other event ends the burst and is sent after it.
+    async fn next(&mut self) -> Option<NumberedEvent> {
+        if let Some(event) = self.held.take() {
+            return Some(event);
+        }
+
+        let mut event = self.recv().await?;
+        if !matches!(event.1, SseEvent::QueueUpdated { .. }) || self.coalesce_window.is_zero() {
+            return Some(event);
+        }
+
+        let deadline = Instant::now() + self.coalesce_window;
+        while let Ok(next) = tokio::time::timeout_at(deadline, self.recv()).await {
+            match next {
+                Some(next @ (_, SseEvent::QueueUpdated { .. })) => event = next,
+                Some(next) => {
+                    self.held = Some(next);
+                    break;
+                }
+                None => break,
+            }
+        }
+        Some(event)
+    }
+}
+
+pub async fn sse(
+    RoomEvents(sse_broadcaster): RoomEvents,
+    State(settings): State<Arc<Settings>>,
+    State(shutdown): State<Shutdown>,
+    headers: HeaderMap,
+) -> Sse<impl stream::Stream<Item = Result<Event, Infallible>>> {
+    // sent by EventSource when it reconnects
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok());
+    let subscription = Subscription::new(
+        sse_broadcaster.clone(),
+        last_event_id,
+        Duration::from_millis(settings.sse.queue_coalesce_ms),
+    );
+
+    let stream = stream::unfold(subscription, |mut subscription| async move {
+        let event = subscription.next().await?;
+        Some((event, subscription))
+    })
+    .filter_map(move |(id, sse_event)| {
+        let event_id = sse_broadcaster.event_id(id);
+        async move {
+            let event_json = serde_json::to_string(&sse_event).ok()?;
+            Some(Ok(Event::default().id(event_id).data(event_json)))
+        }
+    })
+    // end the stream on shutdown so graceful shutdown isn't held open
+    .take_until(async move { shutdown.wait().await });
 
     Sse::new(stream).keep_alive(KeepAlive::default())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscribers_are_counted_until_dropped() {
+        let broadcaster = SseBroadcaster::new(16);
+        assert_eq!(broadcaster.subscribers(), 0);
+
+        let subscription = broadcaster.subscribe_after(None);
+        assert_eq!(broadcaster.subscribers(), 1);
+        drop(subscription);
+        assert_eq!(broadcaster.subscribers(), 0);
+    }
+
+    fn queue_updated() -> SseEvent {
+        SseEvent::QueueUpdated {
+            queue: VecDeque::new(),
+        }
+    }
+
+    fn replayed_ids(broadcaster: &SseBroadcaster, last_event_id: Option<&str>) -> Vec<u64> {
+        let (missed, _, _) = broadcaster.subscribe_after(last_event_id);
+        missed.into_iter().map(|(id, _)| id).collect()
+    }
+
+    #[test]
+    fn buffered_events_are_replayed_after_the_last_id() {
+        let broadcaster = SseBroadcaster::new(4);
+        broadcaster.send(queue_updated());
+        broadcaster.send(SseEvent::KeyChange { current_key: 1 });
+        broadcaster.send(SseEvent::PlaybackPosition {
+            uuid: String::new(),
+            position_seconds: 1.0,
+        });
+        broadcaster.send(SseEvent::TogglePlayback);
+
+        assert!(replayed_ids(&broadcaster, None).is_empty());
+        let last_event_id = broadcaster.event_id(0);
+        assert_eq!(replayed_ids(&broadcaster, Some(&last_event_id)), [1, 3]);
+        let last_event_id = broadcaster.event_id(3);
+        assert!(replayed_ids(&broadcaster, Some(&last_event_id)).is_empty());
+    }
+
+    #[test]
+    fn evicted_events_are_replaced_by_the_latest_queue() {
+        let broadcaster = SseBroadcaster::new(2);
+        broadcaster.send(SseEvent::TogglePlayback);
+        broadcaster.send(queue_updated());
+        for current_key in 1..=3 {
+            broadcaster.send(SseEvent::KeyChange { current_key });
+        }
+
+        // events 0 to 2 were evicted, so a client that saw 0 missed the queue
+        let last_event_id = broadcaster.event_id(0);
+        let (missed, _, _) = broadcaster.subscribe_after(Some(&last_event_id));
+        let ids = missed.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        assert_eq!(ids, [1, 3, 4]);
+        assert!(matches!(missed[0].1, SseEvent::QueueUpdated { .. }));
+
+        // 3 and 4 are still buffered
+        let last_event_id = broadcaster.event_id(2);
+        assert_eq!(replayed_ids(&broadcaster, Some(&last_event_id)), [3, 4]);
+    }
+
+    #[test]
+    fn ids_from_another_epoch_get_the_latest_queue() {
+        let broadcaster = SseBroadcaster::new(4);
+        broadcaster.send(queue_updated());
+        broadcaster.send(SseEvent::TogglePlayback);
+
+        let restarted = SseBroadcaster::new(4);
+        assert_ne!(broadcaster.event_id(1), restarted.event_id(1));
+        assert_eq!(replayed_ids(&broadcaster, Some("1")), [0, 1]);
+        let last_event_id = restarted.event_id(0);
+        assert_eq!(replayed_ids(&broadcaster, Some(&last_event_id)), [0, 1]);
+    }
+
+    fn subscription(broadcaster: &Arc<SseBroadcaster>, coalesce_window: Duration) -> Subscription {
+        Subscription::new(broadcaster.clone(), None, coalesce_window)
+    }
+
+    #[tokio::test]
+    async fn queue_update_bursts_collapse_into_the_latest() {
+        let broadcaster = Arc::new(SseBroadcaster::new(16));
+        let mut subscription = subscription(&broadcaster, Duration::from_millis(50));
+        for _ in 0..3 {
+            broadcaster.send(queue_updated());
+        }
+        broadcaster.send(SseEvent::KeyChange { current_key: 1 });
+        broadcaster.send(queue_updated());
+
+        // the key change ends the burst and keeps its place
+        let ids = [
+            subscription.next().await.unwrap(),
+            subscription.next().await.unwrap(),
+            subscription.next().await.unwrap(),
+        ]
+        .map(|(id, _)| id);
+        assert_eq!(ids, [2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn zero_window_sends_every_update() {
+        let broadcaster = Arc::new(SseBroadcaster::new(16));
+        let mut subscription = subscription(&broadcaster, Duration::ZERO);
+        broadcaster.send(queue_updated());
+        broadcaster.send(queue_updated());
+
+        assert_eq!(subscription.next().await.unwrap().0, 0);
+        assert_eq!(subscription.next().await.unwrap().0, 1);
+    }
+
+    #[test]
+    fn the_replay_buffer_evicts_its_oldest_events() {
+        let broadcaster = SseBroadcaster::new(3);
+        for _ in 0..4 {
+            broadcaster.send(queue_updated());
+        }
+        // ticks aren't kept, but still take an id
+        broadcaster.send(SseEvent::PlaybackPosition {
+            uuid: String::new(),
+            position_seconds: 1.0,
+        });
+        broadcaster.send(queue_updated());
+        assert_eq!(broadcaster.replay_occupancy(), (3, 3));
+
+        let last_event_id = broadcaster.event_id(1);
+        assert_eq!(replayed_ids(&broadcaster, Some(&last_event_id)), [2, 3, 5]);
+    }
+
+    #[tokio::test]
+    async fn lagging_clients_are_caught_up_from_the_buffer() {
+        let broadcaster = Arc::new(SseBroadcaster::new(4));
+        let mut subscription = subscription(&broadcaster, Duration::ZERO);
+        broadcaster.send(SseEvent::KeyChange { current_key: 1 });
+        broadcaster.send(SseEvent::SongFailed {
+            uuid: String::new(),
+            reason: "timed out".to_string(),
+        });
+        // position ticks push both out of the channel, but not the buffer
+        for position in 0..6 {
+            broadcaster.send(SseEvent::PlaybackPosition {
+                uuid: String::new(),
+                position_seconds: position as f64,
+            });
+        }
+
+        let mut ids = Vec::new();
+        for _ in 0..6 {
+            ids.push(subscription.next().await.unwrap().0);
+        }
+        assert_eq!(ids, [0, 1, 4, 5, 6, 7]);
+
+        // once caught up, new events arrive as usual
+        broadcaster.send(SseEvent::TogglePlayback);
+        assert_eq!(subscription.next().await.unwrap().0, 8);
+    }
+
+    #[tokio::test]
+    async fn lagged_events_dropped_from_the_buffer_get_the_latest_queue() {
+        let broadcaster = Arc::new(SseBroadcaster::new(2));
+        let mut subscription = subscription(&broadcaster, Duration::ZERO);
+        broadcaster.send(queue_updated());
+        for current_key in 1..=3 {
+            broadcaster.send(SseEvent::KeyChange { current_key });
+        }
+
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            ids.push(subscription.next().await.unwrap().0);
+        }
+        assert_eq!(ids, [0, 2, 3]);
+    }
+}
@@ -1,10 +1,32 @@
 use axum::{
-    extract::Path,
-    http::StatusCode,
+    body::Body,
+    extract::{Path, State},
+    http::{header, StatusCode},
     response::{IntoResponse, Response},
+    Json,
+};
+use quick_xml::{
+    events::{BytesText, Event},
+    Reader, Writer,
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::SystemTime,
 };
-use std::path::PathBuf;
 use tokio::{fs::File, io::AsyncReadExt};
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    actors::video_downloader::VideoStatus,
+    settings::Settings,
+    utils::{
+        mpd::adaptation_set_templates,
+        slug::{self, AssetPaths},
+    },
+};
 
 #[derive(Debug)]
 pub struct FileError(std::io::Error);
@@ -19,23 +41,650 @@ impl IntoResponse for FileError {
     }
 }
 
-pub async fn serve_dash_file(Path((song_name, file)): Path<(String, String)>) -> Result<Response, FileError> {
-    let path = PathBuf::from("./")
-        .join("assets")
-        .join(&song_name)
-        .join (&file);
-
-    let mut file = File::open(&path).await.map_err(FileError)?;
-    let mut contents = vec![];
-    file.read_to_end(&mut contents).await.map_err(FileError)?;
+/// A file in a song's folder, `None` when either name could reach outside
+/// the assets dir.
+fn dash_file_path(assets: &AssetPaths, folder: &str, file: &str) -> Option<PathBuf> {
+    let plain_file = !file.is_empty() && !file.starts_with('.') && !file.contains(['/', '\\']);
+    (slug::is_song_key(folder) && plain_file).then(|| assets.song_dir(folder).join(file))
+}
 
-    let content_type = match path.extension().and_then(|ext| ext.to_str()) {
+fn content_type(path: &std::path::Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
         Some("mpd") => "application/dash+xml",
         Some("m4s") => "video/iso.segment",
         Some("mp4") => "video/mp4",
+        Some("m4a") => "audio/mp4",
+        Some("webm") => "video/webm",
+        Some("vtt") => "text/vtt",
         _ => "application/octet-stream",
+    }
+}
+
+struct CachedManifest {
+    modified: SystemTime,
+    contents: Vec<u8>,
+}
+
+/// Rewritten manifests by base path and song folder, invalidated when the
+/// file on disk changes (e.g. the song was re-downloaded) and forgotten when
+/// the folder is deleted.
+#[derive(Clone, Default)]
+pub struct ManifestCache(Arc<Mutex<HashMap<(String, String), CachedManifest>>>);
+
+impl ManifestCache {
+    /// Forgets the manifests of a deleted song folder.
+    pub fn remove(&self, folder: &str) {
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|(_, cached_folder), _| cached_folder != folder);
+    }
+}
+
+/// Percent-encodes a single URL path segment.
+fn encode_path_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn absolute_url(base_url: &str, url: &str) -> String {
+    if url.starts_with('/') || url.contains("://") {
+        url.to_string()
+    } else {
+        format!("{}{}", base_url, url)
+    }
+}
+
+/// Rewrites relative `BaseURL`s and segment template paths in an MPD so they
+/// resolve under `base_url` regardless of how the player resolves the
+/// manifest's own URL.
+fn rewrite_manifest(manifest: &[u8], base_url: &str) -> std::io::Result<Vec<u8>> {
+    let mut reader = Reader::from_reader(manifest);
+    let mut writer = Writer::new(Vec::with_capacity(manifest.len()));
+    let mut in_base_url = false;
+    // templates resolve against a BaseURL when there is one, so only that
+    // needs rewriting
+    let mut has_base_url = false;
+
+    loop {
+        let event = reader.read_event().map_err(std::io::Error::other)?;
+        match event {
+            Event::Start(ref e) | Event::Empty(ref e)
+                if !has_base_url && e.name().as_ref() == b"SegmentTemplate" =>
+            {
+                let mut rewritten = e.clone();
+                rewritten.clear_attributes();
+                for attr in e.attributes() {
+                    let attr = attr.map_err(std::io::Error::other)?;
+                    let value = attr.unescape_value().map_err(std::io::Error::other)?;
+                    let value = match attr.key.as_ref() {
+                        b"initialization" | b"media" => absolute_url(base_url, &value),
+                        _ => value.into_owned(),
+                    };
+                    let key =
+                        std::str::from_utf8(attr.key.as_ref()).map_err(std::io::Error::other)?;
+                    rewritten.push_attribute((key, value.as_str()));
+                }
+                let event = match event {
+                    Event::Start(_) => Event::Start(rewritten),
+                    _ => Event::Empty(rewritten),
+                };
+                writer.write_event(event)?;
+            }
+            Event::Start(ref e) if e.name().as_ref() == b"BaseURL" => {
+                in_base_url = true;
+                has_base_url = true;
+                writer.write_event(event)?;
+            }
+            Event::End(ref e) if e.name().as_ref() == b"BaseURL" => {
+                in_base_url = false;
+                writer.write_event(event)?;
+            }
+            Event::Text(ref text) if in_base_url => {
+                let url = text.unescape().map_err(std::io::Error::other)?;
+                let url = absolute_url(base_url, url.trim());
+                writer.write_event(Event::Text(BytesText::new(&url)))?;
+            }
+            Event::Eof => break,
+            event => writer.write_event(event)?,
+        }
+    }
+
+    Ok(writer.into_inner())
+}
+
+/// Loads a song's manifest with its URLs made absolute under the
+/// `/dash/{folder}/` route it's served from, behind `base_path`.
+async fn load_manifest(
+    cache: &ManifestCache,
+    base_path: &str,
+    folder: &str,
+    path: &std::path::Path,
+) -> std::io::Result<Vec<u8>> {
+    let key = (base_path.to_string(), folder.to_string());
+    let modified = tokio::fs::metadata(path).await?.modified()?;
+    if let Some(cached) = cache.0.lock().unwrap().get(&key) {
+        if cached.modified == modified {
+            return Ok(cached.contents.clone());
+        }
+    }
+
+    let manifest = tokio::fs::read(path).await?;
+    let base_url = format!("{}/dash/{}/", base_path, encode_path_segment(folder));
+    let contents = rewrite_manifest(&manifest, &base_url)?;
+
+    cache.0.lock().unwrap().insert(
+        key,
+        CachedManifest {
+            modified,
+            contents: contents.clone(),
+        },
+    );
+    Ok(contents)
+}
+
+fn is_manifest(path: &std::path::Path) -> bool {
+    content_type(path) == "application/dash+xml"
+}
+
+pub async fn serve_dash_file(
+    State(settings): State<Arc<Settings>>,
+    State(assets): State<AssetPaths>,
+    State(manifests): State<ManifestCache>,
+    Path((folder, file)): Path<(String, String)>,
+) -> Result<Response, FileError> {
+    let Some(path) = dash_file_path(&assets, &folder, &file) else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+
+    let contents = if is_manifest(&path) {
+        load_manifest(&manifests, &settings.server.base_path(), &folder, &path)
+            .await
+            .map_err(FileError)?
+    } else {
+        let mut file = File::open(&path).await.map_err(FileError)?;
+        let mut contents = vec![];
+        file.read_to_end(&mut contents).await.map_err(FileError)?;
+        contents
     };
 
-    Ok((StatusCode::OK, [("Content-Type", content_type)], contents).into_response())
+    Ok((
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, content_type(&path).to_string()),
+            (header::CONTENT_LENGTH, contents.len().to_string()),
+        ],
+        contents,
+    )
+        .into_response())
 }
 
+pub async fn head_dash_file(
+    State(settings): State<Arc<Settings>>,
+    State(assets): State<AssetPaths>,
+    State(manifests): State<ManifestCache>,
+    Path((folder, file)): Path<(String, String)>,
+) -> Result<Response, FileError> {
+    let Some(path) = dash_file_path(&assets, &folder, &file) else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+
+    let content_length = if is_manifest(&path) {
+        load_manifest(&manifests, &settings.server.base_path(), &folder, &path)
+            .await
+            .map_err(FileError)?
+            .len() as u64
+    } else {
+        tokio::fs::metadata(&path).await.map_err(FileError)?.len()
+    };
+
+    Ok((
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, content_type(&path).to_string()),
+            (header::CONTENT_LENGTH, content_length.to_string()),
+        ],
+    )
+        .into_response())
+}
+
+#[derive(Serialize)]
+pub struct DashRepresentation {
+    id: String,
+    init: Option<String>,
+    segments: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct DashAdaptationSet {
+    id: Option<usize>,
+    content_type: Option<String>,
+    // semitone shift of the audio this set carries, from `status.json`
+    key_shift: Option<i32>,
+    representations: Vec<DashRepresentation>,
+}
+
+#[derive(Serialize)]
+pub struct DashIndex {
+    manifest: String,
+    adaptation_sets: Vec<DashAdaptationSet>,
+}
+
+/// Whether `file` is named by a segment template, i.e. everything before the
+/// first unresolved `$` matches and, for media segments, a number follows.
+fn matches_template(file: &str, template: &str, numbered: bool) -> bool {
+    match template.split_once('$') {
+        None => file == template,
+        Some((prefix, _)) => file.strip_prefix(prefix).is_some_and(|rest| {
+            !rest.is_empty() && (!numbered || rest.starts_with(|c: char| c.is_ascii_digit()))
+        }),
+    }
+}
+
+/// Lists the DASH files of a song by adaptation set, so players and
+/// debugging tools can see what exists without parsing the manifest.
+pub async fn dash_index(
+    State(assets): State<AssetPaths>,
+    Path(folder): Path<String>,
+) -> Result<Response, FileError> {
+    if !slug::is_song_key(&folder) {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    let song_dir = assets.song_dir(&folder);
+    let Ok(status) = VideoStatus::load(&song_dir) else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+
+    let mut files = BTreeSet::new();
+    let mut entries = tokio::fs::read_dir(&song_dir).await.map_err(FileError)?;
+    while let Some(entry) = entries.next_entry().await.map_err(FileError)? {
+        if let Ok(name) = entry.file_name().into_string() {
+            files.insert(name);
+        }
+    }
+
+    let Some(manifest) = files
+        .iter()
+        .find(|name| is_manifest(std::path::Path::new(name)))
+        .cloned()
+    else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let contents = tokio::fs::read(song_dir.join(&manifest))
+        .await
+        .map_err(FileError)?;
+    let templates = adaptation_set_templates(&contents).map_err(FileError)?;
+
+    let adaptation_sets = templates
+        .into_iter()
+        .map(|set| DashAdaptationSet {
+            id: set.id,
+            content_type: set.content_type,
+            key_shift: status
+                .audio_adaptation_sets
+                .iter()
+                .find(|(_, id)| Some(**id) == set.id)
+                .map(|(shift, _)| *shift),
+            representations: set
+                .representations
+                .into_iter()
+                .map(|representation| DashRepresentation {
+                    init: representation.initialization.and_then(|template| {
+                        files
+                            .iter()
+                            .find(|file| matches_template(file, &template, false))
+                            .cloned()
+                    }),
+                    segments: representation
+                        .media
+                        .map(|template| {
+                            files
+                                .iter()
+                                .filter(|file| matches_template(file, &template, true))
+                                .cloned()
+                                .collect()
+                        })
+                        .unwrap_or_default(),
+                    id: representation.id,
+                })
+                .collect(),
+        })
+        .collect();
+
+    Ok(Json(DashIndex {
+        manifest,
+        adaptation_sets,
+    })
+    .into_response())
+}
+
+/// Streams the retained source video of a song for archival.
+pub async fn download_source_file(
+    State(assets): State<AssetPaths>,
+    Path(folder): Path<String>,
+) -> Result<Response, FileError> {
+    if !slug::is_song_key(&folder) {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    let song_dir = assets.song_dir(&folder);
+
+    let source_file = match VideoStatus::load(&song_dir)
+        .ok()
+        .and_then(|status| status.source_file)
+    {
+        Some(source_file) => source_file,
+        None => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+
+    let path = song_dir.join(&source_file);
+    let file = File::open(&path).await.map_err(FileError)?;
+    let metadata = file.metadata().await.map_err(FileError)?;
+
+    Ok((
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, content_type(&path).to_string()),
+            (header::CONTENT_LENGTH, metadata.len().to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", source_file),
+            ),
+        ],
+        Body::from_stream(ReaderStream::new(file)),
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{settings::AssetLayout, utils::slug::song_key};
+
+    use super::*;
+
+    const LINK: &str = "https://www.youtube.com/watch?v=aaaaaaaaaaa";
+
+    fn header(response: &Response, name: header::HeaderName) -> &str {
+        response.headers()[name].to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn head_and_get_report_the_same_length() {
+        let root = tempfile::tempdir().unwrap();
+        let assets = AssetPaths::new(root.path(), AssetLayout::PerSong);
+        let folder = song_key("Song", LINK);
+        std::fs::create_dir_all(assets.song_dir(&folder)).unwrap();
+        std::fs::write(assets.song_dir(&folder).join("chunk-1-00001.m4s"), "12345").unwrap();
+
+        let settings = Arc::new(Settings::default());
+        let file = (folder.clone(), "chunk-1-00001.m4s".to_string());
+        let manifests = ManifestCache::default();
+        let head = head_dash_file(
+            State(settings.clone()),
+            State(assets.clone()),
+            State(manifests.clone()),
+            Path(file.clone()),
+        )
+        .await
+        .unwrap();
+        let get = serve_dash_file(State(settings), State(assets), State(manifests), Path(file))
+            .await
+            .unwrap();
+
+        for response in [&head, &get] {
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(header(response, header::CONTENT_LENGTH), "5");
+            assert_eq!(header(response, header::CONTENT_TYPE), "video/iso.segment");
+        }
+    }
+
+    #[test]
+    fn paths_outside_the_song_folder_are_rejected() {
+        let assets = AssetPaths::new("assets", AssetLayout::PerSong);
+        let folder = song_key("Song", LINK);
+
+        assert!(dash_file_path(&assets, &folder, "manifest.mpd").is_some());
+        for file in ["", ".hidden", "../status.json", "a/b.m4s", "a\\b.m4s"] {
+            assert!(dash_file_path(&assets, &folder, file).is_none(), "{}", file);
+        }
+        assert!(dash_file_path(&assets, "..", "manifest.mpd").is_none());
+    }
+
+    #[test]
+    fn segment_files_are_matched_back_to_their_template() {
+        use crate::utils::dash_processor::{
+            media_segment_name, INIT_SEGMENT_TEMPLATE, MEDIA_SEGMENT_TEMPLATE,
+        };
+
+        let segment = media_segment_name(MEDIA_SEGMENT_TEMPLATE, 1, 3);
+        assert!(matches_template(&segment, MEDIA_SEGMENT_TEMPLATE, true));
+        assert!(!matches_template(
+            "chunk-stream.m4s",
+            MEDIA_SEGMENT_TEMPLATE,
+            true
+        ));
+        assert!(matches_template(
+            "init-stream1.m4s",
+            INIT_SEGMENT_TEMPLATE,
+            false
+        ));
+        assert!(!matches_template(
+            "status.json",
+            INIT_SEGMENT_TEMPLATE,
+            false
+        ));
+    }
+
+    const MANIFEST: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<MPD><Period><AdaptationSet id="1">
+<SegmentTemplate timescale="1000" initialization="init-stream$RepresentationID$.m4s" media="chunk-stream$RepresentationID$-$Number%05d$.m4s"/>
+</AdaptationSet></Period></MPD>"#;
+
+    #[tokio::test]
+    async fn served_manifests_point_at_the_segment_route() {
+        let root = tempfile::tempdir().unwrap();
+        let assets = AssetPaths::new(root.path(), AssetLayout::PerSong);
+        let folder = song_key("Manifest Song", LINK);
+        std::fs::create_dir_all(assets.song_dir(&folder)).unwrap();
+        std::fs::write(assets.song_dir(&folder).join("manifest.mpd"), MANIFEST).unwrap();
+
+        let file = (folder.clone(), "manifest.mpd".to_string());
+        let response = serve_dash_file(
+            State(Arc::new(Settings::default())),
+            State(assets),
+            State(ManifestCache::default()),
+            Path(file),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            header(&response, header::CONTENT_TYPE),
+            "application/dash+xml"
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        let base_url = format!("/dash/{}/", folder);
+        assert!(body.contains(&format!(r#"initialization="{}init-stream"#, base_url)));
+        assert!(body.contains(&format!(r#"media="{}chunk-stream"#, base_url)));
+        assert!(body.contains(r#"timescale="1000""#));
+    }
+
+    #[tokio::test]
+    async fn cached_manifests_are_kept_per_base_path_until_evicted() {
+        let root = tempfile::tempdir().unwrap();
+        let assets = AssetPaths::new(root.path(), AssetLayout::PerSong);
+        let folder = song_key("Cached Manifest Song", LINK);
+        let path = assets.song_dir(&folder).join("manifest.mpd");
+        std::fs::create_dir_all(assets.song_dir(&folder)).unwrap();
+        std::fs::write(&path, MANIFEST).unwrap();
+
+        let manifests = ManifestCache::default();
+        for base_path in ["", "/karaoke"] {
+            let manifest = load_manifest(&manifests, base_path, &folder, &path)
+                .await
+                .unwrap();
+            let manifest = String::from_utf8(manifest).unwrap();
+            let base_url = format!("{}/dash/{}/", base_path, folder);
+            assert!(manifest.contains(&format!(r#"media="{}chunk-stream"#, base_url)));
+        }
+        assert_eq!(manifests.0.lock().unwrap().len(), 2);
+
+        manifests.remove(&folder);
+        assert!(manifests.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn base_urls_are_made_absolute_instead_of_templates() {
+        let manifest =
+            r#"<MPD><BaseURL>media/</BaseURL><SegmentTemplate media="chunk.m4s"/></MPD>"#;
+        let rewritten = rewrite_manifest(manifest.as_bytes(), "/dash/song/").unwrap();
+        assert_eq!(
+            String::from_utf8(rewritten).unwrap(),
+            r#"<MPD><BaseURL>/dash/song/media/</BaseURL><SegmentTemplate media="chunk.m4s"/></MPD>"#
+        );
+
+        // absolute URLs are left alone
+        let manifest = r#"<MPD><BaseURL>https://cdn.example/a/</BaseURL></MPD>"#;
+        let rewritten = rewrite_manifest(manifest.as_bytes(), "/dash/song/").unwrap();
+        assert_eq!(rewritten, manifest.as_bytes());
+    }
+
+    #[test]
+    fn captions_are_served_as_webvtt() {
+        assert_eq!(
+            content_type(std::path::Path::new("song.en.vtt")),
+            "text/vtt"
+        );
+        assert_eq!(
+            content_type(std::path::Path::new("manifest.mpd")),
+            "application/dash+xml"
+        );
+    }
+
+    #[tokio::test]
+    async fn dated_songs_are_served_from_where_they_were_written() {
+        let root = tempfile::tempdir().unwrap();
+        let assets = AssetPaths::new(root.path(), AssetLayout::Dated);
+        let folder = song_key("Dated Song", LINK);
+        let song_dir = assets.song_dir(&folder);
+        assert_ne!(song_dir, root.path().join(&folder));
+        std::fs::create_dir_all(&song_dir).unwrap();
+        std::fs::write(song_dir.join("chunk-1-00001.m4s"), "123").unwrap();
+
+        let settings = Arc::new(Settings::default());
+        let file = (folder.clone(), "chunk-1-00001.m4s".to_string());
+        let response = serve_dash_file(
+            State(settings.clone()),
+            State(assets.clone()),
+            State(ManifestCache::default()),
+            Path(file.clone()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, header::CONTENT_LENGTH), "3");
+
+        // the default layout looks for the song at the root instead
+        let per_song = AssetPaths::new(root.path(), AssetLayout::PerSong);
+        assert!(
+            serve_dash_file(
+                State(settings),
+                State(per_song),
+                State(ManifestCache::default()),
+                Path(file)
+            )
+            .await
+            .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn dash_index_lists_only_dash_files_by_adaptation_set() {
+        const INDEXED_MANIFEST: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<MPD><Period>
+<AdaptationSet id="0" contentType="video">
+<SegmentTemplate initialization="init-stream$RepresentationID$.m4s" media="chunk-stream$RepresentationID$-$Number%05d$.m4s"/>
+<Representation id="0"/>
+</AdaptationSet>
+<AdaptationSet id="1" contentType="audio">
+<SegmentTemplate initialization="init-stream$RepresentationID$.m4s" media="chunk-stream$RepresentationID$-$Number%05d$.m4s"/>
+<Representation id="1"/>
+</AdaptationSet>
+</Period></MPD>"#;
+
+        let root = tempfile::tempdir().unwrap();
+        let assets = AssetPaths::new(root.path(), AssetLayout::PerSong);
+        let folder = song_key("Indexed Song", LINK);
+        let dir = assets.song_dir(&folder);
+        crate::actors::video_downloader::tests::write_status(
+            &dir,
+            serde_json::json!({
+                "display_name": "Indexed Song",
+                "segments": 2,
+                "is_key_changeable": true,
+                "audio_adaptation_sets": { "0": 1 },
+            }),
+        );
+        std::fs::write(dir.join("song.mpd"), INDEXED_MANIFEST).unwrap();
+        for file in [
+            "init-stream0.m4s",
+            "init-stream1.m4s",
+            "chunk-stream0-00001.m4s",
+            "chunk-stream0-00002.m4s",
+            "chunk-stream1-00001.m4s",
+            "song.mp4",
+        ] {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+
+        let response = dash_index(State(assets.clone()), Path(folder))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let index: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            index,
+            serde_json::json!({
+                "manifest": "song.mpd",
+                "adaptation_sets": [
+                    {
+                        "id": 0,
+                        "content_type": "video",
+                        "key_shift": null,
+                        "representations": [{
+                            "id": "0",
+                            "init": "init-stream0.m4s",
+                            "segments": ["chunk-stream0-00001.m4s", "chunk-stream0-00002.m4s"],
+                        }],
+                    },
+                    {
+                        "id": 1,
+                        "content_type": "audio",
+                        "key_shift": 0,
+                        "representations": [{
+                            "id": "1",
+                            "init": "init-stream1.m4s",
+                            "segments": ["chunk-stream1-00001.m4s"],
+                        }],
+                    },
+                ],
+            })
+        );
+
+        let unknown = song_key("Unknown Song", LINK);
+        let response = dash_index(State(assets), Path(unknown)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
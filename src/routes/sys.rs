@@ -1,18 +1,524 @@
-use axum::{http::StatusCode, response::IntoResponse, Json};
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, OriginalUri, Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
 use local_ip_address::local_ip;
-use serde::Serialize;
-use tracing::debug;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    actors::video_downloader::{VideoDlActorHandle, SEGMENT_SECONDS},
+    event_log::EventLog,
+    rooms::{RoomError, Rooms},
+    routes::{extract::ValidQuery, karaoke::restore_pins},
+    settings::Settings,
+    shutdown::Shutdown,
+    utils::{
+        binary::{self, BinaryPaths, BinaryVersions, RecordedVersions},
+        dash_processor::{DashProcessor, ProcessingMode},
+        process::ProcessGroups,
+        title::TitleCleaner,
+    },
+};
 
 #[derive(Serialize)]
 struct ServerIpResponse {
-    ip: String
+    ip: String,
+    // where clients reach the app, for QR codes
+    url: String,
+}
+
+fn forwarded_header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        // proxies append to the list, the first entry is the client-facing one
+        .and_then(|value| value.split(',').next())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
 }
 
 pub async fn server_ip(
+    State(settings): State<Arc<Settings>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse, StatusCode> {
     let my_local_ip = local_ip().unwrap();
 
     debug!("my local ip {:?}", my_local_ip);
 
-    Ok((StatusCode::OK, Json(ServerIpResponse { ip: my_local_ip.to_string() })))
-}
\ No newline at end of file
+    // behind a reverse proxy the LAN address isn't what clients should use,
+    // but only a trusted proxy is believed about the address it serves
+    let forwarded = |name| {
+        settings
+            .server
+            .trusted_proxies
+            .contains(&peer.ip())
+            .then(|| forwarded_header(&headers, name))
+            .flatten()
+    };
+    let scheme = forwarded("x-forwarded-proto").unwrap_or("http");
+    let host = match forwarded("x-forwarded-host") {
+        Some(host) => host.to_string(),
+        None => format!("{}:{}", my_local_ip, crate::SERVER_PORT),
+    };
+    let url = format!("{}://{}{}/", scheme, host, settings.server.base_path());
+
+    Ok((StatusCode::OK, Json(ServerIpResponse { ip: my_local_ip.to_string(), url })))
+}
+
+#[derive(Serialize)]
+struct VersionResponse {
+    version: &'static str,
+    // set through `FERRIS_GIT_HASH` when the binary was built
+    git_hash: Option<&'static str>,
+    binaries: Option<BinaryVersions>,
+}
+
+pub async fn version(State(versions): State<RecordedVersions>) -> impl IntoResponse {
+    Json(VersionResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: option_env!("FERRIS_GIT_HASH"),
+        binaries: versions.get(),
+    })
+}
+
+#[derive(Serialize)]
+struct UpdateYtdlpResponse {
+    updated: bool,
+    version: Option<String>,
+    error: Option<String>,
+}
+
+// held while yt-dlp replaces itself, so concurrent updates don't both
+// write the binary
+static YTDLP_UPDATE_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Runs yt-dlp's self-update without a restart, for when extraction breaks
+/// mid-event. Downloads already running keep the binary they started with.
+pub async fn update_ytdlp(
+    State(binaries): State<BinaryPaths>,
+    State(versions): State<RecordedVersions>,
+    State(settings): State<Arc<Settings>>,
+) -> impl IntoResponse {
+    if settings.binaries.ytdlp.is_some() {
+        return (
+            StatusCode::CONFLICT,
+            Json(UpdateYtdlpResponse {
+                updated: false,
+                version: versions.get().and_then(|versions| versions.yt_dlp),
+                error: Some("yt-dlp is external and updated outside the server".to_string()),
+            }),
+        );
+    }
+
+    let _guard = YTDLP_UPDATE_LOCK.lock().await;
+
+    info!("Updating yt-dlp on request");
+    let ytdlp_path = binaries.ytdlp.clone();
+    let recorded = versions.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        binary::update_ytdlp(&ytdlp_path).map(|_| recorded.refresh_ytdlp(&ytdlp_path))
+    })
+    .await;
+
+    let (status, response) = match result {
+        Ok(Ok(version)) => {
+            info!("yt-dlp is at version {:?}", version);
+            (
+                StatusCode::OK,
+                UpdateYtdlpResponse {
+                    updated: true,
+                    version,
+                    error: None,
+                },
+            )
+        }
+        Ok(Err(err)) => {
+            warn!("yt-dlp update failed: {}", err);
+            (
+                StatusCode::BAD_GATEWAY,
+                UpdateYtdlpResponse {
+                    updated: false,
+                    version: versions.get().and_then(|versions| versions.yt_dlp),
+                    error: Some(err.to_string()),
+                },
+            )
+        }
+        Err(err) => {
+            warn!("yt-dlp update task failed: {}", err);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                UpdateYtdlpResponse {
+                    updated: false,
+                    version: versions.get().and_then(|versions| versions.yt_dlp),
+                    error: Some(err.to_string()),
+                },
+            )
+        }
+    };
+    (status, Json(response))
+}
+
+#[derive(Deserialize, Default)]
+pub struct ShutdownRequest {
+    #[serde(default)]
+    exit_code: i32,
+}
+
+/// Drains downloads and stops the server through the same path as SIGTERM.
+pub async fn shutdown_server(
+    State(shutdown): State<Shutdown>,
+    payload: Option<Json<ShutdownRequest>>,
+) -> impl IntoResponse {
+    let exit_code = payload.map(|Json(payload)| payload.exit_code).unwrap_or_default();
+    info!("received shutdown request with exit code {}", exit_code);
+
+    if !shutdown.request(exit_code) {
+        return StatusCode::CONFLICT;
+    }
+    StatusCode::ACCEPTED
+}
+
+/// Starts a room for `?room=<name>` requests. Rooms are only created here so
+/// clients can't fill the `rooms.max_rooms` slots with rooms nobody removes.
+pub async fn create_room(
+    State(rooms): State<Rooms>,
+    State(videodl_actor_handle): State<Arc<VideoDlActorHandle>>,
+    State(title_cleaner): State<Arc<TitleCleaner>>,
+    Path(room): Path<String>,
+) -> Result<impl IntoResponse, RoomError> {
+    let song_actor_handle = rooms.create(&room)?.song_actor_handle;
+    let pins = rooms.pins().load(&room);
+    restore_pins(song_actor_handle, videodl_actor_handle, &title_cleaner, pins).await;
+    Ok(StatusCode::CREATED)
+}
+
+pub async fn remove_room(
+    State(rooms): State<Rooms>,
+    Path(room): Path<String>,
+) -> Result<impl IntoResponse, RoomError> {
+    rooms.remove(&room)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_downloads(
+    State(videodl_actor_handle): State<Arc<VideoDlActorHandle>>,
+) -> impl IntoResponse {
+    (StatusCode::OK, Json(videodl_actor_handle.active_downloads()))
+}
+
+pub async fn cancel_download(
+    State(videodl_actor_handle): State<Arc<VideoDlActorHandle>>,
+    Path(song_uuid): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let song_uuid = Uuid::parse_str(&song_uuid).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    if videodl_actor_handle.cancel_download(song_uuid) {
+        warn!("cancelling download for song: {}", song_uuid);
+        Ok(StatusCode::ACCEPTED)
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
+
+/// The yt-dlp or ffmpeg output of a song's failed download, as plain text.
+pub async fn song_download_log(
+    State(videodl_actor_handle): State<Arc<VideoDlActorHandle>>,
+    Path(song_uuid): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let song_uuid = Uuid::parse_str(&song_uuid).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    videodl_actor_handle
+        .download_log(song_uuid)
+        .map(|log| (StatusCode::OK, log))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FfmpegPreviewMode {
+    Copy,
+    PitchShift,
+}
+
+#[derive(Deserialize)]
+pub struct FfmpegPreviewRequest {
+    mode: FfmpegPreviewMode,
+}
+
+/// Returns the ffmpeg arguments a download would be transcoded with under
+/// the current settings, without running anything.
+pub async fn preview_ffmpeg_command(
+    State(settings): State<Arc<Settings>>,
+    State(binaries): State<BinaryPaths>,
+    State(processes): State<ProcessGroups>,
+    ValidQuery(request): ValidQuery<FfmpegPreviewRequest>,
+) -> impl IntoResponse {
+    let dash_processor = DashProcessor::from_settings(
+        binaries.ffmpeg,
+        processes,
+        SEGMENT_SECONDS,
+        &settings.download,
+        &settings.transcode,
+    );
+    let mode = ProcessingMode::for_song(
+        matches!(request.mode, FfmpegPreviewMode::PitchShift),
+        &settings.transcode.pitch_shifts,
+    );
+
+    let args = dash_processor.build_command_args("input.mp4", "output.mpd", &mode);
+    (StatusCode::OK, Json(args))
+}
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    // unix timestamps in seconds, both inclusive
+    since: Option<u64>,
+    until: Option<u64>,
+}
+
+/// Reads back the persisted event log, oldest first.
+pub async fn list_events(
+    State(event_log): State<EventLog>,
+    ValidQuery(query): ValidQuery<EventsQuery>,
+) -> impl IntoResponse {
+    let result =
+        tokio::task::spawn_blocking(move || event_log.read(query.since, query.until)).await;
+    match result {
+        Ok(Ok(events)) => (StatusCode::OK, Json(events)).into_response(),
+        Ok(Err(err)) => {
+            warn!("failed to read event log: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+        Err(err) => {
+            warn!("event log reader panicked: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct NotFound {
+    error: &'static str,
+    path: String,
+}
+
+/// Fallback for unknown routes, so API clients get a body they can parse.
+/// The embedded frontends are nested services and answer their own paths.
+pub async fn not_found(OriginalUri(uri): OriginalUri) -> impl IntoResponse {
+    (
+        StatusCode::NOT_FOUND,
+        Json(NotFound {
+            error: "not found",
+            path: uri.path().to_string(),
+        }),
+    )
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt, path::PathBuf};
+
+    use axum::response::Response;
+
+    use super::*;
+
+    /// A yt-dlp that answers `-U` with `update_status` and reports a version.
+    fn stub_ytdlp(dir: &std::path::Path, update_status: i32) -> BinaryPaths {
+        let ytdlp = dir.join("yt-dlp");
+        let script = format!(
+            "#!/bin/sh\ncase \"$1\" in\n  -U) exit {} ;;\n  --version) echo 2099.01.01 ;;\nesac\n",
+            update_status
+        );
+        fs::write(&ytdlp, script).unwrap();
+        fs::set_permissions(&ytdlp, fs::Permissions::from_mode(0o755)).unwrap();
+        BinaryPaths {
+            ytdlp,
+            ffmpeg: PathBuf::from("ffmpeg"),
+        }
+    }
+
+    async fn update(
+        binaries: BinaryPaths,
+        versions: RecordedVersions,
+        settings: Settings,
+    ) -> (StatusCode, serde_json::Value) {
+        let response: Response =
+            update_ytdlp(State(binaries), State(versions), State(Arc::new(settings)))
+                .await
+                .into_response();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn only_trusted_proxies_set_the_join_url() {
+        let proxy: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let guest: SocketAddr = "192.168.1.10:40000".parse().unwrap();
+        let mut settings = Settings::default();
+        settings.server.trusted_proxies = vec![proxy.ip()];
+        let settings = Arc::new(settings);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-proto", "https".parse().unwrap());
+        headers.insert("x-forwarded-host", "karaoke.example".parse().unwrap());
+
+        let join_url = |peer| {
+            let request = server_ip(State(settings.clone()), ConnectInfo(peer), headers.clone());
+            async move {
+                let response = request.await.into_response();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap()["url"]
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            }
+        };
+        assert_eq!(join_url(proxy).await, "https://karaoke.example/");
+        let spoofed = join_url(guest).await;
+        assert!(spoofed.starts_with("http://"), "{}", spoofed);
+        assert!(!spoofed.contains("karaoke.example"), "{}", spoofed);
+    }
+
+    #[tokio::test]
+    async fn updates_report_the_new_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let versions = RecordedVersions::new(BinaryVersions {
+            ffmpeg: None,
+            yt_dlp: Some("2024.12.13".to_string()),
+        });
+        let (status, body) =
+            update(stub_ytdlp(dir.path(), 0), versions.clone(), Settings::default()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["updated"], true);
+        assert_eq!(body["version"], "2099.01.01");
+        assert!(body["error"].is_null());
+
+        // `/version` reports the update too
+        let recorded = versions.get().unwrap().yt_dlp;
+        assert_eq!(recorded.as_deref(), Some("2099.01.01"));
+    }
+
+    #[tokio::test]
+    async fn failed_updates_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let binaries = stub_ytdlp(dir.path(), 1);
+        let (status, body) = update(binaries, RecordedVersions::default(), Settings::default()).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(body["updated"], false);
+        assert!(body["error"].is_string());
+    }
+
+    #[tokio::test]
+    async fn external_ytdlp_is_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let binaries = stub_ytdlp(dir.path(), 0);
+        let mut settings = Settings::default();
+        settings.binaries.ytdlp = Some(binaries.ytdlp.clone());
+
+        let (status, body) = update(binaries, RecordedVersions::default(), settings).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["updated"], false);
+    }
+
+    #[tokio::test]
+    async fn failed_downloads_leave_a_log_for_admins() {
+        use crate::actors::{
+            song_coordinator::{QueuedSongStatus, Song},
+            video_downloader::tests::handle,
+        };
+
+        let assets = tempfile::tempdir().unwrap();
+        let videodl = Arc::new(handle(assets.path()));
+        let room = crate::rooms::tests::rooms(1).get("default").unwrap();
+        let song = Song::new(
+            "Song".to_string(),
+            "https://www.youtube.com/watch?v=aaaaaaaaaaa".to_string(),
+            QueuedSongStatus::InProgress,
+            false,
+            None,
+        );
+        let download = videodl.download_video(&song, room.song_actor_handle.clone());
+        let Err(err) = download.await else {
+            panic!("the download should fail without yt-dlp");
+        };
+
+        let log = |uuid: Uuid| song_download_log(State(videodl.clone()), Path(uuid.to_string()));
+        let response = log(song.uuid).await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, err.to_string());
+
+        let response = log(Uuid::new_v4()).await.into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn download_logs_keep_what_yt_dlp_printed() {
+        use crate::actors::{
+            song_coordinator::{QueuedSongStatus, Song},
+            video_downloader::tests::handle_with_ytdlp,
+        };
+
+        let room = crate::rooms::tests::rooms(1).get("default").unwrap();
+        // failing outright, and exiting cleanly with output that can't be used
+        for (exit_code, stdout) in [(1, ""), (0, "not metadata")] {
+            let assets = tempfile::tempdir().unwrap();
+            let ytdlp = assets.path().join("yt-dlp");
+            let script = format!(
+                "#!/bin/sh\necho 'WARNING: [youtube] stub warning' >&2\necho '{}'\nexit {}\n",
+                stdout, exit_code
+            );
+            fs::write(&ytdlp, script).unwrap();
+            fs::set_permissions(&ytdlp, fs::Permissions::from_mode(0o755)).unwrap();
+            let videodl = Arc::new(handle_with_ytdlp(assets.path(), Default::default(), ytdlp));
+
+            let song = Song::new(
+                "Song".to_string(),
+                "https://www.youtube.com/watch?v=aaaaaaaaaaa".to_string(),
+                QueuedSongStatus::InProgress,
+                false,
+                None,
+            );
+            let download = videodl.download_video(&song, room.song_actor_handle.clone());
+            let Err(err) = download.await else {
+                panic!("the stub yt-dlp should fail the download");
+            };
+
+            let response = song_download_log(State(videodl), Path(song.uuid.to_string()))
+                .await
+                .into_response();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let log = String::from_utf8(body.to_vec()).unwrap();
+            assert!(log.contains("WARNING: [youtube] stub warning"), "{}", log);
+            assert!(log.ends_with(&err.to_string()), "{}", log);
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_can_only_be_requested_once() {
+        let shutdown = Shutdown::new();
+        let request = Some(Json(ShutdownRequest { exit_code: 3 }));
+
+        let first = shutdown_server(State(shutdown.clone()), request).await;
+        let second = shutdown_server(State(shutdown.clone()), None).await;
+
+        assert_eq!(first.into_response().status(), StatusCode::ACCEPTED);
+        assert_eq!(second.into_response().status(), StatusCode::CONFLICT);
+        assert_eq!(shutdown.exit_code(), 3);
+    }
+}
@@ -0,0 +1,553 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use config::{Config, ConfigError, Environment, File};
+use serde::Deserialize;
+
+use crate::utils::dash_processor::{PitchPreset, Rendition, VideoOutput};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub admin: AdminSettings,
+    pub binaries: BinarySettings,
+    pub cors: CorsSettings,
+    pub download: DownloadSettings,
+    pub events: EventLogSettings,
+    pub idle: IdleSettings,
+    pub log: LogSettings,
+    pub playback: PlaybackSettings,
+    pub queue: QueueSettings,
+    pub rooms: RoomSettings,
+    pub search: SearchSettings,
+    pub server: ServerSettings,
+    pub sse: SseSettings,
+    pub transcode: TranscodeSettings,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct AdminSettings {
+    // bearer token for `/admin` routes, which are disabled when unset
+    pub token: Option<String>,
+    // how long shutdown waits for in-flight downloads to finish
+    pub shutdown_drain_seconds: u64,
+}
+
+impl Default for AdminSettings {
+    fn default() -> Self {
+        AdminSettings {
+            token: None,
+            shutdown_drain_seconds: 30,
+        }
+    }
+}
+
+impl std::fmt::Debug for AdminSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdminSettings")
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("shutdown_drain_seconds", &self.shutdown_drain_seconds)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BinarySettings {
+    // used instead of the embedded binaries, e.g. a system ffmpeg built with
+    // hardware encoders. Either a path or a name looked up in PATH. An
+    // external yt-dlp isn't self-updated
+    pub ffmpeg: Option<PathBuf>,
+    pub ytdlp: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CorsSettings {
+    // an empty list allows any value, which suits a closed LAN
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DownloadSettings {
+    // keep the merged source mp4 next to the DASH output for offline replay
+    pub retain_source: bool,
+    // scratch space for yt-dlp fragments and ffmpeg output, e.g. a tmpfs to
+    // spare the SD card. Unset works in the assets folder directly
+    pub temp_dir: Option<PathBuf>,
+    // longer videos are rejected before downloading, unlimited when unset
+    pub max_duration_seconds: Option<u64>,
+    // fetch and serve audio only, for venues showing lyrics elsewhere
+    pub audio_only: bool,
+    // cut non-music intros and outros with yt-dlp's SponsorBlock support,
+    // needs network access to the SponsorBlock API
+    pub sponsorblock: bool,
+    // also fetch the video's captions as WebVTT for a lyrics display, falling
+    // back to auto-generated ones. Not every video has them
+    pub subtitles: bool,
+    // yt-dlp `--sub-langs` patterns, localize for non-English venues
+    pub subtitle_langs: String,
+    // two-letter country code yt-dlp poses as to get past region blocks,
+    // e.g. `US`. Unset leaves yt-dlp's own bypass
+    pub geo_bypass_country: Option<String>,
+    // `per_song` keeps each song in `assets/<song>`, `dated` nests them
+    // under the UTC day they were downloaded, `assets/<YYYY-MM-DD>/<song>`,
+    // for syncing to external storage by day. Existing downloads aren't
+    // moved
+    pub layout: AssetLayout,
+    // downloads queued or running at once. Further queue requests, edits
+    // and retries are rejected with 503 until some finish
+    pub max_pending: usize,
+    // downloads one client IP can have running at once, so a single guest
+    // can't occupy every consumer or pending download. A playlist or batch
+    // counts as one. Their further queue requests are rejected with 429
+    // until earlier ones finish. Zero is unlimited
+    pub max_per_client: usize,
+}
+
+impl Default for DownloadSettings {
+    fn default() -> Self {
+        DownloadSettings {
+            retain_source: false,
+            temp_dir: None,
+            max_duration_seconds: Some(15 * 60),
+            audio_only: false,
+            sponsorblock: false,
+            subtitles: false,
+            subtitle_langs: "en.*".to_string(),
+            geo_bypass_country: None,
+            layout: AssetLayout::default(),
+            max_pending: 100,
+            max_per_client: 10,
+        }
+    }
+}
+
+/// What the key is set to when a new song starts.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyResetPolicy {
+    // back to the original key
+    #[default]
+    Always,
+    // the key the song was queued with, or the original
+    PreferSong,
+    // the key the previous song ended in, e.g. for one singer all night
+    Persist,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AssetLayout {
+    #[default]
+    PerSong,
+    Dated,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct EventLogSettings {
+    // record queued, played, skipped and failed songs to `events.jsonl` in
+    // the config dir
+    pub enabled: bool,
+    // the log is rotated once it reaches this size, keeping one old file
+    pub max_bytes: u64,
+}
+
+impl Default for EventLogSettings {
+    fn default() -> Self {
+        EventLogSettings {
+            enabled: true,
+            max_bytes: 5 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct IdleSettings {
+    // clear the queue after this long without a mutating request or a song
+    // playing, never when unset
+    pub clear_after_minutes: Option<u64>,
+    // also delete the downloaded songs when clearing
+    pub evict_assets: bool,
+    // also count a client subscribed to a room's events as activity. Leave
+    // off when a display stays subscribed, like a kiosk's browser
+    pub subscribers_count_as_activity: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogRotation {
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LogSettings {
+    // also write logs to `logs/ferris.log` in the config dir, for headless
+    // setups where stdout isn't captured
+    pub file: bool,
+    // filter directives for the file, independent of RUST_LOG for stdout
+    pub file_level: String,
+    pub rotation: LogRotation,
+    // rotated files kept before the oldest is deleted
+    pub max_files: usize,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        LogSettings {
+            file: false,
+            file_level: "ferris=info,tower_http=info".to_string(),
+            rotation: LogRotation::default(),
+            max_files: 7,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PlaybackSettings {
+    // how often the now-playing position is broadcast while playing
+    pub tick_interval_ms: u64,
+    // cap on `/play_next?wait=`, so long polls don't pile up
+    pub max_next_wait_seconds: u64,
+    // the key a new song starts in: `always` resets it, `prefer_song` uses
+    // the key the song was queued with and `persist` keeps the previous one
+    pub key_reset: KeyResetPolicy,
+    // semitones the key can be shifted either way. Keys outside
+    // transcode.pitch_shifts can still be picked and are encoded on demand
+    pub max_key_shift: u8,
+    // pause autoplay leaves between songs
+    pub gap_ms: u64,
+    // how long before autoplay moves on clients are sent `SongEnding` to
+    // start a fade out. Zero doesn't send it
+    pub ending_lead_ms: u64,
+}
+
+impl Default for PlaybackSettings {
+    fn default() -> Self {
+        PlaybackSettings {
+            tick_interval_ms: 1000,
+            max_next_wait_seconds: 30,
+            key_reset: KeyResetPolicy::default(),
+            max_key_shift: 3,
+            gap_ms: 0,
+            ending_lead_ms: 3000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct QueueSettings {
+    // unlimited when unset
+    pub max_length: Option<usize>,
+    // entries queued from a single playlist link
+    pub playlist_max_items: usize,
+    // used when a queue request leaves out `is_key_changeable`, key-changeable
+    // songs take several times longer to transcode
+    pub default_key_changeable: bool,
+    // leading words ignored when sorting songs by title, localize for
+    // non-English venues
+    pub sort_articles: Vec<String>,
+    // queuing the song that's playing adds it again as a replay, otherwise
+    // it's rejected like a song already in the queue
+    pub allow_requeue_playing: bool,
+    // strip matches of title_noise_patterns from titles as they're queued,
+    // keeping the original in the song's `original_name`
+    pub clean_titles: bool,
+    // regexes for tags like `(Official Video)` or a trailing `Lyrics`
+    pub title_noise_patterns: Vec<String>,
+    // titles longer than this many characters are cut with an ellipsis as
+    // they're queued, keeping the full title in `original_name`. 0 keeps
+    // every title whole
+    pub max_name_length: usize,
+    // songs listed by `/upcoming` when the request leaves out `count`
+    pub upcoming_count: usize,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        QueueSettings {
+            max_length: None,
+            playlist_max_items: 25,
+            default_key_changeable: false,
+            sort_articles: vec!["the".to_string(), "a".to_string(), "an".to_string()],
+            allow_requeue_playing: true,
+            clean_titles: false,
+            title_noise_patterns: vec![
+                // a bracketed group mentioning any noise word, `(feat. X)` stays
+                r"(?i)[(\[][^)\]]*\b(official|video|audio|lyrics?|hd|hq|4k|1080p|720p|remaster(ed)?|visuali[sz]er|m/?v)\b[^)\]]*[)\]]"
+                    .to_string(),
+                // trailing words outside brackets, e.g. `Song - Lyrics`
+                r"(?i)(\s+[-|]?\s*(official (music )?video|lyrics?( video)?|hd|hq|4k))+\s*$"
+                    .to_string(),
+            ],
+            max_name_length: 150,
+            upcoming_count: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RoomSettings {
+    // independent queues, e.g. one per screen, picked with `?room=<name>`.
+    // Rooms besides the default one, which requests without a name go to,
+    // are created with `POST /admin/rooms/<name>`
+    pub max_rooms: usize,
+}
+
+impl Default for RoomSettings {
+    fn default() -> Self {
+        RoomSettings { max_rooms: 1 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SearchSettings {
+    // appended to queries in karaoke mode, localize for non-English venues
+    pub karaoke_terms: String,
+    // re-rank results by keyword_weights matched against title and channel
+    pub rank_results: bool,
+    pub keyword_weights: HashMap<String, i32>,
+    // title terms hidden from family-friendly searches
+    pub explicit_terms: Vec<String>,
+    // extra attempts when a yt-dlp search fails
+    pub retries: u32,
+    // searches allowed per client IP, each one runs yt-dlp. Zero disables
+    // the limit
+    pub rate_limit_per_minute: u32,
+    pub rate_limit_burst: u32,
+    // transliterate queries to ASCII before searching, requests can opt
+    // out with `transliterate=false`. Turn off for non-Latin catalogues
+    pub transliterate: bool,
+}
+
+impl Default for SearchSettings {
+    fn default() -> Self {
+        SearchSettings {
+            karaoke_terms: "karaoke instrumental".to_string(),
+            rank_results: false,
+            keyword_weights: HashMap::from([
+                ("karaoke".to_string(), 3),
+                ("instrumental".to_string(), 2),
+                ("sing king".to_string(), 2),
+                ("live".to_string(), -2),
+                ("cover".to_string(), -1),
+                ("reaction".to_string(), -3),
+            ]),
+            explicit_terms: vec![
+                "explicit".to_string(),
+                "uncensored".to_string(),
+                "nsfw".to_string(),
+            ],
+            retries: 2,
+            rate_limit_per_minute: 20,
+            rate_limit_burst: 5,
+            transliterate: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ServerSettings {
+    // path prefix when served under a subpath behind a reverse proxy, e.g.
+    // `/karaoke`. Every route, including the generated DASH URLs, moves
+    // under it
+    pub base_path: String,
+    // refuse to start when a frontend's dist dir was empty at build time,
+    // instead of only warning
+    pub require_frontends: bool,
+    // serve this frontend (`goldie` or `phippy`) at the root instead of
+    // redirecting to its mount, with unmatched page loads falling back to
+    // its index.html. Other unmatched requests still get the JSON 404. It
+    // stays available under its own mount too
+    pub root_frontend: Option<String>,
+    // addresses of reverse proxies whose `X-Forwarded-For` names the client,
+    // e.g. `127.0.0.1`. Per-client download quotas and rate limits then
+    // apply to that client instead of the proxy, and the proxy's
+    // `X-Forwarded-Host` and `X-Forwarded-Proto` set the advertised join URL
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+impl ServerSettings {
+    /// The configured prefix with a leading and no trailing slash, or an
+    /// empty string when serving from the root.
+    pub fn base_path(&self) -> String {
+        let trimmed = self.base_path.trim_matches('/');
+        if trimmed.is_empty() {
+            String::new()
+        } else {
+            format!("/{}", trimmed)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SseSettings {
+    // queue updates to one client within this window are collapsed into the
+    // latest, sparing slow clients the bursts from imports and reorders.
+    // Zero sends every update
+    pub queue_coalesce_ms: u64,
+    // events kept per room for clients reconnecting with `Last-Event-ID`
+    // or lagging behind, which also sizes the channel to the clients. Size
+    // it to the events expected during a typical disconnect
+    pub replay_buffer: usize,
+}
+
+impl Default for SseSettings {
+    fn default() -> Self {
+        SseSettings {
+            queue_coalesce_ms: 50,
+            replay_buffer: 256,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TranscodeSettings {
+    // empty keeps the fast single-rendition `-c:v copy` path
+    pub renditions: Vec<Rendition>,
+    // ffmpeg runs allowed at once, downloads are not limited by this
+    pub max_concurrent: usize,
+    // limit pitch-shifted audio to avoid clipping on large shifts
+    pub limiter: bool,
+    // rubberband settings for pitch shifting: `default`, `fast` for weak
+    // hardware like a Pi, or `quality` for strong hosts
+    pub pitch_preset: PitchPreset,
+    // semitone shifts encoded up front for key-changeable songs, separate
+    // from the keys playback.max_key_shift lets singers pick. A narrower set
+    // transcodes faster, but keys outside it are encoded when first
+    // requested, which takes a while and re-downloads the source unless
+    // download.retain_source is set
+    pub pitch_shifts: Vec<i32>,
+    // fixed output format for playback hardware that needs one, e.g. 48000
+    // and 2. Unset keeps the source's
+    pub audio_sample_rate: Option<u32>,
+    pub audio_channels: Option<u32>,
+    // `source` keeps the music video, `still` or `pattern` replace it with a
+    // generated background so only the audio is downloaded
+    pub video_output: VideoOutput,
+    // times a failed transcode is run again before the song fails. The
+    // downloaded source is reused, only ffmpeg is rerun
+    pub retries: u32,
+}
+
+impl Default for TranscodeSettings {
+    fn default() -> Self {
+        TranscodeSettings {
+            renditions: Vec::new(),
+            max_concurrent: 1,
+            limiter: false,
+            pitch_preset: PitchPreset::default(),
+            pitch_shifts: (-3..=3).collect(),
+            audio_sample_rate: None,
+            audio_channels: None,
+            video_output: VideoOutput::default(),
+            retries: 1,
+        }
+    }
+}
+
+impl Settings {
+    /// Loads settings from an optional `ferris.{toml,json,yaml}` file in the
+    /// config dir, overridden by `FERRIS_`-prefixed environment variables
+    /// (nested keys separated by `__`).
+    pub fn load(config_dir: &Path) -> Result<Self, ConfigError> {
+        Config::builder()
+            .add_source(File::from(config_dir.join("ferris")).required(false))
+            .add_source(environment())
+            .build()?
+            .try_deserialize()
+    }
+}
+
+/// The `FERRIS_` environment source. The list settings registered here are
+/// given comma separated, e.g.
+/// `FERRIS_SERVER__TRUSTED_PROXIES=10.0.0.1,10.0.0.2`, and every other value
+/// stays a single string even if it has commas. `queue.title_noise_patterns`
+/// is left out as regexes can have commas, and `transcode.renditions` as it's
+/// a list of tables, so both are only set from the config file.
+fn environment() -> Environment {
+    Environment::with_prefix("FERRIS")
+        .prefix_separator("_")
+        .separator("__")
+        .try_parsing(true)
+        .list_separator(",")
+        .with_list_parse_key("cors.allowed_origins")
+        .with_list_parse_key("cors.allowed_methods")
+        .with_list_parse_key("cors.allowed_headers")
+        .with_list_parse_key("server.trusted_proxies")
+        .with_list_parse_key("queue.sort_articles")
+        .with_list_parse_key("search.explicit_terms")
+        .with_list_parse_key("transcode.pitch_shifts")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_env(vars: &[(&str, &str)]) -> Settings {
+        let vars = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Config::builder()
+            .add_source(environment().source(Some(vars)))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    #[test]
+    fn list_settings_are_read_comma_separated_from_the_environment() {
+        let settings = from_env(&[
+            (
+                "FERRIS_CORS__ALLOWED_ORIGINS",
+                "http://karaoke.local,http://10.0.0.5:8000",
+            ),
+            ("FERRIS_CORS__ALLOWED_METHODS", "GET"),
+            ("FERRIS_SERVER__TRUSTED_PROXIES", "10.0.0.1,10.0.0.2"),
+            ("FERRIS_SEARCH__KARAOKE_TERMS", "karaoke, instrumental"),
+            ("FERRIS_QUEUE__SORT_ARTICLES", "der,die,das"),
+            ("FERRIS_SEARCH__EXPLICIT_TERMS", "explicit"),
+            ("FERRIS_TRANSCODE__PITCH_SHIFTS", "-2,0,2"),
+        ]);
+
+        assert_eq!(
+            settings.cors.allowed_origins,
+            ["http://karaoke.local", "http://10.0.0.5:8000"]
+        );
+        assert_eq!(settings.cors.allowed_methods, ["GET"]);
+        assert_eq!(
+            settings.server.trusted_proxies,
+            [
+                "10.0.0.1".parse::<IpAddr>().unwrap(),
+                "10.0.0.2".parse().unwrap()
+            ]
+        );
+        assert_eq!(settings.queue.sort_articles, ["der", "die", "das"]);
+        assert_eq!(settings.search.explicit_terms, ["explicit"]);
+        assert_eq!(settings.transcode.pitch_shifts, [-2, 0, 2]);
+        // not a list setting, so the comma is kept
+        assert_eq!(settings.search.karaoke_terms, "karaoke, instrumental");
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicI32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::{actors::video_downloader::VideoDlActorHandle, rooms::Rooms, routes::sse::SseEvent};
+
+/// Shared trigger for the graceful-shutdown path, used by both the OS signal
+/// handler and the admin shutdown endpoint. A shutdown is first requested,
+/// which starts draining downloads, and then triggered, which stops the
+/// server.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    drain: CancellationToken,
+    token: CancellationToken,
+    exit_code: Arc<AtomicI32>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Shutdown::default()
+    }
+
+    /// Starts a graceful shutdown through `drain_on_request`. Returns `false`
+    /// when one was already requested, leaving its exit code in place.
+    pub fn request(&self, exit_code: i32) -> bool {
+        if self.requested.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        info!("shutdown requested with exit code {}", exit_code);
+        self.exit_code.store(exit_code, Ordering::SeqCst);
+        self.drain.cancel();
+        true
+    }
+
+    /// Stops the server now, without waiting for downloads.
+    pub fn trigger(&self) {
+        info!("shutting down");
+        self.token.cancel();
+    }
+
+    pub async fn wait(&self) {
+        self.token.cancelled().await
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        self.exit_code.load(Ordering::SeqCst)
+    }
+}
+
+/// Once a shutdown is requested, tells clients the server is going away and
+/// gives in-flight downloads up to `drain_timeout` to finish before stopping
+/// it, so songs half way through a transcode aren't left broken.
+pub async fn drain_on_request(
+    shutdown: Shutdown,
+    rooms: Rooms,
+    videodl_actor_handle: Arc<VideoDlActorHandle>,
+    drain_timeout: Duration,
+) {
+    tokio::select! {
+        _ = shutdown.drain.cancelled() => {}
+        // triggered directly, nothing left to drain for
+        _ = shutdown.wait() => return,
+    }
+
+    rooms.broadcast_all(SseEvent::ServerRestarting);
+    videodl_actor_handle.drain(drain_timeout).await;
+    shutdown.trigger();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn requested_shutdowns_drain_before_stopping() {
+        let assets = tempfile::tempdir().unwrap();
+        let videodl = crate::actors::video_downloader::tests::handle(assets.path());
+        let rooms = crate::rooms::tests::rooms(1);
+        let (_, mut events, _) = rooms
+            .get("default")
+            .unwrap()
+            .sse_broadcaster
+            .subscribe_after(None);
+        let shutdown = Shutdown::new();
+        let draining = tokio::spawn(drain_on_request(
+            shutdown.clone(),
+            rooms,
+            Arc::new(videodl),
+            Duration::from_secs(5),
+        ));
+
+        assert!(shutdown.request(3));
+        // a later request doesn't replace the first one's exit code
+        assert!(!shutdown.request(0));
+        tokio::time::timeout(Duration::from_secs(5), shutdown.wait())
+            .await
+            .unwrap();
+        draining.await.unwrap();
+
+        assert_eq!(shutdown.exit_code(), 3);
+        let (_, event) = events.recv().await.unwrap();
+        assert!(matches!(event, SseEvent::ServerRestarting));
+    }
+}
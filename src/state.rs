@@ -1,55 +1,113 @@
 use std::sync::Arc;
 
 use axum::extract::FromRef;
-use tokio::sync;
 
-use crate::{actors::{song_coordinator::SongActorHandle, video_downloader::VideoDlActorHandle, video_searcher::VideoSearcherActorHandle}, routes::sse::SseEvent};
+use crate::{
+    actors::{video_downloader::VideoDlActorHandle, video_searcher::VideoSearcherActorHandle},
+    event_log::EventLog,
+    rooms::Rooms,
+    routes::{healthcheck::DependencyProbe, streaming::ManifestCache},
+    settings::Settings,
+    shutdown::Shutdown,
+    utils::{
+        binary::{BinaryPaths, RecordedVersions},
+        process::ProcessGroups,
+        slug::AssetPaths,
+        title::TitleCleaner,
+    },
+};
 
 #[derive(Clone)]
 pub struct AppState {
-    pub song_actor_handle: Arc<SongActorHandle>,
+    pub rooms: Rooms,
     pub videodl_actor_handle: Arc<VideoDlActorHandle>,
     pub videosearcher_actor_handle: Arc<VideoSearcherActorHandle>,
-    pub sse_broadcaster: Arc<sync::broadcast::Sender<SseEvent>>
+    pub settings: Arc<Settings>,
+    pub binaries: BinaryPaths,
+    pub dependency_probe: DependencyProbe,
+    pub versions: RecordedVersions,
+    pub assets: AssetPaths,
+    pub manifests: ManifestCache,
+    pub processes: ProcessGroups,
+    pub shutdown: Shutdown,
+    pub event_log: EventLog,
+    pub title_cleaner: Arc<TitleCleaner>,
 }
 
-impl AppState {
-    pub fn new(
-        song_actor_handle: Arc<SongActorHandle>,
-        videodl_actor_handle: Arc<VideoDlActorHandle>,
-        videosearcher_actor_handle: Arc<VideoSearcherActorHandle>,
-        sse_broadcaster: Arc<sync::broadcast::Sender<SseEvent>>
-    ) -> Self {
-        AppState {
-            song_actor_handle,
-            videodl_actor_handle,
-            videosearcher_actor_handle,
-            sse_broadcaster
-        }
+impl FromRef<AppState> for Arc<VideoDlActorHandle> {
+    fn from_ref(app_state: &AppState) -> Self {
+        app_state.videodl_actor_handle.clone()
     }
 }
 
-impl FromRef<AppState> for Arc<SongActorHandle> {
+impl FromRef<AppState> for Arc<VideoSearcherActorHandle> {
     fn from_ref(app_state: &AppState) -> Self {
-        app_state.song_actor_handle.clone()
+        app_state.videosearcher_actor_handle.clone()
     }
 }
 
-impl FromRef<AppState> for Arc<VideoDlActorHandle> {
+impl FromRef<AppState> for Arc<Settings> {
     fn from_ref(app_state: &AppState) -> Self {
-        app_state.videodl_actor_handle.clone()
+        app_state.settings.clone()
     }
 }
 
-impl FromRef<AppState> for Arc<VideoSearcherActorHandle> {
+impl FromRef<AppState> for Shutdown {
     fn from_ref(app_state: &AppState) -> Self {
-        app_state.videosearcher_actor_handle.clone()
+        app_state.shutdown.clone()
+    }
+}
+
+impl FromRef<AppState> for EventLog {
+    fn from_ref(app_state: &AppState) -> Self {
+        app_state.event_log.clone()
+    }
+}
+
+impl FromRef<AppState> for BinaryPaths {
+    fn from_ref(app_state: &AppState) -> Self {
+        app_state.binaries.clone()
+    }
+}
+
+impl FromRef<AppState> for DependencyProbe {
+    fn from_ref(app_state: &AppState) -> Self {
+        app_state.dependency_probe.clone()
+    }
+}
+
+impl FromRef<AppState> for RecordedVersions {
+    fn from_ref(app_state: &AppState) -> Self {
+        app_state.versions.clone()
+    }
+}
+
+impl FromRef<AppState> for AssetPaths {
+    fn from_ref(app_state: &AppState) -> Self {
+        app_state.assets.clone()
+    }
+}
+
+impl FromRef<AppState> for ManifestCache {
+    fn from_ref(app_state: &AppState) -> Self {
+        app_state.manifests.clone()
+    }
+}
+
+impl FromRef<AppState> for ProcessGroups {
+    fn from_ref(app_state: &AppState) -> Self {
+        app_state.processes.clone()
     }
 }
 
-impl FromRef<AppState> for Arc<sync::broadcast::Sender<SseEvent>> {
+impl FromRef<AppState> for Rooms {
     fn from_ref(app_state: &AppState) -> Self {
-        app_state.sse_broadcaster.clone()
+        app_state.rooms.clone()
     }
 }
 
+impl FromRef<AppState> for Arc<TitleCleaner> {
+    fn from_ref(app_state: &AppState) -> Self {
+        app_state.title_cleaner.clone()
+    }
+}
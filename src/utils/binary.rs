@@ -1,6 +1,14 @@
-use std::{fs, path::PathBuf, process::Command};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Arc, RwLock},
+};
 use rust_embed::RustEmbed;
-use tracing::{debug, error, info};
+use serde::Serialize;
+use tracing::{debug, error, info, warn};
+
+use crate::settings::BinarySettings;
 
 #[derive(RustEmbed)]
 #[folder = "embedded/"]
@@ -20,7 +28,14 @@ impl Binary {
         }
     }
 
-    fn get_path(&self, config_dir: &PathBuf) -> PathBuf {
+    fn version_flag(&self) -> &'static str {
+        match self {
+            Binary::Ytdlp => "--version",
+            Binary::Ffmpeg => "-version",
+        }
+    }
+
+    fn get_path(&self, config_dir: &Path) -> PathBuf {
         config_dir.join(if cfg!(windows) {
             format!("{}.exe", self.name())
         } else {
@@ -29,6 +44,23 @@ impl Binary {
     }
 }
 
+/// Where the binaries live, bundled or external, handed to whatever runs
+/// them.
+#[derive(Debug, Clone)]
+pub struct BinaryPaths {
+    pub ytdlp: PathBuf,
+    pub ffmpeg: PathBuf,
+}
+
+impl BinaryPaths {
+    pub fn in_dir(config_dir: &Path) -> Self {
+        BinaryPaths {
+            ytdlp: Binary::Ytdlp.get_path(config_dir),
+            ffmpeg: Binary::Ffmpeg.get_path(config_dir),
+        }
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum DependencyError {
     #[error("IO error: {0}")]
@@ -37,6 +69,9 @@ pub enum DependencyError {
     #[error("Failed to determine config directory")]
     NoConfigDir,
 
+    #[error("Config directory already set to {}", .0.display())]
+    ConfigDirAlreadySet(PathBuf),
+
     #[error("Could not find embedded binary: {0}")]
     MissingBinary(String),
 
@@ -45,16 +80,27 @@ pub enum DependencyError {
 
     #[error("Command failed: {0}")]
     CommandFailed(String),
-}
 
-pub fn update_ytdlp(config_dir: &PathBuf) -> Result<(), DependencyError> {
-    let ytdlp_path = Binary::Ytdlp.get_path(config_dir);
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+
+    #[error("External {} not found or not executable: {}", .0, .1.display())]
+    ExternalBinaryNotFound(&'static str, PathBuf),
+
+    #[error("Assets directory {} is not writable: {}", .0.display(), .1)]
+    AssetsNotWritable(PathBuf, std::io::Error),
+
+    #[error("Frontends not embedded in this build: {}", .0.join(", "))]
+    MissingFrontends(Vec<&'static str>),
+}
 
+/// Runs yt-dlp's self-update, replacing the binary at `ytdlp_path`.
+pub fn update_ytdlp(ytdlp_path: &Path) -> Result<(), DependencyError> {
     debug!(
         "Updating yt-dlp at path: {}",
         ytdlp_path.display()
     );
-    let status = Command::new(&ytdlp_path).arg("-U").status().map_err(|e| {
+    let status = Command::new(ytdlp_path).arg("-U").status().map_err(|e| {
         error!("Failed to execute yt-dlp update: {}", e);
         DependencyError::CommandFailed(e.to_string())
     })?;
@@ -68,7 +114,80 @@ pub fn update_ytdlp(config_dir: &PathBuf) -> Result<(), DependencyError> {
     Ok(())
 }
 
-pub fn setup_binary(binary: Binary, config_dir: &PathBuf) -> Result<(), DependencyError> {
+/// Extracts the embedded binaries that aren't replaced by external ones and
+/// updates the embedded yt-dlp. External binaries are only checked.
+pub fn setup_binaries(
+    config_dir: &Path,
+    settings: &BinarySettings,
+) -> Result<BinaryPaths, DependencyError> {
+    let embedded = BinaryPaths::in_dir(config_dir);
+
+    let ffmpeg = match &settings.ffmpeg {
+        Some(configured) => resolve_external(Binary::Ffmpeg, configured)?,
+        None => {
+            setup_binary(Binary::Ffmpeg, config_dir)?;
+            embedded.ffmpeg
+        }
+    };
+
+    let ytdlp = match &settings.ytdlp {
+        Some(configured) => resolve_external(Binary::Ytdlp, configured)?,
+        None => {
+            setup_binary(Binary::Ytdlp, config_dir)?;
+            update_ytdlp(&embedded.ytdlp)?;
+            embedded.ytdlp
+        }
+    };
+
+    Ok(BinaryPaths { ytdlp, ffmpeg })
+}
+
+/// Finds a configured external binary, given as a path or as a name looked
+/// up in `PATH`.
+fn resolve_external(binary: Binary, configured: &Path) -> Result<PathBuf, DependencyError> {
+    let configured = if cfg!(windows) && configured.extension().is_none() {
+        configured.with_extension("exe")
+    } else {
+        configured.to_path_buf()
+    };
+
+    let candidates: Vec<PathBuf> = if configured.components().count() > 1 {
+        vec![configured.clone()]
+    } else {
+        env::var_os("PATH")
+            .map(|paths| {
+                env::split_paths(&paths)
+                    .map(|dir| dir.join(&configured))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+
+    let path = candidates
+        .into_iter()
+        .find(|candidate| is_executable(candidate))
+        .ok_or_else(|| DependencyError::ExternalBinaryNotFound(binary.name(), configured))?;
+    info!("Using external {} at {}", binary.name(), path.display());
+    Ok(path)
+}
+
+fn is_executable(path: &Path) -> bool {
+    let Ok(metadata) = fs::metadata(path) else {
+        return false;
+    };
+
+    #[cfg(unix)]
+    let executable = {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o111 != 0
+    };
+    #[cfg(not(unix))]
+    let executable = true;
+
+    metadata.is_file() && executable
+}
+
+fn setup_binary(binary: Binary, config_dir: &Path) -> Result<(), DependencyError> {
     let name = binary.name();
     let bin_path = binary.get_path(config_dir);
 
@@ -119,4 +238,69 @@ pub fn setup_binary(binary: Binary, config_dir: &PathBuf) -> Result<(), Dependen
 
     info!("Successfully set up binary: {}", name);
     Ok(())
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BinaryVersions {
+    pub ffmpeg: Option<String>,
+    pub yt_dlp: Option<String>,
+}
+
+/// Runs the binary's version flag and returns the version it reports, e.g.
+/// `2024.12.13` for yt-dlp or `7.1` from ffmpeg's `ffmpeg version 7.1 ...`.
+fn binary_version(binary: Binary, path: &Path) -> Option<String> {
+    let output = Command::new(path)
+        .arg(binary.version_flag())
+        .output()
+        .map_err(|e| warn!("Failed to run {} for its version: {}", binary.name(), e))
+        .ok()?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let first_line = stdout.lines().next()?.trim();
+    let version = match first_line.split_once(" version ") {
+        Some((_, rest)) => rest.split_whitespace().next()?,
+        None => first_line,
+    };
+    Some(version.to_string())
+}
+
+/// Runs both binaries for their versions. A `None` version means the binary
+/// could not be run.
+pub fn run_versions(binaries: &BinaryPaths) -> BinaryVersions {
+    BinaryVersions {
+        ffmpeg: binary_version(Binary::Ffmpeg, &binaries.ffmpeg),
+        yt_dlp: binary_version(Binary::Ytdlp, &binaries.ytdlp),
+    }
+}
+
+/// The binary versions recorded at startup, reported by `/version` and
+/// kept current when yt-dlp updates itself.
+#[derive(Debug, Clone, Default)]
+pub struct RecordedVersions(Arc<RwLock<Option<BinaryVersions>>>);
+
+impl RecordedVersions {
+    pub fn new(versions: BinaryVersions) -> Self {
+        RecordedVersions(Arc::new(RwLock::new(Some(versions))))
+    }
+
+    /// Records the versions of the set up binaries at startup.
+    pub fn probe(binaries: &BinaryPaths) -> Self {
+        let versions = run_versions(binaries);
+        info!("Binary versions: {:?}", versions);
+        RecordedVersions::new(versions)
+    }
+
+    /// The recorded versions, or `None` when nothing was probed.
+    pub fn get(&self) -> Option<BinaryVersions> {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Re-reads the yt-dlp version after it updated itself at runtime.
+    pub fn refresh_ytdlp(&self, ytdlp_path: &Path) -> Option<String> {
+        let version = binary_version(Binary::Ytdlp, ytdlp_path);
+        if let Some(versions) = self.0.write().unwrap().as_mut() {
+            versions.yt_dlp = version.clone();
+        }
+        version
+    }
+}
@@ -1,7 +1,39 @@
-use std::process::Command;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::PathBuf};
+use tokio::process::Command;
 use tracing::{debug, error};
 
-use crate::globals;
+use crate::{
+    settings::{DownloadSettings, TranscodeSettings},
+    utils::process::ProcessGroups,
+};
+
+/// Segment names passed to ffmpeg, kept here so cache validation can derive
+/// the same names. These match ffmpeg's own defaults.
+pub const INIT_SEGMENT_TEMPLATE: &str = "init-stream$RepresentationID$.$ext$";
+pub const MEDIA_SEGMENT_TEMPLATE: &str = "chunk-stream$RepresentationID$-$Number%05d$.$ext$";
+
+/// Expands a media segment template for one representation and segment
+/// number. Supports `$Number$` and zero-padded `$Number%0Nd$`.
+pub fn media_segment_name(template: &str, representation_id: usize, number: u32) -> String {
+    let mut name = template
+        .replace("$RepresentationID$", &representation_id.to_string())
+        .replace("$ext$", "m4s")
+        .replace("$Number$", &number.to_string());
+
+    if let Some(start) = name.find("$Number%0") {
+        let width_start = start + "$Number%0".len();
+        if let Some(width_len) = name[width_start..].find("d$") {
+            let width = name[width_start..width_start + width_len]
+                .parse::<usize>()
+                .unwrap_or_default();
+            let end = width_start + width_len + "d$".len();
+            name.replace_range(start..end, &format!("{:0width$}", number, width = width));
+        }
+    }
+
+    name
+}
 
 #[derive(Debug)]
 pub enum ProcessingMode {
@@ -9,20 +41,246 @@ pub enum ProcessingMode {
     PitchShift(Vec<i32>),
 }
 
+impl ProcessingMode {
+    /// The mode a song is first processed with, rendering `pitch_shifts` for
+    /// key-changeable songs. The original key is always included.
+    pub fn for_song(is_key_changeable: bool, pitch_shifts: &[i32]) -> Self {
+        if is_key_changeable {
+            let mut shifts = pitch_shifts.to_vec();
+            shifts.push(0);
+            shifts.sort_unstable();
+            shifts.dedup();
+            ProcessingMode::PitchShift(shifts)
+        } else {
+            ProcessingMode::Copy
+        }
+    }
+
+    /// Maps each semitone shift to the adaptation set id of its audio stream,
+    /// matching the layout from `build_adaptation_sets`.
+    pub fn audio_adaptation_sets(&self) -> BTreeMap<i32, usize> {
+        match self {
+            ProcessingMode::Copy => BTreeMap::from([(0, 1)]),
+            ProcessingMode::PitchShift(shifts) => shifts
+                .iter()
+                .enumerate()
+                .map(|(i, shift)| (*shift, i + 1))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Rendition {
+    pub height: u32,
+    pub video_bitrate: String,
+}
+
+/// rubberband settings for pitch-shifted streams, trading quality for CPU
+/// time. Options ffmpeg doesn't know would fail every transcode, so only
+/// these fixed sets are offered.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PitchPreset {
+    /// ffmpeg's defaults.
+    #[default]
+    Default,
+    /// Short windows and speed-first pitch handling, for a Pi.
+    Fast,
+    /// Long windows with smoothing, several times slower.
+    Quality,
+}
+
+impl PitchPreset {
+    /// The options appended to `rubberband=pitch=<ratio>`.
+    fn rubberband_options(&self) -> &'static str {
+        match self {
+            PitchPreset::Default => "",
+            PitchPreset::Fast => ":transients=crisp:window=short:pitchq=speed",
+            PitchPreset::Quality => ":transients=mixed:window=long:smoothing=on:pitchq=quality",
+        }
+    }
+}
+
+/// Where a song's video stream comes from. Generated backgrounds replace
+/// the music video with a cheap lavfi source, so only the audio has to be
+/// downloaded and the video encodes in a fraction of the time and size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VideoOutput {
+    /// The downloaded music video.
+    #[default]
+    Source,
+    /// A single dark frame.
+    Still,
+    /// A slowly evolving cellular pattern.
+    Pattern,
+}
+
+impl VideoOutput {
+    /// The lavfi graph generating the background, `None` for the source.
+    fn lavfi_source(&self) -> Option<&'static str> {
+        match self {
+            VideoOutput::Source => None,
+            VideoOutput::Still => Some("color=c=0x14141f:s=1280x720:r=1"),
+            VideoOutput::Pattern => Some(
+                "life=s=640x360:r=10:ratio=0.08:mold=20:life_color=0x6b4fbb:death_color=0x14141f:mold_color=0x2a2440",
+            ),
+        }
+    }
+
+    /// Whether the source video goes unused, so only its audio is needed.
+    pub fn is_generated(&self) -> bool {
+        *self != VideoOutput::Source
+    }
+}
+
 pub struct DashProcessor {
+    ffmpeg_path: PathBuf,
+    processes: ProcessGroups,
     segment_duration: u32,
+    renditions: Vec<Rendition>,
+    audio_only: bool,
+    video_output: VideoOutput,
+    limiter: bool,
+    pitch_preset: PitchPreset,
+    sample_rate: Option<u32>,
+    channels: Option<u32>,
 }
 
 impl DashProcessor {
-    pub fn new(segment_duration: u32) -> Self {
-        DashProcessor { segment_duration }
+    /// A processor configured the way downloads are transcoded.
+    pub fn from_settings(
+        ffmpeg_path: PathBuf,
+        processes: ProcessGroups,
+        segment_duration: u32,
+        download_settings: &DownloadSettings,
+        transcode_settings: &TranscodeSettings,
+    ) -> Self {
+        DashProcessor::new(ffmpeg_path, processes, segment_duration)
+            .with_renditions(transcode_settings.renditions.clone())
+            .with_audio_only(download_settings.audio_only)
+            .with_video_output(transcode_settings.video_output)
+            .with_limiter(transcode_settings.limiter)
+            .with_pitch_preset(transcode_settings.pitch_preset)
+            .with_audio_format(
+                transcode_settings.audio_sample_rate,
+                transcode_settings.audio_channels,
+            )
+    }
+
+    pub fn new(ffmpeg_path: PathBuf, processes: ProcessGroups, segment_duration: u32) -> Self {
+        DashProcessor {
+            ffmpeg_path,
+            processes,
+            segment_duration,
+            renditions: Vec::new(),
+            audio_only: false,
+            video_output: VideoOutput::Source,
+            limiter: false,
+            pitch_preset: PitchPreset::Default,
+            sample_rate: None,
+            channels: None,
+        }
+    }
+
+    /// Re-encodes the video into one scaled stream per rendition instead of
+    /// copying the source stream. An empty ladder keeps the copy path.
+    pub fn with_renditions(mut self, renditions: Vec<Rendition>) -> Self {
+        self.renditions = renditions;
+        self
+    }
+
+    /// Leaves the video out of the output entirely, for setups that show
+    /// lyrics on another device. Renditions are ignored.
+    pub fn with_audio_only(mut self, audio_only: bool) -> Self {
+        self.audio_only = audio_only;
+        self
+    }
+
+    /// Replaces the source video with a generated background encoded as a
+    /// single stream. Renditions are ignored, and so is this when the output
+    /// is audio only.
+    pub fn with_video_output(mut self, video_output: VideoOutput) -> Self {
+        self.video_output = video_output;
+        self
+    }
+
+    /// Adds a limiter after loudnorm on pitch-shifted streams, catching the
+    /// clipping large shifts can introduce.
+    pub fn with_limiter(mut self, limiter: bool) -> Self {
+        self.limiter = limiter;
+        self
+    }
+
+    /// Sets the rubberband options used for every pitch-shifted stream.
+    pub fn with_pitch_preset(mut self, pitch_preset: PitchPreset) -> Self {
+        self.pitch_preset = pitch_preset;
+        self
+    }
+
+    /// Resamples and remixes every audio stream to a fixed format, for
+    /// playback hardware that can't handle whatever the source carries.
+    /// `None` keeps what the filter chain outputs.
+    pub fn with_audio_format(mut self, sample_rate: Option<u32>, channels: Option<u32>) -> Self {
+        self.sample_rate = sample_rate;
+        self.channels = channels;
+        self
+    }
+
+    /// The lavfi graph read as a second input in place of the source video.
+    fn generated_video(&self) -> Option<&'static str> {
+        if self.audio_only {
+            None
+        } else {
+            self.video_output.lavfi_source()
+        }
+    }
+
+    fn num_video_streams(&self) -> usize {
+        if self.audio_only {
+            0
+        } else if self.generated_video().is_some() {
+            1
+        } else {
+            self.renditions.len().max(1)
+        }
+    }
+
+    fn build_video_filter(&self) -> Option<String> {
+        if self.audio_only || self.generated_video().is_some() || self.renditions.is_empty() {
+            return None;
+        }
+
+        let mut filter = format!("[0:v]split={}", self.renditions.len());
+        for i in 0..self.renditions.len() {
+            filter.push_str(&format!("[v{}]", i));
+        }
+        filter.push(';');
+
+        for (i, rendition) in self.renditions.iter().enumerate() {
+            filter.push_str(&format!(
+                " [v{}]scale=-2:{}[vout{}];",
+                i, rendition.height, i
+            ));
+        }
+
+        filter.pop(); // Remove the last semicolon
+        Some(filter)
     }
 
     fn build_filter_complex(&self, mode: &ProcessingMode) -> Option<String> {
+        let audio_filter = self.build_audio_filter(mode);
+
+        match self.build_video_filter() {
+            Some(video_filter) => Some(format!("{};{}", video_filter, audio_filter)),
+            None => Some(audio_filter),
+        }
+    }
+
+    fn build_audio_filter(&self, mode: &ProcessingMode) -> String {
         match mode {
-            ProcessingMode::Copy => {
-                Some("[0:a]loudnorm=I=-16:TP=-1.5:LRA=11[normalized]".to_string())
-            }
+            ProcessingMode::Copy => "[0:a]loudnorm=I=-16:TP=-1.5:LRA=11[normalized]".to_string(),
             ProcessingMode::PitchShift(shifts) => {
                 let num_streams = shifts.len();
                 let mut filter = format!("[0:a]asplit={}", num_streams);
@@ -33,36 +291,70 @@ impl DashProcessor {
                 }
                 filter.push(';');
 
+                // ceiling just under loudnorm's -1.5 dBTP target
+                let limiter = if self.limiter {
+                    ",alimiter=limit=0.84"
+                } else {
+                    ""
+                };
+
                 // Process each stream with pitch shift and normalization
                 for (i, semitones) in shifts.iter().enumerate() {
                     let rate_multiplier = 2f64.powf(*semitones as f64 / 12.0);
                     filter.push_str(&format!(
-                        " [a{}]rubberband=pitch={},loudnorm=I=-16:TP=-1.5:LRA=11[p{}];",
-                        i, rate_multiplier, i
+                        " [a{}]rubberband=pitch={}{},loudnorm=I=-16:TP=-1.5:LRA=11{}[p{}];",
+                        i,
+                        rate_multiplier,
+                        self.pitch_preset.rubberband_options(),
+                        limiter,
+                        i
                     ));
                 }
 
                 filter.pop(); // Remove the last semicolon
-                Some(filter)
+                filter
             }
         }
     }
 
     fn build_adaptation_sets(&self, mode: &ProcessingMode) -> String {
-        match mode {
-            ProcessingMode::Copy => "id=0,streams=0 id=1,streams=1".to_string(),
-            ProcessingMode::PitchShift(shifts) => {
-                let mut adaptation_sets = String::from("id=0,streams=0 ");
-                for (i, _) in shifts.iter().enumerate() {
-                    adaptation_sets.push_str(&format!("id={},streams={} ", i + 1, i + 1));
-                }
-                adaptation_sets.trim().to_string()
-            }
+        // all video renditions share adaptation set 0 so players can switch
+        // between them, and the audio set ids stay the same as the copy path
+        let num_video_streams = self.num_video_streams();
+        let mut adaptation_sets = String::new();
+        if num_video_streams > 0 {
+            let video_streams = (0..num_video_streams)
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            adaptation_sets.push_str(&format!("id=0,streams={} ", video_streams));
         }
+
+        let num_audio_streams = match mode {
+            ProcessingMode::Copy => 1,
+            ProcessingMode::PitchShift(shifts) => shifts.len(),
+        };
+        for i in 0..num_audio_streams {
+            adaptation_sets.push_str(&format!("id={},streams={} ", i + 1, num_video_streams + i));
+        }
+        adaptation_sets.trim().to_string()
     }
 
     fn build_stream_mappings(&self, mode: &ProcessingMode) -> Vec<String> {
-        let mut mappings = vec!["-map".to_string(), "0:v".to_string()];
+        let mut mappings = Vec::new();
+
+        if self.audio_only {
+            // no video mapping
+        } else if self.generated_video().is_some() {
+            mappings.extend(vec!["-map".to_string(), "1:v".to_string()]);
+        } else if self.renditions.is_empty() {
+            mappings.extend(vec!["-map".to_string(), "0:v".to_string()]);
+        } else {
+            for i in 0..self.renditions.len() {
+                mappings.push("-map".to_string());
+                mappings.push(format!("[vout{}]", i));
+            }
+        }
 
         match mode {
             ProcessingMode::Copy => {
@@ -79,6 +371,40 @@ impl DashProcessor {
         mappings
     }
 
+    fn build_video_encodings(&self) -> Vec<String> {
+        if self.audio_only {
+            return Vec::new();
+        }
+
+        let mut encodings = Vec::new();
+        if self.generated_video().is_some() {
+            encodings.extend(vec![
+                "-c:v".to_string(),
+                "libx264".to_string(),
+                "-preset".to_string(),
+                "veryfast".to_string(),
+                "-pix_fmt".to_string(),
+                "yuv420p".to_string(),
+            ]);
+        } else if self.renditions.is_empty() {
+            return vec!["-c:v".to_string(), "copy".to_string()];
+        } else {
+            for (i, rendition) in self.renditions.iter().enumerate() {
+                encodings.push(format!("-c:v:{}", i));
+                encodings.push("libx264".to_string());
+                encodings.push(format!("-b:v:{}", i));
+                encodings.push(rendition.video_bitrate.clone());
+            }
+        }
+
+        // keyframes must line up with segment boundaries for switching
+        encodings.extend(vec![
+            "-force_key_frames".to_string(),
+            format!("expr:gte(t,n_forced*{})", self.segment_duration),
+        ]);
+        encodings
+    }
+
     fn build_audio_encodings(&self, mode: &ProcessingMode) -> Vec<String> {
         let mut encodings = Vec::new();
 
@@ -101,48 +427,374 @@ impl DashProcessor {
             }
         }
 
+        // unindexed, so they apply to every audio stream whatever the mode
+        if let Some(sample_rate) = self.sample_rate {
+            encodings.push("-ar".to_string());
+            encodings.push(sample_rate.to_string());
+        }
+        if let Some(channels) = self.channels {
+            encodings.push("-ac".to_string());
+            encodings.push(channels.to_string());
+        }
+
         encodings
     }
 
-    pub fn execute(
+    /// The ffmpeg arguments `execute` runs, without the program itself.
+    pub fn build_command_args(
         &self,
         input_file: &str,
         output_file: &str,
         mode: &ProcessingMode,
-    ) -> std::io::Result<()> {
-        let ffmpeg_path = globals::get_binary_path("ffmpeg");
-        debug!("Using FFmpeg from path: {}", ffmpeg_path.display());
-
-        let mut command = Command::new(ffmpeg_path);
-        command.arg("-i").arg(input_file).arg("-c:v").arg("copy");
+    ) -> Vec<String> {
+        let mut args = vec!["-i".to_string(), input_file.to_string()];
+        if let Some(lavfi_source) = self.generated_video() {
+            args.extend([
+                "-f".to_string(),
+                "lavfi".to_string(),
+                "-i".to_string(),
+                lavfi_source.to_string(),
+            ]);
+        }
 
         // Add filter complex if needed
         if let Some(filter_complex) = self.build_filter_complex(mode) {
-            command.arg("-filter_complex").arg(filter_complex);
+            args.push("-filter_complex".to_string());
+            args.push(filter_complex);
         }
 
-        command
-            .args(self.build_stream_mappings(mode))
-            .args(self.build_audio_encodings(mode))
-            .arg("-f")
-            .arg("dash")
-            .arg("-adaptation_sets")
-            .arg(self.build_adaptation_sets(mode))
-            .arg("-seg_duration")
-            .arg(self.segment_duration.to_string())
-            .arg(output_file);
+        args.extend(self.build_stream_mappings(mode));
+        args.extend(self.build_video_encodings());
+        args.extend(self.build_audio_encodings(mode));
+        if self.generated_video().is_some() {
+            // the generated input never ends, stop with the song
+            args.push("-shortest".to_string());
+        }
+        args.extend([
+            "-f".to_string(),
+            "dash".to_string(),
+            "-adaptation_sets".to_string(),
+            self.build_adaptation_sets(mode),
+            "-seg_duration".to_string(),
+            self.segment_duration.to_string(),
+            "-init_seg_name".to_string(),
+            INIT_SEGMENT_TEMPLATE.to_string(),
+            "-media_seg_name".to_string(),
+            MEDIA_SEGMENT_TEMPLATE.to_string(),
+            output_file.to_string(),
+        ]);
+        args
+    }
+
+    /// Runs ffmpeg. The child is killed if the returned future is dropped, so
+    /// a cancelled download doesn't leave a transcode running.
+    pub async fn execute(
+        &self,
+        input_file: &str,
+        output_file: &str,
+        mode: &ProcessingMode,
+    ) -> std::io::Result<()> {
+        debug!("Using FFmpeg from path: {}", self.ffmpeg_path.display());
+
+        let mut command = Command::new(&self.ffmpeg_path);
+        command.args(self.build_command_args(input_file, output_file, mode));
 
         debug!("ffmpeg command: {:?}", command);
 
-        let output = command.output()?;
+        let output = self.processes.output(&mut command).await?;
         if !output.status.success() {
             let error = String::from_utf8_lossy(&output.stderr);
             error!("FFmpeg error: {}", error);
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "FFmpeg command failed",
-            ));
+            return Err(std::io::Error::other(format!(
+                "FFmpeg command failed: {}",
+                error.trim()
+            )));
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segment_names_expand_like_ffmpeg() {
+        assert_eq!(
+            media_segment_name(MEDIA_SEGMENT_TEMPLATE, 1, 42),
+            "chunk-stream1-00042.m4s"
+        );
+        assert_eq!(
+            media_segment_name("seg-$RepresentationID$-$Number$.$ext$", 0, 7),
+            "seg-0-7.m4s"
+        );
+        // numbers wider than the padding aren't cut
+        assert_eq!(
+            media_segment_name("$Number%03d$.$ext$", 2, 12345),
+            "12345.m4s"
+        );
+    }
+
+    fn processor() -> DashProcessor {
+        DashProcessor::new(PathBuf::from("ffmpeg"), ProcessGroups::default(), 4)
+    }
+
+    /// The value following `flag` in `args`.
+    fn arg<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+        let index = args.iter().position(|arg| arg == flag)?;
+        args.get(index + 1).map(String::as_str)
+    }
+
+    #[test]
+    fn audio_only_output_leaves_out_the_video() {
+        let processor = processor()
+            .with_renditions(vec![Rendition {
+                height: 480,
+                video_bitrate: "1M".to_string(),
+            }])
+            .with_audio_only(true);
+        let mode = ProcessingMode::PitchShift(vec![0, -2]);
+        let args = processor.build_command_args("in.mp4", "out.mpd", &mode);
+
+        let filter = arg(&args, "-filter_complex").unwrap();
+        assert!(filter.starts_with("[0:a]asplit=2"));
+        assert!(!filter.contains("0:v"));
+        assert!(!args
+            .iter()
+            .any(|arg| arg == "0:v" || arg.starts_with("-c:v")));
+        assert_eq!(
+            arg(&args, "-adaptation_sets"),
+            Some("id=1,streams=0 id=2,streams=1")
+        );
+    }
+
+    #[test]
+    fn video_is_copied_by_default() {
+        let args = processor().build_command_args("in.mp4", "out.mpd", &ProcessingMode::Copy);
+        assert_eq!(arg(&args, "-c:v"), Some("copy"));
+        assert!(args.windows(2).any(|pair| pair == ["-map", "0:v"]));
+        assert_eq!(
+            arg(&args, "-adaptation_sets"),
+            Some("id=0,streams=0 id=1,streams=1")
+        );
+    }
+
+    #[test]
+    fn limiter_ends_every_pitch_shifted_chain() {
+        let limited = processor().with_limiter(true);
+        let mode = ProcessingMode::PitchShift(vec![-3, 0, 4]);
+        let args = limited.build_command_args("in.mp4", "out.mpd", &mode);
+
+        let filter = arg(&args, "-filter_complex").unwrap();
+        let chains = filter.split(';').map(str::trim).collect::<Vec<_>>();
+        assert_eq!(chains[0], "[0:a]asplit=3[a0][a1][a2]");
+        assert_eq!(chains.len(), 4);
+        for (i, chain) in chains[1..].iter().enumerate() {
+            assert!(chain.starts_with(&format!("[a{}]rubberband=pitch=", i)));
+            assert!(chain.ends_with(&format!(
+                ",loudnorm=I=-16:TP=-1.5:LRA=11,alimiter=limit=0.84[p{}]",
+                i
+            )));
+        }
+
+        // copies are only normalized, limiter or not
+        let copy = |processor: DashProcessor| {
+            processor.build_command_args("in.mp4", "out.mpd", &ProcessingMode::Copy)
+        };
+        assert_eq!(copy(limited), copy(processor()));
+    }
+
+    #[test]
+    fn copy_mode_runs_the_full_command() {
+        let args = processor().build_command_args("in.mp4", "out.mpd", &ProcessingMode::Copy);
+        assert_eq!(
+            args,
+            [
+                "-i",
+                "in.mp4",
+                "-filter_complex",
+                "[0:a]loudnorm=I=-16:TP=-1.5:LRA=11[normalized]",
+                "-map",
+                "0:v",
+                "-map",
+                "[normalized]",
+                "-c:v",
+                "copy",
+                "-c:a",
+                "aac",
+                "-b:a",
+                "128k",
+                "-f",
+                "dash",
+                "-adaptation_sets",
+                "id=0,streams=0 id=1,streams=1",
+                "-seg_duration",
+                "4",
+                "-init_seg_name",
+                INIT_SEGMENT_TEMPLATE,
+                "-media_seg_name",
+                MEDIA_SEGMENT_TEMPLATE,
+                "out.mpd",
+            ]
+        );
+    }
+
+    #[test]
+    fn pitch_shift_mode_runs_the_full_command() {
+        let mode = ProcessingMode::PitchShift(vec![-2, 0]);
+        let args = processor().build_command_args("in.mp4", "out.mpd", &mode);
+        let filter = format!(
+            "[0:a]asplit=2[a0][a1]; \
+             [a0]rubberband=pitch={},loudnorm=I=-16:TP=-1.5:LRA=11[p0]; \
+             [a1]rubberband=pitch=1,loudnorm=I=-16:TP=-1.5:LRA=11[p1]",
+            2f64.powf(-2.0 / 12.0)
+        );
+        assert_eq!(
+            args,
+            [
+                "-i",
+                "in.mp4",
+                "-filter_complex",
+                &filter,
+                "-map",
+                "0:v",
+                "-map",
+                "[p0]",
+                "-map",
+                "[p1]",
+                "-c:v",
+                "copy",
+                "-c:a:0",
+                "aac",
+                "-b:a:0",
+                "128k",
+                "-c:a:1",
+                "aac",
+                "-b:a:1",
+                "128k",
+                "-f",
+                "dash",
+                "-adaptation_sets",
+                "id=0,streams=0 id=1,streams=1 id=2,streams=2",
+                "-seg_duration",
+                "4",
+                "-init_seg_name",
+                INIT_SEGMENT_TEMPLATE,
+                "-media_seg_name",
+                MEDIA_SEGMENT_TEMPLATE,
+                "out.mpd",
+            ]
+        );
+    }
+
+    #[test]
+    fn renditions_split_the_video_and_pitch_shifts_the_audio() {
+        let renditions = [(720, "2M"), (360, "600k")]
+            .map(|(height, bitrate)| Rendition {
+                height,
+                video_bitrate: bitrate.to_string(),
+            })
+            .to_vec();
+        let processor = processor().with_renditions(renditions);
+        let mode = ProcessingMode::for_song(true, &[-2]);
+        let args = processor.build_command_args("in.mp4", "out.mpd", &mode);
+
+        let filter = arg(&args, "-filter_complex").unwrap();
+        assert!(filter.starts_with(
+            "[0:v]split=2[v0][v1]; [v0]scale=-2:720[vout0]; [v1]scale=-2:360[vout1];[0:a]asplit=2"
+        ));
+        let maps = args
+            .windows(2)
+            .filter(|pair| pair[0] == "-map")
+            .map(|pair| pair[1].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(maps, ["[vout0]", "[vout1]", "[p0]", "[p1]"]);
+        assert_eq!(arg(&args, "-b:v:1"), Some("600k"));
+        assert_eq!(
+            arg(&args, "-force_key_frames"),
+            Some("expr:gte(t,n_forced*4)")
+        );
+        assert_eq!(
+            arg(&args, "-adaptation_sets"),
+            Some("id=0,streams=0,1 id=1,streams=2 id=2,streams=3")
+        );
+    }
+
+    #[test]
+    fn audio_format_applies_to_every_audio_stream() {
+        let resampled = processor().with_audio_format(Some(48_000), Some(2));
+        for mode in [
+            ProcessingMode::Copy,
+            ProcessingMode::for_song(true, &[-2, 2]),
+        ] {
+            let args = resampled.build_command_args("in.mp4", "out.mpd", &mode);
+            assert_eq!(arg(&args, "-ar"), Some("48000"));
+            assert_eq!(arg(&args, "-ac"), Some("2"));
+            // unindexed, so a single flag covers every stream
+            assert_eq!(args.iter().filter(|arg| arg.starts_with("-ar")).count(), 1);
+        }
+
+        let args = processor().build_command_args("in.mp4", "out.mpd", &ProcessingMode::Copy);
+        assert_eq!(arg(&args, "-ar"), None);
+        assert_eq!(arg(&args, "-ac"), None);
+    }
+
+    #[test]
+    fn pitch_presets_set_the_rubberband_options() {
+        let mode = ProcessingMode::PitchShift(vec![-12, 0]);
+        let filter = |preset| {
+            let processor = processor().with_pitch_preset(preset);
+            processor.build_filter_complex(&mode).unwrap()
+        };
+
+        assert!(filter(PitchPreset::Default).contains(" [a0]rubberband=pitch=0.5,loudnorm"));
+        assert!(filter(PitchPreset::Fast).contains(
+            " [a0]rubberband=pitch=0.5:transients=crisp:window=short:pitchq=speed,loudnorm"
+        ));
+        assert!(filter(PitchPreset::Quality).contains(
+            "[a1]rubberband=pitch=1:transients=mixed:window=long:smoothing=on:pitchq=quality,"
+        ));
+
+        let preset: PitchPreset = serde_json::from_str("\"fast\"").unwrap();
+        assert!(matches!(preset, PitchPreset::Fast));
+        assert!(serde_json::from_str::<PitchPreset>("\"turbo\"").is_err());
+    }
+
+    #[test]
+    fn generated_backgrounds_replace_the_source_video() {
+        let processor = processor()
+            .with_renditions(vec![Rendition {
+                height: 480,
+                video_bitrate: "1M".to_string(),
+            }])
+            .with_video_output(VideoOutput::Still);
+        let mode = ProcessingMode::for_song(true, &[-2]);
+        let args = processor.build_command_args("in.m4a", "out.mpd", &mode);
+
+        assert_eq!(
+            args[..6],
+            [
+                "-i",
+                "in.m4a",
+                "-f",
+                "lavfi",
+                "-i",
+                "color=c=0x14141f:s=1280x720:r=1"
+            ]
+        );
+        assert!(args.windows(2).any(|pair| pair == ["-map", "1:v"]));
+        assert!(!args.iter().any(|arg| arg.contains("0:v")));
+        assert_eq!(arg(&args, "-c:v"), Some("libx264"));
+        assert!(args.iter().any(|arg| arg == "-shortest"));
+        assert_eq!(
+            arg(&args, "-adaptation_sets"),
+            Some("id=0,streams=0 id=1,streams=1 id=2,streams=2")
+        );
+
+        // audio-only output has no video to generate
+        let args = processor
+            .with_audio_only(true)
+            .build_command_args("in.m4a", "out.mpd", &mode);
+        assert!(!args.iter().any(|arg| arg == "lavfi" || arg == "-shortest"));
+    }
+}
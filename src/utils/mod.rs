@@ -1,4 +1,8 @@
 pub mod binary;
 pub mod dash_processor;
+pub mod mpd;
+pub mod process;
+pub mod slug;
+pub mod title;
 pub mod yt_downloader;
 pub mod yt_searcher;
\ No newline at end of file
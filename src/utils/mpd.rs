@@ -0,0 +1,246 @@
+use quick_xml::{
+    events::{BytesStart, Event},
+    Reader, Writer,
+};
+
+fn attr_value(e: &BytesStart, name: &str) -> Option<String> {
+    e.try_get_attribute(name)
+        .ok()
+        .flatten()
+        .and_then(|attr| attr.unescape_value().ok())
+        .map(|value| value.into_owned())
+}
+
+/// Copies an element with each attribute passed through `map`.
+fn map_attributes(
+    e: &BytesStart,
+    map: impl Fn(&[u8], String) -> String,
+) -> std::io::Result<BytesStart<'static>> {
+    let mut rewritten = e.to_owned();
+    rewritten.clear_attributes();
+    for attr in e.attributes() {
+        let attr = attr.map_err(std::io::Error::other)?;
+        let value = attr.unescape_value().map_err(std::io::Error::other)?;
+        let value = map(attr.key.as_ref(), value.into_owned());
+        let key = std::str::from_utf8(attr.key.as_ref()).map_err(std::io::Error::other)?;
+        rewritten.push_attribute((key, value.as_str()));
+    }
+    Ok(rewritten)
+}
+
+/// Keeps a rewritten element as a start tag or a self-closing one, matching
+/// the event it replaces.
+fn same_kind(event: &Event, e: BytesStart<'static>) -> Event<'static> {
+    match event {
+        Event::Empty(_) => Event::Empty(e),
+        _ => Event::Start(e),
+    }
+}
+
+/// Appends the adaptation sets of the `extra` manifest to the period of
+/// `manifest`, for adding streams to a song without re-encoding what it
+/// already has. Adaptation set and representation ids are renumbered past
+/// the existing ones, and segment paths are resolved per representation and
+/// prefixed with `file_prefix`, the name the extra segments were stored
+/// under. Returns the merged manifest and the ids of the appended sets.
+pub fn append_adaptation_sets(
+    manifest: &[u8],
+    extra: &[u8],
+    file_prefix: &str,
+) -> std::io::Result<(Vec<u8>, Vec<usize>)> {
+    let (mut next_set_id, mut next_representation_id) = (0, 0);
+    let mut reader = Reader::from_reader(manifest);
+    loop {
+        match reader.read_event().map_err(std::io::Error::other)? {
+            Event::Start(ref e) | Event::Empty(ref e) => {
+                let id = attr_value(e, "id").and_then(|id| id.parse::<usize>().ok());
+                match (e.name().as_ref(), id) {
+                    (b"AdaptationSet", Some(id)) => next_set_id = next_set_id.max(id + 1),
+                    (b"Representation", Some(id)) => {
+                        next_representation_id = next_representation_id.max(id + 1)
+                    }
+                    _ => {}
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    let mut appended = Vec::new();
+    let mut set_ids = Vec::new();
+    let mut in_adaptation_set = false;
+    let mut representation_id = String::new();
+    let mut reader = Reader::from_reader(extra);
+    loop {
+        let event = reader.read_event().map_err(std::io::Error::other)?;
+        match event {
+            Event::Eof => break,
+            Event::Start(ref e) if e.name().as_ref() == b"AdaptationSet" => {
+                in_adaptation_set = true;
+                set_ids.push(next_set_id);
+                let id = next_set_id.to_string();
+                next_set_id += 1;
+                let e = map_attributes(
+                    e,
+                    |key, value| if key == b"id" { id.clone() } else { value },
+                )?;
+                appended.push(Event::Start(e));
+            }
+            Event::End(ref e) if e.name().as_ref() == b"AdaptationSet" => {
+                in_adaptation_set = false;
+                appended.push(event.into_owned());
+            }
+            _ if !in_adaptation_set => {}
+            Event::Start(ref e) | Event::Empty(ref e) if e.name().as_ref() == b"Representation" => {
+                representation_id = attr_value(e, "id").unwrap_or_default();
+                let id = next_representation_id.to_string();
+                next_representation_id += 1;
+                let e = map_attributes(
+                    e,
+                    |key, value| if key == b"id" { id.clone() } else { value },
+                )?;
+                appended.push(same_kind(&event, e));
+            }
+            Event::Start(ref e) | Event::Empty(ref e)
+                if e.name().as_ref() == b"SegmentTemplate" =>
+            {
+                // the representation is renumbered, so its segment files can
+                // no longer be found through `$RepresentationID$`
+                let e = map_attributes(e, |key, value| match key {
+                    b"initialization" | b"media" => format!(
+                        "{}{}",
+                        file_prefix,
+                        value.replace("$RepresentationID$", &representation_id)
+                    ),
+                    _ => value,
+                })?;
+                appended.push(same_kind(&event, e));
+            }
+            event => appended.push(event.into_owned()),
+        }
+    }
+
+    if set_ids.is_empty() {
+        return Err(std::io::Error::other(
+            "extra manifest has no adaptation sets",
+        ));
+    }
+
+    let mut reader = Reader::from_reader(manifest);
+    let mut writer = Writer::new(Vec::with_capacity(manifest.len() + extra.len()));
+    loop {
+        match reader.read_event().map_err(std::io::Error::other)? {
+            Event::End(e) if e.name().as_ref() == b"Period" => {
+                for event in appended.drain(..) {
+                    writer.write_event(event)?;
+                }
+                writer.write_event(Event::End(e))?;
+            }
+            Event::Eof => break,
+            event => writer.write_event(event)?,
+        }
+    }
+
+    Ok((writer.into_inner(), set_ids))
+}
+
+/// A representation's segment file names, with `$Number$` (and `$ext$`, if
+/// still templated) left unresolved.
+pub struct RepresentationTemplate {
+    pub id: String,
+    pub initialization: Option<String>,
+    pub media: Option<String>,
+}
+
+/// An adaptation set of a manifest and the segment templates of its
+/// representations.
+pub struct AdaptationSetTemplates {
+    pub id: Option<usize>,
+    pub content_type: Option<String>,
+    pub representations: Vec<RepresentationTemplate>,
+}
+
+/// Lists the adaptation sets of `manifest` with the segment file names of
+/// each representation. A set level `SegmentTemplate` applies to every
+/// representation without its own.
+pub fn adaptation_set_templates(manifest: &[u8]) -> std::io::Result<Vec<AdaptationSetTemplates>> {
+    let mut sets = Vec::new();
+    let mut set: Option<(AdaptationSetTemplates, Option<BytesStart<'static>>)> = None;
+    let mut representation: Option<RepresentationTemplate> = None;
+    let mut reader = Reader::from_reader(manifest);
+    loop {
+        let event = reader.read_event().map_err(std::io::Error::other)?;
+        match event {
+            Event::Eof => break,
+            Event::Start(ref e) if e.name().as_ref() == b"AdaptationSet" => {
+                let templates = AdaptationSetTemplates {
+                    id: attr_value(e, "id").and_then(|id| id.parse().ok()),
+                    content_type: attr_value(e, "contentType").or_else(|| {
+                        attr_value(e, "mimeType")
+                            .and_then(|mime| mime.split('/').next().map(str::to_string))
+                    }),
+                    representations: Vec::new(),
+                };
+                set = Some((templates, None));
+            }
+            Event::End(ref e) if e.name().as_ref() == b"AdaptationSet" => {
+                if let Some((mut templates, shared)) = set.take() {
+                    if let Some(shared) = shared {
+                        let unset = templates
+                            .representations
+                            .iter_mut()
+                            .filter(|r| r.initialization.is_none() && r.media.is_none());
+                        for representation in unset {
+                            let resolve = |name: &str| {
+                                attr_value(&shared, name).map(|value| {
+                                    value.replace("$RepresentationID$", &representation.id)
+                                })
+                            };
+                            (representation.initialization, representation.media) =
+                                (resolve("initialization"), resolve("media"));
+                        }
+                    }
+                    sets.push(templates);
+                }
+            }
+            Event::Start(ref e) | Event::Empty(ref e) if e.name().as_ref() == b"Representation" => {
+                representation = Some(RepresentationTemplate {
+                    id: attr_value(e, "id").unwrap_or_default(),
+                    initialization: None,
+                    media: None,
+                });
+                if matches!(event, Event::Empty(_)) {
+                    if let (Some((templates, _)), Some(representation)) =
+                        (set.as_mut(), representation.take())
+                    {
+                        templates.representations.push(representation);
+                    }
+                }
+            }
+            Event::End(ref e) if e.name().as_ref() == b"Representation" => {
+                if let (Some((templates, _)), Some(representation)) =
+                    (set.as_mut(), representation.take())
+                {
+                    templates.representations.push(representation);
+                }
+            }
+            Event::Start(ref e) | Event::Empty(ref e)
+                if e.name().as_ref() == b"SegmentTemplate" =>
+            {
+                if let Some(representation) = representation.as_mut() {
+                    let resolve = |name: &str| {
+                        attr_value(e, name)
+                            .map(|value| value.replace("$RepresentationID$", &representation.id))
+                    };
+                    (representation.initialization, representation.media) =
+                        (resolve("initialization"), resolve("media"));
+                } else if let Some((_, shared)) = set.as_mut() {
+                    *shared = Some(e.to_owned());
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(sets)
+}
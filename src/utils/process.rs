@@ -0,0 +1,260 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeSet,
+    future::Future,
+    process::{Output, Stdio},
+    sync::{Arc, Mutex},
+};
+
+use tokio::process::{Child, Command};
+use tracing::{debug, info};
+
+tokio::task_local! {
+    // stderr of the children run through `ProcessGroups::output`, while
+    // inside `capture_stderr`
+    static CAPTURED_STDERR: RefCell<String>;
+}
+
+/// Runs `future` and returns its output along with the stderr of every
+/// child it ran to completion, so a failed download's log has the raw
+/// output even where its error only keeps a summary.
+pub async fn capture_stderr<F: Future>(future: F) -> (F::Output, String) {
+    let captured = RefCell::default();
+    CAPTURED_STDERR
+        .scope(captured, async {
+            let output = future.await;
+            (output, CAPTURED_STDERR.with(RefCell::take))
+        })
+        .await
+}
+
+/// Spawns the children that download and transcode, keeping track of their
+/// process groups so the ones still running can be killed on shutdown.
+/// Clones share the same registry.
+#[derive(Clone, Default)]
+pub struct ProcessGroups {
+    running: Arc<Mutex<BTreeSet<i32>>>,
+}
+
+impl ProcessGroups {
+    /// Spawns `command` as the leader of a new process group, so it can be
+    /// killed along with everything it started.
+    pub fn spawn(&self, command: &mut Command) -> std::io::Result<(Child, ProcessGroup)> {
+        #[cfg(unix)]
+        command.process_group(0);
+        let child = command.kill_on_drop(true).spawn()?;
+
+        // the child leads its group, so the group id is its pid
+        let pgid = child.id().and_then(|pid| i32::try_from(pid).ok());
+        if let Some(pgid) = pgid {
+            self.running.lock().unwrap().insert(pgid);
+        }
+        Ok((
+            child,
+            ProcessGroup {
+                pgid,
+                groups: self.clone(),
+            },
+        ))
+    }
+
+    /// Runs `command` to completion like `Command::output`, killing its
+    /// process group if the future is dropped first.
+    pub async fn output(&self, command: &mut Command) -> std::io::Result<Output> {
+        let (child, group) = self.spawn(
+            command
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+        )?;
+        let output = child.wait_with_output().await;
+        group.finished();
+        if let Ok(output) = &output {
+            let _ = CAPTURED_STDERR.try_with(|captured| {
+                captured
+                    .borrow_mut()
+                    .push_str(&String::from_utf8_lossy(&output.stderr));
+            });
+        }
+        output
+    }
+
+    /// Kills the process groups of every child still running, so no
+    /// transcode or download outlives the server.
+    pub fn kill_all(&self) {
+        let groups = std::mem::take(&mut *self.running.lock().unwrap());
+        if !groups.is_empty() {
+            info!(
+                "Killing {} child process group(s) on shutdown",
+                groups.len()
+            );
+        }
+        for pgid in groups {
+            kill_group(pgid);
+        }
+    }
+
+    fn forget(&self, pgid: i32) {
+        self.running.lock().unwrap().remove(&pgid);
+    }
+}
+
+/// A child's process group, killed when dropped before the child finished.
+/// `kill_on_drop` only reaches the child itself, while yt-dlp runs ffmpeg to
+/// merge streams and that ffmpeg would outlive it.
+pub struct ProcessGroup {
+    pgid: Option<i32>,
+    groups: ProcessGroups,
+}
+
+impl ProcessGroup {
+    /// Stops tracking the group once the child exited on its own. Killing
+    /// it later could hit an unrelated group that reused the id.
+    pub fn finished(mut self) {
+        if let Some(pgid) = self.pgid.take() {
+            self.groups.forget(pgid);
+        }
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        if let Some(pgid) = self.pgid.take() {
+            self.groups.forget(pgid);
+            debug!("Killing process group {}", pgid);
+            kill_group(pgid);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn kill_group(pgid: i32) {
+    // SAFETY: killpg has no memory safety requirements, a group that already
+    // exited only makes it return ESRCH
+    unsafe {
+        libc::killpg(pgid, libc::SIGKILL);
+    }
+}
+
+/// Without process groups only the direct child is killed, by
+/// `kill_on_drop`.
+#[cfg(not(unix))]
+fn kill_group(_pgid: i32) {}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn sleep_command() -> Command {
+        // the shell's own child checks that the whole group is killed
+        let mut command = Command::new("sh");
+        command.args(["-c", "sleep 30 & wait"]);
+        command
+    }
+
+    fn tracked(groups: &ProcessGroups) -> usize {
+        groups.running.lock().unwrap().len()
+    }
+
+    #[tokio::test]
+    async fn registries_are_independent() {
+        let (first, second) = (ProcessGroups::default(), ProcessGroups::default());
+        let (mut child, _group) = first.spawn(&mut sleep_command()).unwrap();
+        assert_eq!(tracked(&first), 1);
+        assert_eq!(tracked(&second), 0);
+
+        second.kill_all();
+        let still_running = tokio::time::timeout(Duration::from_millis(200), child.wait()).await;
+        assert!(still_running.is_err());
+
+        first.kill_all();
+        assert_eq!(tracked(&first), 0);
+        let status = tokio::time::timeout(Duration::from_secs(5), child.wait())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!status.success());
+    }
+
+    #[tokio::test]
+    async fn dropped_groups_are_killed_and_forgotten() {
+        let groups = ProcessGroups::default();
+        let (mut child, group) = groups.spawn(&mut sleep_command()).unwrap();
+        drop(group);
+        assert_eq!(tracked(&groups), 0);
+        tokio::time::timeout(Duration::from_secs(5), child.wait())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn finished_commands_are_forgotten() {
+        let groups = ProcessGroups::default();
+        let output = groups
+            .output(Command::new("sh").args(["-c", "echo done"]))
+            .await
+            .unwrap();
+        assert_eq!(output.stdout, b"done\n");
+        assert_eq!(tracked(&groups), 0);
+    }
+
+    #[tokio::test]
+    async fn cancelled_commands_take_their_children_with_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+        let script = format!("sleep 30 & echo $! > {}; wait", pid_file.display());
+        let groups = ProcessGroups::default();
+
+        let mut command = Command::new("sh");
+        command.args(["-c", &script]);
+        let cancelled =
+            tokio::time::timeout(Duration::from_millis(300), groups.output(&mut command));
+        assert!(cancelled.await.is_err());
+        assert_eq!(tracked(&groups), 0);
+
+        // the grandchild is gone, or a zombie left for init to reap
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let stat = format!("/proc/{}/stat", pid.trim());
+        let killed = || {
+            std::fs::read_to_string(&stat)
+                .map(|stat| stat.split_whitespace().nth(2) == Some("Z"))
+                .unwrap_or(true)
+        };
+        for _ in 0..50 {
+            if killed() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("sleep {} outlived its cancelled parent", pid.trim());
+    }
+
+    #[tokio::test]
+    async fn stderr_of_finished_children_is_captured() {
+        let processes = ProcessGroups::default();
+        let run = |message: &'static str| {
+            let processes = processes.clone();
+            async move {
+                let script = format!("echo {} >&2", message);
+                let mut command = Command::new("sh");
+                processes
+                    .output(command.args(["-c", &script]))
+                    .await
+                    .unwrap();
+            }
+        };
+
+        let ((), stderr) = capture_stderr(async {
+            run("first").await;
+            run("second").await;
+        })
+        .await;
+        assert_eq!(stderr, "first\nsecond\n");
+
+        // outside a capture it's only returned
+        run("uncaptured").await;
+    }
+}
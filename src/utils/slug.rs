@@ -0,0 +1,308 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use unidecode::unidecode;
+
+use crate::settings::AssetLayout;
+
+// long enough to stay readable, short enough that a folder and its segment
+// names stay well under Windows' 260 character path limit
+const MAX_SLUG_LEN: usize = 80;
+
+/// Folder name for a song's assets: its title transliterated to ASCII, so
+/// CJK and Cyrillic names stay readable on disk, and a hash of the whole
+/// title and the link. Songs whose titles reduce to the same slug, or only
+/// differ past the length cap, still get folders of their own.
+pub fn song_key(title: &str, yt_link: &str) -> String {
+    let slug = song_slug(title);
+    let readable = slug[..slug.len().min(MAX_SLUG_LEN)].trim_end_matches('_');
+    let hash = fnv1a(&format!("{}\n{}", title, yt_link));
+    format!("{}-{:08x}", readable, hash)
+}
+
+/// Whether `folder` could have come from `song_key`, so a folder named in a
+/// request can't reach outside the assets dir.
+pub fn is_song_key(folder: &str) -> bool {
+    !folder.is_empty()
+        && folder.len() <= MAX_SLUG_LEN + 9
+        && folder
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// `name` transliterated to ASCII letters, digits and dashes, with runs of
+/// anything else collapsed to `_`.
+fn song_slug(name: &str) -> String {
+    let mut slug = String::with_capacity(name.len());
+    for c in unidecode(name).chars() {
+        if c.is_ascii_alphanumeric() || c == '-' {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('_') {
+            slug.push('_');
+        }
+    }
+
+    let slug = slug.trim_end_matches('_');
+    if slug.is_empty() {
+        // nothing transliterates (e.g. emoji only), fall back to the raw bytes
+        name.bytes().map(|b| format!("{:02x}", b)).collect()
+    } else {
+        slug.to_string()
+    }
+}
+
+/// Where song folders live: the assets dir and how folders are laid out in
+/// it. Downloads and the routes serving them both resolve folders here so
+/// they can't disagree.
+#[derive(Debug, Clone)]
+pub struct AssetPaths {
+    pub root: PathBuf,
+    pub layout: AssetLayout,
+}
+
+impl AssetPaths {
+    pub fn new(root: impl Into<PathBuf>, layout: AssetLayout) -> Self {
+        AssetPaths {
+            root: root.into(),
+            layout,
+        }
+    }
+
+    /// The path of the folder named `folder`, a `song_key`. Under the dated
+    /// layout that's the partition the song was first downloaded into, or
+    /// today's for a song that wasn't yet.
+    pub fn song_dir(&self, folder: &str) -> PathBuf {
+        match self.layout {
+            AssetLayout::PerSong => self.root.join(folder),
+            AssetLayout::Dated => self
+                .partitions()
+                .into_iter()
+                .map(|partition| partition.join(folder))
+                .find(|dir| dir.is_dir())
+                .unwrap_or_else(|| self.root.join(utc_date(SystemTime::now())).join(folder)),
+        }
+    }
+
+    /// The date partitions of the dated layout.
+    fn partitions(&self) -> Vec<PathBuf> {
+        std::fs::read_dir(&self.root)
+            .map(|entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .map(|entry| entry.path())
+                    .filter(|path| path.is_dir())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Every song folder in the assets dir.
+    pub fn song_dirs(&self) -> std::io::Result<Vec<PathBuf>> {
+        let subdirs = |dir: &Path| -> std::io::Result<Vec<PathBuf>> {
+            Ok(std::fs::read_dir(dir)?
+                .filter_map(Result::ok)
+                .map(|entry| entry.path())
+                .filter(|path| path.is_dir())
+                .collect())
+        };
+
+        match self.layout {
+            AssetLayout::PerSong => subdirs(&self.root),
+            AssetLayout::Dated => {
+                let mut dirs = Vec::new();
+                for partition in subdirs(&self.root)? {
+                    dirs.extend(subdirs(&partition)?);
+                }
+                Ok(dirs)
+            }
+        }
+    }
+}
+
+/// FNV-1a hash, stable across runs and builds unlike std's hasher.
+fn fnv1a(value: &str) -> u32 {
+    value.bytes().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+/// The UTC date of `time` as `YYYY-MM-DD`.
+fn utc_date(time: SystemTime) -> String {
+    let days = time
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs() / 86_400)
+        .unwrap_or(0) as i64;
+
+    // days since 1970-01-01 to a civil date, after Howard Hinnant's
+    // `civil_from_days`
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Key for sorting songs by title: transliterated, lowercased and without a
+/// leading article such as "The", so "The Beatles" sorts under B next to
+/// "beatles tribute".
+pub fn sort_key(name: &str, articles: &[String]) -> String {
+    let key = unidecode(name).trim().to_lowercase();
+    for article in articles {
+        let article = article.trim().to_lowercase();
+        if let Some(rest) = key
+            .strip_prefix(&article)
+            .and_then(|rest| rest.strip_prefix(' '))
+        {
+            return rest.trim_start().to_string();
+        }
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINK: &str = "https://www.youtube.com/watch?v=dQw4w9WgXcQ";
+
+    #[test]
+    fn transliterates_non_ascii_titles() {
+        assert!(song_key("Кино - Группа крови", LINK).starts_with("Kino_-_Gruppa_krovi-"));
+
+        let cjk = song_key("残酷な天使のテーゼ", LINK);
+        assert!(cjk.is_ascii());
+        assert!(is_song_key(&cjk));
+
+        // nothing transliterates, so the raw bytes are used
+        let emoji = song_key("🎤🎶", LINK);
+        assert!(emoji.starts_with("f09f8ea4f09f8eb6-"));
+        assert!(is_song_key(&emoji));
+    }
+
+    #[test]
+    fn titles_sharing_a_slug_get_separate_folders() {
+        assert_eq!(song_slug("AC/DC - Thunderstruck"), song_slug("AC DC - Thunderstruck"));
+        assert_ne!(
+            song_key("AC/DC - Thunderstruck", LINK),
+            song_key("AC DC - Thunderstruck", LINK)
+        );
+    }
+
+    #[test]
+    fn links_get_separate_folders() {
+        let other_link = "https://www.youtube.com/watch?v=oHg5SJYRHA0";
+        assert_ne!(song_key("Song", LINK), song_key("Song", other_link));
+    }
+
+    #[test]
+    fn keys_are_stable() {
+        assert_eq!(song_key("Song", LINK), song_key("Song", LINK));
+    }
+
+    #[test]
+    fn long_titles_are_capped_and_kept_apart() {
+        let prefix = "a".repeat(200);
+        let first = song_key(&format!("{} first", prefix), LINK);
+        let second = song_key(&format!("{} second", prefix), LINK);
+
+        assert_ne!(first, second);
+        assert!(first.len() <= MAX_SLUG_LEN + 9);
+        assert!(is_song_key(&first));
+    }
+
+    #[test]
+    fn rejects_folders_outside_the_assets_dir() {
+        assert!(!is_song_key(""));
+        assert!(!is_song_key(".."));
+        assert!(!is_song_key("../etc"));
+        assert!(!is_song_key("a/b"));
+        assert!(!is_song_key("a\\b"));
+    }
+
+    #[test]
+    fn dated_folders_stay_in_the_partition_they_were_written_to() {
+        let root = tempfile::tempdir().unwrap();
+        let folder = song_key("Song", LINK);
+        let per_song = AssetPaths::new(root.path(), AssetLayout::PerSong);
+        let dated = AssetPaths::new(root.path(), AssetLayout::Dated);
+
+        assert_eq!(per_song.song_dir(&folder), root.path().join(&folder));
+        // new songs go under today
+        let today = utc_date(SystemTime::now());
+        assert_eq!(
+            dated.song_dir(&folder),
+            root.path().join(&today).join(&folder)
+        );
+
+        // and are found where they were written on later days
+        let earlier = root.path().join("2024-02-29").join(&folder);
+        std::fs::create_dir_all(&earlier).unwrap();
+        assert_eq!(dated.song_dir(&folder), earlier);
+    }
+
+    #[test]
+    fn dates_are_utc_calendar_days() {
+        let at = |secs| utc_date(UNIX_EPOCH + std::time::Duration::from_secs(secs));
+        assert_eq!(at(0), "1970-01-01");
+        assert_eq!(at(951_782_400), "2000-02-29");
+        assert_eq!(at(1_709_251_199), "2024-02-29");
+        assert_eq!(at(1_709_251_200), "2024-03-01");
+        assert_eq!(at(4_102_444_800), "2100-01-01");
+    }
+
+    #[test]
+    fn song_dirs_lists_folders_in_either_layout() {
+        for layout in [AssetLayout::PerSong, AssetLayout::Dated] {
+            let root = tempfile::tempdir().unwrap();
+            let assets = AssetPaths::new(root.path(), layout);
+            let mut expected =
+                ["One", "Two", "Three"].map(|title| assets.song_dir(&song_key(title, LINK)));
+            for dir in &expected {
+                std::fs::create_dir_all(dir).unwrap();
+            }
+
+            let mut dirs = assets.song_dirs().unwrap();
+            dirs.sort();
+            expected.sort();
+            assert_eq!(dirs, expected, "{:?}", layout);
+        }
+    }
+
+    #[test]
+    fn mixed_titles_sort_by_their_sort_key() {
+        let articles = ["the".to_string(), "Die".to_string()];
+        let mut names = vec![
+            "the Zombies",
+            "Édith Piaf",
+            "Die Ärzte",
+            "ABBA",
+            "The Beatles",
+            "beatles tribute",
+            "Theory of a Deadman",
+        ];
+        names.sort_by_cached_key(|name| sort_key(name, &articles));
+
+        assert_eq!(
+            names,
+            [
+                "ABBA",
+                "Die Ärzte",
+                "The Beatles",
+                "beatles tribute",
+                "Édith Piaf",
+                "Theory of a Deadman",
+                "the Zombies",
+            ]
+        );
+        // without articles configured, nothing is stripped
+        assert_eq!(sort_key("The Beatles", &[]), "the beatles");
+    }
+}
@@ -0,0 +1,158 @@
+use regex::Regex;
+
+use crate::settings::QueueSettings;
+
+/// Strips upload noise like `(Official Video)` or `[HD]` from titles before
+/// they are queued, and shortens titles past `queue.max_name_length`.
+/// Without `queue.clean_titles` only the length is touched.
+pub struct TitleCleaner {
+    patterns: Vec<Regex>,
+    max_length: usize,
+}
+
+impl TitleCleaner {
+    pub fn new(settings: &QueueSettings) -> Result<Self, regex::Error> {
+        let patterns = if settings.clean_titles {
+            settings
+                .title_noise_patterns
+                .iter()
+                .map(|pattern| Regex::new(pattern))
+                .collect::<Result<_, _>>()?
+        } else {
+            Vec::new()
+        };
+        Ok(TitleCleaner {
+            patterns,
+            max_length: settings.max_name_length,
+        })
+    }
+
+    /// The title with every match of the noise patterns removed and then
+    /// shortened, or `None` when nothing changed.
+    pub fn clean(&self, title: &str) -> Option<String> {
+        let stripped = self.strip_noise(title);
+        let shortened = self.shorten(stripped.as_deref().unwrap_or(title));
+        shortened.or(stripped)
+    }
+
+    /// The title cut to `max_name_length` characters, ending in an ellipsis,
+    /// or `None` when it already fits.
+    pub fn shorten(&self, title: &str) -> Option<String> {
+        if self.max_length == 0 || title.chars().count() <= self.max_length {
+            return None;
+        }
+
+        let kept: String = title.chars().take(self.max_length.saturating_sub(1)).collect();
+        Some(format!("{}…", kept.trim_end()))
+    }
+
+    /// A title that would be left empty is kept.
+    fn strip_noise(&self, title: &str) -> Option<String> {
+        if self.patterns.is_empty() {
+            return None;
+        }
+
+        let mut cleaned = title.to_string();
+        for pattern in &self.patterns {
+            cleaned = pattern.replace_all(&cleaned, " ").into_owned();
+        }
+        let cleaned = cleaned.split_whitespace().collect::<Vec<_>>().join(" ");
+        // separators left dangling by a removed suffix, e.g. `Song - `
+        let cleaned = cleaned.trim_end_matches(|c: char| c == '-' || c == '|' || c.is_whitespace());
+
+        (!cleaned.is_empty() && cleaned != title).then(|| cleaned.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn title_cleaner(clean_titles: bool, max_name_length: usize) -> TitleCleaner {
+        TitleCleaner::new(&QueueSettings {
+            clean_titles,
+            max_name_length,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn long_titles_are_shortened() {
+        let cleaner = title_cleaner(false, 10);
+        assert_eq!(cleaner.shorten("Short"), None);
+        assert_eq!(cleaner.shorten("Exactly 10"), None);
+        assert_eq!(
+            cleaner.shorten("A Very Long Title").as_deref(),
+            Some("A Very Lo…")
+        );
+        // the ellipsis doesn't follow a space, and multi-byte characters
+        // count once
+        assert_eq!(
+            cleaner.shorten("Song Name Twelve").as_deref(),
+            Some("Song Name…")
+        );
+        assert_eq!(
+            cleaner.shorten("Ünïcödé Tïtlé Here").as_deref(),
+            Some("Ünïcödé T…")
+        );
+        assert_eq!(title_cleaner(false, 0).shorten(&"a".repeat(500)), None);
+    }
+
+    #[test]
+    fn noise_is_stripped() {
+        let cleaner = title_cleaner(true, 150);
+        assert_eq!(cleaner.clean("Song [HD]").as_deref(), Some("Song"));
+        assert_eq!(cleaner.clean("Song - Lyrics").as_deref(), Some("Song"));
+        assert_eq!(cleaner.clean("Song (feat. X)"), None);
+        // a title that is nothing but noise is kept
+        assert_eq!(cleaner.clean("(Official Video)"), None);
+    }
+
+    #[test]
+    fn noise_is_stripped_before_shortening() {
+        assert_eq!(
+            title_cleaner(true, 12)
+                .clean("Artist - Song (Official Video)")
+                .as_deref(),
+            Some("Artist - So…")
+        );
+    }
+
+    #[test]
+    fn messy_titles_are_cleaned() {
+        let cleaner = title_cleaner(true, 150);
+        for (messy, cleaned) in [
+            ("Song (Official Video) [HD] 4K Lyrics", "Song"),
+            ("Artist - Song [Official Music Video]", "Artist - Song"),
+            ("Song (Remastered 2011) | Official Video", "Song"),
+            ("Song (Lyric Video) (feat. Guest)", "Song (feat. Guest)"),
+            ("Song [M/V]", "Song"),
+        ] {
+            assert_eq!(cleaner.clean(messy).as_deref(), Some(cleaned), "{}", messy);
+        }
+
+        // off by default
+        let messy = "Artist - Song (Official Video) [HD]";
+        assert_eq!(title_cleaner(false, 150).clean(messy), None);
+    }
+
+    #[test]
+    fn noise_patterns_are_configurable() {
+        let cleaner = TitleCleaner::new(&QueueSettings {
+            clean_titles: true,
+            title_noise_patterns: vec![r"(?i)\s*\(karaoke\)".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(cleaner.clean("Song (Karaoke)").as_deref(), Some("Song"));
+        assert_eq!(cleaner.clean("Song (Official Video)"), None);
+
+        let invalid = QueueSettings {
+            clean_titles: true,
+            title_noise_patterns: vec!["(unclosed".to_string()],
+            ..Default::default()
+        };
+        assert!(TitleCleaner::new(&invalid).is_err());
+    }
+}
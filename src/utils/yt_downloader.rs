@@ -1,25 +1,194 @@
-use std::process::Command;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
 use thiserror::Error;
-use tracing::debug;
+use tokio::process::Command;
+use tracing::{debug, info, warn};
 
-use crate::globals;
+use crate::{
+    settings::DownloadSettings,
+    utils::{binary::BinaryPaths, process::ProcessGroups},
+};
 
 #[derive(Error, Debug)]
 pub enum VideoProcessError {
     #[error("YouTube download failed: {0}")]
     DownloadError(String),
+    #[error("Video is not available in this region: {0}")]
+    GeoBlocked(String),
     #[error("Failed to process filename: {0}")]
     FilenameError(String),
     #[error("Pitch shift processing failed: {0}")]
     PitchShiftError(String),
+    #[error("Transcode failed after {attempts} attempt(s): {reason}")]
+    TranscodeError { attempts: u32, reason: String },
     #[error("Video extraction processing failed: {0}")]
     VideoExtractError(String),
     #[error("Command execution failed: {0}")]
     CommandError(#[from] std::io::Error),
     #[error("Failed to parse duration: {0}")]
     DurationParseError(String),
+    #[error("Download queue is full ({backlog} pending)")]
+    QueueFull { backlog: usize },
+    #[error("Download actor is unavailable")]
+    ActorUnavailable,
+    #[error("Invalid trim range: {0}")]
+    InvalidTrim(String),
+    #[error("Video is too long ({duration_seconds}s, max {max_seconds}s)")]
+    TooLong { duration_seconds: f64, max_seconds: u64 },
+    #[error("Live streams can't be queued")]
+    LiveStream,
+    #[error("Download was cancelled")]
+    Cancelled,
+    #[error("yt-dlp reported an unusable duration: {0}")]
+    InvalidDuration(String),
+    #[error("Pitch shifts are already being added to this song")]
+    AlreadyExtending,
+    #[error("The video has no audio stream")]
+    NoAudioStream,
 }
 
+const MAX_REASON_LEN: usize = 200;
+
+impl VideoProcessError {
+    /// A short reason fit to show guests. yt-dlp failures are cut down to
+    /// its error line, and internal processing errors, which carry paths and
+    /// command output, are replaced by a generic message.
+    pub fn guest_reason(&self) -> String {
+        let reason = match self {
+            VideoProcessError::DownloadError(stderr) => stderr
+                .lines()
+                .rev()
+                .find_map(|line| line.trim().strip_prefix("ERROR:"))
+                // drop yt-dlp's `[extractor] id:` prefix
+                .map(|line| {
+                    let line = line.trim();
+                    line.strip_prefix('[')
+                        .and_then(|rest| rest.split_once("] "))
+                        .and_then(|(_, rest)| rest.split_once(": "))
+                        .map_or(line, |(_, message)| message)
+                        .trim()
+                        .to_string()
+                })
+                .unwrap_or_else(|| "The video could not be downloaded".to_string()),
+            VideoProcessError::GeoBlocked(_) => {
+                "This video isn't available in this region, try another upload".to_string()
+            }
+            VideoProcessError::FilenameError(_)
+            | VideoProcessError::PitchShiftError(_)
+            | VideoProcessError::TranscodeError { .. }
+            | VideoProcessError::VideoExtractError(_)
+            | VideoProcessError::CommandError(_)
+            | VideoProcessError::DurationParseError(_)
+            | VideoProcessError::InvalidDuration(_) => {
+                "The video could not be processed".to_string()
+            }
+            err => err.to_string(),
+        };
+
+        match reason.char_indices().nth(MAX_REASON_LEN) {
+            Some((end, _)) => format!("{}…", &reason[..end]),
+            None => reason,
+        }
+    }
+}
+
+/// Lowercased fragments of yt-dlp's messages for region-restricted videos.
+const GEO_BLOCK_SIGNATURES: [&str; 4] = [
+    "not available in your country",
+    "not available from your location",
+    "geo restriction",
+    "geo-restricted",
+];
+
+/// Maps a failed yt-dlp run to an error, telling geo-blocked videos apart
+/// so guests can be pointed to another upload.
+fn download_error(stderr: &str) -> VideoProcessError {
+    let lowercase = stderr.to_lowercase();
+    if GEO_BLOCK_SIGNATURES
+        .iter()
+        .any(|signature| lowercase.contains(signature))
+    {
+        VideoProcessError::GeoBlocked(stderr.trim().to_string())
+    } else {
+        VideoProcessError::DownloadError(stderr.to_string())
+    }
+}
+
+/// Optional start/end offsets in seconds used to cut intros and outros.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TrimRange {
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+}
+
+impl TrimRange {
+    /// Returns `None` when neither bound is set so the full video is used.
+    pub fn new(start: Option<f64>, end: Option<f64>) -> Option<Self> {
+        if start.is_none() && end.is_none() {
+            None
+        } else {
+            Some(TrimRange { start, end })
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), VideoProcessError> {
+        let start = self.start.unwrap_or(0.0);
+        if start < 0.0 {
+            return Err(VideoProcessError::InvalidTrim(format!(
+                "start {} is negative",
+                start
+            )));
+        }
+
+        if let Some(end) = self.end {
+            if start >= end {
+                return Err(VideoProcessError::InvalidTrim(format!(
+                    "start {} must be before end {}",
+                    start, end
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn download_section(&self) -> String {
+        format!(
+            "*{}-{}",
+            self.start.unwrap_or(0.0),
+            self.end
+                .map(|end| end.to_string())
+                .unwrap_or_else(|| "inf".to_string())
+        )
+    }
+
+    /// Checks the range against the full video length and returns the length
+    /// of the trimmed section.
+    pub fn trimmed_duration(&self, duration_seconds: f64) -> Result<f64, VideoProcessError> {
+        let start = self.start.unwrap_or(0.0);
+        let end = self.end.unwrap_or(duration_seconds);
+
+        if start >= duration_seconds || end > duration_seconds {
+            return Err(VideoProcessError::InvalidTrim(format!(
+                "range {}-{} is outside the video duration {}",
+                start, end, duration_seconds
+            )));
+        }
+
+        Ok(end - start)
+    }
+}
+
+/// Format preference, falling back (yt-dlp's `/`) from an avc1 stream at up to
+/// 720p, which DASH can copy without re-encoding, to whatever is available.
+const FORMAT_SELECTOR: &str = "bestvideo[height<=720][vcodec^=avc1]+bestaudio\
+    /bestvideo[height<=720]+bestaudio\
+    /best[height<=720]\
+    /best";
+
+/// Audio-only format preference, m4a first since it needs no conversion.
+const AUDIO_FORMAT_SELECTOR: &str = "bestaudio[ext=m4a]/bestaudio/best";
+
 #[derive(Debug)]
 pub struct VideoMetadata {
     pub directory: String,
@@ -28,54 +197,267 @@ pub struct VideoMetadata {
     pub duration_seconds: f64,
 }
 
+impl VideoMetadata {
+    pub fn path(&self) -> String {
+        format!("{}/{}.{}", self.directory, self.filename, self.extension)
+    }
+}
+
+/// What yt-dlp reports about a video without downloading it.
+#[derive(Debug)]
+pub struct RemoteMetadata {
+    // absent for live streams
+    pub duration_seconds: Option<f64>,
+    pub is_live: bool,
+}
+
+/// Reads the fields of yt-dlp's `-j` output that are checked before a
+/// download.
+fn parse_metadata(json: &[u8]) -> Result<RemoteMetadata, VideoProcessError> {
+    let json: serde_json::Value = serde_json::from_slice(json)
+        .map_err(|e| VideoProcessError::DurationParseError(e.to_string()))?;
+
+    Ok(RemoteMetadata {
+        duration_seconds: json.get("duration").and_then(|v| v.as_f64()),
+        is_live: json
+            .get("is_live")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false),
+    })
+}
+
+impl RemoteMetadata {
+    /// Rejects live streams, trim ranges outside the video and videos (or
+    /// trimmed sections) longer than `max_seconds`. A video without a
+    /// reported duration can't be checked and is let through.
+    pub fn check(
+        &self,
+        trim: &Option<TrimRange>,
+        max_seconds: Option<u64>,
+    ) -> Result<(), VideoProcessError> {
+        if self.is_live {
+            return Err(VideoProcessError::LiveStream);
+        }
+
+        let Some(duration_seconds) = self.duration_seconds else {
+            return Ok(());
+        };
+        let duration_seconds = match trim {
+            Some(trim) => trim.trimmed_duration(duration_seconds)?,
+            None => duration_seconds,
+        };
+
+        match max_seconds {
+            Some(max_seconds) if duration_seconds > max_seconds as f64 => {
+                Err(VideoProcessError::TooLong {
+                    duration_seconds,
+                    max_seconds,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
 #[derive(Clone)]
-pub struct YtDownloader {}
+pub struct YtDownloader {
+    binaries: BinaryPaths,
+    processes: ProcessGroups,
+    sponsorblock: bool,
+    // `--sub-langs` patterns, no captions are fetched when unset
+    subtitle_langs: Option<String>,
+    geo_bypass_country: Option<String>,
+}
 
 impl YtDownloader {
-    pub async fn download(
+    pub fn new(
+        binaries: BinaryPaths,
+        processes: ProcessGroups,
+        download_settings: &DownloadSettings,
+    ) -> Self {
+        YtDownloader {
+            binaries,
+            processes,
+            sponsorblock: download_settings.sponsorblock,
+            subtitle_langs: download_settings
+                .subtitles
+                .then(|| download_settings.subtitle_langs.clone()),
+            geo_bypass_country: download_settings.geo_bypass_country.clone(),
+        }
+    }
+
+    /// Options shared by every yt-dlp run that reaches the video.
+    fn common_args(&self) -> Vec<String> {
+        match &self.geo_bypass_country {
+            Some(country) => vec!["--geo-bypass-country".to_string(), country.clone()],
+            None => Vec::new(),
+        }
+    }
+
+    pub async fn fetch_metadata(&self, yt_link: &str) -> Result<RemoteMetadata, VideoProcessError> {
+        let mut args = self.common_args();
+        args.extend(["-j", "--no-playlist", "--skip-download", "--", yt_link].map(str::to_string));
+        debug!("yt-dlp metadata command: {:?}", args);
+
+        let output = self
+            .processes
+            .output(Command::new(&self.binaries.ytdlp).args(args))
+            .await
+            .map_err(VideoProcessError::CommandError)?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(download_error(&stderr));
+        }
+
+        parse_metadata(&output.stdout)
+    }
+
+    /// The yt-dlp arguments downloading `yt_link` to `<song_dir>/<file_name>`.
+    fn download_args(
         &self,
         yt_link: &str,
-        base_dir: &str,
+        song_dir: &Path,
         file_name: &str,
-    ) -> Result<VideoMetadata, VideoProcessError> {
-        let ffmpeg_path = globals::get_binary_path("ffmpeg");
-
-        let args = vec![
+        trim: &Option<TrimRange>,
+        temp_dir: Option<&Path>,
+        audio_only: bool,
+    ) -> Result<Vec<String>, VideoProcessError> {
+        let mut args = vec![
             "-f".to_string(),
-            "bestvideo[height<=720][vcodec^=avc1]+bestaudio".to_string(),
+            if audio_only {
+                AUDIO_FORMAT_SELECTOR.to_string()
+            } else {
+                FORMAT_SELECTOR.to_string()
+            },
             "-o".to_string(),
-            format!("{}/{}/{}.%(ext)s", base_dir, file_name, file_name),
+            format!("{}/{}.%(ext)s", song_dir.display(), file_name),
             "--merge-output-format".to_string(),
             "mp4".to_string(),
             "--restrict-filenames".to_string(),
             "--print".to_string(),
-            "filename,duration".to_string(),  // Print both filename and duration
+            "filename,duration,format_id".to_string(),
             "--no-simulate".to_string(),
             "--ffmpeg-location".to_string(),
-            ffmpeg_path.to_string_lossy().to_string(),
-            "--".to_string(),
-            format!("{}", yt_link.to_string()),
+            self.binaries.ffmpeg.to_string_lossy().to_string(),
         ];
 
+        args.extend(self.common_args());
+
+        if let Some(temp_dir) = temp_dir {
+            args.push("--paths".to_string());
+            args.push(format!("temp:{}", temp_dir.display()));
+        }
+
+        if let Some(trim) = trim {
+            trim.validate()?;
+            args.push("--download-sections".to_string());
+            args.push(trim.download_section());
+        }
+
+        if self.sponsorblock {
+            args.push("--sponsorblock-remove".to_string());
+            args.push("music_offtopic".to_string());
+        }
+
+        if let Some(subtitle_langs) = &self.subtitle_langs {
+            // written next to the video as `<file_name>.<lang>.vtt`. They
+            // cover the full video, so trimmed songs' captions run early
+            args.extend([
+                "--write-subs".to_string(),
+                "--write-auto-subs".to_string(),
+                "--sub-format".to_string(),
+                "vtt".to_string(),
+                "--sub-langs".to_string(),
+                subtitle_langs.clone(),
+            ]);
+        }
+
+        args.push("--".to_string());
+        args.push(yt_link.to_string());
+        Ok(args)
+    }
+
+    pub async fn download(
+        &self,
+        yt_link: &str,
+        song_dir: &Path,
+        file_name: &str,
+        trim: Option<TrimRange>,
+        temp_dir: Option<&Path>,
+        audio_only: bool,
+    ) -> Result<VideoMetadata, VideoProcessError> {
+        let args = self.download_args(yt_link, song_dir, file_name, &trim, temp_dir, audio_only)?;
         debug!("yt-dlp command: {:?}", args);
 
-        let ytdlp_path = globals::get_binary_path("yt-dlp");
-        debug!("Using yt-dlp from path: {}", ytdlp_path.display());
+        debug!("Using yt-dlp from path: {}", self.binaries.ytdlp.display());
 
-        let output = Command::new(ytdlp_path)
-            .args(&args)
-            .output()
+        let output = self
+            .processes
+            .output(Command::new(&self.binaries.ytdlp).args(&args))
+            .await
             .map_err(VideoProcessError::CommandError)?;
 
-        if !output.status.success() {
+        let mut parsed = if output.status.success() {
+            self.parse_output(&output.stdout)?
+        } else {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(VideoProcessError::DownloadError(stderr.to_string()));
+            warn!("yt-dlp exited with {}", output.status);
+            self.output_despite_failure(&output.stdout, &stderr)?
+        };
+        debug!("parseed {:?}", parsed);
+
+        if self.sponsorblock {
+            // removed segments shrink the file, so the printed duration is stale
+            parsed.duration_seconds = self.probe_duration(&parsed.path()).await?;
+        } else if let Some(trim) = &trim {
+            // yt-dlp reports the full video duration, not the downloaded section
+            parsed.duration_seconds = match trim.trimmed_duration(parsed.duration_seconds) {
+                Ok(duration_seconds) => duration_seconds,
+                Err(err) => {
+                    // the section doesn't match the video, don't leave it behind
+                    if let Err(e) = tokio::fs::remove_file(parsed.path()).await {
+                        warn!("Failed to remove {}: {}", parsed.path(), e);
+                    }
+                    return Err(err);
+                }
+            };
         }
 
-        let parsed = self.parse_output(&output.stdout);
-        debug!("parseed {:?}", parsed);
+        Ok(parsed)
+    }
 
-        parsed
+    /// Reads the container duration from ffmpeg's input banner. ffmpeg exits
+    /// with an error since no output is given, so only stderr is inspected.
+    async fn probe_duration(&self, path: &str) -> Result<f64, VideoProcessError> {
+        let output = self
+            .processes
+            .output(Command::new(&self.binaries.ffmpeg).args(["-hide_banner", "-i", path]))
+            .await
+            .map_err(VideoProcessError::CommandError)?;
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let seconds = banner_duration(&stderr)
+            .map_err(|err| VideoProcessError::InvalidDuration(format!("{} in {}", err, path)))?;
+        debug!("probed {} duration: {}s", path, seconds);
+        Ok(seconds)
+    }
+
+    /// The video a failed yt-dlp run still wrote. Post-processing warnings
+    /// can fail the exit status even though the video was written, so only
+    /// fail if it's actually missing.
+    fn output_despite_failure(
+        &self,
+        stdout: &[u8],
+        stderr: &str,
+    ) -> Result<VideoMetadata, VideoProcessError> {
+        match self.parse_output(stdout) {
+            Ok(parsed) if Path::new(&parsed.path()).exists() => {
+                warn!("yt-dlp still produced {}: {}", parsed.path(), stderr.trim());
+                Ok(parsed)
+            }
+            _ => Err(download_error(stderr)),
+        }
     }
 
     fn parse_output(&self, output: &[u8]) -> Result<VideoMetadata, VideoProcessError> {
@@ -84,19 +466,23 @@ impl YtDownloader {
         
         // Split the output into lines
         let lines: Vec<&str> = output_str.lines().collect();
-        if lines.len() != 2 {
+        if lines.len() != 3 {
             return Err(VideoProcessError::FilenameError(
-                "Expected filename and duration output".to_string(),
+                "Expected filename, duration and format output".to_string(),
             ));
         }
 
         let filename = lines[0].trim();
         let duration_str = lines[1].trim();
+        info!("yt-dlp selected format: {}", lines[2].trim());
 
-        // Parse the duration (convert from string to f64)
+        // yt-dlp prints `NA` or `0` for live or broken videos, which would
+        // otherwise produce a zero-segment status that never matches on disk
         let duration_seconds = duration_str
             .parse::<f64>()
-            .map_err(|e| VideoProcessError::DurationParseError(e.to_string()))?;
+            .ok()
+            .filter(|duration| duration.is_finite() && *duration > 0.0)
+            .ok_or_else(|| VideoProcessError::InvalidDuration(duration_str.to_string()))?;
 
         // Split the path into components
         let path_parts: Vec<&str> = filename.rsplitn(2, '/').collect();
@@ -119,4 +505,260 @@ impl YtDownloader {
             duration_seconds,
         })
     }
-}
\ No newline at end of file
+}
+
+/// The `Duration: HH:MM:SS.ss` from ffmpeg's input banner, in seconds. The
+/// error is what was found instead.
+fn banner_duration(stderr: &str) -> Result<f64, String> {
+    let duration_str = stderr
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Duration: "))
+        .and_then(|rest| rest.split(',').next())
+        .ok_or_else(|| "no duration".to_string())?;
+
+    duration_str
+        .split(':')
+        .try_fold(0.0, |total, part| part.parse::<f64>().map(|value| total * 60.0 + value))
+        .ok()
+        .filter(|duration| *duration > 0.0)
+        .ok_or_else(|| format!("duration {}", duration_str))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::settings::DownloadSettings;
+
+    use super::*;
+
+    fn downloader(settings: DownloadSettings) -> YtDownloader {
+        let binaries = BinaryPaths {
+            ytdlp: PathBuf::from("yt-dlp"),
+            ffmpeg: PathBuf::from("ffmpeg"),
+        };
+        YtDownloader::new(binaries, ProcessGroups::default(), &settings)
+    }
+
+    /// The value following `flag` in `args`.
+    fn arg<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+        let index = args.iter().position(|arg| arg == flag)?;
+        args.get(index + 1).map(String::as_str)
+    }
+
+    fn args(downloader: &YtDownloader, trim: &Option<TrimRange>, audio_only: bool) -> Vec<String> {
+        let (link, song_dir) = ("https://youtu.be/a", Path::new("assets/song"));
+        downloader
+            .download_args(link, song_dir, "song", trim, None, audio_only)
+            .unwrap()
+    }
+
+    #[test]
+    fn formats_fall_back_from_avc1_to_anything() {
+        let args = args(&downloader(DownloadSettings::default()), &None, false);
+        let formats = arg(&args, "-f").unwrap().split('/').collect::<Vec<_>>();
+
+        assert_eq!(
+            formats.first(),
+            Some(&"bestvideo[height<=720][vcodec^=avc1]+bestaudio")
+        );
+        assert_eq!(formats.last(), Some(&"best"));
+    }
+
+    fn metadata(duration_seconds: Option<f64>) -> RemoteMetadata {
+        RemoteMetadata {
+            duration_seconds,
+            is_live: false,
+        }
+    }
+
+    #[test]
+    fn trim_is_checked_without_a_duration_limit() {
+        let video = metadata(Some(200.0));
+        assert!(video
+            .check(&TrimRange::new(Some(10.0), Some(190.0)), None)
+            .is_ok());
+        assert!(matches!(
+            video.check(&TrimRange::new(Some(250.0), None), None),
+            Err(VideoProcessError::InvalidTrim(_))
+        ));
+        assert!(matches!(
+            video.check(&TrimRange::new(None, Some(201.0)), None),
+            Err(VideoProcessError::InvalidTrim(_))
+        ));
+    }
+
+    #[test]
+    fn limit_applies_to_the_trimmed_section() {
+        let video = metadata(Some(1200.0));
+        assert!(matches!(
+            video.check(&None, Some(900)),
+            Err(VideoProcessError::TooLong {
+                max_seconds: 900,
+                ..
+            })
+        ));
+        assert!(video
+            .check(&TrimRange::new(Some(600.0), None), Some(900))
+            .is_ok());
+    }
+
+    #[test]
+    fn over_limit_videos_are_rejected_from_their_metadata() {
+        let json =
+            br#"{"id": "aaaaaaaaaaa", "title": "Concert", "duration": 5400.5, "is_live": false}"#;
+        let metadata = parse_metadata(json).unwrap();
+
+        assert_eq!(metadata.duration_seconds, Some(5400.5));
+        assert!(!metadata.is_live);
+        assert!(matches!(
+            metadata.check(&None, Some(900)),
+            Err(VideoProcessError::TooLong {
+                max_seconds: 900,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn metadata_without_optional_fields_parses() {
+        let metadata = parse_metadata(br#"{"id": "aaaaaaaaaaa"}"#).unwrap();
+        assert_eq!(metadata.duration_seconds, None);
+        assert!(!metadata.is_live);
+        assert!(matches!(
+            parse_metadata(b"not json"),
+            Err(VideoProcessError::DurationParseError(_))
+        ));
+    }
+
+    #[test]
+    fn live_streams_are_rejected_and_unknown_durations_let_through() {
+        let json =
+            br#"{"id": "aaaaaaaaaaa", "title": "Stream", "duration": null, "is_live": true}"#;
+        let live = parse_metadata(json).unwrap();
+        assert_eq!(live.duration_seconds, None);
+        assert!(matches!(
+            live.check(&None, None),
+            Err(VideoProcessError::LiveStream)
+        ));
+        assert!(metadata(None)
+            .check(&TrimRange::new(Some(30.0), None), Some(900))
+            .is_ok());
+    }
+
+    #[test]
+    fn failed_runs_that_wrote_the_video_succeed() {
+        let song_dir = tempfile::tempdir().unwrap();
+        let downloader = downloader(DownloadSettings::default());
+        let stdout = format!("{}/song.mp4\n212.5\n137+140\n", song_dir.path().display());
+        let stderr = "WARNING: Post-processing: Conversion failed!\nERROR: Postprocessing failed";
+
+        assert!(matches!(
+            downloader.output_despite_failure(stdout.as_bytes(), stderr),
+            Err(VideoProcessError::DownloadError(_))
+        ));
+
+        std::fs::write(song_dir.path().join("song.mp4"), "").unwrap();
+        let parsed = downloader
+            .output_despite_failure(stdout.as_bytes(), stderr)
+            .unwrap();
+        assert_eq!(parsed.filename, "song");
+        assert_eq!(parsed.extension, "mp4");
+        assert_eq!(parsed.duration_seconds, 212.5);
+    }
+
+    #[test]
+    fn audio_only_downloads_fetch_just_the_audio() {
+        let args = args(&downloader(DownloadSettings::default()), &None, true);
+        assert_eq!(arg(&args, "-f"), Some("bestaudio[ext=m4a]/bestaudio/best"));
+    }
+
+    #[test]
+    fn missing_or_zero_durations_are_rejected() {
+        let downloader = downloader(DownloadSettings::default());
+        for duration in ["NA", "0", "-3", "inf"] {
+            let stdout = format!("assets/song/song.mp4\n{}\n18\n", duration);
+            let err = downloader.parse_output(stdout.as_bytes()).unwrap_err();
+            assert!(
+                matches!(&err, VideoProcessError::InvalidDuration(value) if value == duration),
+                "{}: {:?}",
+                duration,
+                err
+            );
+        }
+
+        let parsed = downloader
+            .parse_output(b"assets/song/song.mp4\n180\n18\n")
+            .unwrap();
+        assert_eq!(parsed.duration_seconds, 180.0);
+    }
+
+    #[test]
+    fn sponsorblock_removes_offtopic_segments_when_enabled() {
+        let args_without = args(&downloader(DownloadSettings::default()), &None, false);
+        assert_eq!(arg(&args_without, "--sponsorblock-remove"), None);
+
+        let downloader = downloader(DownloadSettings {
+            sponsorblock: true,
+            ..Default::default()
+        });
+        let args = args(&downloader, &None, false);
+        assert_eq!(arg(&args, "--sponsorblock-remove"), Some("music_offtopic"));
+    }
+
+    #[test]
+    fn shortened_durations_are_read_from_the_ffmpeg_banner() {
+        let stderr = "Input #0, mov,mp4,m4a,3gp,3g2,mj2, from 'song.mp4':\n  \
+                      Duration: 00:03:25.50, start: 0.000000, bitrate: 1200 kb/s\n\
+                      At least one output file must be specified\n";
+        assert_eq!(banner_duration(stderr), Ok(205.5));
+        assert!(banner_duration("  Duration: N/A, bitrate: N/A").is_err());
+        assert!(banner_duration("song.mp4: No such file or directory").is_err());
+    }
+
+    #[test]
+    fn subtitles_are_written_as_vtt_when_enabled() {
+        let args_without = args(&downloader(DownloadSettings::default()), &None, false);
+        assert!(!args_without.iter().any(|arg| arg.contains("subs")));
+
+        let downloader = downloader(DownloadSettings {
+            subtitles: true,
+            subtitle_langs: "en.*,ja".to_string(),
+            ..Default::default()
+        });
+        let args = args(&downloader, &None, false);
+        assert!(args.contains(&"--write-subs".to_string()));
+        assert!(args.contains(&"--write-auto-subs".to_string()));
+        assert_eq!(arg(&args, "--sub-format"), Some("vtt"));
+        assert_eq!(arg(&args, "--sub-langs"), Some("en.*,ja"));
+    }
+
+    #[test]
+    fn geo_blocked_videos_are_told_apart() {
+        let stderr = "ERROR: [youtube] aaaaaaaaaaa: The uploader has not made this video \
+                      available in your country\nERROR: [youtube] aaaaaaaaaaa: Video \
+                      unavailable. This video is not available in your country";
+        let err = download_error(stderr);
+        assert!(matches!(err, VideoProcessError::GeoBlocked(_)));
+        assert_eq!(
+            err.guest_reason(),
+            "This video isn't available in this region, try another upload"
+        );
+
+        let err = download_error("ERROR: [youtube] aaaaaaaaaaa: Private video");
+        assert!(matches!(err, VideoProcessError::DownloadError(_)));
+    }
+
+    #[test]
+    fn geo_bypass_country_is_passed_when_set() {
+        let args_without = args(&downloader(DownloadSettings::default()), &None, false);
+        assert_eq!(arg(&args_without, "--geo-bypass-country"), None);
+
+        let downloader = downloader(DownloadSettings {
+            geo_bypass_country: Some("US".to_string()),
+            ..Default::default()
+        });
+        let args = args(&downloader, &None, false);
+        assert_eq!(arg(&args, "--geo-bypass-country"), Some("US"));
+    }
+}
@@ -1,15 +1,72 @@
+use axum::http::Uri;
 use serde::{Deserialize, Serialize};
+use std::{path::PathBuf, process::Stdio, time::Duration};
 use thiserror::Error;
-use tracing::{debug, info};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, BufReader},
+    process::Command,
+    sync::mpsc,
+};
+use tracing::{debug, info, warn};
 use unidecode::unidecode;
 
-use crate::globals;
+use crate::{settings::SearchSettings, utils::process::ProcessGroups};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SearchResult {
     pub title: String,
     pub url: String,
     pub id: String,
+    pub channel: Option<String>,
+    pub age_limit: Option<u32>,
+    // `YYYYMMDD`, often missing from flat search results
+    pub upload_date: Option<String>,
+}
+
+/// Results returned per search.
+const NUM_RESULTS: u32 = 10;
+
+/// Per-request search switches.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SearchOptions {
+    // append the configured karaoke terms to the query
+    pub karaoke: bool,
+    // drop age-restricted results and titles matching `explicit_terms`
+    pub family_friendly: bool,
+    // overrides the configured `transliterate` for this search
+    pub transliterate: Option<bool>,
+    // only videos uploaded on or after / on or before these dates
+    pub uploaded_after: Option<UploadDate>,
+    pub uploaded_before: Option<UploadDate>,
+}
+
+/// A day in yt-dlp's `YYYYMMDD` form, taken as that or `YYYY-MM-DD`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(try_from = "String")]
+pub struct UploadDate(u32);
+
+impl TryFrom<String> for UploadDate {
+    type Error = String;
+
+    fn try_from(date: String) -> Result<Self, Self::Error> {
+        let digits = date.replace('-', "");
+        let invalid = || format!("invalid date {}, expected YYYYMMDD", date);
+        if digits.len() != 8 || !digits.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let value: u32 = digits.parse().map_err(|_| invalid())?;
+        let (month, day) = (value / 100 % 100, value % 100);
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return Err(invalid());
+        }
+        Ok(UploadDate(value))
+    }
+}
+
+impl std::fmt::Display for UploadDate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:08}", self.0)
+    }
 }
 
 #[derive(Error, Debug)]
@@ -20,63 +77,763 @@ pub enum SearchError {
     JsonParseError(#[from] serde_json::Error),
     #[error("Missing required fields in response")]
     MissingFields,
+    #[error("yt-dlp search failed: {0}")]
+    SearchFailed(String),
+    #[error("Search actor is unavailable")]
+    ActorUnavailable,
+    #[error("Search was superseded by a newer query from the same client")]
+    Superseded,
 }
 
-pub struct YtSearcher {}
+/// Whether a link points at a playlist rather than a single video. A link
+/// that names a video, by a `v` param or a `youtu.be/<id>` path, is treated
+/// as the video even when it also carries a `list`.
+pub fn is_playlist_url(link: &str) -> bool {
+    let uri = if link.contains("://") {
+        link.parse::<Uri>()
+    } else {
+        format!("https://{}", link).parse::<Uri>()
+    };
+    let Ok(uri) = uri else {
+        return false;
+    };
+    if uri.path() == "/playlist" {
+        return true;
+    }
 
-impl YtSearcher {
-    pub async fn search(&self, query: &str) -> Result<Vec<SearchResult>, SearchError> {
-        info!("searching yt-dlp for: {}", query);
+    let params: Vec<&str> = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .map(|param| param.split_once('=').map_or(param, |(name, _)| name))
+        .collect();
+    let short_video = uri.host().is_some_and(|host| host.ends_with("youtu.be"))
+        && !uri.path().trim_matches('/').is_empty();
+    params.contains(&"list") && !params.contains(&"v") && !short_video
+}
+
+/// The non-empty lines of yt-dlp's `-j` output. Each line is validated as
+/// UTF-8 on its own, so one malformed line is skipped instead of lossily
+/// decoding the whole batch.
+fn output_lines(output: &[u8]) -> impl Iterator<Item = &str> {
+    output
+        .split(|byte| *byte == b'\n')
+        .filter_map(|line| match std::str::from_utf8(line) {
+            Ok(line) => Some(line.trim_end_matches('\r')),
+            Err(err) => {
+                warn!("skipping yt-dlp output line that isn't UTF-8: {}", err);
+                None
+            }
+        })
+        .filter(|line| !line.trim().is_empty())
+}
+
+/// Parses one line of yt-dlp's `-j` output.
+fn parse_result(line: &str) -> Result<SearchResult, SearchError> {
+    let json: serde_json::Value = serde_json::from_str(line)?;
+    
+    let title = json.get("title")
+        .and_then(|v| v.as_str())
+        .ok_or(SearchError::MissingFields)?;
         
-        let num_results = 10;
-        let search_query = format!("ytsearch{}:\"{}\"", num_results, unidecode(query));
+    let url = json.get("url")
+        .and_then(|v| v.as_str())
+        .ok_or(SearchError::MissingFields)?;
         
-        let args = [
+    let id = json.get("id")
+        .and_then(|v| v.as_str())
+        .ok_or(SearchError::MissingFields)?;
+
+    let channel = json.get("channel")
+        .or_else(|| json.get("uploader"))
+        .and_then(|v| v.as_str());
+
+    // rarely present in flat search results
+    let age_limit = json.get("age_limit")
+        .and_then(|v| v.as_u64())
+        .map(|age_limit| age_limit as u32);
+
+    let upload_date = json.get("upload_date").and_then(|v| v.as_str());
+
+    Ok(SearchResult {
+        title: title.to_string(),
+        url: url.to_string(),
+        id: id.to_string(),
+        channel: channel.map(|channel| channel.to_string()),
+        age_limit,
+        upload_date: upload_date.map(|date| date.to_string()),
+    })
+}
+
+/// Whether a result is dated outside the requested upload range. yt-dlp
+/// filters by the same dates, but flat search results often carry none, so
+/// undated results are kept rather than guessed at.
+fn outside_date_range(result: &SearchResult, options: &SearchOptions) -> bool {
+    let Some(date) = result
+        .upload_date
+        .clone()
+        .and_then(|date| UploadDate::try_from(date).ok())
+    else {
+        return false;
+    };
+    let outside = options.uploaded_after.is_some_and(|after| date < after)
+        || options.uploaded_before.is_some_and(|before| date > before);
+    if outside {
+        debug!("filtered search result uploaded {}: {}", date, result.title);
+    }
+    outside
+}
+
+/// One result of a search, or `None` for a line that isn't one, which is
+/// skipped rather than failing the whole search.
+fn parse_search_result(line: &str) -> Option<SearchResult> {
+    parse_result(line)
+        .map_err(|err| warn!("skipping unparseable search result: {}", err))
+        .ok()
+}
+
+/// Private and deleted playlist entries are still listed by a flat
+/// extraction, with a placeholder title.
+fn is_unavailable(result: &SearchResult) -> bool {
+    matches!(result.title.as_str(), "[Private video]" | "[Deleted video]")
+}
+
+/// The available entries of a flat playlist extraction.
+fn playlist_entries(output: &[u8]) -> Vec<SearchResult> {
+    output_lines(output)
+        .filter_map(|line| match parse_result(line) {
+            Ok(result) => Some(result),
+            Err(err) => {
+                debug!("skipping unparseable playlist entry: {}", err);
+                None
+            }
+        })
+        .filter(|result| {
+            let unavailable = is_unavailable(result);
+            if unavailable {
+                debug!("skipping unavailable playlist entry: {}", result.id);
+            }
+            !unavailable
+        })
+        .collect()
+}
+
+pub struct YtSearcher {
+    ytdlp_path: PathBuf,
+    processes: ProcessGroups,
+    settings: SearchSettings,
+}
+
+impl YtSearcher {
+    pub fn new(ytdlp_path: PathBuf, processes: ProcessGroups, settings: SearchSettings) -> Self {
+        YtSearcher {
+            ytdlp_path,
+            processes,
+            settings,
+        }
+    }
+
+    fn build_search_query(&self, query: &str, options: SearchOptions, num_results: u32) -> String {
+        let karaoke_terms = self.settings.karaoke_terms.trim();
+        let query = if options.karaoke && !karaoke_terms.is_empty() {
+            format!("{} {}", query, karaoke_terms)
+        } else {
+            query.to_string()
+        };
+        // ASCII helps match romanized titles but mangles e.g. Japanese ones
+        let query = if options.transliterate.unwrap_or(self.settings.transliterate) {
+            unidecode(&query)
+        } else {
+            query
+        };
+
+        format!("ytsearch{}:\"{}\"", num_results, query)
+    }
+
+    /// The yt-dlp arguments for a search, ending with the query itself.
+    fn search_args(&self, query: &str, options: SearchOptions) -> Vec<String> {
+        // over-fetch when ranking or filtering so there are enough results
+        // left to fill the page
+        let fetch_count = if self.settings.rank_results || options.family_friendly {
+            NUM_RESULTS * 2
+        } else {
+            NUM_RESULTS
+        };
+        let search_query = self.build_search_query(query, options, fetch_count);
+
+        let mut args = [
             "-j",
             "--no-playlist",
             "--flat-playlist",
             "--match-filter",
             "!is_channel",
-            &search_query,
-        ];
-        
+        ]
+        .map(String::from)
+        .to_vec();
+        if let Some(after) = options.uploaded_after {
+            args.extend(["--dateafter".to_string(), after.to_string()]);
+        }
+        if let Some(before) = options.uploaded_before {
+            args.extend(["--datebefore".to_string(), before.to_string()]);
+        }
+        args.push(search_query);
         debug!("yt-dlp search command: {:?}", args.join(" "));
+        args
+    }
 
+    pub async fn search(
+        &self,
+        query: &str,
+        options: SearchOptions,
+        retries: u32,
+    ) -> Result<Vec<SearchResult>, SearchError> {
+        info!("searching yt-dlp for: {} ({:?})", query, options);
 
-        let ytdlp_path = globals::get_binary_path("yt-dlp");
-        debug!("Using yt-dlp from path: {}", ytdlp_path.display());
-
-        let output = std::process::Command::new(ytdlp_path)
-            .args(&args)
-            .output()?;
-
-        let output_str = String::from_utf8_lossy(&output.stdout);
-        debug!("search results: {}", output_str);
-
-        output_str
-            .lines()
-            .filter(|line| !line.trim().is_empty())
-            .map(|line| {
-                let json: serde_json::Value = serde_json::from_str(line)?;
-                
-                let title = json.get("title")
-                    .and_then(|v| v.as_str())
-                    .ok_or(SearchError::MissingFields)?;
-                    
-                let url = json.get("url")
-                    .and_then(|v| v.as_str())
-                    .ok_or(SearchError::MissingFields)?;
-                    
-                let id = json.get("id")
-                    .and_then(|v| v.as_str())
-                    .ok_or(SearchError::MissingFields)?;
-
-                Ok(SearchResult {
-                    title: title.to_string(),
-                    url: url.to_string(),
-                    id: id.to_string(),
-                })
-            })
+        let args = self.search_args(query, options);
+        let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+
+        let output = self.run_with_retries(&args, retries).await?;
+
+        debug!("search results: {}", String::from_utf8_lossy(&output));
+
+        let results: Vec<_> = output_lines(&output)
+            .filter_map(parse_search_result)
+            .filter(|result| !outside_date_range(result, &options))
+            .collect();
+
+        let results = if options.family_friendly {
+            self.filter_explicit(results)
+        } else {
+            results
+        };
+
+        Ok(self.rank_results(results, NUM_RESULTS as usize))
+    }
+
+    /// Like `search`, but sends each result on `results` as soon as yt-dlp
+    /// prints it, so clients can render them as they arrive. Ranking needs
+    /// every result first, so with `rank_results` set they are sent together
+    /// at the end. Stops once the receiver is dropped. A failure is returned
+    /// rather than sent, for the caller to report.
+    pub async fn search_streaming(
+        &self,
+        query: &str,
+        options: SearchOptions,
+        retries: u32,
+        results: &mpsc::Sender<Result<SearchResult, SearchError>>,
+    ) -> Result<(), SearchError> {
+        info!("streaming yt-dlp search for: {} ({:?})", query, options);
+
+        let args = self.search_args(query, options);
+        let mut attempt = 0;
+        let mut sent = 0;
+        loop {
+            match self.stream_search(&args, options, results, &mut sent).await {
+                Ok(()) => return Ok(()),
+                // once results were sent, a retry would send them again
+                Err(err) if attempt < retries && sent == 0 => {
+                    attempt += 1;
+                    let backoff = Duration::from_millis(500 * attempt as u64);
+                    warn!(
+                        "search attempt {} failed, retrying in {:?}: {}",
+                        attempt, backoff, err
+                    );
+                    tokio::time::sleep(backoff).await;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn stream_search(
+        &self,
+        args: &[String],
+        options: SearchOptions,
+        results: &mpsc::Sender<Result<SearchResult, SearchError>>,
+        sent: &mut u32,
+    ) -> Result<(), SearchError> {
+        debug!("Using yt-dlp from path: {}", self.ytdlp_path.display());
+
+        // killed on drop, so returning early stops the search
+        let (mut child, group) = self.processes.spawn(
+            Command::new(&self.ytdlp_path)
+                .args(args)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped()),
+        )?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| std::io::Error::other("yt-dlp stdout was not captured"))?;
+        // drained alongside stdout so a chatty yt-dlp can't block on a full pipe
+        let stderr = child.stderr.take();
+        let stderr_task = tokio::spawn(async move {
+            let mut buf = Vec::new();
+            if let Some(mut stderr) = stderr {
+                let _ = stderr.read_to_end(&mut buf).await;
+            }
+            buf
+        });
+
+        let mut printed = false;
+        let mut to_rank = Vec::new();
+        let mut lines = BufReader::new(stdout).split(b'\n');
+        while let Some(line) = lines.next_segment().await? {
+            for line in output_lines(&line) {
+                printed = true;
+                let Some(result) = parse_search_result(line) else {
+                    continue;
+                };
+                if outside_date_range(&result, &options) {
+                    continue;
+                }
+                if options.family_friendly && self.is_explicit(&result) {
+                    continue;
+                }
+                if self.settings.rank_results {
+                    to_rank.push(result);
+                    continue;
+                }
+
+                if results.send(Ok(result)).await.is_err() {
+                    debug!("search receiver dropped, stopping yt-dlp");
+                    return Ok(());
+                }
+                *sent += 1;
+                if *sent == NUM_RESULTS {
+                    return Ok(());
+                }
+            }
+        }
+
+        let status = child.wait().await?;
+        group.finished();
+        if !status.success() && !printed {
+            let stderr = stderr_task.await.unwrap_or_default();
+            let stderr = String::from_utf8_lossy(&stderr);
+            return Err(SearchError::SearchFailed(stderr.trim().to_string()));
+        }
+
+        for result in self.rank_results(to_rank, NUM_RESULTS as usize) {
+            if results.send(Ok(result)).await.is_err() {
+                break;
+            }
+            *sent += 1;
+        }
+        Ok(())
+    }
+
+    /// Drops age-restricted results and those whose title contains one of the
+    /// configured explicit terms. Flat search results seldom include
+    /// `age_limit`, so in practice this is a best-effort title match.
+    fn filter_explicit(&self, results: Vec<SearchResult>) -> Vec<SearchResult> {
+        results
+            .into_iter()
+            .filter(|result| !self.is_explicit(result))
             .collect()
     }
-}
\ No newline at end of file
+
+    fn is_explicit(&self, result: &SearchResult) -> bool {
+        let title = words(&result.title);
+        let is_explicit = result.age_limit.is_some_and(|age_limit| age_limit >= 18)
+            || self
+                .settings
+                .explicit_terms
+                .iter()
+                .any(|term| contains_phrase(&title, term));
+        if is_explicit {
+            debug!("filtered explicit search result: {}", result.title);
+        }
+        is_explicit
+    }
+
+    /// Lists up to `max_items` available entries of a playlist.
+    pub async fn expand_playlist(
+        &self,
+        url: &str,
+        max_items: usize,
+        retries: u32,
+    ) -> Result<Vec<SearchResult>, SearchError> {
+        info!("expanding playlist: {} (max {} items)", url, max_items);
+
+        let max_items = max_items.to_string();
+        let args = ["-j", "--flat-playlist", "--playlist-end", &max_items, "--", url];
+        let output = self.run_with_retries(&args, retries).await?;
+        Ok(playlist_entries(&output))
+    }
+
+    async fn run_with_retries(&self, args: &[&str], retries: u32) -> Result<Vec<u8>, SearchError> {
+        let mut attempt = 0;
+        loop {
+            match self.run_search(args).await {
+                Ok(output) => return Ok(output),
+                Err(err) if attempt < retries => {
+                    attempt += 1;
+                    let backoff = Duration::from_millis(500 * attempt as u64);
+                    warn!(
+                        "search attempt {} failed, retrying in {:?}: {}",
+                        attempt, backoff, err
+                    );
+                    tokio::time::sleep(backoff).await;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn run_search(&self, args: &[&str]) -> Result<Vec<u8>, SearchError> {
+        debug!("Using yt-dlp from path: {}", self.ytdlp_path.display());
+
+        let output = self
+            .processes
+            .output(Command::new(&self.ytdlp_path).args(args))
+            .await?;
+        // a single unavailable entry fails the exit status while the other
+        // results are still printed, so only fail when nothing came back
+        if !output.status.success() && output.stdout.is_empty() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(SearchError::SearchFailed(stderr.trim().to_string()));
+        }
+
+        Ok(output.stdout)
+    }
+
+    /// Sorts results by keyword score when ranking is enabled, then truncates
+    /// to `count`. The sort is stable so ties keep yt-dlp's order.
+    fn rank_results(&self, mut results: Vec<SearchResult>, count: usize) -> Vec<SearchResult> {
+        if self.settings.rank_results {
+            results.sort_by_cached_key(|result| std::cmp::Reverse(self.score(result)));
+        }
+
+        results.truncate(count);
+        results
+    }
+
+    fn score(&self, result: &SearchResult) -> i32 {
+        let title = words(&result.title);
+        let channel = words(result.channel.as_deref().unwrap_or_default());
+
+        self.settings
+            .keyword_weights
+            .iter()
+            .filter(|(keyword, _)| {
+                contains_phrase(&title, keyword) || contains_phrase(&channel, keyword)
+            })
+            .map(|(_, weight)| weight)
+            .sum()
+    }
+}
+
+/// The lowercased words of `text`, split on anything not alphanumeric.
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Whether the words of `phrase` appear consecutively in `words`, so "live"
+/// matches "Live at Wembley" but not "Delivery".
+fn contains_phrase(words: &[String], phrase: &str) -> bool {
+    let phrase = self::words(phrase);
+    !phrase.is_empty() && words.windows(phrase.len()).any(|window| window == phrase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(title: &str, channel: Option<&str>) -> SearchResult {
+        SearchResult {
+            title: title.to_string(),
+            url: format!("https://www.youtube.com/watch?v={}", title.len()),
+            id: title.to_string(),
+            channel: channel.map(str::to_string),
+            age_limit: None,
+            upload_date: None,
+        }
+    }
+
+    fn searcher(settings: SearchSettings) -> YtSearcher {
+        YtSearcher::new(PathBuf::new(), ProcessGroups::default(), settings)
+    }
+
+    fn titles(results: &[SearchResult]) -> Vec<&str> {
+        results.iter().map(|result| result.title.as_str()).collect()
+    }
+
+    #[test]
+    fn ranking_matches_whole_words() {
+        let searcher = searcher(SearchSettings {
+            rank_results: true,
+            ..Default::default()
+        });
+        let results = vec![
+            result("Song (Live Reaction)", None),
+            result("Delivery Song", None),
+            result("Song Cover", None),
+            result("Song", Some("Sing King")),
+            result("Song (Karaoke Version)", None),
+            result("Songkaraoke", Some("Singking")),
+        ];
+
+        let ranked = searcher.rank_results(results, 10);
+        assert_eq!(
+            titles(&ranked),
+            [
+                "Song (Karaoke Version)",
+                "Song",
+                "Delivery Song",
+                "Songkaraoke",
+                "Song Cover",
+                "Song (Live Reaction)",
+            ]
+        );
+    }
+
+    #[test]
+    fn ranking_is_off_by_default_and_still_truncates() {
+        let searcher = searcher(SearchSettings::default());
+        let results = vec![
+            result("Song Live", None),
+            result("Song Karaoke", None),
+            result("Song Cover", None),
+        ];
+
+        let ranked = searcher.rank_results(results, 2);
+        assert_eq!(titles(&ranked), ["Song Live", "Song Karaoke"]);
+    }
+
+    #[test]
+    fn explicit_terms_match_whole_words() {
+        let searcher = searcher(SearchSettings {
+            explicit_terms: vec!["nsfw".to_string(), "dirty version".to_string()],
+            ..Default::default()
+        });
+        let mut adult = result("Adult Song", None);
+        adult.age_limit = Some(18);
+        let results = vec![
+            result("Song [NSFW]", None),
+            result("Song (Dirty Version)", None),
+            result("Dirty Dancing (Radio Version)", None),
+            result("Nsfwish Song", None),
+            adult,
+        ];
+
+        let filtered = searcher.filter_explicit(results);
+        assert_eq!(
+            titles(&filtered),
+            ["Dirty Dancing (Radio Version)", "Nsfwish Song"]
+        );
+    }
+
+    #[test]
+    fn karaoke_mode_appends_the_configured_terms() {
+        let with_terms = searcher(SearchSettings {
+            karaoke_terms: " karaoke instrumental ".to_string(),
+            ..Default::default()
+        });
+        let karaoke = SearchOptions {
+            karaoke: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            with_terms.build_search_query("Song", karaoke, 10),
+            "ytsearch10:\"Song karaoke instrumental\""
+        );
+        assert_eq!(
+            with_terms.build_search_query("Song", SearchOptions::default(), 10),
+            "ytsearch10:\"Song\""
+        );
+
+        let no_terms = searcher(SearchSettings {
+            karaoke_terms: " ".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(
+            no_terms.build_search_query("Song", karaoke, 20),
+            "ytsearch20:\"Song\""
+        );
+    }
+
+    #[test]
+    fn family_friendly_searches_filter_by_age() {
+        let searcher = searcher(SearchSettings::default());
+        let mut teen = result("Teen Song", None);
+        teen.age_limit = Some(13);
+        let mut adult = result("Adult Song", None);
+        adult.age_limit = Some(18);
+        let filtered = searcher.filter_explicit(vec![teen, adult]);
+        assert_eq!(titles(&filtered), ["Teen Song"]);
+    }
+
+    #[test]
+    fn playlist_links_are_told_apart_from_videos() {
+        assert!(is_playlist_url("https://youtube.com/playlist?list=PL1"));
+        assert!(is_playlist_url("https://youtube.com/watch?list=PL1"));
+        // a video played from a playlist is queued as the video
+        assert!(!is_playlist_url("https://youtube.com/watch?v=a&list=PL1"));
+        assert!(!is_playlist_url("https://youtu.be/a"));
+        assert!(!is_playlist_url("https://youtu.be/a?list=PL1"));
+        assert!(!is_playlist_url("https://youtube.com/watch?list=PL1&v=a"));
+        // a list param alone is still a playlist, wherever it sits
+        assert!(is_playlist_url("https://youtube.com/watch?feature=share&list=PL1"));
+        assert!(is_playlist_url("youtube.com/playlist?list=PL1"));
+        // "v=" inside another param doesn't name a video
+        assert!(is_playlist_url("https://youtube.com/watch?list=PL1&nav=1"));
+    }
+
+    #[test]
+    fn unavailable_playlist_entries_are_skipped() {
+        let output = br#"{"id": "a", "title": "First", "url": "https://www.youtube.com/watch?v=a"}
+{"id": "b", "title": "[Private video]", "url": "https://www.youtube.com/watch?v=b"}
+not json
+{"id": "c", "title": "[Deleted video]", "url": "https://www.youtube.com/watch?v=c"}
+{"id": "d", "title": "Second", "url": "https://www.youtube.com/watch?v=d"}
+"#;
+        assert_eq!(titles(&playlist_entries(output)), ["First", "Second"]);
+    }
+
+    #[test]
+    fn multibyte_titles_survive_a_malformed_line() {
+        let title = "Кино – Группа крови 残酷な天使のテーゼ 🎤";
+        let mut output = format!(
+            "{}\r\n",
+            serde_json::json!({ "id": "a", "title": title, "url": "https://youtu.be/a" })
+        )
+        .into_bytes();
+        // a line cut inside a multibyte character
+        output.extend_from_slice(b"{\"id\": \"b\", \"title\": \"\xe6\xae\"}\n");
+
+        let lines = output_lines(&output).collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1);
+        let result = parse_result(lines[0]).unwrap();
+        assert_eq!(result.title, title);
+    }
+
+    #[test]
+    fn transliteration_can_be_turned_off() {
+        let query = "夜に駆ける";
+        let transliterating = searcher(SearchSettings::default());
+        let verbatim = SearchOptions {
+            transliterate: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(
+            transliterating.build_search_query(query, verbatim, 10),
+            "ytsearch10:\"夜に駆ける\""
+        );
+        let transliterated = transliterating.build_search_query(query, Default::default(), 10);
+        assert!(transliterated.is_ascii());
+
+        // a configured default is still overridden per request
+        let raw = searcher(SearchSettings {
+            transliterate: false,
+            ..Default::default()
+        });
+        assert_eq!(
+            raw.build_search_query(query, SearchOptions::default(), 10),
+            "ytsearch10:\"夜に駆ける\""
+        );
+        let ascii = SearchOptions {
+            transliterate: Some(true),
+            ..Default::default()
+        };
+        assert!(raw.build_search_query(query, ascii, 10).is_ascii());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn streamed_results_arrive_before_the_search_ends() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let ytdlp = dir.path().join("yt-dlp");
+        let go = dir.path().join("go");
+        // the second result is held back until the test has seen the first
+        let script = format!(
+            "#!/bin/sh\n\
+             echo '{{\"title\":\"First\",\"url\":\"u1\",\"id\":\"1\"}}'\n\
+             echo 'not json'\n\
+             while [ ! -f {} ]; do sleep 0.05; done\n\
+             echo '{{\"title\":\"Second\",\"url\":\"u2\",\"id\":\"2\"}}'\n",
+            go.display()
+        );
+        std::fs::write(&ytdlp, script).unwrap();
+        std::fs::set_permissions(&ytdlp, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let searcher = YtSearcher::new(ytdlp, ProcessGroups::default(), Default::default());
+        let (send, mut recv) = mpsc::channel(NUM_RESULTS as usize);
+        let search = tokio::spawn(async move {
+            searcher
+                .search_streaming("Song", SearchOptions::default(), 0, &send)
+                .await
+        });
+
+        let first = recv.recv().await.unwrap().unwrap();
+        assert_eq!(first.title, "First");
+        std::fs::write(&go, "").unwrap();
+        let second = recv.recv().await.unwrap().unwrap();
+        assert_eq!(second.title, "Second");
+
+        search.await.unwrap().unwrap();
+        assert!(recv.recv().await.is_none());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unparseable_lines_are_skipped_by_batch_searches() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let ytdlp = dir.path().join("yt-dlp");
+        let script = "#!/bin/sh\n\
+             echo '{\"title\":\"First\",\"url\":\"u1\",\"id\":\"1\"}'\n\
+             echo 'not json'\n\
+             echo '{\"title\":\"Second\",\"url\":\"u2\",\"id\":\"2\"}'\n";
+        std::fs::write(&ytdlp, script).unwrap();
+        std::fs::set_permissions(&ytdlp, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let searcher = YtSearcher::new(ytdlp, ProcessGroups::default(), Default::default());
+        let results = searcher
+            .search("Song", SearchOptions::default(), 0)
+            .await
+            .unwrap();
+        assert_eq!(titles(&results), ["First", "Second"]);
+    }
+
+    #[test]
+    fn upload_date_bounds_are_passed_to_yt_dlp() {
+        let date = |date: &str| UploadDate::try_from(date.to_string()).unwrap();
+        let searcher = searcher(SearchSettings::default());
+        let bounded = SearchOptions {
+            uploaded_after: Some(date("2020-01-31")),
+            uploaded_before: Some(date("20241231")),
+            ..Default::default()
+        };
+
+        let args = searcher.search_args("Song", bounded);
+        let arg = |flag| {
+            let index = args.iter().position(|arg| arg == flag)?;
+            args.get(index + 1).map(String::as_str)
+        };
+        assert_eq!(arg("--dateafter"), Some("20200131"));
+        assert_eq!(arg("--datebefore"), Some("20241231"));
+        assert_eq!(args.last().unwrap(), "ytsearch10:\"Song\"");
+
+        let unbounded = searcher.search_args("Song", SearchOptions::default());
+        assert!(!unbounded.iter().any(|arg| arg.starts_with("--date")));
+
+        // undated results are kept, dated ones outside the range dropped
+        let mut old = result("Old", None);
+        old.upload_date = Some("20190101".to_string());
+        let mut recent = result("Recent", None);
+        recent.upload_date = Some("20230601".to_string());
+        assert!(outside_date_range(&old, &bounded));
+        assert!(!outside_date_range(&recent, &bounded));
+        assert!(!outside_date_range(&result("Undated", None), &bounded));
+
+        assert!(UploadDate::try_from("2020-13-01".to_string()).is_err());
+    }
+}